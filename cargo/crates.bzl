@@ -31,6 +31,56 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.adler-0.2.3.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__aead__0_3_2",
+        url = "https://crates.io/api/v1/crates/aead/0.3.2/download",
+        type = "tar.gz",
+        sha256 = "7fc95d1bdb8e6666b2b217308eeeb09f2d6728d104be3e31916cc74d15420331",
+        strip_prefix = "aead-0.3.2",
+        build_file = Label("//cargo/remote:BUILD.aead-0.3.2.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__aes_siv__0_5_0",
+        url = "https://crates.io/api/v1/crates/aes-siv/0.5.0/download",
+        type = "tar.gz",
+        sha256 = "d0efed46e44a34d455eff2465e57627853b0e33c44658284f383691c7a634c8b",
+        strip_prefix = "aes-siv-0.5.0",
+        build_file = Label("//cargo/remote:BUILD.aes-siv-0.5.0.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__aes_soft__0_6_4",
+        url = "https://crates.io/api/v1/crates/aes-soft/0.6.4/download",
+        type = "tar.gz",
+        sha256 = "be14c7498ea50828a38d0e24a765ed2effe92a705885b57d029cd67d45744072",
+        strip_prefix = "aes-soft-0.6.4",
+        build_file = Label("//cargo/remote:BUILD.aes-soft-0.6.4.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__aes__0_6_0",
+        url = "https://crates.io/api/v1/crates/aes/0.6.0/download",
+        type = "tar.gz",
+        sha256 = "884391ef1066acaa41e766ba8f596341b96e93ce34f9a43e7d24bf0a0eaf0561",
+        strip_prefix = "aes-0.6.0",
+        build_file = Label("//cargo/remote:BUILD.aes-0.6.0.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__aesni__0_10_0",
+        url = "https://crates.io/api/v1/crates/aesni/0.10.0/download",
+        type = "tar.gz",
+        sha256 = "ea2e11f5e94c2f7d386164cc2aa1f97823fed6f259e486940a71c174dd01b0ce",
+        strip_prefix = "aesni-0.10.0",
+        build_file = Label("//cargo/remote:BUILD.aesni-0.10.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__ahash__0_4_7",
@@ -251,6 +301,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.blake3-0.3.7.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__block_buffer__0_9_0",
+        url = "https://crates.io/api/v1/crates/block-buffer/0.9.0/download",
+        type = "tar.gz",
+        sha256 = "4152116fd6e9dadb291ae18fc1ec3575ed6d84c29642d97890f4b4a3417297e4",
+        strip_prefix = "block-buffer-0.9.0",
+        build_file = Label("//cargo/remote:BUILD.block-buffer-0.9.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__bumpalo__3_4_0",
@@ -341,6 +401,26 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.chrono-0.4.19.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__cipher__0_2_5",
+        url = "https://crates.io/api/v1/crates/cipher/0.2.5/download",
+        type = "tar.gz",
+        sha256 = "12f8e7987cbd042a63249497f41aed09f8e65add917ea6566effbc56578d6801",
+        strip_prefix = "cipher-0.2.5",
+        build_file = Label("//cargo/remote:BUILD.cipher-0.2.5.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__cmac__0_5_1",
+        url = "https://crates.io/api/v1/crates/cmac/0.5.1/download",
+        type = "tar.gz",
+        sha256 = "73d4de4f7724e5fe70addfb2bd37c2abd2f95084a429d7773b0b9645499b4272",
+        strip_prefix = "cmac-0.5.1",
+        build_file = Label("//cargo/remote:BUILD.cmac-0.5.1.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__coarsetime__0_1_18",
@@ -361,6 +441,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.constant_time_eq-0.1.5.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__cpuid_bool__0_1_2",
+        url = "https://crates.io/api/v1/crates/cpuid-bool/0.1.2/download",
+        type = "tar.gz",
+        sha256 = "8aebca1129a03dc6dc2b127edd729435bbc4a37e1d5f4d7513165089ceb02634",
+        strip_prefix = "cpuid-bool-0.1.2",
+        build_file = Label("//cargo/remote:BUILD.cpuid-bool-0.1.2.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__core_foundation__0_9_1",
@@ -421,6 +511,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.crossbeam-utils-0.8.1.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__crypto_mac__0_10_0",
+        url = "https://crates.io/api/v1/crates/crypto-mac/0.10.0/download",
+        type = "tar.gz",
+        sha256 = "4857fd85a0c34b3c3297875b747c1e02e06b6a0ea32dd892d8192b9ce0813ea6",
+        strip_prefix = "crypto-mac-0.10.0",
+        build_file = Label("//cargo/remote:BUILD.crypto-mac-0.10.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__crypto_mac__0_8_0",
@@ -441,6 +541,26 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.ctor-0.1.17.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__ctr__0_6_0",
+        url = "https://crates.io/api/v1/crates/ctr/0.6.0/download",
+        type = "tar.gz",
+        sha256 = "fb4a30d54f7443bf3d6191dcd486aca19e67cb3c49fa7a06a319966346707e7f",
+        strip_prefix = "ctr-0.6.0",
+        build_file = Label("//cargo/remote:BUILD.ctr-0.6.0.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__dbl__0_3_0",
+        url = "https://crates.io/api/v1/crates/dbl/0.3.0/download",
+        type = "tar.gz",
+        sha256 = "2735145c3b9ba15f2d7a3ae8cdafcbc8c98a7bef7f62afe9d08bd99fbf7130de",
+        strip_prefix = "dbl-0.3.0",
+        build_file = Label("//cargo/remote:BUILD.dbl-0.3.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__derivative__2_1_3",
@@ -891,6 +1011,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.hex-0.4.2.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__hmac__0_10_1",
+        url = "https://crates.io/api/v1/crates/hmac/0.10.1/download",
+        type = "tar.gz",
+        sha256 = "c1441c6b1e930e2817404b5046f1f989899143a12bf92de603b69f4e0aee1e15",
+        strip_prefix = "hmac-0.10.1",
+        build_file = Label("//cargo/remote:BUILD.hmac-0.10.1.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__htmlescape__0_3_1",
@@ -1411,6 +1541,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.once_cell-1.5.2.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__opaque_debug__0_3_0",
+        url = "https://crates.io/api/v1/crates/opaque-debug/0.3.0/download",
+        type = "tar.gz",
+        sha256 = "624a8340c38c1b80fd549087862da4ba43e08858af025b236e509b6649fc13d5",
+        strip_prefix = "opaque-debug-0.3.0",
+        build_file = Label("//cargo/remote:BUILD.opaque-debug-0.3.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__openssl__0_10_32",
@@ -1471,6 +1611,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.paste-1.0.4.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__pbkdf2__0_6_0",
+        url = "https://crates.io/api/v1/crates/pbkdf2/0.6.0/download",
+        type = "tar.gz",
+        sha256 = "b3b8c0d71734018084da0c0354193a5edfb81b20d2d57a92c5b154aefc554a4a",
+        strip_prefix = "pbkdf2-0.6.0",
+        build_file = Label("//cargo/remote:BUILD.pbkdf2-0.6.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__percent_encoding__2_1_0",
@@ -1571,6 +1721,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.pkg-config-0.3.19.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__pmac__0_5_1",
+        url = "https://crates.io/api/v1/crates/pmac/0.5.1/download",
+        type = "tar.gz",
+        sha256 = "e1c257eb89109a7e6115f40d44ca6d1816ecf9e90a1b38f6d477c78c1de505cb",
+        strip_prefix = "pmac-0.5.1",
+        build_file = Label("//cargo/remote:BUILD.pmac-0.5.1.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__podio__0_1_7",
@@ -1901,6 +2061,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.ryu-1.0.5.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__salsa20__0_7_2",
+        url = "https://crates.io/api/v1/crates/salsa20/0.7.2/download",
+        type = "tar.gz",
+        sha256 = "399f290ffc409596022fce5ea5d4138184be4784f2b28c62c59f0d8389059a15",
+        strip_prefix = "salsa20-0.7.2",
+        build_file = Label("//cargo/remote:BUILD.salsa20-0.7.2.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__schannel__0_1_19",
@@ -1921,6 +2091,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.scopeguard-1.1.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__scrypt__0_5_0",
+        url = "https://crates.io/api/v1/crates/scrypt/0.5.0/download",
+        type = "tar.gz",
+        sha256 = "8da492dab03f925d977776a0b7233d7b934d6dc2b94faead48928e2e9bacedb9",
+        strip_prefix = "scrypt-0.5.0",
+        build_file = Label("//cargo/remote:BUILD.scrypt-0.5.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__sct__0_6_0",
@@ -2041,6 +2221,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.sha1-0.6.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__sha2__0_9_2",
+        url = "https://crates.io/api/v1/crates/sha2/0.9.2/download",
+        type = "tar.gz",
+        sha256 = "6e7aab86fe2149bad8c507606bdb3f4ef5e7b2380eb92350f56122cca72a42a8",
+        strip_prefix = "sha2-0.9.2",
+        build_file = Label("//cargo/remote:BUILD.sha2-0.9.2.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__slab__0_4_2",
@@ -2821,6 +3011,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//cargo/remote:BUILD.wyz-0.2.0.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__zeroize__1_2_0",
+        url = "https://crates.io/api/v1/crates/zeroize/1.2.0/download",
+        type = "tar.gz",
+        sha256 = "81a974bcdd357f0dca4d41677db03436324d45a4c9ed2d0b873a5a360ce41c36",
+        strip_prefix = "zeroize-1.2.0",
+        build_file = Label("//cargo/remote:BUILD.zeroize-1.2.0.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__zip__0_5_6",
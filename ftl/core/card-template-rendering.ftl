@@ -21,6 +21,9 @@ card-template-rendering-conditional-not-open = Found '{ $found }', but missing '
 # when the user referenced a field that doesn't exist
 # eg, Found '{{Field}}', but there is not field called 'Field'
 card-template-rendering-no-such-field = Found '{ $found }', but there is no field called '{ $field }'
+# when the user compared a special field whose content depends on the card
+# eg, Found '{{#Deck:is=Spanish}}', but only fields and tags can be compared
+card-template-rendering-unsupported-comparison = Found '{ $found }', but only fields and tags can be compared
 # This message is shown when the front side of the card is blank,
 # either due to a badly-designed template, or because required fields
 # are missing.
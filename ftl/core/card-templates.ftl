@@ -19,6 +19,8 @@ card-templates-night-mode = Night Mode
 card-templates-add-mobile-class = Add Mobile Class
 card-templates-preview-settings = Options
 card-templates-invalid-template-number = Card template { $number } has a problem.
+card-templates-missing-front-side = The back template does not show the front of the card. Consider adding { "{{FrontSide}}" }.
+card-templates-missing-cloze-filter = The front template of a cloze note type should contain a cloze field, such as { "{{cloze:Text}}" }.
 card-templates-changes-saved = Changes saved.
 card-templates-discard-changes = Discard changes?
 card-templates-add-card-type = Add Card Type...
@@ -14,3 +14,10 @@ fields-sort-by-this-field-in-the = Sort by this field in the browser
 fields-that-field-name-is-already-used = That field name is already used.
 fields-name-first-letter-not-valid = The field name should not start with #, ^ or /.
 fields-name-invalid-letter = The field name should not contain :, ", { "{" } or { "}" }.
+fields-required-field-empty = The field '{ $field }' is required.
+fields-pattern-mismatch = The content of '{ $field }' does not match the expected format.
+fields-too-long =
+    { $max ->
+        [one] The field '{ $field }' is limited to { $max } character.
+       *[other] The field '{ $field }' is limited to { $max } characters.
+    }
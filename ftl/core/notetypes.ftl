@@ -5,6 +5,9 @@ notetypes-back-field = Back
 notetypes-add-reverse-field = Add Reverse
 notetypes-text-field = Text
 notetypes-back-extra-field = Back Extra
+notetypes-image-field = Image
+notetypes-masks-field = Masks
+notetypes-header-field = Header
 
 ## Default note type names
 
@@ -13,6 +16,7 @@ notetypes-basic-reversed-name = Basic (and reversed card)
 notetypes-basic-optional-reversed-name = Basic (optional reversed card)
 notetypes-basic-type-answer-name = Basic (type in the answer)
 notetypes-cloze-name = Cloze
+notetypes-image-occlusion-name = Image Occlusion
 
 ## Default card template names
 
@@ -41,6 +41,10 @@ sync-account-required =
     A free account is required to keep your collection synchronized. Please <a href="{ $link }">sign up</a> for an account, then enter your details below.
 sync-sanity-check-failed = Please use the Check Database function, then sync again. If problems persist, please force a full sync in the preferences screen.
 sync-clock-off = Unable to sync - your clock is not set to the correct time.
+sync-encryption-limitations =
+    Note fields, deck names and media files will be encrypted before they are sent. Tags, note types (including their fields and card templates), the searches of filtered decks and review history are still sent unencrypted.
+    
+    Your other devices will need the passphrase to sync. If it is lost, the collection can't be recovered from the server.
 
 ## Buttons
 
@@ -8,3 +8,4 @@ class Backend:
     def __init__(self, *args, **kwargs) -> None: ...
     def command(self, *args, **kwargs) -> Any: ...
     def db_command(self, *args, **kwargs) -> Any: ...
+    def register_template_filter(self, *args, **kwargs) -> Any: ...
@@ -290,6 +290,25 @@ class Collection:
         self.backend.before_upload()
         self.close(save=False, downgrade=True)
 
+    def enable_sync_encryption(self, passphrase: str) -> None:
+        """Encrypt note fields, deck names and media when syncing. Tags,
+        notetypes and filtered deck searches are not encrypted; see
+        sync_encryption_limitations(). Forces a full sync."""
+        self.save(trx=False)
+        self.backend.enable_sync_encryption(passphrase)
+
+    def disable_sync_encryption(self) -> None:
+        "Sync unencrypted again. Forces a full sync."
+        self.save(trx=False)
+        self.backend.disable_sync_encryption()
+
+    def sync_encryption_enabled(self) -> bool:
+        return self.backend.sync_encryption_enabled()
+
+    def sync_encryption_limitations(self) -> str:
+        "What is still sent unencrypted, to show before encryption is enabled."
+        return self.tr(TR.SYNC_ENCRYPTION_LIMITATIONS)
+
     # Object creation helpers
     ##########################################################################
 
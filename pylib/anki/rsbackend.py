@@ -20,7 +20,7 @@ import enum
 import json
 import os
 from dataclasses import dataclass
-from typing import TYPE_CHECKING, Any, Dict, List, Optional, Sequence, Union
+from typing import TYPE_CHECKING, Any, Callable, Dict, List, Optional, Sequence, Union
 
 import anki._rsbridge
 import anki.backend_pb2 as pb
@@ -233,6 +233,18 @@ class RustBackend(RustBackendGenerated):
     def db_rollback(self) -> None:
         return self._db_command(dict(kind="rollback"))
 
+    def register_template_filter(
+        self, name: str, func: Callable[[str, str, str, bool, int], str]
+    ) -> None:
+        """Register a filter that is applied while the Rust code renders
+        templates, so it can be combined with the built in filters.
+
+        The function receives the field text, field name, filter argument
+        (eg '20' in {{myfilter:20:Field}}), whether the question is being
+        rendered, and the card ordinal, and returns the new text. Names may
+        not contain spaces or colons, or ValueError is raised."""
+        self._backend.register_template_filter(name, func)
+
     def _db_command(self, input: Dict[str, Any]) -> Any:
         try:
             return from_json_bytes(self._backend.db_command(to_json_bytes(input)))
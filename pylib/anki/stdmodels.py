@@ -46,6 +46,10 @@ def addClozeModel(col: Collection) -> NoteType:
     return add_stock_notetype(col, StockNoteType.STOCK_NOTE_TYPE_CLOZE)
 
 
+def addImageOcclusionModel(col: Collection) -> NoteType:
+    return add_stock_notetype(col, StockNoteType.STOCK_NOTE_TYPE_IMAGE_OCCLUSION)
+
+
 def get_stock_notetypes(
     col: Collection,
 ) -> List[Tuple[str, Callable[[Collection], NoteType]]]:
@@ -60,6 +64,7 @@ def get_stock_notetypes(
             addForwardOptionalReverse,
         ),
         (StockNoteType.STOCK_NOTE_TYPE_CLOZE, addClozeModel),
+        (StockNoteType.STOCK_NOTE_TYPE_IMAGE_OCCLUSION, addImageOcclusionModel),
     ]:
         m = from_json_bytes(col.backend.get_stock_notetype_legacy(kind))
         out.append((m["name"], func))
@@ -129,10 +129,14 @@ def get_method(
         return Method.APPLY_CHANGES
     elif s == "chunk":
         return Method.CHUNK
+    elif s == "resumeChunk":
+        return Method.RESUME_CHUNK
     elif s == "applyChunk":
         return Method.APPLY_CHUNK
     elif s == "sanityCheck2":
         return Method.SANITY_CHECK
+    elif s == "sanityCheckIds":
+        return Method.SANITY_CHECK_IDS
     elif s == "finish":
         return Method.FINISH
     elif s == "abort":
@@ -22,6 +22,10 @@ Your function should decide if it wants to modify the text by checking
 the filter_name argument, and then return the text whether it has been
 modified or not.
 
+Filters that need to be usable in any position, such as before built in
+filters, can instead be registered with col.backend.register_template_filter(),
+and will then be applied by the Rust code.
+
 A Python implementation of the standard filters is currently available in the
 template_legacy.py file, using the legacy addHook() system.
 """
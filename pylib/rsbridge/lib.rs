@@ -2,7 +2,8 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use anki::backend::{init_backend, Backend as RustBackend, BackendMethod};
-use pyo3::exceptions::PyException;
+use anki::template_filters::{FilterContext, WithArg};
+use pyo3::exceptions::{PyException, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 use pyo3::{create_exception, wrap_pyfunction};
@@ -91,6 +92,37 @@ impl Backend {
         let out_obj = PyBytes::new(py, &out_bytes);
         Ok(out_obj.into())
     }
+
+    /// Make a template filter available to the Rust rendering code. The
+    /// function is called with (text, field_name, arg, question_side,
+    /// card_ord), and should return the new text. If it raises, the error
+    /// is printed and the text is left unchanged.
+    fn register_template_filter(&self, name: &str, func: PyObject) -> PyResult<()> {
+        self.backend
+            .register_template_filter(
+                name,
+                WithArg(move |text: &str, ctx: &FilterContext| -> String {
+                    Python::with_gil(|py| {
+                        func.call1(
+                            py,
+                            (
+                                text,
+                                ctx.field_name,
+                                ctx.arg,
+                                ctx.question_side,
+                                ctx.card_ord,
+                            ),
+                        )
+                        .and_then(|out| out.extract::<String>(py))
+                        .unwrap_or_else(|err| {
+                            err.print(py);
+                            text.to_string()
+                        })
+                    })
+                }),
+            )
+            .map_err(|err| PyValueError::new_err(err.to_string()))
+    }
 }
 
 // Module definition
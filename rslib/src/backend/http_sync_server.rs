@@ -11,10 +11,10 @@ use crate::{
     sync::{
         http::{
             ApplyChangesIn, ApplyChunkIn, ApplyGravesIn, HostKeyIn, HostKeyOut, MetaIn,
-            SanityCheckIn, StartIn, SyncRequest,
+            ResumeChunkIn, SanityCheckIdsIn, SanityCheckIn, StartIn, SyncRequest,
         },
-        Chunk, Graves, LocalServer, SanityCheckOut, SanityCheckStatus, SyncMeta, SyncServer,
-        UnchunkedChanges, SYNC_VERSION_MAX, SYNC_VERSION_MIN,
+        Chunk, DigestIDs, Graves, LocalServer, SanityCheckOut, SanityCheckStatus, SyncMeta,
+        SyncServer, UnchunkedChanges, SYNC_VERSION_MAX, SYNC_VERSION_MIN,
     },
 };
 
@@ -124,6 +124,13 @@ impl Backend {
         })
     }
 
+    fn resume_chunk(&self, input: ResumeChunkIn) -> Result<Chunk> {
+        self.with_sync_server(|server| {
+            let mut rt = Runtime::new().unwrap();
+            rt.block_on(server.resume_chunk(input.received))
+        })
+    }
+
     fn apply_chunk(&self, input: ApplyChunkIn) -> Result<()> {
         self.with_sync_server(|server| {
             let mut rt = Runtime::new().unwrap();
@@ -145,6 +152,15 @@ impl Backend {
         })
     }
 
+    /// Sent after a failed sanity check, which has aborted the sync.
+    fn sanity_check_ids(&self, input: SanityCheckIdsIn) -> Result<DigestIDs> {
+        let mut server = self.col_into_server()?;
+        let mut rt = Runtime::new().unwrap();
+        let out = rt.block_on(server.sanity_check_ids(input.digests));
+        self.server_into_col(server);
+        out
+    }
+
     fn finish(&self) -> Result<TimestampMillis> {
         let out = self.with_sync_server(|server| {
             let mut rt = Runtime::new().unwrap();
@@ -199,8 +215,10 @@ impl Backend {
             SyncRequest::ApplyGraves(v) => to_vec(&self.apply_graves(v)?),
             SyncRequest::ApplyChanges(v) => to_vec(&self.apply_changes(v)?),
             SyncRequest::Chunk => to_vec(&self.chunk()?),
+            SyncRequest::ResumeChunk(v) => to_vec(&self.resume_chunk(v)?),
             SyncRequest::ApplyChunk(v) => to_vec(&self.apply_chunk(v)?),
             SyncRequest::SanityCheck(v) => to_vec(&self.sanity_check(v)?),
+            SyncRequest::SanityCheckIds(v) => to_vec(&self.sanity_check_ids(v)?),
             SyncRequest::Finish => to_vec(&self.finish()?),
             SyncRequest::Abort => to_vec(&self.abort()?),
             SyncRequest::FullUpload(v) => to_vec(&self.upload(v)?),
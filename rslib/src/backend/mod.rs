@@ -29,8 +29,8 @@ use crate::{
     media::MediaManager,
     notes::{Note, NoteID},
     notetype::{
-        all_stock_notetypes, CardTemplateSchema11, NoteType, NoteTypeID, NoteTypeSchema11,
-        RenderCardOutput,
+        check_notetype_templates, get_stock_notetype, CardTemplateSchema11, NoteType, NoteTypeID,
+        NoteTypeSchema11, RenderCardOutput, StockNoteType, TemplateSide,
     },
     sched::new::NewCardSortOrder,
     sched::timespan::{answer_button_time, time_span},
@@ -45,6 +45,7 @@ use crate::{
         SyncStage,
     },
     template::RenderedNode,
+    template_filters::{FilterRegistry, TemplateFilter},
     text::{escape_anki_wildcards, extract_av_tags, strip_av_tags, AVTag},
     timestamp::TimestampSecs,
     types::Usn,
@@ -114,6 +115,8 @@ struct BackendState {
     remote_sync_status: RemoteSyncStatus,
     media_sync_abort: Option<AbortHandle>,
     http_sync_server: Option<LocalServer>,
+    /// Copied into each collection that is opened.
+    template_filters: FilterRegistry,
 }
 
 #[derive(Default, Debug)]
@@ -161,6 +164,7 @@ fn anki_error_to_proto_error(err: AnkiError, i18n: &I18n) -> pb::BackendError {
         AnkiError::DeckIsFiltered => V::DeckIsFiltered(Empty {}),
         AnkiError::SearchError(_) => V::InvalidInput(pb::Empty {}),
         AnkiError::TemplateSaveError { .. } => V::TemplateParse(pb::Empty {}),
+        AnkiError::InvalidNoteField { .. } => V::InvalidInput(pb::Empty {}),
     };
 
     pb::BackendError {
@@ -1013,6 +1017,24 @@ impl BackendService for Backend {
         })
     }
 
+    fn note_field_problems(&self, input: pb::Note) -> BackendResult<pb::NoteFieldProblemsOut> {
+        let note: Note = input.into();
+        self.with_col(|col| {
+            let nt = col
+                .get_notetype(note.notetype_id)?
+                .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+            let problems = col
+                .note_field_problems(&note)?
+                .into_iter()
+                .map(|(ord, problem)| pb::note_field_problems_out::Problem {
+                    field_ord: ord as u32,
+                    message: problem.localized(&col.i18n, &nt.fields[ord].name),
+                })
+                .collect();
+            Ok(pb::NoteFieldProblemsOut { problems })
+        })
+    }
+
     fn cards_of_note(&self, input: pb::NoteId) -> BackendResult<pb::CardIDs> {
         self.with_col(|col| {
             col.storage
@@ -1027,10 +1049,8 @@ impl BackendService for Backend {
     //-------------------------------------------------------------------
 
     fn get_stock_notetype_legacy(&self, input: pb::GetStockNotetypeIn) -> BackendResult<pb::Json> {
-        // fixme: use individual functions instead of full vec
-        let mut all = all_stock_notetypes(&self.i18n);
-        let idx = (input.kind as usize).min(all.len() - 1);
-        let nt = all.swap_remove(idx);
+        let kind = StockNoteType::from_i32(input.kind).unwrap_or(StockNoteType::Basic);
+        let nt = get_stock_notetype(kind, &self.i18n);
         let schema11: NoteTypeSchema11 = nt.into();
         serde_json::to_vec(&schema11)
             .map_err(Into::into)
@@ -1106,6 +1126,25 @@ impl BackendService for Backend {
             .map(Into::into)
     }
 
+    fn check_notetype_templates(
+        &self,
+        input: pb::Json,
+    ) -> BackendResult<pb::CheckNotetypeTemplatesOut> {
+        let legacy: NoteTypeSchema11 = serde_json::from_slice(&input.json)?;
+        let nt: NoteType = legacy.into();
+        let warnings = check_notetype_templates(&nt)
+            .into_iter()
+            .map(|w| pb::check_notetype_templates_out::Warning {
+                template_ord: w.template_ord as u32,
+                template_name: w.template_name.clone(),
+                question_side: w.side == TemplateSide::Question,
+                location: w.location.map(|val| pb::OptionalUInt32 { val: val as u32 }),
+                message: w.message(&self.i18n),
+            })
+            .collect();
+        Ok(pb::CheckNotetypeTemplatesOut { warnings })
+    }
+
     // media
     //-------------------------------------------------------------------
 
@@ -1214,7 +1253,7 @@ impl BackendService for Backend {
         };
         let logger = default_logger(log_path)?;
 
-        let new_col = open_collection(
+        let mut new_col = open_collection(
             input.collection_path,
             input.media_folder_path,
             input.media_db_path,
@@ -1222,6 +1261,7 @@ impl BackendService for Backend {
             self.i18n.clone(),
             logger,
         )?;
+        new_col.state.template_filters = self.state.lock().unwrap().template_filters.clone();
 
         *col = Some(new_col);
 
@@ -1296,6 +1336,26 @@ impl BackendService for Backend {
         self.with_col(|col| col.before_upload().map(Into::into))
     }
 
+    fn set_sync_passphrase(&self, input: pb::String) -> BackendResult<Empty> {
+        self.with_col(|col| col.set_sync_passphrase(&input.val).map(Into::into))
+    }
+
+    fn enable_sync_encryption(&self, input: pb::String) -> BackendResult<Empty> {
+        self.with_col(|col| col.enable_sync_encryption(&input.val).map(Into::into))
+    }
+
+    fn disable_sync_encryption(&self, _input: Empty) -> BackendResult<Empty> {
+        self.with_col(|col| col.disable_sync_encryption().map(Into::into))
+    }
+
+    fn sync_encryption_enabled(&self, _input: Empty) -> BackendResult<pb::Bool> {
+        self.with_col(|col| {
+            Ok(pb::Bool {
+                val: col.sync_encryption_enabled(),
+            })
+        })
+    }
+
     fn sync_server_method(&self, input: pb::SyncServerMethodIn) -> BackendResult<pb::Json> {
         let req = SyncRequest::from_method_and_data(input.method(), input.data)?;
         self.sync_server_method_inner(req).map(Into::into)
@@ -1438,6 +1498,22 @@ impl Backend {
         &self.i18n
     }
 
+    /// Make a filter available to card templates, in the open collection
+    /// and any collection opened later.
+    pub fn register_template_filter<F>(&self, name: &str, filter: F) -> Result<()>
+    where
+        F: TemplateFilter + 'static,
+    {
+        // lock in the same order as open_collection()
+        let mut col = self.col.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        state.template_filters.register(name, filter)?;
+        if let Some(col) = col.as_mut() {
+            col.state.template_filters = state.template_filters.clone();
+        }
+        Ok(())
+    }
+
     pub fn run_command_bytes(&self, method: u32, input: &[u8]) -> result::Result<Vec<u8>, Vec<u8>> {
         self.run_command_bytes2_inner(method, input).map_err(|err| {
             let backend_err = anki_error_to_proto_error(err, &self.i18n);
@@ -1536,7 +1612,15 @@ impl Backend {
         let folder = col.media_folder.clone();
         let db = col.media_db.clone();
         let log = col.log.clone();
+        let key = col.sync_key();
         drop(guard);
+        let key = match key {
+            Ok(key) => key,
+            Err(e) => {
+                self.state.lock().unwrap().media_sync_abort.take();
+                return Err(e);
+            }
+        };
 
         // start the sync
         let mut handler = self.new_progress_handler();
@@ -1544,7 +1628,7 @@ impl Backend {
 
         let mgr = MediaManager::new(&folder, &db)?;
         let rt = self.runtime_handle();
-        let sync_fut = mgr.sync_media(progress_fn, input.host_number, &input.hkey, log);
+        let sync_fut = mgr.sync_media(progress_fn, input.host_number, &input.hkey, key, log);
         let abortable_sync = Abortable::new(sync_fut, abort_reg);
         let result = rt.block_on(abortable_sync);
 
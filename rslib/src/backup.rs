@@ -0,0 +1,487 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Collection backups.
+//!
+//! A backup is a .colpkg file containing a schema 11 copy of the collection
+//! (without media), named after the local time it was created at, e.g.
+//! `backup-2020-11-04-09.30.00.colpkg`. The snapshot is taken with
+//! `VACUUM INTO`, which only holds the collection for as long as the copy
+//! takes; downgrading, compressing and thinning out old backups happens on a
+//! background thread.
+//!
+//! Restoring checks the package and the database inside it before the
+//! collection file is replaced.
+
+use crate::{
+    collection::Collection,
+    err::{AnkiError, DBErrorKind, Result},
+    i18n::I18n,
+    log::{debug, Logger},
+    storage::SqliteStorage,
+    timestamp::TimestampMillis,
+};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime};
+use rusqlite::NO_PARAMS;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+};
+use tempfile::NamedTempFile;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const BACKUP_FORMAT_STRING: &str = "backup-%Y-%m-%d-%H.%M.%S.colpkg";
+
+/// How many backups to retain in a backup folder. The most recent backup of
+/// each day is kept for the given number of days; after that, the most
+/// recent backup of each week, and then of each month.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackupLimits {
+    pub daily: u32,
+    pub weekly: u32,
+    pub monthly: u32,
+}
+
+impl Default for BackupLimits {
+    fn default() -> Self {
+        BackupLimits {
+            daily: 12,
+            weekly: 10,
+            monthly: 9,
+        }
+    }
+}
+
+impl Collection {
+    /// Write a snapshot of the collection into `backup_folder`, then compress
+    /// it and remove old backups according to `limits` in the background.
+    /// The collection is only held while the database is copied.
+    ///
+    /// The returned handle resolves to the path of the new backup. It must
+    /// not be called while a transaction is in progress.
+    pub fn create_backup<P: AsRef<Path>>(
+        &mut self,
+        backup_folder: P,
+        limits: BackupLimits,
+    ) -> Result<JoinHandle<Result<PathBuf>>> {
+        let backup_folder = backup_folder.as_ref().to_owned();
+        fs::create_dir_all(&backup_folder)?;
+        let snapshot = tempfile::Builder::new()
+            .prefix(".snapshot")
+            .tempfile_in(&backup_folder)?;
+        self.storage.snapshot_to(snapshot.path())?;
+
+        let log = self.log.clone();
+        let now = Local::now().naive_local();
+        Ok(thread::spawn(move || {
+            let path = finish_backup(snapshot, &backup_folder, now, &log)?;
+            thin_backups(&backup_folder, limits, &log)?;
+            Ok(path)
+        }))
+    }
+}
+
+impl SqliteStorage {
+    /// Copy the database into the provided empty file. If the legacy code has
+    /// a transaction open, it is committed first and reopened afterwards.
+    pub(crate) fn snapshot_to(&self, path: &Path) -> Result<()> {
+        let in_trx = !self.db.is_autocommit();
+        if in_trx {
+            self.commit_trx()?;
+        }
+        let result = self
+            .db
+            .execute("vacuum into ?", &[path.to_string_lossy().to_string()])
+            .map(|_| ());
+        if in_trx {
+            self.begin_trx()?;
+        }
+        result.map_err(Into::into)
+    }
+}
+
+/// Downgrade the snapshot and package it up, returning the final path.
+fn finish_backup(
+    snapshot: NamedTempFile,
+    backup_folder: &Path,
+    now: NaiveDateTime,
+    log: &Logger,
+) -> Result<PathBuf> {
+    // the snapshot was taken from an open collection, so it needs to be
+    // downgraded and switched out of WAL mode before older clients can read it
+    let i18n = I18n::new(&[""], "", log.clone());
+    SqliteStorage::open_or_create(snapshot.path(), &i18n, false)?.close(true)?;
+
+    let mut package = NamedTempFile::new_in(backup_folder)?;
+    write_colpkg(snapshot.path(), package.as_file_mut())?;
+    let path = backup_folder.join(now.format(BACKUP_FORMAT_STRING).to_string());
+    package
+        .persist(&path)
+        .map_err(|e| AnkiError::IOError {
+            info: format!("backup save failed: {}", e),
+        })?;
+    debug!(log, "backup created"; "path"=>path.to_string_lossy().to_string());
+
+    Ok(path)
+}
+
+fn write_colpkg(col_path: &Path, out: &mut File) -> Result<()> {
+    let mut zip = ZipWriter::new(out);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("collection.anki21", options)?;
+    io::copy(&mut File::open(col_path)?, &mut zip)?;
+    // backups don't include media, so the media map is empty
+    zip.start_file("media", options)?;
+    io::Write::write_all(&mut zip, b"{}")?;
+    zip.finish()?;
+
+    Ok(())
+}
+
+/// What a verified backup contains.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+    pub schema_version: u8,
+    pub notes: u32,
+    pub cards: u32,
+    pub revlog_entries: u32,
+    pub media_files: u32,
+    pub last_modified: TimestampMillis,
+}
+
+/// Check the provided backup is intact and can be restored, without
+/// changing anything.
+pub fn verify_backup<P: AsRef<Path>>(backup: P) -> Result<BackupInfo> {
+    let folder = tempfile::tempdir()?;
+    extract_backup(backup.as_ref(), folder.path()).map(|(_, _, info)| info)
+}
+
+impl Collection {
+    /// Replace the collection with the contents of the provided backup, after
+    /// verifying it. The collection is closed, and the caller must re-open it
+    /// afterwards, even if an error is returned. As the restored collection
+    /// may be behind AnkiWeb, the next sync will be a full sync.
+    pub fn restore_backup<P: AsRef<Path>>(self, backup: P) -> Result<BackupInfo> {
+        let col_path = self.col_path.clone();
+        let folder = col_path.parent().unwrap_or_else(|| Path::new(""));
+        let (col_file, storage, info) = extract_backup(backup.as_ref(), folder)?;
+        storage.set_schema_modified()?;
+        drop(storage);
+
+        self.close(false)?;
+        // overwrite existing collection atomically
+        col_file
+            .persist(&col_path)
+            .map_err(|e| AnkiError::IOError {
+                info: format!("restore failed: {}", e),
+            })?;
+
+        Ok(info)
+    }
+}
+
+/// Verify the package and extract its collection into a temporary file in
+/// `folder`, returning it along with the opened database.
+fn extract_backup(
+    backup: &Path,
+    folder: &Path,
+) -> Result<(NamedTempFile, SqliteStorage, BackupInfo)> {
+    let mut zip = ZipArchive::new(File::open(backup)?)?;
+    // reading each file to the end verifies its checksum
+    for idx in 0..zip.len() {
+        io::copy(&mut zip.by_index(idx)?, &mut io::sink())?;
+    }
+
+    // the media manifest maps numbered files in the package to filenames
+    let manifest: HashMap<String, String> = serde_json::from_reader(zip.by_name("media")?)?;
+    for idx in manifest.keys() {
+        zip.by_name(idx)?;
+    }
+
+    let col_name = if zip.by_name("collection.anki21").is_ok() {
+        "collection.anki21"
+    } else {
+        "collection.anki2"
+    };
+    let mut col_file = NamedTempFile::new_in(folder)?;
+    io::copy(&mut zip.by_name(col_name)?, col_file.as_file_mut())?;
+
+    let (storage, schema_version) = SqliteStorage::open_existing_without_upgrade(col_file.path())?;
+    if storage.quick_check_corrupt() {
+        return Err(AnkiError::DBError {
+            info: "backup is corrupt".to_string(),
+            kind: DBErrorKind::Corrupt,
+        });
+    }
+    let count = |table: &str| -> Result<u32> {
+        storage
+            .db
+            .query_row(&format!("select count() from {}", table), NO_PARAMS, |r| {
+                r.get(0)
+            })
+            .map_err(Into::into)
+    };
+    let info = BackupInfo {
+        schema_version,
+        notes: count("notes")?,
+        cards: count("cards")?,
+        revlog_entries: count("revlog")?,
+        media_files: manifest.len() as u32,
+        last_modified: storage.get_modified_time()?,
+    };
+
+    Ok((col_file, storage, info))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Backup {
+    path: PathBuf,
+    datetime: NaiveDateTime,
+}
+
+impl Backup {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let datetime = NaiveDateTime::parse_from_str(name, BACKUP_FORMAT_STRING).ok()?;
+        Some(Backup { path, datetime })
+    }
+
+    fn date(&self) -> NaiveDate {
+        self.datetime.date()
+    }
+}
+
+fn thin_backups(backup_folder: &Path, limits: BackupLimits, log: &Logger) -> Result<()> {
+    let mut backups = vec![];
+    for entry in fs::read_dir(backup_folder)? {
+        if let Some(backup) = Backup::from_path(entry?.path()) {
+            backups.push(backup);
+        }
+    }
+    for backup in backups_to_remove(backups, limits) {
+        debug!(log, "removing old backup"; "path"=>backup.path.to_string_lossy().to_string());
+        fs::remove_file(&backup.path)?;
+    }
+
+    Ok(())
+}
+
+/// Walk backups from newest to oldest, keeping the newest backup of each day
+/// until the daily limit is used up, then the newest of each week, and then
+/// of each month.
+fn backups_to_remove(mut backups: Vec<Backup>, limits: BackupLimits) -> Vec<Backup> {
+    backups.sort_unstable_by(|a, b| b.datetime.cmp(&a.datetime));
+
+    let mut last_kept: Option<NaiveDate> = None;
+    let mut kept = BackupLimits {
+        daily: 0,
+        weekly: 0,
+        monthly: 0,
+    };
+    let mut remove = vec![];
+
+    for backup in backups {
+        let date = backup.date();
+        let keep = if kept.daily < limits.daily {
+            let keep = last_kept.map_or(true, |last| last != date);
+            kept.daily += keep as u32;
+            keep
+        } else if kept.weekly < limits.weekly {
+            let keep = last_kept.map_or(true, |last| last.iso_week() != date.iso_week());
+            kept.weekly += keep as u32;
+            keep
+        } else if kept.monthly < limits.monthly {
+            let keep = last_kept.map_or(true, |last| {
+                (last.year(), last.month()) != (date.year(), date.month())
+            });
+            kept.monthly += keep as u32;
+            keep
+        } else {
+            false
+        };
+
+        if keep {
+            last_kept = Some(date);
+        } else {
+            remove.push(backup);
+        }
+    }
+
+    remove
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collection::{open_collection, open_test_collection},
+        decks::DeckID,
+        log,
+    };
+
+    fn backup(date: &str) -> Backup {
+        let datetime =
+            NaiveDateTime::parse_from_str(&format!("{} 12:00", date), "%Y-%m-%d %H:%M").unwrap();
+        Backup {
+            path: PathBuf::from(datetime.format(BACKUP_FORMAT_STRING).to_string()),
+            datetime,
+        }
+    }
+
+    #[test]
+    fn thinning() {
+        let limits = BackupLimits {
+            daily: 2,
+            weekly: 2,
+            monthly: 1,
+        };
+        let mut backups: Vec<_> = [
+            // kept as dailies; the earlier backup on the 20th goes
+            "2020-10-21",
+            "2020-10-20",
+            "2020-10-20",
+            // same week as the last daily, then two weeklies
+            "2020-10-19",
+            "2020-10-18",
+            "2020-10-11",
+            // kept as the monthly, as the last weekly was in October
+            "2020-10-09",
+            "2020-09-30",
+            "2020-08-01",
+        ]
+        .iter()
+        .map(|d| backup(d))
+        .collect();
+        // the second backup on the 20th is older
+        backups[2].datetime -= chrono::Duration::hours(1);
+
+        let removed: Vec<_> = backups_to_remove(backups.clone(), limits)
+            .into_iter()
+            .map(|b| b.datetime)
+            .collect();
+        assert_eq!(
+            removed,
+            vec![
+                backups[2].datetime,
+                backups[3].datetime,
+                backups[6].datetime,
+                backups[8].datetime,
+            ]
+        );
+    }
+
+    #[test]
+    fn creating() -> Result<()> {
+        let mut col = open_test_collection();
+        let mut note = col.get_notetype_by_name("Basic")?.unwrap().new_note();
+        note.fields[0] = "one".into();
+        col.add_note(&mut note, DeckID(1))?;
+
+        let dir = tempfile::tempdir()?;
+        // an unrelated file and a stale backup that exceeds the limits
+        fs::write(dir.path().join("other.txt"), "")?;
+        fs::write(dir.path().join("backup-2000-01-01-00.00.00.colpkg"), "")?;
+        let limits = BackupLimits {
+            daily: 1,
+            weekly: 0,
+            monthly: 0,
+        };
+
+        let path = col.create_backup(dir.path(), limits)?.join().unwrap()?;
+
+        let mut names: Vec<_> = fs::read_dir(dir.path())?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                path.file_name().unwrap().to_str().unwrap().to_string(),
+                "other.txt".to_string()
+            ]
+        );
+
+        let mut zip = zip::ZipArchive::new(File::open(&path)?)?;
+        let mut col_file = NamedTempFile::new()?;
+        io::copy(
+            &mut zip.by_name("collection.anki21")?,
+            col_file.as_file_mut(),
+        )?;
+        let db = crate::storage::open_and_check_sqlite_file(col_file.path())?;
+        let (ver, notes): (u8, u32) = db.query_row(
+            "select (select ver from col), (select count() from notes)",
+            rusqlite::NO_PARAMS,
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )?;
+        assert_eq!((ver, notes), (11, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn restoring() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let col_path = dir.path().join("collection.anki2");
+        let open = || {
+            let i18n = I18n::new(&[""], "", log::terminal());
+            open_collection(
+                col_path.clone(),
+                "".into(),
+                "".into(),
+                false,
+                i18n,
+                log::terminal(),
+            )
+        };
+
+        let mut col = open()?;
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        col.add_note(&mut note, DeckID(1))?;
+        let backup = col
+            .create_backup(dir.path().join("backups"), BackupLimits::default())?
+            .join()
+            .unwrap()?;
+        let schema_mtime = col.storage.get_schema_mtime()?;
+
+        let mut note = nt.new_note();
+        col.add_note(&mut note, DeckID(1))?;
+
+        let info = verify_backup(&backup)?;
+        assert_eq!(
+            (
+                info.schema_version,
+                info.notes,
+                info.cards,
+                info.media_files
+            ),
+            (11, 1, 1, 0)
+        );
+        assert_eq!(col.restore_backup(&backup)?, info);
+
+        let col = open()?;
+        assert_eq!(col.storage.total_notes()?, 1);
+        assert!(col.storage.get_schema_mtime()? > schema_mtime);
+
+        // a damaged package or database is rejected
+        let damaged = dir.path().join("damaged.colpkg");
+        let mut data = fs::read(&backup)?;
+        data.truncate(data.len() / 2);
+        fs::write(&damaged, data)?;
+        assert!(verify_backup(&damaged).is_err());
+
+        let mut zip = ZipWriter::new(File::create(&damaged)?);
+        zip.start_file("collection.anki21", FileOptions::default())?;
+        io::Write::write_all(&mut zip, b"not a database")?;
+        zip.start_file("media", FileOptions::default())?;
+        io::Write::write_all(&mut zip, b"{}")?;
+        zip.finish()?;
+        assert!(verify_backup(&damaged).is_err());
+
+        Ok(())
+    }
+}
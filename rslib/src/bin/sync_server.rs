@@ -0,0 +1,74 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A self-hosted sync server. See anki::sync::http_server for details.
+
+use anki::sync::http_server::{add_user, run_sync_server, SyncServerConfig};
+use std::{
+    env,
+    io::{self, BufRead},
+    path::PathBuf,
+    process,
+};
+
+const USAGE: &str = "usage:
+  anki-sync-server [--host HOST] [--port PORT] [--data DIR]
+  anki-sync-server [--data DIR] add-user USERNAME
+
+add-user reads the password from stdin, and replaces the password of an
+existing user. The data folder defaults to $SYNC_BASE, or ./sync-data.
+Clients should set SYNC_ENDPOINT to http://HOST:PORT/sync/";
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut config = SyncServerConfig {
+        host: "0.0.0.0".into(),
+        port: 8080,
+        data_dir: env::var_os("SYNC_BASE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| "sync-data".into()),
+    };
+    let mut new_user = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--host" => config.host = next_value(&mut args, &arg)?,
+            "--port" => {
+                config.port = next_value(&mut args, &arg)?
+                    .parse()
+                    .map_err(|_| "invalid port".to_string())?
+            }
+            "--data" => config.data_dir = next_value(&mut args, &arg)?.into(),
+            "add-user" => new_user = Some(next_value(&mut args, &arg)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => return Err(format!("unexpected argument: {}\n\n{}", arg, USAGE)),
+        }
+    }
+
+    if let Some(username) = new_user {
+        let mut password = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .map_err(|err| err.to_string())?;
+        let password = password.trim_end_matches(&['\r', '\n'][..]);
+        add_user(&config.data_dir, &username, password).map_err(|err| err.to_string())
+    } else {
+        run_sync_server(&config).map_err(|err| err.to_string())
+    }
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, name: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", name))
+}
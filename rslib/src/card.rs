@@ -121,6 +121,30 @@ impl Undoable for UpdateCardUndo {
     }
 }
 
+/// Undoing the addition of a card removes it.
+#[derive(Debug)]
+pub(crate) struct AddCardUndo(Card);
+
+impl Undoable for AddCardUndo {
+    fn apply(&self, col: &mut crate::collection::Collection, usn: Usn) -> Result<()> {
+        col.remove_card_undoable(&self.0, usn)
+    }
+}
+
+/// Undoing the removal of a card adds it back with the same ID.
+#[derive(Debug)]
+pub(crate) struct RemoveCardUndo(Card);
+
+impl Undoable for RemoveCardUndo {
+    fn apply(&self, col: &mut crate::collection::Collection, usn: Usn) -> Result<()> {
+        let mut card = self.0.clone();
+        card.set_modified(usn);
+        col.storage.add_or_update_card(&card)?;
+        col.state.undo.save_undoable(Box::new(AddCardUndo(card)));
+        Ok(())
+    }
+}
+
 impl Card {
     pub fn new(nid: NoteID, ord: u16, deck_id: DeckID, due: i32) -> Self {
         let mut card = Card::default();
@@ -164,7 +188,20 @@ impl Collection {
         }
         card.mtime = TimestampSecs::now();
         card.usn = self.usn()?;
-        self.storage.add_card(card)
+        self.storage.add_card(card)?;
+        self.state
+            .undo
+            .save_undoable(Box::new(AddCardUndo(card.clone())));
+        Ok(())
+    }
+
+    fn remove_card_undoable(&mut self, card: &Card, usn: Usn) -> Result<()> {
+        self.storage.remove_card(card.id)?;
+        self.storage.add_card_grave(card.id, usn)?;
+        self.state
+            .undo
+            .save_undoable(Box::new(RemoveCardUndo(card.clone())));
+        Ok(())
     }
 
     /// Remove cards and any resulting orphaned notes.
@@ -9,20 +9,9 @@ use regex::Captures;
 use regex::Regex;
 use std::borrow::Cow;
 use std::collections::HashSet;
+use std::fmt::Write;
 
 lazy_static! {
-    static ref CLOZE: Regex = Regex::new(
-        r#"(?xsi)
-            \{\{
-            c(\d+)::    # 1 = cloze number
-            (.*?)       # 2 = clozed text
-            (?:
-              ::(.*?)   # 3 = optional hint
-            )?
-            \}\}
-        "#
-    )
-    .unwrap();
     static ref MATHJAX: Regex = Regex::new(
         r#"(?xsi)
             (\\[(\[])       # 1 = mathjax opening tag
@@ -33,95 +22,260 @@ lazy_static! {
     .unwrap();
 }
 
-mod cloze_caps {
-    // cloze ordinal
-    pub const ORD: usize = 1;
-    // the occluded text
-    pub const TEXT: usize = 2;
-    // optional hint
-    pub const HINT: usize = 3;
-}
-
 mod mathjax_caps {
     pub const OPENING_TAG: usize = 1;
     pub const INNER_TEXT: usize = 2;
     pub const CLOSING_TAG: usize = 3;
 }
 
-pub fn reveal_cloze_text(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
-    let mut cloze_ord_was_in_text = false;
+// Parsing
+//----------------------------------------
 
-    let output = CLOZE.replace_all(text, |caps: &Captures| {
-        let captured_ord = caps
-            .get(cloze_caps::ORD)
-            .unwrap()
-            .as_str()
-            .parse()
-            .unwrap_or(0);
-
-        if captured_ord != cloze_ord {
-            // other cloze deletions are unchanged
-            return caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
-        } else {
-            cloze_ord_was_in_text = true;
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// `{{c1::`, with the ordinal and the original text
+    OpenCloze(u16, &'a str),
+    Text(&'a str),
+    CloseCloze,
+}
+
+/// Split text into cloze openings, closings and the text in between.
+fn tokenize(mut text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    while !text.is_empty() {
+        match next_cloze_marker(text) {
+            Some((idx, token, len)) => {
+                if idx > 0 {
+                    tokens.push(Token::Text(&text[..idx]));
+                }
+                tokens.push(token);
+                text = &text[idx + len..];
+            }
+            None => {
+                tokens.push(Token::Text(text));
+                break;
+            }
         }
+    }
+    tokens
+}
 
-        let replacement;
-        if question {
-            // hint provided?
-            if let Some(hint) = caps.get(cloze_caps::HINT) {
-                replacement = format!("[{}]", hint.as_str());
-            } else {
-                replacement = "[...]".to_string()
+/// Returns the position, token and length of the first cloze opening or
+/// closing in text.
+fn next_cloze_marker(text: &str) -> Option<(usize, Token, usize)> {
+    let bytes = text.as_bytes();
+    for idx in 0..bytes.len().saturating_sub(1) {
+        match &bytes[idx..idx + 2] {
+            b"}}" => return Some((idx, Token::CloseCloze, 2)),
+            b"{{" => {
+                if let Some((ordinal, len)) = cloze_opening(&text[idx..]) {
+                    return Some((idx, Token::OpenCloze(ordinal, &text[idx..idx + len]), len));
+                }
             }
-        } else {
-            replacement = caps.get(cloze_caps::TEXT).unwrap().as_str().to_owned();
+            _ => (),
         }
+    }
+    None
+}
 
-        format!("<span class=cloze>{}</span>", replacement)
-    });
+/// If text starts with `{{cN::`, return the ordinal and length of the opening.
+fn cloze_opening(text: &str) -> Option<(u16, usize)> {
+    let rest = text.strip_prefix("{{")?;
+    let rest = rest.strip_prefix('c').or_else(|| rest.strip_prefix('C'))?;
+    let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == 0 || !rest[digits..].starts_with("::") {
+        return None;
+    }
+    let ordinal = rest[..digits].parse().ok()?;
+    Some((ordinal, 3 + digits + 2))
+}
 
-    if !cloze_ord_was_in_text {
-        return "".into();
+#[derive(Debug)]
+enum TextOrCloze<'a> {
+    Text(&'a str),
+    Cloze(ExtractedCloze<'a>),
+}
+
+#[derive(Debug)]
+struct ExtractedCloze<'a> {
+    ordinal: u16,
+    /// the original opening text, such as `{{c1::`
+    opening: &'a str,
+    nodes: Vec<TextOrCloze<'a>>,
+    hint: Option<&'a str>,
+}
+
+/// Parse text into a tree of text and (possibly nested) clozes. Closings
+/// without an opening, and openings without a closing, are treated as text.
+fn parse_text_with_clozes(text: &str) -> Vec<TextOrCloze> {
+    let mut nodes = vec![];
+    let mut open_clozes: Vec<ExtractedCloze> = vec![];
+    for token in tokenize(text) {
+        match token {
+            Token::OpenCloze(ordinal, opening) => open_clozes.push(ExtractedCloze {
+                ordinal,
+                opening,
+                nodes: vec![],
+                hint: None,
+            }),
+            Token::Text(text) => {
+                if let Some(cloze) = open_clozes.last_mut() {
+                    cloze.nodes.push(TextOrCloze::Text(text));
+                } else {
+                    nodes.push(TextOrCloze::Text(text));
+                }
+            }
+            Token::CloseCloze => {
+                if let Some(mut cloze) = open_clozes.pop() {
+                    // a hint is separated from the end of the cloze by ::
+                    if let Some(TextOrCloze::Text(text)) = cloze.nodes.last_mut() {
+                        let full_text = *text;
+                        if let Some(idx) = full_text.find("::") {
+                            cloze.hint = Some(&full_text[idx + 2..]);
+                            *text = &full_text[..idx];
+                        }
+                    }
+                    if let Some(outer) = open_clozes.last_mut() {
+                        outer.nodes.push(TextOrCloze::Cloze(cloze));
+                    } else {
+                        nodes.push(TextOrCloze::Cloze(cloze));
+                    }
+                } else {
+                    nodes.push(TextOrCloze::Text("}}"));
+                }
+            }
+        }
     }
 
-    // if no cloze deletions are found, Anki returns an empty string
-    match output {
-        Cow::Borrowed(_) => "".into(),
-        other => other,
+    // unterminated clozes are restored to text
+    for cloze in open_clozes {
+        nodes.push(TextOrCloze::Text(cloze.opening));
+        nodes.extend(cloze.nodes);
     }
+
+    nodes
 }
 
-pub fn reveal_cloze_text_only(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
-    CLOZE
-        .captures_iter(text)
-        .filter(|caps| {
-            let captured_ord = caps
-                .get(cloze_caps::ORD)
-                .unwrap()
-                .as_str()
-                .parse()
-                .unwrap_or(0);
-
-            captured_ord == cloze_ord
-        })
-        .map(|caps| {
-            let cloze = if question {
-                // hint provided?
-                if let Some(hint) = caps.get(cloze_caps::HINT) {
-                    hint.as_str()
-                } else {
-                    "..."
+/// Reverses parse_text_with_clozes().
+#[cfg(test)]
+fn nodes_to_string(buf: &mut String, nodes: &[TextOrCloze]) {
+    for node in nodes {
+        match node {
+            TextOrCloze::Text(text) => buf.push_str(text),
+            TextOrCloze::Cloze(cloze) => {
+                buf.push_str(cloze.opening);
+                nodes_to_string(buf, &cloze.nodes);
+                if let Some(hint) = cloze.hint {
+                    buf.push_str("::");
+                    buf.push_str(hint);
                 }
+                buf.push_str("}}");
+            }
+        }
+    }
+}
+
+// Rendering
+//----------------------------------------
+
+/// Write the text of nodes, revealing or hiding any clozes inside them.
+fn reveal_nodes(buf: &mut String, nodes: &[TextOrCloze], cloze_ord: u16, question: bool) {
+    for node in nodes {
+        match node {
+            TextOrCloze::Text(text) => buf.push_str(text),
+            TextOrCloze::Cloze(cloze) => reveal_cloze(buf, cloze, cloze_ord, question),
+        }
+    }
+}
+
+fn reveal_cloze(buf: &mut String, cloze: &ExtractedCloze, cloze_ord: u16, question: bool) {
+    if cloze.ordinal != cloze_ord {
+        // other cloze deletions are unchanged, but may contain the active one
+        reveal_nodes(buf, &cloze.nodes, cloze_ord, question);
+    } else if question {
+        write!(
+            buf,
+            "<span class=cloze>[{}]</span>",
+            cloze.hint.unwrap_or("...")
+        )
+        .unwrap();
+    } else {
+        buf.push_str("<span class=cloze>");
+        reveal_nodes(buf, &cloze.nodes, cloze_ord, question);
+        buf.push_str("</span>");
+    }
+}
+
+/// Write the text of nodes with all clozes revealed and no markup.
+fn write_plain_text(buf: &mut String, nodes: &[TextOrCloze]) {
+    for node in nodes {
+        match node {
+            TextOrCloze::Text(text) => buf.push_str(text),
+            TextOrCloze::Cloze(cloze) => write_plain_text(buf, &cloze.nodes),
+        }
+    }
+}
+
+/// Calls the provided function with each cloze matching cloze_ord,
+/// including nested ones. Clozes inside a matching cloze are not visited.
+fn for_each_active_cloze<'a>(
+    nodes: &'a [TextOrCloze<'a>],
+    cloze_ord: u16,
+    func: &mut impl FnMut(&'a ExtractedCloze<'a>),
+) {
+    for node in nodes {
+        if let TextOrCloze::Cloze(cloze) = node {
+            if cloze.ordinal == cloze_ord {
+                func(cloze);
             } else {
-                caps.get(cloze_caps::TEXT).unwrap().as_str()
-            };
+                for_each_active_cloze(&cloze.nodes, cloze_ord, func);
+            }
+        }
+    }
+}
 
-            cloze
+pub fn reveal_cloze_text(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
+    let nodes = parse_text_with_clozes(text);
+    let mut cloze_ord_was_in_text = false;
+    for_each_active_cloze(&nodes, cloze_ord, &mut |_| cloze_ord_was_in_text = true);
+    // if the cloze is not found, Anki returns an empty string
+    if !cloze_ord_was_in_text {
+        return "".into();
+    }
+
+    let mut buf = String::with_capacity(text.len());
+    reveal_nodes(&mut buf, &nodes, cloze_ord, question);
+    buf.into()
+}
+
+pub fn reveal_cloze_text_only(text: &str, cloze_ord: u16, question: bool) -> Cow<str> {
+    let nodes = parse_text_with_clozes(text);
+    let mut output = vec![];
+    for_each_active_cloze(&nodes, cloze_ord, &mut |cloze| {
+        output.push(if question {
+            cloze.hint.unwrap_or("...").to_string()
+        } else {
+            let mut buf = String::new();
+            write_plain_text(&mut buf, &cloze.nodes);
+            buf
         })
-        .collect::<Vec<_>>()
-        .join(", ")
-        .into()
+    });
+
+    output.join(", ").into()
+}
+
+/// Replace all cloze deletions with their text, discarding hints.
+pub fn strip_clozes(text: &str) -> Cow<str> {
+    let nodes = parse_text_with_clozes(text);
+    if !nodes
+        .iter()
+        .any(|node| matches!(node, TextOrCloze::Cloze(_)))
+    {
+        return text.into();
+    }
+    let mut buf = String::with_capacity(text.len());
+    write_plain_text(&mut buf, &nodes);
+    buf.into()
 }
 
 /// If text contains any LaTeX tags, render the front and back
@@ -149,9 +303,14 @@ pub fn cloze_numbers_in_string(html: &str) -> HashSet<u16> {
 
 #[allow(clippy::implicit_hasher)]
 pub fn add_cloze_numbers_in_string(field: &str, set: &mut HashSet<u16>) {
-    for cap in CLOZE.captures_iter(field) {
-        if let Ok(n) = cap[1].parse() {
-            set.insert(n);
+    add_cloze_numbers_in_nodes(&parse_text_with_clozes(field), set);
+}
+
+fn add_cloze_numbers_in_nodes(nodes: &[TextOrCloze], set: &mut HashSet<u16>) {
+    for node in nodes {
+        if let TextOrCloze::Cloze(cloze) = node {
+            set.insert(cloze.ordinal);
+            add_cloze_numbers_in_nodes(&cloze.nodes, set);
         }
     }
 }
@@ -233,4 +392,96 @@ mod test {
             r"\(&lt;&gt;\)"
         );
     }
+
+    #[test]
+    fn nested() {
+        let text = "{{c1::foo {{c2::bar::hint}} baz}} {{c3::qux}}";
+        assert_eq!(
+            cloze_numbers_in_string(text),
+            vec![1, 2, 3].into_iter().collect::<HashSet<u16>>()
+        );
+
+        // the outer cloze hides the inner one
+        assert_eq!(
+            reveal_cloze_text(text, 1, true),
+            "<span class=cloze>[...]</span> qux"
+        );
+        assert_eq!(
+            reveal_cloze_text(text, 1, false),
+            "<span class=cloze>foo bar baz</span> qux"
+        );
+        // the inner cloze is shown inside the revealed outer text
+        assert_eq!(
+            reveal_cloze_text(text, 2, true),
+            "foo <span class=cloze>[hint]</span> baz qux"
+        );
+        assert_eq!(
+            reveal_cloze_text(text, 2, false),
+            "foo <span class=cloze>bar</span> baz qux"
+        );
+        assert_eq!(reveal_cloze_text(text, 4, false), "");
+
+        assert_eq!(reveal_cloze_text_only(text, 1, false), "foo bar baz");
+        assert_eq!(reveal_cloze_text_only(text, 2, true), "hint");
+
+        // overlapping clozes with the same number
+        let text = "{{c1::a {{c1::b}} c}}";
+        assert_eq!(
+            reveal_cloze_text(text, 1, false),
+            "<span class=cloze>a <span class=cloze>b</span> c</span>"
+        );
+        assert_eq!(reveal_cloze_text_only(text, 1, false), "a b c");
+    }
+
+    #[test]
+    fn unbalanced() {
+        // unmatched openings and closings are left as text
+        assert_eq!(reveal_cloze_text("{{c1::foo", 1, false), "");
+        assert_eq!(
+            reveal_cloze_text("{{c1::foo {{c2::bar}}", 2, false),
+            "{{c1::foo <span class=cloze>bar</span>"
+        );
+        assert_eq!(
+            reveal_cloze_text("}} {{c1::foo}} }}", 1, true),
+            "}} <span class=cloze>[...]</span> }}"
+        );
+        assert_eq!(
+            cloze_numbers_in_string("{{c1::a {{c2::b}}"),
+            vec![2].into_iter().collect::<HashSet<u16>>()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in &[
+            "test",
+            "{{c2::te}}{{c1::s}}t{{",
+            "{{c1::foo}} {{c2::bar::baz}}",
+            "[latex]{{c1::foo}} {{c2::bar::baz}}[/latex]",
+            "foo {{c1::bar}}",
+            "{{c1::foo}} {{c1::bar}}",
+            "{{c1::foo {{c2::bar::hint}} baz}} {{c3::qux}}",
+            "{{C10::upper}}",
+            "}} {{c1::a {{c2::b}}",
+            r"{{c1::\(<foo>&lt;&gt;</foo>\)}}",
+        ] {
+            let mut buf = String::new();
+            nodes_to_string(&mut buf, &parse_text_with_clozes(text));
+            assert_eq!(&buf, text);
+        }
+    }
+
+    #[test]
+    fn mathjax_in_cloze() {
+        let text = r"{{c1::\(<b>x</b>\)}} {{c2::\(y\)::<i>hint</i>}}";
+        let revealed = reveal_cloze_text(text, 1, false);
+        assert_eq!(
+            strip_html_inside_mathjax(&revealed),
+            r"<span class=cloze>\(x\)</span> \(y\)"
+        );
+        assert_eq!(
+            reveal_cloze_text(text, 2, true),
+            r"\(<b>x</b>\) <span class=cloze>[<i>hint</i>]</span>"
+        );
+    }
 }
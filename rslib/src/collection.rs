@@ -6,9 +6,12 @@ use crate::i18n::I18n;
 use crate::log::Logger;
 use crate::types::Usn;
 use crate::{
+    config::ConfigKey,
     decks::{Deck, DeckID},
     notetype::{NoteType, NoteTypeID},
     storage::SqliteStorage,
+    sync::{SanityCheckReport, SyncPassphrase},
+    template_filters::FilterRegistry,
     undo::UndoManager,
 };
 use std::{collections::HashMap, path::PathBuf, sync::Arc};
@@ -57,6 +60,12 @@ pub struct CollectionState {
     pub(crate) undo: UndoManager,
     pub(crate) notetype_cache: HashMap<NoteTypeID, Arc<NoteType>>,
     pub(crate) deck_cache: HashMap<DeckID, Arc<Deck>>,
+    pub(crate) template_filters: FilterRegistry,
+    pub(crate) last_sanity_check: Option<SanityCheckReport>,
+    pub(crate) sync_passphrase: Option<SyncPassphrase>,
+    /// Set while the collection is served to peers, whose pending objects
+    /// are tracked separately from its own.
+    pub(crate) serving_peers: bool,
 }
 
 pub struct Collection {
@@ -74,6 +83,7 @@ pub struct Collection {
 #[derive(Debug, Clone, PartialEq)]
 pub enum CollectionOp {
     UpdateCard,
+    UpdateNote,
 }
 
 impl Collection {
@@ -126,6 +136,8 @@ impl Collection {
             col.storage.clear_deck_conf_usns()?;
             col.storage.clear_deck_usns()?;
             col.storage.clear_notetype_usns()?;
+            // the upload includes any schema changes
+            col.remove_config(ConfigKey::PendingSchemaReplay)?;
             col.storage.increment_usn()?;
             col.storage.set_schema_modified()?;
             col.storage.set_last_sync(col.storage.get_schema_mtime()?)
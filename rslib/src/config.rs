@@ -32,6 +32,7 @@ pub(crate) fn schema11_config_as_string() -> String {
     serde_json::to_string(&obj).unwrap()
 }
 
+#[derive(Clone, Copy)]
 pub(crate) enum ConfigKey {
     BrowserSortKind,
     BrowserSortReverse,
@@ -50,6 +51,13 @@ pub(crate) enum ConfigKey {
     AnswerTimeLimitSecs,
     ShowDayLearningCardsFirst,
     LastUnburiedDay,
+    SyncEncryption,
+    SyncEncryptionCheck,
+    PendingSchemaReplay,
+    LastSyncServer,
+    ServerID,
+    PeerUsn,
+    SyncCheckpoint,
 }
 #[derive(PartialEq, Serialize_repr, Deserialize_repr, Clone, Copy)]
 #[repr(u8)]
@@ -78,10 +86,29 @@ impl From<ConfigKey> for &'static str {
             ConfigKey::AnswerTimeLimitSecs => "timeLim",
             ConfigKey::ShowDayLearningCardsFirst => "dayLearnFirst",
             ConfigKey::LastUnburiedDay => "lastUnburied",
+            ConfigKey::SyncEncryption => "syncEncryption",
+            ConfigKey::SyncEncryptionCheck => "syncEncryptionCheck",
+            ConfigKey::PendingSchemaReplay => "pendingSchemaReplay",
+            ConfigKey::LastSyncServer => "lastSyncServer",
+            ConfigKey::ServerID => "serverId",
+            ConfigKey::PeerUsn => "peerUsn",
+            ConfigKey::SyncCheckpoint => "syncCheckpoint",
         }
     }
 }
 
+/// Keys that describe the sync state of this device, or that the server must
+/// not see. They are not sent to other devices, and are kept when config is
+/// received from them.
+pub(crate) static LOCAL_ONLY_CONFIG: &[&str] = &[
+    "pendingSchemaReplay",
+    "lastSyncServer",
+    "serverId",
+    "peerUsn",
+    "syncCheckpoint",
+    "syncEncryptionCheck",
+];
+
 #[derive(Deserialize, Default)]
 struct BoolLike(#[serde(deserialize_with = "deserialize_bool_from_anything")] bool);
 
@@ -182,12 +182,12 @@ impl DeckSchema11 {
         }
     }
 
-    // pub(crate) fn common_mut(&mut self) -> &mut DeckCommon {
-    //     match self {
-    //         Deck::Normal(d) => &mut d.common,
-    //         Deck::Filtered(d) => &mut d.common,
-    //     }
-    // }
+    pub(crate) fn common_mut(&mut self) -> &mut DeckCommonSchema11 {
+        match self {
+            DeckSchema11::Normal(d) => &mut d.common,
+            DeckSchema11::Filtered(d) => &mut d.common,
+        }
+    }
 
     pub fn id(&self) -> DeckID {
         self.common().id
@@ -62,6 +62,12 @@ pub enum AnkiError {
 
     #[fail(display = "Invalid search.")]
     SearchError(SearchErrorKind),
+
+    #[fail(display = "invalid content in field {}: {:?}", field, problem)]
+    InvalidNoteField {
+        field: String,
+        problem: FieldProblem,
+    },
 }
 
 // error helpers
@@ -211,12 +217,36 @@ impl AnkiError {
                     tr_args!("reason" => reason.into_owned()),
                 )
             }
+            AnkiError::InvalidNoteField { field, problem } => problem.localized(i18n, field),
             _ => format!("{:?}", self),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// A field's content does not satisfy the options of the field.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FieldProblem {
+    Empty,
+    PatternMismatch,
+    TooLong { max_length: u32 },
+}
+
+impl FieldProblem {
+    pub fn localized(&self, i18n: &I18n, field: &str) -> String {
+        match self {
+            FieldProblem::Empty => i18n.trn(TR::FieldsRequiredFieldEmpty, tr_strs!["field"=>field]),
+            FieldProblem::PatternMismatch => {
+                i18n.trn(TR::FieldsPatternMismatch, tr_strs!["field"=>field])
+            }
+            FieldProblem::TooLong { max_length } => i18n.trn(
+                TR::FieldsTooLong,
+                tr_args!["field"=>field, "max"=>*max_length],
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum TemplateError {
     NoClosingBrackets(String),
     ConditionalNotClosed(String),
@@ -228,6 +258,7 @@ pub enum TemplateError {
         filters: String,
         field: String,
     },
+    UnsupportedComparison(String),
 }
 
 impl From<io::Error> for AnkiError {
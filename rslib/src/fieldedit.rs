@@ -0,0 +1,170 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use crate::{
+    collection::{Collection, CollectionOp},
+    err::{AnkiError, Result},
+    notes::NoteID,
+    notetype::CardGenContext,
+    template::field_is_empty,
+};
+use itertools::Itertools;
+
+/// A change to apply to a single field of many notes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldEdit {
+    Set(String),
+    Append(String),
+    Prepend(String),
+    Clear,
+}
+
+impl FieldEdit {
+    /// Returns the new field content, or None if it is unchanged.
+    fn apply(&self, text: &str) -> Option<String> {
+        let new_text = match self {
+            FieldEdit::Set(new) => new.clone(),
+            FieldEdit::Append(suffix) => format!("{}{}", text, suffix),
+            FieldEdit::Prepend(prefix) => format!("{}{}", prefix, text),
+            FieldEdit::Clear => String::new(),
+        };
+        if new_text == text {
+            None
+        } else {
+            Some(new_text)
+        }
+    }
+}
+
+impl Collection {
+    /// Apply `edit` to the field called `field_name` on each of the provided
+    /// notes, returning the number of notes that changed. Notes whose
+    /// notetype has no such field are left alone.
+    ///
+    /// Undoing reverts the notes, and removes any cards that were generated.
+    pub fn edit_field(
+        &mut self,
+        nids: &[NoteID],
+        field_name: &str,
+        edit: FieldEdit,
+    ) -> Result<usize> {
+        self.transact(Some(CollectionOp::UpdateNote), |col| {
+            col.edit_field_inner(nids, field_name, &edit)
+        })
+    }
+
+    fn edit_field_inner(
+        &mut self,
+        nids: &[NoteID],
+        field_name: &str,
+        edit: &FieldEdit,
+    ) -> Result<usize> {
+        let nids_by_notetype = self.storage.note_ids_by_notetype(nids)?;
+        let norm = self.normalize_note_text();
+        let usn = self.usn()?;
+        let mut changed_notes = 0;
+
+        for (ntid, group) in &nids_by_notetype.into_iter().group_by(|tup| tup.0) {
+            let nt = self
+                .get_notetype(ntid)?
+                .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+            let ord = match nt.get_field_ord(field_name) {
+                Some(ord) => ord,
+                None => continue,
+            };
+            // card existence only depends on which fields are empty, apart
+            // from cloze deletions and compared fields
+            let always_generate =
+                nt.is_cloze() || nt.compared_keys().contains(&nt.fields[ord].name);
+            let ctx = CardGenContext::new(&nt, usn);
+
+            for (_, nid) in group {
+                let original = self.storage.get_note(nid)?.unwrap();
+                let mut note = original.clone();
+                let txt = match note.fields.get_mut(ord) {
+                    Some(txt) => txt,
+                    None => continue,
+                };
+                let new_txt = match edit.apply(txt) {
+                    Some(new_txt) => new_txt,
+                    None => continue,
+                };
+                let generate_cards =
+                    always_generate || field_is_empty(txt) != field_is_empty(&new_txt);
+                *txt = new_txt;
+
+                self.update_note_undoable(&mut note, &original, &nt, usn, norm)?;
+                if generate_cards {
+                    self.generate_cards_for_existing_note(&ctx, &note)?;
+                }
+                changed_notes += 1;
+            }
+        }
+
+        Ok(changed_notes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID};
+
+    #[test]
+    fn editing() -> Result<()> {
+        let mut col = open_test_collection();
+
+        let nt = col
+            .get_notetype_by_name("Basic (optional reversed card)")?
+            .unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = "one".into();
+        note.fields[1] = "back".into();
+        col.add_note(&mut note, DeckID(1))?;
+
+        let nt = col.get_notetype_by_name("Cloze")?.unwrap();
+        let mut note2 = nt.new_note();
+        note2.fields[0] = "{{c1::two}}".into();
+        col.add_note(&mut note2, DeckID(1))?;
+
+        let nids = vec![note.id, note2.id];
+        let cnt = col.edit_field(&nids, "Front", FieldEdit::Append(" 1".into()))?;
+        // the cloze note has no Front field
+        assert_eq!(cnt, 1);
+        let cnt = col.edit_field(&nids, "Front", FieldEdit::Prepend("0 ".into()))?;
+        assert_eq!(cnt, 1);
+        let note = col.storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.fields[0], "0 one 1");
+
+        // setting the reverse field generates a new card
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 1);
+        col.edit_field(&nids, "Add Reverse", FieldEdit::Set("y".into()))?;
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 2);
+        // setting it to the same value is a no-op
+        let cnt = col.edit_field(&nids, "Add Reverse", FieldEdit::Set("y".into()))?;
+        assert_eq!(cnt, 0);
+
+        // as do new cloze numbers
+        col.edit_field(&nids, "Text", FieldEdit::Append(" {{c2::three}}".into()))?;
+        assert_eq!(col.storage.all_cards_of_note(note2.id)?.len(), 2);
+
+        // edits can be undone and redone
+        col.edit_field(&nids, "Front", FieldEdit::Clear)?;
+        assert_eq!(col.storage.get_note(note.id)?.unwrap().fields[0], "");
+        assert_eq!(col.can_undo(), Some(CollectionOp::UpdateNote));
+        col.undo()?;
+        assert_eq!(col.storage.get_note(note.id)?.unwrap().fields[0], "0 one 1");
+        col.redo()?;
+        assert_eq!(col.storage.get_note(note.id)?.unwrap().fields[0], "");
+
+        // as are the cards they generate
+        col.edit_field(&nids, "Text", FieldEdit::Append(" {{c3::four}}".into()))?;
+        assert_eq!(col.storage.all_cards_of_note(note2.id)?.len(), 3);
+        col.undo()?;
+        assert_eq!(col.storage.all_cards_of_note(note2.id)?.len(), 2);
+        col.redo()?;
+        assert_eq!(col.storage.all_cards_of_note(note2.id)?.len(), 3);
+
+        Ok(())
+    }
+}
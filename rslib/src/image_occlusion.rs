@@ -0,0 +1,256 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Image occlusion masks.
+//!
+//! Masks are stored in a field as a series of entries like
+//! `{{m1::rect:10,20,30,15}}`. The number is the mask group, and like cloze
+//! numbers, one card is generated for each group. It is followed by the
+//! shape (rect or ellipse), and the left, top, width and height of the mask
+//! as percentages of the image size. Anything outside the entries, such as
+//! line breaks, is ignored.
+
+use crate::template::{field_references, RenderContext};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{borrow::Cow, collections::HashSet, fmt::Write};
+
+lazy_static! {
+    static ref MASK: Regex = Regex::new(
+        r#"(?xi)
+            \{\{
+            m(\d+)::            # 1 = mask group
+            (rect|ellipse):     # 2 = shape
+            ([^}]*)             # 3 = coordinates
+            \}\}
+        "#
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskShape {
+    Rect,
+    Ellipse,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    pub group: u16,
+    pub shape: MaskShape,
+    pub left: f32,
+    pub top: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Mask {
+    fn write_svg(&self, buf: &mut String, class: &str) {
+        match self.shape {
+            MaskShape::Rect => write!(
+                buf,
+                r#"<rect class="{}" x="{}" y="{}" width="{}" height="{}"/>"#,
+                class, self.left, self.top, self.width, self.height
+            ),
+            MaskShape::Ellipse => write!(
+                buf,
+                r#"<ellipse class="{}" cx="{}" cy="{}" rx="{}" ry="{}"/>"#,
+                class,
+                self.left + self.width / 2.0,
+                self.top + self.height / 2.0,
+                self.width / 2.0,
+                self.height / 2.0
+            ),
+        }
+        .unwrap();
+    }
+}
+
+/// Parse the masks in a field. Entries with invalid coordinates are skipped.
+pub fn parse_masks(text: &str) -> Vec<Mask> {
+    MASK.captures_iter(text)
+        .filter_map(|caps| {
+            let group = caps[1].parse().ok()?;
+            let shape = if caps[2].eq_ignore_ascii_case("rect") {
+                MaskShape::Rect
+            } else {
+                MaskShape::Ellipse
+            };
+            let coords = caps[3]
+                .split(',')
+                .map(|n| n.trim().parse::<f32>().ok().filter(|n| n.is_finite()))
+                .collect::<Option<Vec<_>>>()?;
+            if let [left, top, width, height] = coords[..] {
+                if width >= 0.0 && height >= 0.0 {
+                    return Some(Mask {
+                        group,
+                        shape,
+                        left,
+                        top,
+                        width,
+                        height,
+                    });
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+/// True if the template applies the image-occlusion filter to a field. Only
+/// notetypes with such a template treat masks as card groups, so that mask-like
+/// text in ordinary cloze notes does not generate cards.
+pub(crate) fn uses_image_occlusion(template: &str) -> bool {
+    field_references(template)
+        .iter()
+        .any(|r| r.filters.contains(&"image-occlusion"))
+}
+
+pub fn mask_groups_in_string(text: &str) -> HashSet<u16> {
+    let mut set = HashSet::with_capacity(4);
+    add_mask_groups_in_string(text, &mut set);
+    set
+}
+
+#[allow(clippy::implicit_hasher)]
+pub fn add_mask_groups_in_string(text: &str, set: &mut HashSet<u16>) {
+    for mask in parse_masks(text) {
+        set.insert(mask.group);
+    }
+}
+
+/// Render the masks as an SVG overlay for the image. On the question side,
+/// masks in the card's group are marked active; on the answer side, they are
+/// marked revealed. Masks in other groups stay hidden on both sides.
+pub(crate) fn image_occlusion_filter<'a>(text: &'a str, context: &RenderContext) -> Cow<'a, str> {
+    let mut buf = String::from(
+        r#"<svg class="image-occlusion" viewBox="0 0 100 100" preserveAspectRatio="none">"#,
+    );
+    for mask in parse_masks(text) {
+        let class = if mask.group != context.card_ord + 1 {
+            "mask"
+        } else if context.question_side {
+            "mask active"
+        } else {
+            "mask revealed"
+        };
+        mask.write_svg(&mut buf, class);
+    }
+    buf.push_str("</svg>");
+
+    buf.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collection::open_test_collection,
+        decks::DeckID,
+        err::Result,
+        notetype::{get_stock_notetype, StockNoteType},
+        template::RenderedNode,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn parsing() {
+        let text = "{{m1::rect:10,20,30,15}}<br>{{m2::ellipse: 50, 50, 10, 20}}\
+                    {{m2::rect:1,2,3}}{{m3::rect:1,2,3,x}}{{m4::rect:1,2,-3,4}}";
+        assert_eq!(
+            parse_masks(text),
+            vec![
+                Mask {
+                    group: 1,
+                    shape: MaskShape::Rect,
+                    left: 10.0,
+                    top: 20.0,
+                    width: 30.0,
+                    height: 15.0
+                },
+                Mask {
+                    group: 2,
+                    shape: MaskShape::Ellipse,
+                    left: 50.0,
+                    top: 50.0,
+                    width: 10.0,
+                    height: 20.0
+                },
+            ]
+        );
+        assert_eq!(
+            mask_groups_in_string(text),
+            vec![1, 2].into_iter().collect::<HashSet<u16>>()
+        );
+        // cloze deletions are not masks
+        assert!(mask_groups_in_string("{{c1::rect:1,2,3,4}}").is_empty());
+    }
+
+    #[test]
+    fn rendering() {
+        let text = "{{m1::rect:10,20,30,15}}{{m2::ellipse:50,50,10,20}}";
+        let fields = HashMap::new();
+        let nonempty_fields = HashSet::new();
+        let mut context = RenderContext {
+            fields: &fields,
+            nonempty_fields: &nonempty_fields,
+            question_side: true,
+            card_ord: 1,
+            filters: &Default::default(),
+        };
+        assert_eq!(
+            image_occlusion_filter(text, &context),
+            concat!(
+                r#"<svg class="image-occlusion" viewBox="0 0 100 100" preserveAspectRatio="none">"#,
+                r#"<rect class="mask" x="10" y="20" width="30" height="15"/>"#,
+                r#"<ellipse class="mask active" cx="55" cy="60" rx="5" ry="10"/>"#,
+                "</svg>"
+            )
+        );
+        context.question_side = false;
+        assert!(image_occlusion_filter(text, &context).contains(r#"class="mask revealed""#));
+    }
+
+    #[test]
+    fn cards() -> Result<()> {
+        let mut col = open_test_collection();
+        // not added to new collections
+        assert!(col.get_notetype_by_name("Image Occlusion")?.is_none());
+        let mut nt = get_stock_notetype(StockNoteType::ImageOcclusion, &col.i18n);
+        col.add_notetype(&mut nt)?;
+        assert!(nt.is_image_occlusion());
+        let mut note = nt.new_note();
+        note.fields[0] = r#"<img src="diagram.png">"#.into();
+        note.fields[1] = "{{m1::rect:0,0,10,10}}<br>{{m3::rect:20,20,10,10}}\
+                          {{m3::ellipse:50,50,10,10}}"
+            .into();
+        col.add_note(&mut note, DeckID(1))?;
+
+        // one card per mask group
+        let cards = col.storage.all_cards_of_note(note.id)?;
+        let mut ords: Vec<_> = cards.iter().map(|c| c.template_idx).collect();
+        ords.sort_unstable();
+        assert_eq!(ords, vec![0, 2]);
+
+        let card = cards.iter().find(|c| c.template_idx == 2).unwrap();
+        let out = col.render_existing_card(card.id, false)?;
+        let text = |nodes: &[RenderedNode]| match &nodes[0] {
+            RenderedNode::Text { text } => text.clone(),
+            _ => unreachable!(),
+        };
+        let question = text(&out.qnodes);
+        assert!(question.contains(r#"<img src="diagram.png">"#));
+        assert_eq!(question.matches("mask active").count(), 2);
+        assert_eq!(text(&out.anodes).matches("mask revealed").count(), 2);
+
+        // masks in an ordinary cloze notetype are left alone
+        let nt = col.get_notetype_by_name("Cloze")?.unwrap();
+        assert!(!nt.is_image_occlusion());
+        let mut note = nt.new_note();
+        note.fields[0] = "{{c1::text}} {{m2::rect:0,0,10,10}}".into();
+        col.add_note(&mut note, DeckID(1))?;
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 1);
+
+        Ok(())
+    }
+}
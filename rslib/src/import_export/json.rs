@@ -0,0 +1,456 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A deterministic JSON representation of a subset of a collection, intended
+//! for keeping shared decks in version control.
+//!
+//! Items are sorted (notetypes and deck configs by ID, decks by name and notes
+//! by GUID), object keys are sorted, and values that change on every
+//! modification (mtimes, USNs, daily study counts) are omitted, so exporting
+//! an unchanged collection twice produces identical output.
+
+use crate::{
+    deckconf::{DeckConf, DeckConfSchema11},
+    decks::{human_deck_name_to_native, DeckKind},
+    notetype::{CardGenContext, NoteType, NoteTypeID, NoteTypeSchema11},
+    prelude::*,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+const NOTETYPE_VOLATILE_KEYS: &[&str] = &["mod", "usn", "req", "did"];
+const DECK_CONFIG_VOLATILE_KEYS: &[&str] = &["mod", "usn"];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct JsonCollection {
+    pub notetypes: Vec<Value>,
+    pub deck_configs: Vec<Value>,
+    pub decks: Vec<JsonDeck>,
+    pub notes: Vec<JsonNote>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonDeck {
+    /// Human-readable name, with '::' separating components.
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub config_id: DeckConfID,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonNote {
+    pub guid: String,
+    pub notetype_id: NoteTypeID,
+    /// The deck new cards of the note are placed in.
+    pub deck: String,
+    pub fields: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct JsonImportOutput {
+    pub notetypes_added: usize,
+    pub notetypes_updated: usize,
+    pub deck_configs_updated: usize,
+    pub decks_updated: usize,
+    pub notes_added: usize,
+    pub notes_updated: usize,
+    pub notes_removed: usize,
+}
+
+impl JsonCollection {
+    pub fn to_json_string(&self) -> Result<String> {
+        let mut text = serde_json::to_string_pretty(self)?;
+        text.push('\n');
+        Ok(text)
+    }
+
+    pub fn from_json_str(text: &str) -> Result<Self> {
+        serde_json::from_str(text).map_err(Into::into)
+    }
+}
+
+fn without_keys(mut val: Value, keys: &[&str]) -> Value {
+    if let Value::Object(ref mut map) = val {
+        for key in keys {
+            map.remove(*key);
+        }
+    }
+    val
+}
+
+/// Add back the keys removed on export, so the schema 11 structs can be
+/// deserialized.
+fn with_default_keys(mut val: Value, keys: &[&str]) -> Value {
+    if let Value::Object(ref mut map) = val {
+        for key in keys {
+            if !map.contains_key(*key) {
+                let default = match *key {
+                    "did" => Value::Null,
+                    "req" => Value::Array(vec![]),
+                    _ => Value::from(0),
+                };
+                map.insert(key.to_string(), default);
+            }
+        }
+    }
+    val
+}
+
+fn notetype_to_json(nt: NoteType) -> Result<Value> {
+    let schema11: NoteTypeSchema11 = nt.into();
+    Ok(without_keys(
+        serde_json::to_value(schema11)?,
+        NOTETYPE_VOLATILE_KEYS,
+    ))
+}
+
+fn notetype_from_json(val: Value) -> Result<NoteType> {
+    let schema11: NoteTypeSchema11 =
+        serde_json::from_value(with_default_keys(val, NOTETYPE_VOLATILE_KEYS))?;
+    Ok(schema11.into())
+}
+
+fn deck_config_to_json(conf: DeckConf) -> Result<Value> {
+    let schema11: DeckConfSchema11 = conf.into();
+    Ok(without_keys(
+        serde_json::to_value(schema11)?,
+        DECK_CONFIG_VOLATILE_KEYS,
+    ))
+}
+
+fn deck_config_from_json(val: Value) -> Result<DeckConf> {
+    let schema11: DeckConfSchema11 =
+        serde_json::from_value(with_default_keys(val, DECK_CONFIG_VOLATILE_KEYS))?;
+    Ok(schema11.into())
+}
+
+/// Point the incoming fields/templates at the existing items with the same
+/// name, so that renames are not needed and content is preserved.
+fn match_existing_ords(incoming: &mut NoteType, existing: &NoteType) {
+    for field in &mut incoming.fields {
+        field.ord = existing.get_field_ord(&field.name).map(|ord| ord as u32);
+    }
+    for template in &mut incoming.templates {
+        template.ord = existing
+            .templates
+            .iter()
+            .position(|t| t.name == template.name)
+            .map(|ord| ord as u32);
+    }
+}
+
+impl Collection {
+    /// Export the notes matching the search, along with their notetypes,
+    /// decks and deck options.
+    pub fn export_json(&mut self, search: &str) -> Result<JsonCollection> {
+        let nids = self.search_notes(search)?;
+        let mut notetype_ids = HashSet::new();
+        let mut deck_ids = HashSet::new();
+        let mut deck_names: HashMap<DeckID, String> = HashMap::new();
+        let mut notes = Vec::with_capacity(nids.len());
+
+        for nid in nids {
+            let note = self.storage.get_note(nid)?.ok_or(AnkiError::NotFound)?;
+            let did = self
+                .storage
+                .all_cards_of_note(nid)?
+                .into_iter()
+                .min_by_key(|card| card.template_idx)
+                .map(|card| {
+                    if card.original_deck_id.0 > 0 {
+                        card.original_deck_id
+                    } else {
+                        card.deck_id
+                    }
+                })
+                .unwrap_or(DeckID(1));
+            if let Entry::Vacant(entry) = deck_names.entry(did) {
+                let name = self
+                    .get_deck(did)?
+                    .map(|deck| deck.human_name())
+                    .unwrap_or_default();
+                entry.insert(name);
+            }
+            notetype_ids.insert(note.notetype_id);
+            deck_ids.insert(did);
+            notes.push(JsonNote {
+                guid: note.guid.clone(),
+                notetype_id: note.notetype_id,
+                deck: deck_names[&did].clone(),
+                fields: note.fields().clone(),
+                tags: note.tags,
+            });
+        }
+        notes.sort_unstable_by(|a, b| a.guid.cmp(&b.guid));
+
+        let mut notetype_ids: Vec<_> = notetype_ids.into_iter().collect();
+        notetype_ids.sort_unstable();
+        let mut notetypes = Vec::with_capacity(notetype_ids.len());
+        for ntid in notetype_ids {
+            let nt = self
+                .storage
+                .get_notetype(ntid)?
+                .ok_or(AnkiError::NotFound)?;
+            notetypes.push(notetype_to_json(nt)?);
+        }
+
+        let mut decks = vec![];
+        let mut config_ids = HashSet::new();
+        for did in deck_ids {
+            if let Some(deck) = self.storage.get_deck(did)? {
+                if let DeckKind::Normal(ref normal) = deck.kind {
+                    config_ids.insert(DeckConfID(normal.config_id));
+                    decks.push(JsonDeck {
+                        name: deck.human_name(),
+                        description: normal.description.clone(),
+                        config_id: DeckConfID(normal.config_id),
+                    });
+                }
+            }
+        }
+        decks.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        let mut config_ids: Vec<_> = config_ids.into_iter().collect();
+        config_ids.sort_unstable();
+        let mut deck_configs = Vec::with_capacity(config_ids.len());
+        for dcid in config_ids {
+            if let Some(conf) = self.storage.get_deck_config(dcid)? {
+                deck_configs.push(deck_config_to_json(conf)?);
+            }
+        }
+
+        Ok(JsonCollection {
+            notetypes,
+            deck_configs,
+            decks,
+            notes,
+        })
+    }
+
+    /// Apply an exported document to the collection. Notes are matched by
+    /// GUID, notetypes and deck options by ID, and decks by name. If
+    /// remove_missing is true, notes in the document's decks that are not
+    /// in the document are deleted.
+    pub fn import_json(
+        &mut self,
+        doc: JsonCollection,
+        remove_missing: bool,
+    ) -> Result<JsonImportOutput> {
+        self.transact(None, |col| col.import_json_inner(doc, remove_missing))
+    }
+
+    fn import_json_inner(
+        &mut self,
+        doc: JsonCollection,
+        remove_missing: bool,
+    ) -> Result<JsonImportOutput> {
+        let mut out = JsonImportOutput::default();
+        let usn = self.usn()?;
+
+        // notetypes merged into a local copy with a different ID
+        let mut notetype_ids = HashMap::new();
+        for val in doc.notetypes {
+            let nt = notetype_from_json(val)?;
+            let incoming_id = nt.id;
+            let ntid = self.import_json_notetype(nt, usn, &mut out)?;
+            if ntid != incoming_id {
+                notetype_ids.insert(incoming_id, ntid);
+            }
+        }
+
+        for val in doc.deck_configs {
+            let mut conf = deck_config_from_json(val)?;
+            if let Some(existing) = self.storage.get_deck_config(conf.id)? {
+                if existing.name == conf.name && existing.inner == conf.inner {
+                    continue;
+                }
+            }
+            self.add_or_update_deck_config(&mut conf, false)?;
+            out.deck_configs_updated += 1;
+        }
+
+        let mut deck_ids = HashMap::new();
+        for jdeck in doc.decks {
+            let mut deck = self.get_or_create_normal_deck(&jdeck.name)?;
+            let original = deck.clone();
+            if let DeckKind::Normal(ref mut normal) = deck.kind {
+                normal.description = jdeck.description;
+                if self.storage.get_deck_config(jdeck.config_id)?.is_some() {
+                    normal.config_id = jdeck.config_id.0;
+                }
+            }
+            if deck != original {
+                self.add_or_update_deck(&mut deck)?;
+                out.decks_updated += 1;
+            }
+            deck_ids.insert(human_deck_name_to_native(&jdeck.name), deck.id);
+        }
+
+        let mut guids = HashSet::new();
+        for mut jnote in doc.notes {
+            guids.insert(jnote.guid.clone());
+            if let Some(ntid) = notetype_ids.get(&jnote.notetype_id) {
+                jnote.notetype_id = *ntid;
+            }
+            let did = match deck_ids.get(&human_deck_name_to_native(&jnote.deck)) {
+                Some(did) => *did,
+                None => self.get_or_create_normal_deck(&jnote.deck)?.id,
+            };
+            self.import_json_note(jnote, did, &mut out)?;
+        }
+
+        if remove_missing && !deck_ids.is_empty() {
+            let dids: Vec<_> = deck_ids.values().cloned().collect();
+            let to_remove: Vec<_> = self
+                .storage
+                .note_ids_and_guids_in_decks(&dids)?
+                .into_iter()
+                .filter(|(_, guid)| !guids.contains(guid))
+                .map(|(nid, _)| nid)
+                .collect();
+            out.notes_removed = to_remove.len();
+            self.remove_notes(&to_remove)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the ID of the local notetype the incoming one was applied to.
+    fn import_json_notetype(
+        &mut self,
+        mut nt: NoteType,
+        usn: Usn,
+        out: &mut JsonImportOutput,
+    ) -> Result<NoteTypeID> {
+        if let Some(existing) = self.get_notetype(nt.id)? {
+            if notetype_to_json(existing.as_ref().clone())? == notetype_to_json(nt.clone())? {
+                return Ok(nt.id);
+            }
+            match_existing_ords(&mut nt, &existing);
+            nt.config.target_deck_id = existing.config.target_deck_id;
+            nt.mtime_secs = existing.mtime_secs;
+            for template in &mut nt.templates {
+                if let Some(ord) = template.ord {
+                    let existing_template = &existing.templates[ord as usize];
+                    template.mtime_secs = existing_template.mtime_secs;
+                    template.usn = existing_template.usn;
+                }
+            }
+            self.update_notetype(&mut nt, false)?;
+            out.notetypes_updated += 1;
+        } else if let Some(local_id) = self.get_notetype_by_origin(nt.origin_id())? {
+            // a copy distributed from the same notetype
+            if self.update_notetype_from_upstream(local_id, &nt)? {
+                out.notetypes_updated += 1;
+            }
+            return Ok(local_id);
+        } else {
+            nt.prepare_for_adding()?;
+            nt.set_modified(usn);
+            self.ensure_notetype_name_unique(&mut nt, usn)?;
+            self.storage.add_or_update_notetype(&nt)?;
+            out.notetypes_added += 1;
+        }
+
+        Ok(nt.id)
+    }
+
+    fn import_json_note(
+        &mut self,
+        jnote: JsonNote,
+        did: DeckID,
+        out: &mut JsonImportOutput,
+    ) -> Result<()> {
+        let nt = self
+            .get_notetype(jnote.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+        let ctx = CardGenContext::new(&nt, self.usn()?);
+        let norm = self.normalize_note_text();
+
+        if let Some(nid) = self.storage.get_note_id_by_guid(&jnote.guid)? {
+            let original = self.storage.get_note(nid)?.ok_or(AnkiError::NotFound)?;
+            if original.notetype_id != nt.id {
+                return Err(AnkiError::invalid_input(format!(
+                    "note {} uses a different notetype",
+                    jnote.guid
+                )));
+            }
+            let mut note = original.clone();
+            note.fields = jnote.fields;
+            note.fix_field_count(&nt);
+            note.tags = jnote.tags;
+            if note != original {
+                note.prepare_for_update(&nt, norm)?;
+                self.update_note_inner_generating_cards(&ctx, &mut note, true, norm)?;
+                out.notes_updated += 1;
+            }
+        } else {
+            let mut note = nt.new_note();
+            note.guid = jnote.guid;
+            note.fields = jnote.fields;
+            note.fix_field_count(&nt);
+            note.tags = jnote.tags;
+            self.add_note_inner(&ctx, &mut note, did, norm)?;
+            out.notes_added += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::collection::open_test_collection;
+
+    #[test]
+    fn json_round_trip() -> Result<()> {
+        let mut col = open_test_collection();
+        let deck = col.get_or_create_normal_deck("Shared::Verbs")?;
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        for word in &["b", "a"] {
+            let mut note = nt.new_note();
+            note.fields[0] = word.to_string();
+            note.tags = vec!["verb".into()];
+            col.add_note(&mut note, deck.id)?;
+        }
+
+        let doc = col.export_json("deck:Shared::Verbs")?;
+        assert_eq!(doc.notes.len(), 2);
+        assert!(doc.notes[0].guid < doc.notes[1].guid);
+        assert_eq!(doc.notes[0].deck, "Shared::Verbs");
+        // output is stable
+        let text = doc.to_json_string()?;
+        assert_eq!(
+            text,
+            col.export_json("deck:Shared::Verbs")?.to_json_string()?
+        );
+
+        // applying the export to a fresh collection adds everything
+        let mut col2 = open_test_collection();
+        let out = col2.import_json(JsonCollection::from_json_str(&text)?, false)?;
+        assert_eq!(out.notes_added, 2);
+        assert_eq!(out.notetypes_updated, 0);
+        assert_eq!(col2.export_json("deck:Shared::Verbs")?.notes, doc.notes);
+
+        // modify a note, remove the other, and apply the diff back
+        let mut doc2 = doc;
+        doc2.notes[0].fields[1] = "back".into();
+        let removed_guid = doc2.notes.pop().unwrap().guid;
+        let out = col.import_json(doc2.clone(), false)?;
+        assert_eq!(out.notes_updated, 1);
+        assert_eq!(out.notes_removed, 0);
+        assert!(col.storage.get_note_id_by_guid(&removed_guid)?.is_some());
+
+        let out = col.import_json(doc2, true)?;
+        assert_eq!(out.notes_updated, 0);
+        assert_eq!(out.notes_removed, 1);
+        assert!(col.storage.get_note_id_by_guid(&removed_guid)?.is_none());
+
+        Ok(())
+    }
+}
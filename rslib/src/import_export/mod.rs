@@ -0,0 +1,10 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+pub mod json;
+pub mod review_history;
+
+pub use json::{JsonCollection, JsonDeck, JsonImportOutput, JsonNote};
+pub use review_history::{
+    parse_review_history_csv, ExternalReview, ReviewHistoryImportOptions, ReviewHistoryImportOutput,
+};
@@ -0,0 +1,673 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Import of review history exported by other spaced repetition tools.
+//!
+//! The expected input is a CSV file with one review per line:
+//!
+//! `card key,timestamp,rating,duration`
+//!
+//! - card key: the text of the matching field in the target note (HTML is
+//!   ignored when comparing)
+//! - timestamp: when the review took place, in seconds since the UNIX epoch
+//! - rating: 1 (again), 2 (hard), 3 (good) or 4 (easy)
+//! - duration: seconds taken to answer; may be fractional
+//!
+//! An optional header row is skipped, as are blank lines and lines starting
+//! with '#'. Fields may be quoted with double quotes.
+
+use crate::{
+    card::{CardQueue, CardType},
+    deckconf::{DeckConf, DeckConfigInner},
+    notetype::NoteTypeID,
+    prelude::*,
+    revlog::{RevlogEntry, RevlogID, RevlogReviewKind},
+    text::strip_html_preserving_media_filenames,
+};
+use std::collections::{HashMap, HashSet};
+
+/// A single review read from an external tool's export.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExternalReview {
+    pub card_key: String,
+    pub timestamp: TimestampMillis,
+    /// 1-4, with the same meaning as Anki's answer buttons.
+    pub rating: u8,
+    pub taken_millis: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReviewHistoryImportOptions {
+    /// Notetype of the notes the reviews should be matched against.
+    pub notetype_id: NoteTypeID,
+    /// Field whose content is compared with the card key.
+    pub key_field: String,
+    /// The card of the matched note that receives the reviews.
+    pub card_ordinal: u16,
+    /// If true, the interval, due date and ease of each affected card are
+    /// recalculated from its complete review history.
+    pub reschedule: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReviewHistoryImportOutput {
+    pub imported: usize,
+    /// Reviews that were already present in the collection, or repeated in
+    /// the input.
+    pub duplicates: usize,
+    /// Keys that did not match any note, or whose note lacked the card.
+    pub unmatched_keys: Vec<String>,
+    pub cards_rescheduled: usize,
+}
+
+/// Parse the CSV format described in the module documentation.
+pub fn parse_review_history_csv(text: &str) -> Result<Vec<ExternalReview>> {
+    let mut reviews = vec![];
+    let mut first_row = true;
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let columns = split_csv_line(line).ok_or_else(|| {
+            AnkiError::invalid_input(format!("line {}: unbalanced quotes", idx + 1))
+        })?;
+        if columns.len() != 4 {
+            return Err(AnkiError::invalid_input(format!(
+                "line {}: expected 4 columns, found {}",
+                idx + 1,
+                columns.len()
+            )));
+        }
+        let is_first_row = std::mem::replace(&mut first_row, false);
+        match review_from_columns(&columns) {
+            Some(review) => reviews.push(review),
+            // a non-numeric timestamp on the first row is a header
+            None if is_first_row && columns[1].trim().parse::<f64>().is_err() => continue,
+            None => {
+                return Err(AnkiError::invalid_input(format!(
+                    "line {}: invalid review",
+                    idx + 1
+                )))
+            }
+        }
+    }
+
+    Ok(reviews)
+}
+
+fn review_from_columns(columns: &[String]) -> Option<ExternalReview> {
+    let timestamp: f64 = columns[1].trim().parse().ok()?;
+    let rating: u8 = columns[2].trim().parse().ok()?;
+    let duration: f64 = columns[3].trim().parse().ok()?;
+    if !(1..=4).contains(&rating) || timestamp < 0.0 || duration < 0.0 {
+        return None;
+    }
+
+    Some(ExternalReview {
+        card_key: columns[0].clone(),
+        timestamp: TimestampMillis((timestamp * 1000.0) as i64),
+        rating,
+        taken_millis: (duration * 1000.0).min(u32::max_value() as f64) as u32,
+    })
+}
+
+/// Split a line into columns, handling double-quoted columns and doubled
+/// quotes inside them. Returns None if a quote was not closed.
+fn split_csv_line(line: &str) -> Option<Vec<String>> {
+    let mut columns = vec![];
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    current.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if current.trim().is_empty() => {
+                current.clear();
+                in_quotes = true;
+            }
+            ',' if !in_quotes => columns.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        None
+    } else {
+        columns.push(current);
+        Some(columns)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplayPhase {
+    New,
+    Learning,
+    Review,
+    Relearning,
+}
+
+/// Tracks a card's scheduling state while its history is replayed, using
+/// the V2 scheduler's rules and the card's deck options.
+struct HistoryReplayer<'a> {
+    conf: &'a DeckConfigInner,
+    phase: ReplayPhase,
+    step: usize,
+    interval: u32,
+    ease_factor: u16,
+    due_day: i32,
+    revlog_interval: i32,
+    reps: u32,
+    lapses: u32,
+}
+
+impl<'a> HistoryReplayer<'a> {
+    fn new(conf: &'a DeckConfigInner) -> Self {
+        HistoryReplayer {
+            conf,
+            phase: ReplayPhase::New,
+            step: 0,
+            interval: 0,
+            ease_factor: 0,
+            due_day: 0,
+            revlog_interval: 0,
+            reps: 0,
+            lapses: 0,
+        }
+    }
+
+    /// Apply an existing revlog entry without altering it.
+    fn apply_existing(&mut self, entry: &RevlogEntry, day: i32) {
+        if entry.review_kind == RevlogReviewKind::Manual {
+            if entry.interval > 0 {
+                self.phase = ReplayPhase::Review;
+                self.interval = entry.interval as u32;
+                self.due_day = day + entry.interval;
+                if self.ease_factor == 0 {
+                    self.ease_factor = (self.conf.initial_ease * 1000.0) as u16;
+                }
+                self.revlog_interval = entry.interval;
+            }
+        } else if entry.button_chosen > 0 {
+            self.answer(entry.button_chosen, day);
+        }
+    }
+
+    /// Update state for an answer, returning the revlog kind and interval.
+    fn answer(&mut self, rating: u8, day: i32) -> (RevlogReviewKind, i32) {
+        self.reps += 1;
+        if self.phase == ReplayPhase::New {
+            self.phase = ReplayPhase::Learning;
+            self.step = 0;
+            self.ease_factor = (self.conf.initial_ease * 1000.0) as u16;
+        }
+
+        let kind = match self.phase {
+            ReplayPhase::New | ReplayPhase::Learning => {
+                self.answer_learning(rating, day);
+                RevlogReviewKind::Learning
+            }
+            ReplayPhase::Review => {
+                let early = day < self.due_day;
+                self.answer_review(rating, day);
+                if early {
+                    RevlogReviewKind::EarlyReview
+                } else {
+                    RevlogReviewKind::Review
+                }
+            }
+            ReplayPhase::Relearning => {
+                self.answer_relearning(rating, day);
+                RevlogReviewKind::Relearning
+            }
+        };
+
+        self.revlog_interval = match self.phase {
+            ReplayPhase::Review => self.interval as i32,
+            ReplayPhase::Relearning => -learning_step_secs(&self.conf.relearn_steps, self.step),
+            _ => -learning_step_secs(&self.conf.learn_steps, self.step),
+        };
+
+        (kind, self.revlog_interval)
+    }
+
+    fn answer_learning(&mut self, rating: u8, day: i32) {
+        match rating {
+            1 => self.step = 0,
+            2 => (),
+            3 => {
+                self.step += 1;
+                if self.step >= self.conf.learn_steps.len() {
+                    self.graduate(self.conf.graduating_interval_good, day);
+                }
+            }
+            _ => self.graduate(self.conf.graduating_interval_easy, day),
+        }
+    }
+
+    fn graduate(&mut self, interval: u32, day: i32) {
+        self.phase = ReplayPhase::Review;
+        self.step = 0;
+        self.interval = self.constrain_interval(interval as f32, 1);
+        self.due_day = day + self.interval as i32;
+    }
+
+    fn answer_review(&mut self, rating: u8, day: i32) {
+        let days_late = (day - self.due_day).max(0) as f32;
+        // early reviews are scheduled from the time that has actually elapsed
+        let elapsed = if day < self.due_day {
+            (self.interval as i32 - (self.due_day - day)).max(1) as u32
+        } else {
+            self.interval.max(1)
+        };
+        let ease = self.ease_factor as f32 / 1000.0;
+
+        if rating == 1 {
+            self.lapses += 1;
+            self.ease_factor = self.ease_factor.saturating_sub(200).max(1300);
+            let lapsed = (self.interval as f32 * self.conf.lapse_multiplier) as u32;
+            self.interval = lapsed
+                .max(self.conf.minimum_review_interval)
+                .max(1)
+                .min(self.conf.maximum_review_interval.max(1));
+            if self.conf.relearn_steps.is_empty() {
+                self.due_day = day + self.interval as i32;
+            } else {
+                self.phase = ReplayPhase::Relearning;
+                self.step = 0;
+            }
+            return;
+        }
+
+        let hard = self.constrain_interval(elapsed as f32 * self.conf.hard_multiplier, elapsed + 1);
+        self.interval = match rating {
+            2 => {
+                self.ease_factor = self.ease_factor.saturating_sub(150).max(1300);
+                hard
+            }
+            3 => self.constrain_interval((elapsed as f32 + days_late / 2.0) * ease, hard + 1),
+            _ => {
+                let good =
+                    self.constrain_interval((elapsed as f32 + days_late / 2.0) * ease, hard + 1);
+                self.ease_factor = self.ease_factor.saturating_add(150);
+                self.constrain_interval(
+                    (elapsed as f32 + days_late) * ease * self.conf.easy_multiplier,
+                    good + 1,
+                )
+            }
+        };
+        self.due_day = day + self.interval as i32;
+    }
+
+    fn answer_relearning(&mut self, rating: u8, day: i32) {
+        match rating {
+            1 => self.step = 0,
+            2 => (),
+            3 => {
+                self.step += 1;
+                if self.step >= self.conf.relearn_steps.len() {
+                    self.phase = ReplayPhase::Review;
+                    self.step = 0;
+                    self.due_day = day + self.interval as i32;
+                }
+            }
+            _ => {
+                self.phase = ReplayPhase::Review;
+                self.step = 0;
+                self.interval = self.constrain_interval(self.interval as f32 + 1.0, 1);
+                self.due_day = day + self.interval as i32;
+            }
+        }
+    }
+
+    fn constrain_interval(&self, interval: f32, minimum: u32) -> u32 {
+        ((interval * self.conf.interval_multiplier) as u32)
+            .max(minimum)
+            .min(self.conf.maximum_review_interval.max(1))
+            .max(1)
+    }
+
+    /// Update the card to reflect the replayed history.
+    fn apply_to_card(&self, card: &mut Card, last_review: TimestampSecs) {
+        let (ctype, steps) = match self.phase {
+            ReplayPhase::New => return,
+            ReplayPhase::Learning => (CardType::Learn, &self.conf.learn_steps),
+            ReplayPhase::Review => (CardType::Review, &self.conf.learn_steps),
+            ReplayPhase::Relearning => (CardType::Relearn, &self.conf.relearn_steps),
+        };
+
+        card.remove_from_filtered_deck_before_reschedule();
+        card.ctype = ctype;
+        card.interval = self.interval;
+        card.ease_factor = self.ease_factor;
+        card.reps = self.reps;
+        card.lapses = self.lapses;
+        let queue = if ctype == CardType::Review {
+            card.due = self.due_day.max(0);
+            card.remaining_steps = 0;
+            CardQueue::Review
+        } else {
+            card.due = (last_review.0 + learning_step_secs(steps, self.step) as i64) as i32;
+            card.remaining_steps = steps.len().saturating_sub(self.step) as u32;
+            CardQueue::Learn
+        };
+        // leave suspended and buried cards where they are
+        if (card.queue as i8) >= 0 {
+            card.queue = queue;
+        }
+    }
+}
+
+fn learning_step_secs(steps: &[f32], idx: usize) -> i32 {
+    steps
+        .get(idx)
+        .or_else(|| steps.last())
+        .map(|mins| (mins * 60.0) as i32)
+        .unwrap_or(60)
+        .max(1)
+}
+
+impl Collection {
+    /// Import review history from an external tool, attaching each review
+    /// to the card of the note whose key field matches.
+    pub fn import_review_history(
+        &mut self,
+        reviews: Vec<ExternalReview>,
+        opts: &ReviewHistoryImportOptions,
+    ) -> Result<ReviewHistoryImportOutput> {
+        self.transact(None, |col| col.import_review_history_inner(reviews, opts))
+    }
+
+    fn import_review_history_inner(
+        &mut self,
+        reviews: Vec<ExternalReview>,
+        opts: &ReviewHistoryImportOptions,
+    ) -> Result<ReviewHistoryImportOutput> {
+        let nt = self
+            .get_notetype(opts.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+        let field_ord = nt
+            .get_field_ord(&opts.key_field)
+            .ok_or_else(|| AnkiError::invalid_input("missing key field"))?;
+        let notes_by_key: HashMap<String, NoteID> = self
+            .storage
+            .note_ids_and_field(opts.notetype_id, field_ord)?
+            .into_iter()
+            .map(|(nid, field)| (normalized_key(&field), nid))
+            .collect();
+
+        let mut out = ReviewHistoryImportOutput::default();
+        let mut unmatched = HashSet::new();
+        let mut reviews_by_card: HashMap<CardID, Vec<ExternalReview>> = HashMap::new();
+        for review in reviews {
+            let card = match notes_by_key.get(&normalized_key(&review.card_key)) {
+                Some(nid) => self.storage.get_card_by_ordinal(*nid, opts.card_ordinal)?,
+                None => None,
+            };
+            if let Some(card) = card {
+                reviews_by_card.entry(card.id).or_default().push(review);
+            } else if unmatched.insert(review.card_key.clone()) {
+                out.unmatched_keys.push(review.card_key);
+            }
+        }
+
+        let usn = self.usn()?;
+        let timing = self.timing_today()?;
+        let day_for_stamp = |millis: i64| -> i32 {
+            let days_ago = (timing.next_day_at - 1 - millis / 1000).max(0) / 86_400;
+            timing.days_elapsed as i32 - days_ago as i32
+        };
+
+        for (cid, mut imported) in reviews_by_card {
+            let original = match self.storage.get_card(cid)? {
+                Some(card) => card,
+                None => continue,
+            };
+            let existing = self.storage.get_revlog_entries_for_card(cid)?;
+            // repeated rows in the input only count once
+            let before = imported.len();
+            imported.sort_by_key(|r| r.timestamp);
+            imported.dedup_by_key(|r| r.timestamp);
+            let mut kept = Vec::with_capacity(imported.len());
+            for review in imported {
+                if !self.review_already_imported(cid, review.timestamp)? {
+                    kept.push(review);
+                }
+            }
+            out.duplicates += before - kept.len();
+            let imported = kept;
+            if imported.is_empty() {
+                continue;
+            }
+
+            let conf = self.deck_config_for_card(&original)?;
+            let mut replayer = HistoryReplayer::new(&conf.inner);
+
+            // merge the imported reviews into the existing history
+            let mut history: Vec<(
+                TimestampMillis,
+                Option<&RevlogEntry>,
+                Option<ExternalReview>,
+            )> = existing.iter().map(|e| (e.id, Some(e), None)).collect();
+            history.extend(imported.into_iter().map(|r| (r.timestamp, None, Some(r))));
+            history.sort_by_key(|(stamp, _, _)| *stamp);
+
+            let mut last_review = TimestampSecs(0);
+            for (stamp, entry, review) in history {
+                let day = day_for_stamp(stamp.0);
+                last_review = stamp.as_secs();
+                if let Some(entry) = entry {
+                    replayer.apply_existing(entry, day);
+                } else if let Some(review) = review {
+                    let last_interval = replayer.revlog_interval;
+                    let (review_kind, interval) = replayer.answer(review.rating, day);
+                    self.storage.add_revlog_entry(&RevlogEntry {
+                        id: self.free_revlog_id(review.timestamp)?,
+                        cid,
+                        usn,
+                        button_chosen: review.rating,
+                        interval,
+                        last_interval,
+                        ease_factor: replayer.ease_factor as u32,
+                        taken_millis: review.taken_millis,
+                        review_kind,
+                    })?;
+                    out.imported += 1;
+                }
+            }
+
+            if opts.reschedule {
+                let mut card = original.clone();
+                replayer.apply_to_card(&mut card, last_review);
+                if card != original {
+                    self.update_card(&mut card, &original, usn)?;
+                    out.cards_rescheduled += 1;
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn deck_config_for_card(&mut self, card: &Card) -> Result<DeckConf> {
+        let did = if card.original_deck_id.0 > 0 {
+            card.original_deck_id
+        } else {
+            card.deck_id
+        };
+        let dcid = self
+            .get_deck(did)?
+            .and_then(|deck| deck.config_id())
+            .unwrap_or(DeckConfID(1));
+        self.get_deck_config(dcid, true)?
+            .ok_or_else(|| AnkiError::invalid_input("missing deck config"))
+    }
+
+    /// True if a review of the card at the given time was imported before.
+    /// Reviews are stored at the first free ID at or after their timestamp
+    /// (see free_revlog_id()), so the same IDs are checked here.
+    fn review_already_imported(&self, cid: CardID, stamp: TimestampMillis) -> Result<bool> {
+        let mut id = stamp.0;
+        while let Some(entry) = self.storage.get_revlog_entry(RevlogID(id))? {
+            if entry.cid == cid {
+                return Ok(true);
+            }
+            id += 1;
+        }
+        Ok(false)
+    }
+
+    /// The first unused revlog ID at or after the timestamp. Taking the next
+    /// millisecond instead of the largest ID + 1 keeps the original time
+    /// recoverable, so a later import can recognise the review.
+    fn free_revlog_id(&self, stamp: TimestampMillis) -> Result<TimestampMillis> {
+        let mut id = stamp.0;
+        while self.storage.get_revlog_entry(RevlogID(id))?.is_some() {
+            id += 1;
+        }
+        Ok(TimestampMillis(id))
+    }
+}
+
+fn normalized_key(text: &str) -> String {
+    strip_html_preserving_media_filenames(text)
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::collection::open_test_collection;
+
+    #[test]
+    fn csv_parsing() -> Result<()> {
+        let reviews = parse_review_history_csv(
+            "key,timestamp,rating,duration\n\
+             \"foo, bar\",1600000000,3,2.5\n\
+             \n\
+             # comment\n\
+             baz,1600000100.5,1,0\n",
+        )?;
+        assert_eq!(
+            reviews,
+            vec![
+                ExternalReview {
+                    card_key: "foo, bar".into(),
+                    timestamp: TimestampMillis(1_600_000_000_000),
+                    rating: 3,
+                    taken_millis: 2500,
+                },
+                ExternalReview {
+                    card_key: "baz".into(),
+                    timestamp: TimestampMillis(1_600_000_100_500),
+                    rating: 1,
+                    taken_millis: 0,
+                }
+            ]
+        );
+
+        assert!(parse_review_history_csv("foo,1600000000,5,1").is_err());
+        assert!(parse_review_history_csv("foo,1600000000,3").is_err());
+        assert!(parse_review_history_csv("\"foo,1600000000,3,1").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn importing() -> Result<()> {
+        let mut col = open_test_collection();
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = "<b>hello</b>".into();
+        col.add_note(&mut note, DeckID(1))?;
+
+        let now = TimestampSecs::now().0;
+        let review = |secs_ago: i64, rating: u8| ExternalReview {
+            card_key: "hello".into(),
+            timestamp: TimestampMillis((now - secs_ago) * 1000),
+            rating,
+            taken_millis: 3000,
+        };
+        let reviews = vec![
+            review(86_400 * 10 + 600, 3),
+            review(86_400 * 10, 3),
+            review(86_400 * 9, 3),
+            review(86_400 * 2, 1),
+            // repeated row
+            review(86_400 * 9, 3),
+            ExternalReview {
+                card_key: "missing".into(),
+                ..review(86_400, 3)
+            },
+        ];
+        let opts = ReviewHistoryImportOptions {
+            notetype_id: nt.id,
+            key_field: "Front".into(),
+            card_ordinal: 0,
+            reschedule: true,
+        };
+        let out = col.import_review_history(reviews.clone(), &opts)?;
+        assert_eq!(out.imported, 4);
+        assert_eq!(out.duplicates, 1);
+        assert_eq!(out.unmatched_keys, vec!["missing".to_string()]);
+        assert_eq!(out.cards_rescheduled, 1);
+
+        let card = col.storage.get_card_by_ordinal(note.id, 0)?.unwrap();
+        let kinds: Vec<_> = col
+            .storage
+            .get_revlog_entries_for_card(card.id)?
+            .into_iter()
+            .map(|e| e.review_kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                RevlogReviewKind::Learning,
+                RevlogReviewKind::Learning,
+                RevlogReviewKind::Review,
+                RevlogReviewKind::Review
+            ]
+        );
+        assert_eq!(card.ctype, CardType::Relearn);
+        assert_eq!(card.queue, CardQueue::Learn);
+        assert_eq!(card.reps, 4);
+        assert_eq!(card.lapses, 1);
+
+        // importing the same history again is a no-op
+        let out = col.import_review_history(reviews, &opts)?;
+        assert_eq!(out.imported, 0);
+        assert_eq!(out.duplicates, 5);
+
+        // a review whose timestamp is taken by another card's review is
+        // stored at the next free ID, and still recognised when imported again
+        let stamp = TimestampMillis((now - 86_400) * 1000);
+        col.storage.add_revlog_entry(&RevlogEntry {
+            id: stamp,
+            cid: CardID(1),
+            ..Default::default()
+        })?;
+        let reviews = vec![review(86_400, 3)];
+        let out = col.import_review_history(reviews.clone(), &opts)?;
+        assert_eq!(out.imported, 1);
+        assert_eq!(
+            col.storage
+                .get_revlog_entry(RevlogID(stamp.0 + 1))?
+                .map(|e| e.cid),
+            Some(card.id)
+        );
+        let out = col.import_review_history(reviews, &opts)?;
+        assert_eq!(out.imported, 0);
+        assert_eq!(out.duplicates, 1);
+
+        Ok(())
+    }
+}
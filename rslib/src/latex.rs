@@ -2,11 +2,40 @@
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
 use crate::cloze::expand_clozes_to_reveal_latex;
+use crate::err::{AnkiError, Result};
 use crate::media::files::sha1_of_data;
 use crate::text::strip_html;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
-use std::borrow::Cow;
+use std::{borrow::Cow, fs, path::Path, process::Command};
+
+static PNG_COMMANDS: &[&[&str]] = &[
+    &["latex", "-interaction=nonstopmode", "tmp.tex"],
+    &[
+        "dvipng", "-D", "200", "-T", "tight", "tmp.dvi", "-o", "tmp.png",
+    ],
+];
+
+static SVG_COMMANDS: &[&[&str]] = &[
+    &["latex", "-interaction=nonstopmode", "tmp.tex"],
+    &[
+        "dvisvgm",
+        "--no-fonts",
+        "--exact",
+        "-Z",
+        "2",
+        "tmp.dvi",
+        "-o",
+        "tmp.svg",
+    ],
+];
+
+/// Commands that could read or write other files. Running LaTeX is only
+/// really secure in a jail, but these are the most common.
+static UNSAFE_COMMANDS: &[&str] = &[
+    "write18", "readline", "input", "include", "catcode", "openout", "write", "loop", "def",
+    "shipout",
+];
 
 lazy_static! {
     static ref LATEX: Regex = Regex::new(
@@ -97,6 +126,70 @@ fn fname_for_latex(latex: &str, svg: bool) -> String {
     format!("latex-{}.{}", csum, ext)
 }
 
+/// Run LaTeX on the extracted text, wrapped in the notetype's header and
+/// footer, saving the resulting image into the media folder. Requires latex
+/// and dvipng or dvisvgm to be installed.
+pub(crate) fn generate_latex_image(
+    extracted: &ExtractedLatex,
+    header: &str,
+    footer: &str,
+    svg: bool,
+    media_folder: &Path,
+) -> Result<()> {
+    let latex = format!("{}\n{}\n{}", header, extracted.latex, footer);
+    if let Some(command) = unsafe_latex_command(&latex) {
+        return Err(AnkiError::invalid_input(format!(
+            "LaTeX command not allowed: \\{}",
+            command
+        )));
+    }
+
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("tmp.tex"), &latex)?;
+    let (commands, ext) = if svg {
+        (SVG_COMMANDS, "svg")
+    } else {
+        (PNG_COMMANDS, "png")
+    };
+    for args in commands {
+        let output = Command::new(args[0])
+            .args(&args[1..])
+            .current_dir(dir.path())
+            .output()?;
+        if !output.status.success() {
+            return Err(AnkiError::IOError {
+                info: format!(
+                    "{} failed: {}",
+                    args[0],
+                    String::from_utf8_lossy(&output.stdout)
+                ),
+            });
+        }
+    }
+    fs::copy(
+        dir.path().join(format!("tmp.{}", ext)),
+        media_folder.join(&extracted.fname),
+    )?;
+
+    Ok(())
+}
+
+/// The first disallowed command in the text, if any. Commands only match
+/// when not followed by a letter, so `\definecolor` is not mistaken for `\def`.
+fn unsafe_latex_command(latex: &str) -> Option<&'static str> {
+    let latex = latex.replace("\\includegraphics", "");
+    UNSAFE_COMMANDS.iter().copied().find(|command| {
+        let needle = format!("\\{}", command);
+        latex.match_indices(&needle).any(|(idx, _)| {
+            !latex[idx + needle.len()..]
+                .chars()
+                .next()
+                .map(|c| c.is_ascii_alphabetic())
+                .unwrap_or_default()
+        })
+    })
+}
+
 fn image_link_for_fname(src: &str, fname: &str) -> String {
     format!(
         "<img class=latex alt=\"{}\" src=\"{}\">",
@@ -107,7 +200,7 @@ fn image_link_for_fname(src: &str, fname: &str) -> String {
 
 #[cfg(test)]
 mod test {
-    use crate::latex::{extract_latex, ExtractedLatex};
+    use crate::latex::{extract_latex, unsafe_latex_command, ExtractedLatex};
 
     #[test]
     fn latex() {
@@ -142,4 +235,13 @@ mod test {
             }]
         );
     }
+
+    #[test]
+    fn unsafe_commands() {
+        assert_eq!(unsafe_latex_command(r"$x^2$"), None);
+        assert_eq!(unsafe_latex_command(r"\input{/etc/passwd}"), Some("input"));
+        assert_eq!(unsafe_latex_command(r"\write18{rm}"), Some("write18"));
+        assert_eq!(unsafe_latex_command(r"\definecolor{c}{rgb}{1,0,0}"), None);
+        assert_eq!(unsafe_latex_command(r"\includegraphics{a.png}"), None);
+    }
 }
@@ -5,6 +5,7 @@
 
 pub mod backend;
 mod backend_proto;
+pub mod backup;
 pub mod card;
 pub mod cloze;
 pub mod collection;
@@ -13,10 +14,13 @@ pub mod dbcheck;
 pub mod deckconf;
 pub mod decks;
 pub mod err;
+pub mod fieldedit;
 pub mod filtered;
 pub mod findreplace;
 mod fluent_proto;
 pub mod i18n;
+pub mod image_occlusion;
+pub mod import_export;
 pub mod latex;
 pub mod log;
 pub mod media;
@@ -30,7 +34,7 @@ pub mod search;
 pub mod serde;
 mod stats;
 pub mod storage;
-mod sync;
+pub mod sync;
 pub mod tags;
 pub mod template;
 pub mod template_filters;
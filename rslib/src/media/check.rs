@@ -392,7 +392,7 @@ where
                 &self.mgr.media_folder,
             )? {
                 // note was modified, needs saving
-                note.prepare_for_update(nt, false)?;
+                note.prepare_for_update_unchecked(nt, false)?;
                 note.set_modified(usn);
                 self.ctx.storage.update_note(&note)?;
                 collection_modified = true;
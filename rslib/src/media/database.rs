@@ -211,6 +211,22 @@ delete from media where fname=?"
         results
     }
 
+    /// Used by the sync server, which stores the usn each entry was last
+    /// changed in its mtime column.
+    pub(super) fn entries_changed_since(
+        &mut self,
+        usn: i32,
+        max_entries: u32,
+    ) -> Result<Vec<MediaEntry>> {
+        let mut stmt = self.db.prepare(
+            "select fname, csum, mtime, dirty from media where mtime > ? order by mtime limit ?",
+        )?;
+        let results: rusqlite::Result<Vec<_>> = stmt
+            .query_map(params![usn, max_entries], row_to_entry)?
+            .collect();
+        Ok(results?)
+    }
+
     pub(super) fn all_mtimes(&mut self) -> Result<HashMap<String, i64>> {
         let mut stmt = self
             .db
@@ -221,6 +237,14 @@ delete from media where fname=?"
         Ok(map?)
     }
 
+    /// The names of all files, including deleted ones.
+    pub(super) fn all_fnames(&mut self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare("select fname from media")?;
+        let fnames: std::result::Result<Vec<String>, rusqlite::Error> =
+            stmt.query_map(NO_PARAMS, |row| row.get(0))?.collect();
+        Ok(fnames?)
+    }
+
     pub(super) fn force_resync(&mut self) -> Result<()> {
         self.db
             .execute_batch("delete from media; update meta set lastUsn = 0, dirMod = 0")
@@ -5,6 +5,7 @@ use crate::err::Result;
 use crate::media::database::{open_or_create, MediaDatabaseContext, MediaEntry};
 use crate::media::files::{add_data_to_folder_uniquely, mtime_as_i64, remove_files, sha1_of_data};
 use crate::media::sync::{MediaSyncProgress, MediaSyncer};
+use crate::sync::SyncKey;
 use rusqlite::Connection;
 use slog::Logger;
 use std::borrow::Cow;
@@ -14,6 +15,7 @@ pub mod changetracker;
 pub mod check;
 pub mod database;
 pub mod files;
+pub mod server;
 pub mod sync;
 
 pub struct MediaManager {
@@ -127,22 +129,31 @@ impl MediaManager {
         })
     }
 
-    /// Sync media.
+    /// Sync media. If `key` is provided, files are encrypted before they are
+    /// sent.
     pub async fn sync_media<'a, F>(
         &'a self,
         progress: F,
         host_number: u32,
         hkey: &'a str,
+        key: Option<SyncKey>,
         log: Logger,
     ) -> Result<()>
     where
         F: FnMut(MediaSyncProgress) -> bool,
     {
         let mut syncer = MediaSyncer::new(self, progress, host_number, log);
+        syncer.set_encryption_key(key);
         syncer.sync(hkey).await
     }
 
     pub fn dbctx(&self) -> MediaDatabaseContext {
         MediaDatabaseContext::new(&self.db)
     }
+
+    /// Forget what has been synced, so every file is compared with the
+    /// server on the next sync.
+    pub(crate) fn force_resync(&self) -> Result<()> {
+        self.dbctx().transact(|ctx| ctx.force_resync())
+    }
 }
@@ -0,0 +1,244 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! The server side of media syncing, answering the requests MediaSyncer
+//! makes.
+//!
+//! The server uses the same database as clients do, but as it never scans its
+//! media folder, the mtime column instead holds the usn in which each file was
+//! last changed, and lastUsn in the meta table holds the current usn.
+//!
+//! When a profile's media is served to peers, the folder also belongs to a
+//! client, so the server keeps its own database next to the client's, and
+//! picks up changes made to the folder by the client with
+//! register_folder_changes().
+
+use crate::err::{AnkiError, Result};
+use crate::media::changetracker::ChangeTracker;
+use crate::media::database::MediaEntry;
+use crate::media::files::{data_for_file, normalize_filename, remove_files, sha1_of_data};
+use crate::media::sync::{
+    FinalizeRequest, RecordBatchRequest, ServerMediaRecord, SyncBeginResponse, UploadReply,
+    SYNC_MAX_BYTES,
+};
+use crate::media::MediaManager;
+use serde_derive::{Deserialize, Serialize};
+use slog::{debug, Logger};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The maximum number of records returned by a mediaChanges request.
+static SYNC_MAX_RECORDS: u32 = 250;
+
+/// The envelope replies are wrapped in.
+#[derive(Serialize)]
+struct Reply<T> {
+    data: Option<T>,
+    err: String,
+}
+
+#[derive(Deserialize)]
+struct DownloadFilesRequest {
+    files: Vec<String>,
+}
+
+pub struct MediaSyncServer {
+    mgr: MediaManager,
+    log: Logger,
+}
+
+impl MediaSyncServer {
+    pub fn new<P: Into<PathBuf>>(media_folder: P, media_db: &Path, log: Logger) -> Result<Self> {
+        let media_folder = media_folder.into();
+        fs::create_dir_all(&media_folder)?;
+        Ok(MediaSyncServer {
+            mgr: MediaManager::new(media_folder, media_db)?,
+            log,
+        })
+    }
+
+    /// Reply to a begin request. The provided key identifies the client in
+    /// subsequent requests.
+    pub fn begin(&self, sync_key: String) -> Result<Vec<u8>> {
+        let usn = self.mgr.dbctx().get_meta()?.last_sync_usn;
+        reply(SyncBeginResponse { sync_key, usn })
+    }
+
+    /// Give files changed in the media folder by its client a new usn, so
+    /// they are sent to other clients. The client's own database, which is
+    /// used to detect the changes, is marked as in sync afterwards.
+    pub fn register_folder_changes(&self, client_db: &Path) -> Result<()> {
+        let client = MediaManager::new(&self.mgr.media_folder, client_db)?;
+        let mut client_ctx = client.dbctx();
+        ChangeTracker::new(&client.media_folder, |_| true, &self.log)
+            .register_changes(&mut client_ctx)?;
+        let pending = client_ctx.get_pending_uploads(u32::max_value())?;
+        let log = &self.log;
+
+        self.mgr.dbctx().transact(|ctx| {
+            let mut meta = ctx.get_meta()?;
+            for entry in &pending {
+                let known_sha1 = ctx.get_entry(&entry.fname)?.and_then(|entry| entry.sha1);
+                if known_sha1 == entry.sha1 {
+                    // written by a sync, or added and removed again
+                    continue;
+                }
+                meta.last_sync_usn += 1;
+                debug!(log, "folder change"; "fname"=>&entry.fname);
+                ctx.set_entry(&MediaEntry {
+                    fname: entry.fname.clone(),
+                    sha1: entry.sha1,
+                    mtime: meta.last_sync_usn as i64,
+                    sync_required: false,
+                })?;
+            }
+            ctx.set_meta(&meta)
+        })?;
+
+        client_ctx.transact(|ctx| {
+            for mut entry in pending {
+                entry.sync_required = false;
+                ctx.set_entry(&entry)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Reply to any other request, whose input is in `data`. Replies are JSON,
+    /// except for downloadFiles, which returns a zip file.
+    pub fn handle(&self, method: &str, data: &[u8]) -> Result<Vec<u8>> {
+        match method {
+            "mediaChanges" => {
+                let req: RecordBatchRequest = serde_json::from_slice(data)?;
+                reply(self.changes_since(req.last_usn)?)
+            }
+            "downloadFiles" => {
+                let req: DownloadFilesRequest = serde_json::from_slice(data)?;
+                self.zip_files(&req.files)
+            }
+            "uploadChanges" => reply(self.apply_uploaded_zip(data)?),
+            "mediaSanity" => {
+                let req: FinalizeRequest = serde_json::from_slice(data)?;
+                let status = if self.mgr.dbctx().count()? == req.local {
+                    "OK"
+                } else {
+                    "FAILED"
+                };
+                reply(status)
+            }
+            _ => Err(AnkiError::invalid_input(format!(
+                "unknown method: {}",
+                method
+            ))),
+        }
+    }
+
+    fn changes_since(&self, usn: i32) -> Result<Vec<ServerMediaRecord>> {
+        Ok(self
+            .mgr
+            .dbctx()
+            .entries_changed_since(usn, SYNC_MAX_RECORDS)?
+            .into_iter()
+            .map(|entry| ServerMediaRecord {
+                fname: entry.fname,
+                usn: entry.mtime as i32,
+                sha1: entry.sha1.map(hex::encode).unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    /// Zip up the requested files, in the format extract_into_media_folder()
+    /// expects. If the files are large, only the first ones are included, and
+    /// the client will request the rest again.
+    fn zip_files(&self, fnames: &[String]) -> Result<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let mut fmap = HashMap::new();
+        let mut accumulated_size = 0;
+
+        for (idx, fname) in fnames.iter().enumerate() {
+            if accumulated_size > SYNC_MAX_BYTES {
+                break;
+            }
+            check_filename(fname)?;
+            let data = data_for_file(&self.mgr.media_folder, fname)?
+                .ok_or_else(|| AnkiError::sync_misc(format!("missing file: {}", fname)))?;
+            accumulated_size += data.len();
+
+            let zip_name = idx.to_string();
+            zip.start_file(zip_name.as_str(), options)?;
+            zip.write_all(&data)?;
+            fmap.insert(zip_name, fname);
+        }
+
+        zip.start_file("_meta", options)?;
+        zip.write_all(serde_json::to_string(&fmap)?.as_bytes())?;
+
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Apply a zip built by zip_files() in sync.rs. Each file is given its own
+    /// usn, which the client relies on to check no other changes were made.
+    fn apply_uploaded_zip(&self, data: &[u8]) -> Result<UploadReply> {
+        let mut zip = zip::ZipArchive::new(io::Cursor::new(data))?;
+        let entries: Vec<(String, Option<String>)> =
+            serde_json::from_reader(zip.by_name("_meta")?)?;
+        let media_folder = &self.mgr.media_folder;
+        let log = &self.log;
+
+        self.mgr.dbctx().transact(|ctx| {
+            let mut meta = ctx.get_meta()?;
+            for (fname, zip_name) in &entries {
+                check_filename(fname)?;
+                meta.last_sync_usn += 1;
+                let sha1 = if let Some(zip_name) = zip_name {
+                    let mut data = vec![];
+                    zip.by_name(zip_name)?.read_to_end(&mut data)?;
+                    debug!(log, "write"; "fname"=>fname);
+                    fs::write(media_folder.join(fname), &data)?;
+                    Some(sha1_of_data(&data))
+                } else {
+                    debug!(log, "remove"; "fname"=>fname);
+                    remove_files(media_folder, &[fname])?;
+                    None
+                };
+                ctx.set_entry(&MediaEntry {
+                    fname: fname.clone(),
+                    sha1,
+                    mtime: meta.last_sync_usn as i64,
+                    sync_required: false,
+                })?;
+            }
+            ctx.set_meta(&meta)?;
+
+            Ok(UploadReply {
+                processed: entries.len(),
+                current_usn: meta.last_sync_usn,
+            })
+        })
+    }
+}
+
+fn reply<T: serde::Serialize>(data: T) -> Result<Vec<u8>> {
+    let reply = Reply {
+        data: Some(data),
+        err: String::new(),
+    };
+    serde_json::to_vec(&reply).map_err(Into::into)
+}
+
+/// Clients only send normalized names, and others could refer to files
+/// outside the media folder.
+fn check_filename(fname: &str) -> Result<()> {
+    match normalize_filename(fname) {
+        Cow::Borrowed(_) => Ok(()),
+        Cow::Owned(_) => Err(AnkiError::invalid_input(format!(
+            "invalid filename: {}",
+            fname
+        ))),
+    }
+}
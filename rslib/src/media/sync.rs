@@ -5,10 +5,13 @@ use crate::err::{AnkiError, Result, SyncErrorKind};
 use crate::media::changetracker::ChangeTracker;
 use crate::media::database::{MediaDatabaseContext, MediaDatabaseMetadata, MediaEntry};
 use crate::media::files::{
-    add_file_from_ankiweb, data_for_file, mtime_as_i64, normalize_filename, AddedFile,
+    add_file_from_ankiweb, data_for_file, mtime_as_i64, normalize_filename, sha1_of_data, AddedFile,
 };
 use crate::media::MediaManager;
-use crate::{sync::Timeouts, version};
+use crate::{
+    sync::{is_encrypted_media_name, SyncKey, Timeouts},
+    version,
+};
 use bytes::Bytes;
 use reqwest::{multipart, Client, Response};
 use serde_derive::{Deserialize, Serialize};
@@ -23,7 +26,7 @@ use time::Duration;
 use version::sync_client_version;
 
 static SYNC_MAX_FILES: usize = 25;
-static SYNC_MAX_BYTES: usize = (2.5 * 1024.0 * 1024.0) as usize;
+pub(super) static SYNC_MAX_BYTES: usize = (2.5 * 1024.0 * 1024.0) as usize;
 static SYNC_SINGLE_FILE_MAX_BYTES: usize = 100 * 1024 * 1024;
 
 #[derive(Debug, Default, Clone, Copy)]
@@ -46,6 +49,9 @@ where
     progress_cb: P,
     progress: MediaSyncProgress,
     endpoint: String,
+    /// If set, files are encrypted, and stored on the server under hashed
+    /// names.
+    key: Option<SyncKey>,
     log: Logger,
 }
 
@@ -55,11 +61,11 @@ struct SyncBeginResult {
     err: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct SyncBeginResponse {
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct SyncBeginResponse {
     #[serde(rename = "sk")]
-    sync_key: String,
-    usn: i32,
+    pub sync_key: String,
+    pub usn: i32,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -78,10 +84,10 @@ enum RequiredChange {
     RemovePending,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct RecordBatchRequest {
-    last_usn: i32,
+pub(super) struct RecordBatchRequest {
+    pub last_usn: i32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -90,11 +96,12 @@ struct RecordBatchResult {
     err: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct ServerMediaRecord {
-    fname: String,
-    usn: i32,
-    sha1: String,
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct ServerMediaRecord {
+    pub fname: String,
+    pub usn: i32,
+    /// Empty if deleted.
+    pub sha1: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -105,7 +112,7 @@ struct ZipRequest<'a> {
 
 #[derive(Serialize_tuple)]
 struct UploadEntry<'a> {
-    fname: &'a str,
+    fname: Cow<'a, str>,
     in_zip_name: Option<String>,
 }
 
@@ -115,15 +122,15 @@ struct UploadResult {
     err: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct UploadReply {
-    processed: usize,
-    current_usn: i32,
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct UploadReply {
+    pub processed: usize,
+    pub current_usn: i32,
 }
 
-#[derive(Serialize)]
-struct FinalizeRequest {
-    local: u32,
+#[derive(Serialize, Deserialize)]
+pub(super) struct FinalizeRequest {
+    pub local: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -172,10 +179,21 @@ where
             progress_cb,
             progress: Default::default(),
             endpoint,
+            key: None,
             log,
         }
     }
 
+    /// Send requests to the provided URL instead of AnkiWeb, eg
+    /// `http://localhost:8080/msync/`.
+    pub fn set_endpoint(&mut self, endpoint: String) {
+        self.endpoint = endpoint;
+    }
+
+    pub fn set_encryption_key(&mut self, key: Option<SyncKey>) {
+        self.key = key;
+    }
+
     fn skey(&self) -> &str {
         self.skey.as_ref().unwrap()
     }
@@ -207,7 +225,10 @@ where
                 self.log,
                 "differs from local usn {}, fetching changes", client_usn
             );
-            self.fetch_changes(meta).await?;
+            let stale = self.fetch_changes(meta).await?;
+            if !stale.is_empty() {
+                self.remove_server_files(&stale).await?;
+            }
             actions_performed = true;
         }
 
@@ -264,8 +285,16 @@ where
         }
     }
 
-    async fn fetch_changes(&mut self, mut meta: MediaDatabaseMetadata) -> Result<()> {
+    /// Returns the names of files the server stores under the naming scheme
+    /// that was used before encryption was enabled or disabled.
+    async fn fetch_changes(&mut self, mut meta: MediaDatabaseMetadata) -> Result<Vec<String>> {
         let mut last_usn = meta.last_sync_usn;
+        let mut stale = vec![];
+        // the real names of the files the server knows by their hashed names
+        let mut real_names = match &self.key {
+            Some(key) => local_names_by_media_name(&mut self.ctx, key)?,
+            None => HashMap::new(),
+        };
         loop {
             debug!(
                 self.log,
@@ -283,6 +312,34 @@ where
             self.progress.checked += batch.len();
             self.fire_progress_cb()?;
 
+            let encrypted = self.key.is_some();
+            let batch: Vec<_> = batch
+                .into_iter()
+                .filter(|record| {
+                    if is_encrypted_media_name(&record.fname) == encrypted {
+                        true
+                    } else {
+                        if !record.sha1.is_empty() {
+                            stale.push(record.fname.clone());
+                        }
+                        false
+                    }
+                })
+                .collect();
+
+            let mut server_names = HashMap::new();
+            let batch = match &self.key {
+                Some(key) => decrypt_record_names(
+                    &mut self.ctx,
+                    &self.mgr.media_folder,
+                    key,
+                    &real_names,
+                    batch,
+                    &mut server_names,
+                )?,
+                None => batch,
+            };
+
             let (to_download, to_delete, to_remove_pending) =
                 determine_required_changes(&mut self.ctx, &batch, &self.log)?;
 
@@ -298,17 +355,22 @@ where
                 let batch: Vec<_> = dl_fnames
                     .iter()
                     .take(SYNC_MAX_FILES)
-                    .map(ToOwned::to_owned)
+                    .map(|&fname| server_names.get(fname).unwrap_or(fname))
                     .collect();
                 let zip_data = self.fetch_zip(batch.as_slice()).await?;
                 let download_batch = extract_into_media_folder(
                     self.mgr.media_folder.as_path(),
                     zip_data,
+                    self.key.as_ref(),
                     &self.log,
-                )?
-                .into_iter();
+                )?;
                 let len = download_batch.len();
                 dl_fnames = &dl_fnames[len..];
+                if let Some(key) = &self.key {
+                    for file in &download_batch {
+                        real_names.insert(key.media_name(&file.fname), file.fname.clone());
+                    }
+                }
                 downloaded.extend(download_batch);
 
                 self.progress.downloaded_files += len;
@@ -331,6 +393,30 @@ where
                 Ok(())
             })?;
         }
+        Ok(stale)
+    }
+
+    /// Delete files from the server by the names it stores them under. The
+    /// local folder and database are left alone.
+    async fn remove_server_files(&mut self, fnames: &[String]) -> Result<()> {
+        for batch in fnames.chunks(SYNC_MAX_FILES) {
+            debug!(self.log, "removing stale files from server"; "count"=>batch.len());
+            let reply = self.send_zip_data(zip_deletions(batch)?).await?;
+
+            self.progress.uploaded_deletions += reply.processed;
+            self.fire_progress_cb()?;
+
+            let fname_cnt = reply.processed as i32;
+            self.ctx.transact(|ctx| {
+                let mut meta = ctx.get_meta()?;
+                if meta.last_sync_usn + fname_cnt == reply.current_usn {
+                    meta.last_sync_usn = reply.current_usn;
+                    ctx.set_meta(&meta)?;
+                }
+                Ok(())
+            })?;
+        }
+
         Ok(())
     }
 
@@ -341,7 +427,13 @@ where
                 break;
             }
 
-            let zip_data = zip_files(&mut self.ctx, &self.mgr.media_folder, &pending, &self.log)?;
+            let zip_data = zip_files(
+                &mut self.ctx,
+                &self.mgr.media_folder,
+                &pending,
+                self.key.as_ref(),
+                &self.log,
+            )?;
             if zip_data.is_none() {
                 self.progress.checked += pending.len();
                 self.fire_progress_cb()?;
@@ -575,8 +667,8 @@ async fn ankiweb_request(
     let data_part = data_part.file_name("data");
 
     let form = multipart::Form::new()
-        .part("data", data_part)
-        .text("sk", skey.to_string());
+        .text("sk", skey.to_string())
+        .part("data", data_part);
 
     let mut req = client.post(url).multipart(form);
 
@@ -587,9 +679,12 @@ async fn ankiweb_request(
     req.send().await?.error_for_status().map_err(Into::into)
 }
 
+/// Names in the returned files are those of the media folder, and differ
+/// from the names requested if `key` was provided.
 fn extract_into_media_folder(
     media_folder: &Path,
     zip: Bytes,
+    key: Option<&SyncKey>,
     log: &Logger,
 ) -> Result<Vec<AddedFile>> {
     let reader = io::Cursor::new(zip);
@@ -613,7 +708,13 @@ fn extract_into_media_folder(
         let mut data = Vec::with_capacity(file.size() as usize);
         file.read_to_end(&mut data)?;
 
-        let added = add_file_from_ankiweb(media_folder, real_name, &data, log)?;
+        let added = match key {
+            Some(key) => {
+                let (real_name, data) = key.decrypt_media(real_name, &data)?;
+                add_file_from_ankiweb(media_folder, &real_name, &data, log)?
+            }
+            None => add_file_from_ankiweb(media_folder, real_name, &data, log)?,
+        };
 
         output.push(added);
     }
@@ -700,6 +801,7 @@ fn zip_files<'a>(
     ctx: &mut MediaDatabaseContext,
     media_folder: &Path,
     files: &'a [MediaEntry],
+    key: Option<&SyncKey>,
     log: &Logger,
 ) -> Result<Option<Vec<u8>>> {
     let buf = vec![];
@@ -762,7 +864,10 @@ fn zip_files<'a>(
             }
             accumulated_size += data.len();
             zip.start_file(format!("{}", idx), options)?;
-            zip.write_all(data)?;
+            match key {
+                Some(key) => zip.write_all(&key.encrypt_media(&file.fname, data))?,
+                None => zip.write_all(data)?,
+            }
         }
 
         debug!(
@@ -777,7 +882,10 @@ fn zip_files<'a>(
         );
 
         entries.push(UploadEntry {
-            fname: &file.fname,
+            fname: match key {
+                Some(key) => key.media_name(&file.fname).into(),
+                None => file.fname.as_str().into(),
+            },
             in_zip_name: if file_data.is_some() {
                 Some(format!("{}", idx))
             } else {
@@ -806,6 +914,72 @@ fn zip_files<'a>(
     Ok(Some(w.into_inner()))
 }
 
+/// A zip that only records the deletion of `fnames`.
+fn zip_deletions(fnames: &[String]) -> Result<Vec<u8>> {
+    let entries: Vec<_> = fnames
+        .iter()
+        .map(|fname| UploadEntry {
+            fname: fname.as_str().into(),
+            in_zip_name: None,
+        })
+        .collect();
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file("_meta", options)?;
+    zip.write_all(serde_json::to_string(&entries)?.as_bytes())?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Map the hashed names of local files, including deleted ones, to their
+/// real names.
+fn local_names_by_media_name(
+    ctx: &mut MediaDatabaseContext,
+    key: &SyncKey,
+) -> Result<HashMap<String, String>> {
+    Ok(ctx
+        .all_fnames()?
+        .into_iter()
+        .map(|fname| (key.media_name(&fname), fname))
+        .collect())
+}
+
+/// Replace the hashed names of records with the real names of local files,
+/// recording the hashed names in `server_names`. The server's checksum is of
+/// the encrypted file, so if it matches the local file once encrypted, the
+/// local checksum is used instead. Records of files not present locally keep
+/// their hashed names, and are downloaded.
+fn decrypt_record_names(
+    ctx: &mut MediaDatabaseContext,
+    media_folder: &Path,
+    key: &SyncKey,
+    real_names: &HashMap<String, String>,
+    records: Vec<ServerMediaRecord>,
+    server_names: &mut HashMap<String, String>,
+) -> Result<Vec<ServerMediaRecord>> {
+    let mut out = Vec::with_capacity(records.len());
+    for mut record in records {
+        if let Some(fname) = real_names.get(&record.fname) {
+            if !record.sha1.is_empty() {
+                if let Some(sha1) = ctx.get_entry(fname)?.and_then(|entry| entry.sha1) {
+                    if let Ok(Some(data)) = data_for_file(media_folder, fname) {
+                        let encrypted = key.encrypt_media(fname, &data);
+                        if hex::encode(sha1_of_data(&encrypted)) == record.sha1 {
+                            record.sha1 = hex::encode(sha1);
+                        }
+                    }
+                }
+            }
+            let server_name = std::mem::replace(&mut record.fname, fname.clone());
+            server_names.insert(fname.clone(), server_name);
+        }
+        out.push(record);
+    }
+    Ok(out)
+}
+
 #[cfg(test)]
 mod test {
     use crate::err::Result;
@@ -832,7 +1006,7 @@ mod test {
         let log = crate::log::terminal();
 
         let mgr = MediaManager::new(&media_dir, &media_db)?;
-        mgr.sync_media(progress, 0, hkey, log).await?;
+        mgr.sync_media(progress, 0, hkey, None, log).await?;
 
         Ok(())
     }
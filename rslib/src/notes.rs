@@ -7,12 +7,16 @@ use crate::{
     collection::Collection,
     decks::DeckID,
     define_newtype,
-    err::{AnkiError, Result},
+    err::{AnkiError, FieldProblem, Result},
     notetype::{CardGenContext, NoteField, NoteType, NoteTypeID},
     template::field_is_empty,
-    text::{ensure_string_in_nfc, normalize_to_nfc, strip_html_preserving_media_filenames},
+    text::{
+        ensure_string_in_nfc, normalize_to_nfc, strip_html_preserving_entities,
+        strip_html_preserving_media_filenames,
+    },
     timestamp::TimestampSecs,
     types::Usn,
+    undo::Undoable,
 };
 use itertools::Itertools;
 use num_integer::Integer;
@@ -32,7 +36,7 @@ pub(crate) struct TransformNoteOutput {
     pub mark_modified: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Note {
     pub id: NoteID,
     pub guid: String,
@@ -54,7 +58,11 @@ impl Note {
             mtime: TimestampSecs(0),
             usn: Usn(0),
             tags: vec![],
-            fields: vec!["".to_string(); notetype.fields.len()],
+            fields: notetype
+                .fields
+                .iter()
+                .map(|f| f.config.default_value.clone())
+                .collect(),
             sort_field: None,
             checksum: None,
         }
@@ -77,7 +85,26 @@ impl Note {
     }
 
     /// Prepare note for saving to the database. Does not mark it as modified.
+    /// Fails if a field does not satisfy the field's options.
     pub fn prepare_for_update(&mut self, nt: &NoteType, normalize_text: bool) -> Result<()> {
+        self.prepare_for_update_unchecked(nt, normalize_text)?;
+        if let Some((ord, problem)) = self.field_problems(nt)?.into_iter().next() {
+            return Err(AnkiError::InvalidNoteField {
+                field: nt.fields[ord].name.clone(),
+                problem,
+            });
+        }
+        Ok(())
+    }
+
+    /// Like prepare_for_update(), but accepts content that does not satisfy
+    /// the field options, such as when syncing or changing the notetype of
+    /// existing notes.
+    pub(crate) fn prepare_for_update_unchecked(
+        &mut self,
+        nt: &NoteType,
+        normalize_text: bool,
+    ) -> Result<()> {
         assert!(nt.id == self.notetype_id);
         let notetype_field_count = nt.fields.len().max(1);
         if notetype_field_count != self.fields.len() {
@@ -100,6 +127,15 @@ impl Note {
             }
         }
 
+        for (field, nt_field) in self.fields.iter_mut().zip(&nt.fields) {
+            if nt_field.config.plain_text {
+                let stripped = strip_html_preserving_entities(field);
+                if let Cow::Owned(text) = stripped {
+                    *field = text;
+                }
+            }
+        }
+
         let field1_nohtml = strip_html_preserving_media_filenames(&self.fields()[0]);
         let checksum = field_checksum(field1_nohtml.as_ref());
         let sort_field = if nt.config.sort_field_idx == 0 {
@@ -117,6 +153,18 @@ impl Note {
         Ok(())
     }
 
+    /// The ordinals of fields whose content does not satisfy the field's
+    /// options, and the reason.
+    pub(crate) fn field_problems(&self, nt: &NoteType) -> Result<Vec<(usize, FieldProblem)>> {
+        let mut problems = vec![];
+        for (ord, (text, field)) in self.fields.iter().zip(&nt.fields).enumerate() {
+            if let Some(problem) = field.content_problem(text)? {
+                problems.push((ord, problem));
+            }
+        }
+        Ok(problems)
+    }
+
     pub(crate) fn set_modified(&mut self, usn: Usn) {
         self.mtime = TimestampSecs::now();
         self.usn = usn;
@@ -232,6 +280,22 @@ fn anki_base91(mut n: u64) -> String {
     buf.chars().rev().collect()
 }
 
+#[derive(Debug)]
+pub(crate) struct UpdateNoteUndo(Note);
+
+impl Undoable for UpdateNoteUndo {
+    fn apply(&self, col: &mut Collection, usn: Usn) -> Result<()> {
+        let current = col
+            .storage
+            .get_note(self.0.id)?
+            .ok_or_else(|| AnkiError::invalid_input("note disappeared"))?;
+        let nt = col
+            .get_notetype(self.0.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+        col.update_note_undoable(&mut self.0.clone(), &current, &nt, usn, false)
+    }
+}
+
 fn invalid_char_for_field(c: char) -> bool {
     c.is_ascii_control() && c != '\n' && c != '\t'
 }
@@ -286,6 +350,9 @@ impl Collection {
                 .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
             let ctx = CardGenContext::new(&nt, col.usn()?);
             let norm = col.normalize_note_text();
+            // only edits are validated; other updates must be able to save
+            // notes that predate the field options
+            note.prepare_for_update(&nt, norm)?;
             col.update_note_inner_generating_cards(&ctx, note, true, norm)
         })
     }
@@ -316,13 +383,29 @@ impl Collection {
         normalize_text: bool,
     ) -> Result<()> {
         self.canonify_note_tags(note, usn)?;
-        note.prepare_for_update(nt, normalize_text)?;
+        note.prepare_for_update_unchecked(nt, normalize_text)?;
         if mark_note_modified {
             note.set_modified(usn);
         }
         self.storage.update_note(note)
     }
 
+    /// Update a note without generating cards, recording the original so the
+    /// change can be undone. The note is marked modified.
+    pub(crate) fn update_note_undoable(
+        &mut self,
+        note: &mut Note,
+        original: &Note,
+        nt: &NoteType,
+        usn: Usn,
+        normalize_text: bool,
+    ) -> Result<()> {
+        self.state
+            .undo
+            .save_undoable(Box::new(UpdateNoteUndo(original.clone())));
+        self.update_note_inner_without_cards(note, nt, usn, true, normalize_text)
+    }
+
     /// Remove a note. Cards must already have been deleted.
     pub(crate) fn remove_note_only(&mut self, nid: NoteID, usn: Usn) -> Result<()> {
         if let Some(_note) = self.storage.get_note(nid)? {
@@ -422,6 +505,18 @@ impl Collection {
         Ok(changed_notes)
     }
 
+    /// Fields of the note that do not satisfy the options of the notetype's
+    /// fields, so they can be reported before the note is saved.
+    pub(crate) fn note_field_problems(
+        &mut self,
+        note: &Note,
+    ) -> Result<Vec<(usize, FieldProblem)>> {
+        let nt = self
+            .get_notetype(note.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+        note.field_problems(&nt)
+    }
+
     pub(crate) fn note_is_duplicate_or_empty(&self, note: &Note) -> Result<DuplicateState> {
         if let Some(field1) = note.fields.get(0) {
             let field1 = if self.normalize_note_text() {
@@ -458,7 +553,11 @@ impl Collection {
 mod test {
     use super::{anki_base91, field_checksum};
     use crate::{
-        collection::open_test_collection, config::ConfigKey, decks::DeckID, err::Result,
+        collection::open_test_collection,
+        config::ConfigKey,
+        decks::DeckID,
+        err::{AnkiError, FieldProblem, Result},
+        fieldedit::FieldEdit,
         search::SortMode,
     };
 
@@ -557,4 +656,66 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn field_options() -> Result<()> {
+        let mut col = open_test_collection();
+        let mut nt = col.get_notetype_by_name("Basic")?.unwrap().as_ref().clone();
+        nt.fields[0].config.required = true;
+        nt.fields[0].config.plain_text = true;
+        nt.fields[1].config.default_value = "default".into();
+        nt.fields[1].config.max_length = 10;
+        col.update_notetype(&mut nt, false)?;
+
+        let mut note = nt.new_note();
+        assert_eq!(note.fields[1], "default");
+        note.fields[1] = "".into();
+        assert_eq!(
+            col.note_field_problems(&note)?,
+            vec![(0, FieldProblem::Empty)]
+        );
+        assert_eq!(
+            col.add_note(&mut note, DeckID(1)),
+            Err(AnkiError::InvalidNoteField {
+                field: "Front".into(),
+                problem: FieldProblem::Empty
+            })
+        );
+
+        // formatting is removed from plain text fields
+        note.fields[0] = "<b>a &amp; b</b>".into();
+        col.add_note(&mut note, DeckID(1))?;
+        assert_eq!(note.fields[0], "a &amp; b");
+
+        note.fields[1] = "<i>more than ten</i>".into();
+        assert!(col.update_note(&mut note).is_err());
+
+        // empty fields only need to satisfy the required option
+        nt.fields[1].config.max_length = 0;
+        nt.fields[1].config.pattern = "[a-z]+".into();
+        col.update_notetype(&mut nt, false)?;
+        let mut note = col.storage.get_note(note.id)?.unwrap();
+        assert_eq!(col.note_field_problems(&note)?, vec![]);
+        note.fields[1] = "123".into();
+        assert_eq!(
+            col.note_field_problems(&note)?,
+            vec![(1, FieldProblem::PatternMismatch)]
+        );
+
+        // existing notes that don't satisfy the options can still be
+        // changed by bulk operations
+        note.fields[1] = "".into();
+        col.update_note(&mut note)?;
+        nt.fields[1].config.required = true;
+        col.update_notetype(&mut nt, false)?;
+        assert_eq!(col.add_tags_for_notes(&[note.id], "tag")?, 1);
+        col.edit_field(&[note.id], "Front", FieldEdit::Append("!".into()))?;
+        col.undo()?;
+
+        // invalid patterns are rejected
+        nt.fields[1].config.pattern = "[".into();
+        assert!(col.update_notetype(&mut nt, false).is_err());
+
+        Ok(())
+    }
 }
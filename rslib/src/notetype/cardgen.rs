@@ -9,14 +9,18 @@ use crate::{
     deckconf::{DeckConf, DeckConfID},
     decks::DeckID,
     err::{AnkiError, Result},
+    image_occlusion::add_mask_groups_in_string,
     notes::{Note, NoteID},
     notetype::NoteTypeKind,
-    template::ParsedTemplate,
+    template::{ComparisonValues, ParsedTemplate},
     types::Usn,
 };
 use itertools::Itertools;
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 
 /// Info about an existing card required when generating new cards
 #[derive(Debug, PartialEq)]
@@ -75,7 +79,12 @@ impl CardGenContext<'_> {
 
     /// If template[ord] generates a non-empty question given nonempty_fields, return the provided
     /// deck id, or an overriden one. If question is empty, return None.
-    fn is_nonempty(&self, card_ord: usize, nonempty_fields: &HashSet<&str>) -> bool {
+    fn is_nonempty(
+        &self,
+        card_ord: usize,
+        nonempty_fields: &HashSet<&str>,
+        fields: &HashMap<&str, Cow<str>>,
+    ) -> bool {
         let card = &self.cards[card_ord];
         let template = match card.template {
             Some(ref template) => template,
@@ -85,7 +94,13 @@ impl CardGenContext<'_> {
             }
         };
 
-        template.renders_with_fields(&nonempty_fields)
+        template.renders_with_values(
+            &nonempty_fields,
+            &ComparisonValues {
+                fields,
+                card_ord: card_ord as u16,
+            },
+        )
     }
 
     /// Returns the cards that need to be generated for the provided note.
@@ -119,13 +134,18 @@ impl CardGenContext<'_> {
         extracted: &ExtractedCardInfo,
     ) -> Vec<CardToGenerate> {
         let nonempty_fields = note.nonempty_fields(&self.notetype.fields);
+        // comparisons in templates may refer to tags as well as fields
+        let mut fields = note.fields_map(&self.notetype.fields);
+        fields
+            .entry("Tags")
+            .or_insert_with(|| note.tags.join(" ").into());
 
         self.cards
             .iter()
             .enumerate()
             .filter_map(|(ord, card)| {
                 if !extracted.existing_ords.contains(&(ord as u32))
-                    && self.is_nonempty(ord, &nonempty_fields)
+                    && self.is_nonempty(ord, &nonempty_fields, &fields)
                 {
                     Some(CardToGenerate {
                         ord: ord as u32,
@@ -144,10 +164,14 @@ impl CardGenContext<'_> {
         note: &Note,
         extracted: &ExtractedCardInfo,
     ) -> Vec<CardToGenerate> {
-        // gather all cloze numbers
+        // gather all cloze numbers, and image occlusion mask groups
+        let masks = self.notetype.is_image_occlusion();
         let mut set = HashSet::with_capacity(4);
         for field in note.fields() {
             add_cloze_numbers_in_string(field, &mut set);
+            if masks {
+                add_mask_groups_in_string(field, &mut set);
+            }
         }
         set.into_iter()
             .filter_map(|cloze_ord| {
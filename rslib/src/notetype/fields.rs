@@ -1,9 +1,23 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::backend_proto::{NoteField as NoteFieldProto, NoteFieldConfig, OptionalUInt32};
+use crate::{
+    backend_proto::{NoteField as NoteFieldProto, NoteFieldConfig, OptionalUInt32},
+    err::{AnkiError, FieldProblem, Result},
+    template::field_is_empty,
+    text::strip_html,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{collections::HashMap, sync::Mutex};
 
-#[derive(Debug, PartialEq)]
+lazy_static! {
+    /// Compiled validation patterns, keyed by their source, so they are not
+    /// recompiled each time a note is saved.
+    static ref PATTERNS: Mutex<HashMap<String, Regex>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct NoteField {
     pub ord: Option<u32>,
     pub name: String,
@@ -30,6 +44,11 @@ impl NoteField {
                 rtl: false,
                 font_name: "Arial".into(),
                 font_size: 20,
+                required: false,
+                pattern: String::new(),
+                max_length: 0,
+                default_value: String::new(),
+                plain_text: false,
                 other: vec![],
             },
         }
@@ -48,6 +67,46 @@ impl NoteField {
             self.name = trimmed.into();
         }
     }
+
+    /// The validation pattern, which must match the entire content.
+    pub(crate) fn pattern(&self) -> Result<Option<Regex>> {
+        if self.config.pattern.is_empty() {
+            return Ok(None);
+        }
+        let mut patterns = PATTERNS.lock().unwrap();
+        if let Some(regex) = patterns.get(&self.config.pattern) {
+            return Ok(Some(regex.clone()));
+        }
+        let regex = Regex::new(&format!("^(?:{})$", self.config.pattern)).map_err(|_| {
+            AnkiError::invalid_input(format!("invalid pattern for field {}", self.name))
+        })?;
+        patterns.insert(self.config.pattern.clone(), regex.clone());
+        Ok(Some(regex))
+    }
+
+    /// Check content against the field's options. Length and pattern are
+    /// checked against the text without HTML, and do not apply when the
+    /// field is empty.
+    pub(crate) fn content_problem(&self, text: &str) -> Result<Option<FieldProblem>> {
+        if field_is_empty(text) {
+            return Ok(if self.config.required {
+                Some(FieldProblem::Empty)
+            } else {
+                None
+            });
+        }
+        let text = strip_html(text);
+        let max_length = self.config.max_length;
+        if max_length > 0 && text.chars().count() > max_length as usize {
+            return Ok(Some(FieldProblem::TooLong { max_length }));
+        }
+        if let Some(pattern) = self.pattern()? {
+            if !pattern.is_match(&text) {
+                return Ok(Some(FieldProblem::PatternMismatch));
+            }
+        }
+        Ok(None)
+    }
 }
 
 #[cfg(test)]
@@ -60,4 +119,30 @@ mod test {
         field.fix_name();
         assert_eq!(&field.name, "test field name #/^");
     }
+
+    #[test]
+    fn content() -> Result<()> {
+        let mut field = NoteField::new("field");
+        assert_eq!(field.content_problem("")?, None);
+        field.config.required = true;
+        assert_eq!(field.content_problem("<br> ")?, Some(FieldProblem::Empty));
+
+        field.config.max_length = 3;
+        assert_eq!(field.content_problem("<b>abc</b>")?, None);
+        assert_eq!(
+            field.content_problem("abcd")?,
+            Some(FieldProblem::TooLong { max_length: 3 })
+        );
+
+        field.config.pattern = r"\d+".into();
+        assert_eq!(field.content_problem("12")?, None);
+        assert_eq!(
+            field.content_problem("1a")?,
+            Some(FieldProblem::PatternMismatch)
+        );
+        field.config.pattern = "(".into();
+        assert!(field.content_problem("1").is_err());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,406 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Rendering cards as self-contained HTML documents, so they can be
+//! displayed without the desktop app.
+
+use super::NoteType;
+use crate::{
+    card::CardID,
+    collection::Collection,
+    err::Result,
+    latex::{extract_latex, generate_latex_image, ExtractedLatex},
+    media::files::normalize_filename,
+    notes::NoteID,
+    template::RenderedNode,
+    text::{av_tags_to_html, decode_entities, replace_html_media_filenames, strip_av_tags},
+};
+use askama::Template;
+use lazy_static::lazy_static;
+use regex::Regex;
+use slog::warn;
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+lazy_static! {
+    static ref TYPE_ANSWER: Regex = Regex::new(r"\[\[type:.+?\]\]").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HtmlMedia {
+    /// Refer to media files with the provided prefix, such as "media/".
+    /// The referenced files need to be copied alongside the output.
+    Link { prefix: String },
+    /// Include media files in the output as data URLs.
+    Embed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtmlCardOptions {
+    pub media: HtmlMedia,
+    pub night_mode: bool,
+}
+
+impl Default for HtmlCardOptions {
+    fn default() -> Self {
+        HtmlCardOptions {
+            media: HtmlMedia::Link {
+                prefix: String::new(),
+            },
+            night_mode: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtmlCard {
+    /// A complete HTML document for the question side.
+    pub question: String,
+    /// A complete HTML document for the answer side.
+    pub answer: String,
+    /// Media files in the media folder that the card refers to.
+    pub media_files: Vec<String>,
+    /// Files the card refers to that are not in the media folder, such as
+    /// LaTeX images that could not be generated.
+    pub missing_media: Vec<String>,
+}
+
+/// A rendered card without the surrounding document.
+pub(crate) struct HtmlCardBody {
+    pub note_id: NoteID,
+    pub question: String,
+    pub answer: String,
+    pub css: String,
+    pub body_class: String,
+}
+
+#[derive(Template)]
+#[template(path = "../src/notetype/card_document.html")]
+struct CardDocumentTemplate<'a> {
+    css: &'a str,
+    body_class: &'a str,
+    content: &'a str,
+}
+
+impl Collection {
+    /// Render an existing card as standalone HTML documents, including the
+    /// notetype's styling. Filters that are not known to the backend are
+    /// skipped, sounds become audio elements and text to speech is removed.
+    pub fn render_card_html(&mut self, cid: CardID, options: &HtmlCardOptions) -> Result<HtmlCard> {
+        let mut media = HtmlMediaResolver::new(self.media_folder.clone(), options.media.clone());
+        let body = self.render_card_html_body(cid, options.night_mode, false, &mut media)?;
+        let (media_files, missing_media) = media.into_files();
+
+        Ok(HtmlCard {
+            question: html_document(&body.css, &body.body_class, &body.question),
+            answer: html_document(&body.css, &body.body_class, &body.answer),
+            media_files,
+            missing_media,
+        })
+    }
+
+    pub(crate) fn render_card_html_body(
+        &mut self,
+        cid: CardID,
+        night_mode: bool,
+        strip_av: bool,
+        media: &mut HtmlMediaResolver,
+    ) -> Result<HtmlCardBody> {
+        let (output, card, nt) = self.render_existing_card_with_notetype(cid, false)?;
+        let question = nodes_to_html(&output.qnodes, "");
+        let answer = nodes_to_html(&output.anodes, &strip_av_tags(&question));
+        let svg = nt.config.latex_svg;
+        let (question, mut latex) = prepare_text(&question, svg, strip_av);
+        let (answer, answer_latex) = prepare_text(&answer, svg, strip_av);
+        latex.extend(answer_latex);
+        self.generate_missing_latex(&latex, &nt);
+
+        Ok(HtmlCardBody {
+            note_id: card.note_id,
+            question: media.resolve(&question),
+            answer: media.resolve(&answer),
+            css: nt.config.css.clone(),
+            body_class: body_class(card.template_idx, night_mode),
+        })
+    }
+
+    /// Generate any LaTeX images not already in the media folder. Failures
+    /// are logged, and the images are then reported as missing.
+    fn generate_missing_latex(&self, latex: &[ExtractedLatex], nt: &NoteType) {
+        for extracted in latex {
+            if self.media_folder.join(&extracted.fname).is_file() {
+                continue;
+            }
+            if let Err(err) = generate_latex_image(
+                extracted,
+                &nt.config.latex_pre,
+                &nt.config.latex_post,
+                nt.config.latex_svg,
+                &self.media_folder,
+            ) {
+                warn!(self.log, "unable to generate LaTeX"; "fname"=>&extracted.fname, "err"=>?err);
+            }
+        }
+    }
+}
+
+/// Join rendered nodes, dropping filters that only the frontend can apply.
+fn nodes_to_html(nodes: &[RenderedNode], front_side: &str) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            RenderedNode::Text { text } => text.as_str(),
+            RenderedNode::Replacement { field_name, .. } if field_name == "FrontSide" => front_side,
+            RenderedNode::Replacement { current_text, .. } => current_text.as_str(),
+        })
+        .collect()
+}
+
+/// Remove type answer boxes and optionally sounds, and replace LaTeX with
+/// image references, returning the LaTeX that was found.
+fn prepare_text(text: &str, svg: bool, strip_av: bool) -> (String, Vec<ExtractedLatex>) {
+    let mut text = TYPE_ANSWER.replace_all(text, "");
+    if strip_av {
+        text = strip_av_tags(&text).into_owned().into();
+    }
+    extract_latex(&text, svg)
+}
+
+pub(crate) fn body_class(card_ord: u16, night_mode: bool) -> String {
+    let mut class = format!("card card{}", card_ord + 1);
+    if night_mode {
+        class.push_str(" nightMode night_mode");
+    }
+    class
+}
+
+pub(crate) fn html_document(css: &str, body_class: &str, content: &str) -> String {
+    CardDocumentTemplate {
+        css,
+        body_class,
+        content,
+    }
+    .render()
+    .unwrap()
+}
+
+/// Rewrites media references in rendered cards, keeping track of the files
+/// that were referenced.
+pub(crate) struct HtmlMediaResolver {
+    folder: PathBuf,
+    mode: HtmlMedia,
+    found: BTreeSet<String>,
+    missing: BTreeSet<String>,
+}
+
+impl HtmlMediaResolver {
+    pub(crate) fn new(folder: PathBuf, mode: HtmlMedia) -> Self {
+        HtmlMediaResolver {
+            folder,
+            mode,
+            found: Default::default(),
+            missing: Default::default(),
+        }
+    }
+
+    pub(crate) fn resolve(&mut self, html: &str) -> String {
+        let html = replace_html_media_filenames(html, |fname| {
+            match self.url_for(&decode_entities(fname)) {
+                Some(url) => escape_attribute(&url),
+                None => fname.to_string(),
+            }
+        });
+        av_tags_to_html(&html, |fname| {
+            self.url_for(fname).unwrap_or_else(|| fname.to_string())
+        })
+        .into_owned()
+    }
+
+    /// (found, missing) filenames, sorted.
+    pub(crate) fn into_files(self) -> (Vec<String>, Vec<String>) {
+        (
+            self.found.into_iter().collect(),
+            self.missing.into_iter().collect(),
+        )
+    }
+
+    /// The URL to use for a filename, or None if the reference should be
+    /// left alone.
+    fn url_for(&mut self, fname: &str) -> Option<String> {
+        if fname.contains("://") || fname.starts_with("data:") {
+            return None;
+        }
+        // names that are not valid in the media folder, such as ones with
+        // path separators or '..', could refer to files outside it
+        let url = if normalize_filename(fname) != fname {
+            None
+        } else {
+            let path = self.folder.join(fname);
+            match &self.mode {
+                HtmlMedia::Link { prefix } => {
+                    if path.is_file() {
+                        Some(format!("{}{}", prefix, percent_encode(fname)))
+                    } else {
+                        None
+                    }
+                }
+                HtmlMedia::Embed => fs::read(&path).ok().map(|data| data_url(&path, &data)),
+            }
+        };
+        if url.is_some() {
+            self.found.insert(fname.to_string());
+        } else {
+            self.missing.insert(fname.to_string());
+        }
+        url
+    }
+}
+
+/// Encode a filename for use in a relative URL.
+fn percent_encode(fname: &str) -> String {
+    let mut out = String::with_capacity(fname.len());
+    for byte in fname.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn escape_attribute(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn data_url(path: &Path, data: &[u8]) -> String {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mime = match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    };
+    format!("data:{};base64,{}", mime, base64_encode(data))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID};
+
+    #[test]
+    fn encoding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(percent_encode("a b&c#ü.mp3"), "a%20b%26c%23%C3%BC.mp3");
+    }
+
+    #[test]
+    fn rendering() -> Result<()> {
+        let mut col = open_test_collection();
+        let dir = tempfile::tempdir()?;
+        let media_folder = dir.path().join("media");
+        fs::create_dir(&media_folder)?;
+        col.media_folder = media_folder.clone();
+        fs::write(media_folder.join("foo.png"), b"foo")?;
+        fs::write(media_folder.join("a&b.mp3"), b"")?;
+        fs::write(dir.path().join("secret.png"), b"")?;
+
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = concat!(
+            r#"<img src="foo.png"><img src="http://x/y.png"><img src="../secret.png">"#,
+            r"[$]\input{x}[/$]"
+        )
+        .into();
+        note.fields[1] = "back[sound:a&amp;b.mp3][anki:tts][en_US]back[/anki:tts]".into();
+        col.add_note(&mut note, DeckID(1))?;
+        let cid = col.storage.all_cards_of_note(note.id)?[0].id;
+
+        let options = HtmlCardOptions {
+            media: HtmlMedia::Link {
+                prefix: "media/".into(),
+            },
+            night_mode: true,
+        };
+        let card = col.render_card_html(cid, &options)?;
+        assert!(card.question.starts_with("<!doctype html>"));
+        assert!(card.question.contains(&nt.config.css));
+        assert!(card
+            .question
+            .contains(r#"<body class="card card1 nightMode night_mode">"#));
+        assert!(card.question.contains(r#"<img src="media/foo.png">"#));
+        assert!(card.question.contains(r#"<img src="http://x/y.png">"#));
+        // the front side is included on the answer
+        assert!(card.answer.contains(r#"<img src="media/foo.png">"#));
+        assert!(card
+            .answer
+            .contains(r#"<audio controls src="media/a%26b.mp3"></audio>"#));
+        assert!(!card.answer.contains("anki:tts"));
+        assert_eq!(card.media_files, vec!["a&b.mp3", "foo.png"]);
+        // files outside the media folder are not referred to, and LaTeX with
+        // disallowed commands is not generated
+        assert!(card.question.contains(r#"<img src="../secret.png">"#));
+        assert_eq!(card.missing_media.len(), 2);
+        assert_eq!(card.missing_media[0], "../secret.png");
+        assert!(card.missing_media[1].starts_with("latex-"));
+
+        let options = HtmlCardOptions {
+            media: HtmlMedia::Embed,
+            night_mode: false,
+        };
+        let card = col.render_card_html(cid, &options)?;
+        assert!(card
+            .question
+            .contains(r#"<img src="data:image/png;base64,Zm9v">"#));
+        assert!(card
+            .answer
+            .contains(r#"<audio controls src="data:audio/mpeg;base64,"></audio>"#));
+
+        Ok(())
+    }
+}
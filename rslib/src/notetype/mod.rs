@@ -4,29 +4,41 @@
 mod cardgen;
 mod emptycards;
 mod fields;
+mod htmlcard;
+mod notetypechange;
 mod render;
 mod schema11;
 mod schemachange;
 mod stock;
+mod studysheet;
+mod templatecheck;
 mod templates;
+mod upstream;
 
 pub use crate::backend_proto::{
     card_requirement::Kind as CardRequirementKind, note_type_config::Kind as NoteTypeKind,
     CardRequirement, CardTemplateConfig, NoteFieldConfig, NoteType as NoteTypeProto,
-    NoteTypeConfig,
+    NoteTypeConfig, NoteTypeSchemaChange, StockNoteType,
 };
 pub(crate) use cardgen::{AlreadyGeneratedCardInfo, CardGenContext};
 pub use fields::NoteField;
+pub use htmlcard::{HtmlCard, HtmlCardOptions, HtmlMedia};
 pub(crate) use render::RenderCardOutput;
 pub use schema11::{CardTemplateSchema11, NoteFieldSchema11, NoteTypeSchema11};
-pub use stock::all_stock_notetypes;
+pub use stock::{all_stock_notetypes, get_stock_notetype};
+pub use studysheet::{StudySheet, StudySheetLayout, StudySheetOptions};
+pub use templatecheck::{
+    check_notetype_templates, TemplateSide, TemplateWarning, TemplateWarningKind,
+};
 pub use templates::CardTemplate;
 
 use crate::{
     collection::Collection,
+    config::ConfigKey,
     decks::DeckID,
     define_newtype,
     err::{AnkiError, Result},
+    image_occlusion::uses_image_occlusion,
     notes::Note,
     template::{FieldRequirements, ParsedTemplate},
     text::ensure_string_in_nfc,
@@ -45,7 +57,7 @@ pub(crate) const DEFAULT_CSS: &str = include_str!("styling.css");
 pub(crate) const DEFAULT_LATEX_HEADER: &str = include_str!("header.tex");
 pub(crate) const DEFAULT_LATEX_FOOTER: &str = r"\end{document}";
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct NoteType {
     pub id: NoteTypeID,
     pub name: String,
@@ -332,6 +344,7 @@ impl NoteType {
             if f.name.is_empty() {
                 return Err(AnkiError::invalid_input("Empty field name"));
             }
+            f.pattern()?;
         }
 
         Ok(())
@@ -367,6 +380,31 @@ impl NoteType {
     pub(crate) fn is_cloze(&self) -> bool {
         matches!(self.config.kind(), NoteTypeKind::Cloze)
     }
+
+    /// Fields (and Tags) whose content is compared in the question
+    /// templates, so that edits to them may require cards to be generated.
+    pub(crate) fn compared_keys(&self) -> HashSet<String> {
+        self.templates
+            .iter()
+            .filter_map(|t| t.parsed_question())
+            .flat_map(|tmpl| {
+                tmpl.compared_keys()
+                    .into_iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// A cloze notetype whose question uses the image-occlusion filter, so
+    /// that its mask groups generate cards.
+    pub(crate) fn is_image_occlusion(&self) -> bool {
+        self.is_cloze()
+            && self
+                .templates
+                .iter()
+                .any(|t| uses_image_occlusion(&t.config.q_format))
+    }
 }
 
 impl From<NoteType> for NoteTypeProto {
@@ -399,7 +437,11 @@ impl Collection {
         self.storage.add_new_notetype(nt)
     }
 
-    fn ensure_notetype_name_unique(&self, notetype: &mut NoteType, usn: Usn) -> Result<()> {
+    pub(crate) fn ensure_notetype_name_unique(
+        &self,
+        notetype: &mut NoteType,
+        usn: Usn,
+    ) -> Result<()> {
         loop {
             match self.storage.get_notetype_id(&notetype.name)? {
                 Some(did) if did == notetype.id => {
@@ -415,8 +457,10 @@ impl Collection {
         Ok(())
     }
 
-    /// Saves changes to a note type. This will force a full sync if templates
-    /// or fields have been added/removed/reordered.
+    /// Saves changes to a note type. If templates or fields have been
+    /// added/removed/reordered, the change is logged so other devices can
+    /// apply it when syncing. Servers that can't replay such changes will
+    /// require a full sync.
     pub fn update_notetype(&mut self, nt: &mut NoteType, preserve_usn: bool) -> Result<()> {
         let existing = self.get_notetype(nt.id)?;
         let norm = self.normalize_note_text();
@@ -426,6 +470,10 @@ impl Collection {
                 if existing_notetype.mtime_secs > nt.mtime_secs {
                     return Err(AnkiError::invalid_input("attempt to save stale notetype"));
                 }
+                if nt.record_schema_change(&existing_notetype) {
+                    // servers that can't replay the change need a full sync
+                    col.set_config(ConfigKey::PendingSchemaReplay, &true)?;
+                }
                 col.update_notes_for_changed_fields(
                     nt,
                     existing_notetype.fields.len(),
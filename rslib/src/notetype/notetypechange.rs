@@ -0,0 +1,279 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Moving notes to a different notetype.
+
+use super::{CardGenContext, NoteType, NoteTypeID};
+use crate::{
+    collection::Collection,
+    err::{AnkiError, Result},
+    notes::NoteID,
+};
+use std::collections::HashSet;
+
+impl Collection {
+    /// Change the notetype of the provided notes, which must all share the
+    /// same notetype.
+    ///
+    /// `field_map` has an entry for each field of the new notetype, holding
+    /// the index of the old field to take its content from, or None to
+    /// leave it empty. `template_map` is the same for card templates: cards
+    /// of old templates that are not mapped are removed, and the remaining
+    /// cards keep their scheduling. When changing to or from a cloze
+    /// notetype, `template_map` must be empty, and card ordinals are kept.
+    ///
+    /// This can not be undone, and requires a full sync.
+    pub fn change_notetype(
+        &mut self,
+        nids: &[NoteID],
+        new_ntid: NoteTypeID,
+        field_map: &[Option<usize>],
+        template_map: &[Option<usize>],
+    ) -> Result<()> {
+        let (old_ntid, nids) = self.notetype_of_notes(nids)?;
+        let old_nt = self
+            .get_notetype(old_ntid)?
+            .ok_or_else(|| AnkiError::invalid_input("missing note type"))?;
+        let new_nt = self
+            .get_notetype(new_ntid)?
+            .ok_or_else(|| AnkiError::invalid_input("no such note type"))?;
+        check_field_map(field_map, &old_nt, &new_nt)?;
+        let card_ords = new_card_ords(template_map, &old_nt, &new_nt)?;
+
+        self.transact(None, |col| {
+            col.change_notetype_inner(&nids, &new_nt, field_map, &card_ords)
+        })
+    }
+
+    /// The shared notetype of the notes, and their IDs without duplicates.
+    fn notetype_of_notes(&self, nids: &[NoteID]) -> Result<(NoteTypeID, Vec<NoteID>)> {
+        let unique_nids: HashSet<_> = nids.iter().collect();
+        let ntids_and_nids = self.storage.note_ids_by_notetype(nids)?;
+        if ntids_and_nids.len() != unique_nids.len() {
+            return Err(AnkiError::invalid_input("no such note"));
+        }
+        let ntids: HashSet<_> = ntids_and_nids.iter().map(|(ntid, _)| *ntid).collect();
+        match ntids.into_iter().collect::<Vec<_>>()[..] {
+            [ntid] => Ok((
+                ntid,
+                ntids_and_nids.into_iter().map(|(_, nid)| nid).collect(),
+            )),
+            [] => Err(AnkiError::invalid_input("no notes provided")),
+            _ => Err(AnkiError::invalid_input(
+                "notes must share the same note type",
+            )),
+        }
+    }
+
+    fn change_notetype_inner(
+        &mut self,
+        nids: &[NoteID],
+        new_nt: &NoteType,
+        field_map: &[Option<usize>],
+        card_ords: &CardOrdMap,
+    ) -> Result<()> {
+        self.storage.set_schema_modified()?;
+        let usn = self.usn()?;
+        let norm = self.normalize_note_text();
+        let ctx = CardGenContext::new(new_nt, usn);
+
+        for &nid in nids {
+            let mut note = self.storage.get_note(nid)?.unwrap();
+            note.fields = field_map
+                .iter()
+                .map(|old_idx| {
+                    old_idx
+                        .and_then(|idx| note.fields.get(idx))
+                        .cloned()
+                        .unwrap_or_default()
+                })
+                .collect();
+            note.notetype_id = new_nt.id;
+
+            for mut card in self.storage.all_cards_of_note(nid)? {
+                match card_ords.new_ord(card.template_idx) {
+                    Some(ord) if ord == card.template_idx => (),
+                    Some(ord) => {
+                        card.template_idx = ord;
+                        card.set_modified(usn);
+                        self.storage.update_card(&card)?;
+                    }
+                    None => self.remove_card_only(card, usn)?,
+                }
+            }
+
+            self.update_note_inner_generating_cards(&ctx, &mut note, true, norm)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Maps old card ordinals to new ones.
+enum CardOrdMap {
+    /// Ordinals are unchanged, up to the provided limit.
+    Unchanged { limit: Option<u16> },
+    /// The new ordinal for each old one.
+    Mapped(Vec<Option<u16>>),
+}
+
+impl CardOrdMap {
+    fn new_ord(&self, old_ord: u16) -> Option<u16> {
+        match self {
+            CardOrdMap::Unchanged { limit } => match limit {
+                Some(limit) if old_ord >= *limit => None,
+                _ => Some(old_ord),
+            },
+            CardOrdMap::Mapped(map) => map.get(old_ord as usize).copied().flatten(),
+        }
+    }
+}
+
+fn check_field_map(
+    field_map: &[Option<usize>],
+    old_nt: &NoteType,
+    new_nt: &NoteType,
+) -> Result<()> {
+    if field_map.len() != new_nt.fields.len() {
+        return Err(AnkiError::invalid_input(
+            "field map must have an entry for each new field",
+        ));
+    }
+    if field_map
+        .iter()
+        .flatten()
+        .any(|&old_idx| old_idx >= old_nt.fields.len())
+    {
+        return Err(AnkiError::invalid_input(
+            "field map refers to missing field",
+        ));
+    }
+    Ok(())
+}
+
+fn new_card_ords(
+    template_map: &[Option<usize>],
+    old_nt: &NoteType,
+    new_nt: &NoteType,
+) -> Result<CardOrdMap> {
+    if old_nt.is_cloze() || new_nt.is_cloze() {
+        if !template_map.is_empty() {
+            return Err(AnkiError::invalid_input(
+                "template map must be empty for cloze note types",
+            ));
+        }
+        let limit = if new_nt.is_cloze() {
+            None
+        } else {
+            Some(new_nt.templates.len() as u16)
+        };
+        return Ok(CardOrdMap::Unchanged { limit });
+    }
+
+    if template_map.len() != new_nt.templates.len() {
+        return Err(AnkiError::invalid_input(
+            "template map must have an entry for each new template",
+        ));
+    }
+    let mut map = vec![None; old_nt.templates.len()];
+    for (new_ord, old_ord) in template_map.iter().enumerate() {
+        if let Some(old_ord) = *old_ord {
+            match map.get_mut(old_ord) {
+                None => {
+                    return Err(AnkiError::invalid_input(
+                        "template map refers to missing template",
+                    ))
+                }
+                Some(Some(_)) => {
+                    return Err(AnkiError::invalid_input(
+                        "template map uses a template twice",
+                    ))
+                }
+                Some(entry) => *entry = Some(new_ord as u16),
+            }
+        }
+    }
+    Ok(CardOrdMap::Mapped(map))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID};
+
+    #[test]
+    fn changing() -> Result<()> {
+        let mut col = open_test_collection();
+        let basic = col.get_notetype_by_name("Basic")?.unwrap();
+        let reversed = col
+            .get_notetype_by_name("Basic (and reversed card)")?
+            .unwrap();
+        let cloze = col.get_notetype_by_name("Cloze")?.unwrap();
+
+        let mut note = reversed.new_note();
+        note.fields = vec!["front".into(), "back".into()];
+        col.add_note(&mut note, DeckID(1))?;
+        let cards = col.storage.all_cards_of_note(note.id)?;
+        let reverse_card = cards.iter().find(|c| c.template_idx == 1).unwrap();
+        let mut reverse_card = reverse_card.clone();
+        reverse_card.interval = 10;
+        col.storage.update_card(&reverse_card)?;
+        col.storage.db.execute_batch("update col set scm = 0")?;
+
+        // invalid mappings are rejected before any changes are made
+        let nids = vec![note.id];
+        assert!(col
+            .change_notetype(&nids, basic.id, &[Some(0)], &[Some(1)])
+            .is_err());
+        assert!(col
+            .change_notetype(&nids, basic.id, &[Some(0), Some(2)], &[Some(1)])
+            .is_err());
+        assert!(col
+            .change_notetype(&nids, basic.id, &[Some(0), None], &[Some(2)])
+            .is_err());
+        assert!(col
+            .change_notetype(&nids, reversed.id, &[None, None], &[Some(0), Some(0)])
+            .is_err());
+        assert!(col
+            .change_notetype(&nids, cloze.id, &[Some(0), None], &[Some(0)])
+            .is_err());
+
+        assert_eq!(col.storage.get_schema_mtime()?.0, 0);
+
+        // swap the fields, and keep only the reverse card
+        col.change_notetype(&nids, basic.id, &[Some(1), Some(0)], &[Some(1)])?;
+        let note = col.storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.notetype_id, basic.id);
+        assert_eq!(note.fields, vec!["back".to_string(), "front".into()]);
+        let cards = col.storage.all_cards_of_note(note.id)?;
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].id, reverse_card.id);
+        assert_eq!(cards[0].template_idx, 0);
+        assert_eq!(cards[0].interval, 10);
+        assert_ne!(col.storage.get_schema_mtime()?.0, 0);
+
+        // changing back generates the missing card
+        col.change_notetype(&nids, reversed.id, &[Some(0), Some(1)], &[Some(0), None])?;
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 2);
+
+        // cloze cards keep their ordinals
+        col.change_notetype(&nids, cloze.id, &[Some(0), None], &[])?;
+        let note = col.storage.get_note(note.id)?.unwrap();
+        assert_eq!(note.fields, vec!["back".to_string(), "".into()]);
+        let mut ords: Vec<_> = col
+            .storage
+            .all_cards_of_note(note.id)?
+            .iter()
+            .map(|c| c.template_idx)
+            .collect();
+        ords.sort_unstable();
+        assert_eq!(ords, vec![0, 1]);
+
+        col.change_notetype(&nids, basic.id, &[Some(0), None], &[])?;
+        let cards = col.storage.all_cards_of_note(note.id)?;
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].id, reverse_card.id);
+
+        Ok(())
+    }
+}
@@ -9,8 +9,9 @@ use crate::{
     i18n::{I18n, TR},
     notes::{Note, NoteID},
     template::{field_is_empty, render_card, ParsedTemplate, RenderedNode},
+    template_filters::TemplateFilter,
 };
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
 pub struct RenderCardOutput {
     pub qnodes: Vec<RenderedNode>,
@@ -18,8 +19,28 @@ pub struct RenderCardOutput {
 }
 
 impl Collection {
+    /// Make a filter available to card templates when rendering. Filters
+    /// wrapped in WithArg are passed an argument, referred to in templates
+    /// like `{{name:arg:Field}}`.
+    pub fn register_template_filter<F>(&mut self, name: impl Into<String>, filter: F) -> Result<()>
+    where
+        F: TemplateFilter + 'static,
+    {
+        self.state.template_filters.register(name, filter)
+    }
+
     /// Render an existing card saved in the database.
     pub fn render_existing_card(&mut self, cid: CardID, browser: bool) -> Result<RenderCardOutput> {
+        self.render_existing_card_with_notetype(cid, browser)
+            .map(|(output, _, _)| output)
+    }
+
+    /// Like render_existing_card(), but also returns the card and notetype.
+    pub(super) fn render_existing_card_with_notetype(
+        &mut self,
+        cid: CardID,
+        browser: bool,
+    ) -> Result<(RenderCardOutput, Card, Arc<NoteType>)> {
         let card = self
             .storage
             .get_card(cid)?
@@ -37,7 +58,8 @@ impl Collection {
         }
         .ok_or_else(|| AnkiError::invalid_input("missing template"))?;
 
-        self.render_card_inner(&note, &card, &nt, template, browser)
+        let output = self.render_card_inner(&note, &card, &nt, template, browser)?;
+        Ok((output, card, nt))
     }
 
     /// Render a card that may not yet have been added.
@@ -115,6 +137,7 @@ impl Collection {
             &field_map,
             card.template_idx,
             nt.is_cloze(),
+            &self.state.template_filters,
             &self.i18n,
         )?;
         Ok(RenderCardOutput { qnodes, anodes })
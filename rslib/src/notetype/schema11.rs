@@ -5,7 +5,7 @@ use crate::{
     decks::DeckID,
     notetype::{
         CardRequirement, CardTemplate, CardTemplateConfig, NoteField, NoteFieldConfig, NoteType,
-        NoteTypeConfig,
+        NoteTypeConfig, NoteTypeSchemaChange,
     },
     serde::{default_on_invalid, deserialize_bool_from_anything, deserialize_number_from_string},
     timestamp::TimestampSecs,
@@ -52,10 +52,26 @@ pub struct NoteTypeSchema11 {
     pub latexsvg: bool,
     #[serde(default, deserialize_with = "default_on_invalid")]
     pub(crate) req: CardRequirementsSchema11,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) origin_id: Option<NoteTypeID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) origin_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) schema_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) schema_changes: Vec<NoteTypeSchemaChangeSchema11>,
     #[serde(flatten)]
     pub(crate) other: HashMap<String, Value>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NoteTypeSchemaChangeSchema11 {
+    pub(crate) version: u32,
+    pub(crate) field_ords: Vec<i32>,
+    pub(crate) template_ords: Vec<i32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct CardRequirementsSchema11(pub(crate) Vec<CardRequirementSchema11>);
 
@@ -102,6 +118,10 @@ impl From<NoteTypeSchema11> for NoteType {
                 latex_post: nt.latex_post,
                 latex_svg: nt.latexsvg,
                 reqs: nt.req.0.into_iter().map(Into::into).collect(),
+                origin_id: nt.origin_id.map(|id| id.0).unwrap_or_default(),
+                origin_version: nt.origin_version.unwrap_or_default(),
+                schema_version: nt.schema_version.unwrap_or_default(),
+                schema_changes: nt.schema_changes.into_iter().map(Into::into).collect(),
                 other: other_to_bytes(&nt.other),
             },
             fields: nt.flds.into_iter().map(Into::into).collect(),
@@ -159,11 +179,47 @@ impl From<NoteType> for NoteTypeSchema11 {
             latex_post: c.latex_post,
             latexsvg: c.latex_svg,
             req: CardRequirementsSchema11(c.reqs.into_iter().map(Into::into).collect()),
+            origin_id: if c.origin_id == 0 {
+                None
+            } else {
+                Some(NoteTypeID(c.origin_id))
+            },
+            origin_version: if c.origin_version == 0 {
+                None
+            } else {
+                Some(c.origin_version)
+            },
+            schema_version: if c.schema_version == 0 {
+                None
+            } else {
+                Some(c.schema_version)
+            },
+            schema_changes: c.schema_changes.into_iter().map(Into::into).collect(),
             other: bytes_to_other(&c.other),
         }
     }
 }
 
+impl From<NoteTypeSchemaChangeSchema11> for NoteTypeSchemaChange {
+    fn from(c: NoteTypeSchemaChangeSchema11) -> Self {
+        NoteTypeSchemaChange {
+            version: c.version,
+            field_ords: c.field_ords,
+            template_ords: c.template_ords,
+        }
+    }
+}
+
+impl From<NoteTypeSchemaChange> for NoteTypeSchemaChangeSchema11 {
+    fn from(c: NoteTypeSchemaChange) -> Self {
+        NoteTypeSchemaChangeSchema11 {
+            version: c.version,
+            field_ords: c.field_ords,
+            template_ords: c.template_ords,
+        }
+    }
+}
+
 impl From<CardRequirementSchema11> for CardRequirement {
     fn from(r: CardRequirementSchema11) -> Self {
         CardRequirement {
@@ -202,6 +258,16 @@ pub struct NoteFieldSchema11 {
     pub(crate) rtl: bool,
     pub(crate) font: String,
     pub(crate) size: u16,
+    #[serde(default)]
+    pub(crate) required: bool,
+    #[serde(default)]
+    pub(crate) pattern: String,
+    #[serde(default)]
+    pub(crate) max_length: u32,
+    #[serde(default)]
+    pub(crate) default_value: String,
+    #[serde(default)]
+    pub(crate) plain_text: bool,
     #[serde(flatten)]
     pub(crate) other: HashMap<String, Value>,
 }
@@ -215,6 +281,11 @@ impl Default for NoteFieldSchema11 {
             rtl: false,
             font: "Arial".to_string(),
             size: 20,
+            required: false,
+            pattern: String::new(),
+            max_length: 0,
+            default_value: String::new(),
+            plain_text: false,
             other: Default::default(),
         }
     }
@@ -230,6 +301,11 @@ impl From<NoteFieldSchema11> for NoteField {
                 rtl: f.rtl,
                 font_name: f.font,
                 font_size: f.size as u32,
+                required: f.required,
+                pattern: f.pattern,
+                max_length: f.max_length,
+                default_value: f.default_value,
+                plain_text: f.plain_text,
                 other: other_to_bytes(&f.other),
             },
         }
@@ -248,6 +324,11 @@ impl From<NoteField> for NoteFieldSchema11 {
             rtl: conf.rtl,
             font: conf.font_name,
             size: conf.font_size as u16,
+            required: conf.required,
+            pattern: conf.pattern,
+            max_length: conf.max_length,
+            default_value: conf.default_value,
+            plain_text: conf.plain_text,
             other: bytes_to_other(&conf.other),
         }
     }
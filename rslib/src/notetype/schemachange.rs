@@ -1,8 +1,16 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use super::{CardGenContext, NoteType};
-use crate::{collection::Collection, err::Result};
+use super::{CardGenContext, NoteType, NoteTypeSchemaChange};
+use crate::{
+    collection::Collection,
+    err::{AnkiError, Result, SyncErrorKind},
+    types::Usn,
+};
+
+/// The number of schema changes a notetype keeps. Devices that last synced
+/// before the oldest of them need a full sync to get the current schema.
+const MAX_SCHEMA_CHANGES: usize = 20;
 
 /// True if any ordinals added, removed or reordered.
 fn ords_changed(ords: &[Option<u32>], previous_len: usize) -> bool {
@@ -47,6 +55,124 @@ impl TemplateOrdChanges {
     }
 }
 
+/// Reorder `fields` to match a new field schema.
+fn remapped_fields(fields: &[String], ords: &[Option<u32>]) -> Vec<String> {
+    ords.iter()
+        .map(|ord| {
+            ord.and_then(|idx| fields.get(idx as usize))
+                .map(AsRef::as_ref)
+                .unwrap_or("")
+        })
+        .map(Into::into)
+        .collect()
+}
+
+fn encode_ords(ords: &[Option<u32>]) -> Vec<i32> {
+    ords.iter()
+        .map(|ord| ord.map(|n| n as i32).unwrap_or(-1))
+        .collect()
+}
+
+/// Map each entry of a schema change to an ordinal of the original schema,
+/// given the mapping `current` that applies to the schema prior to the
+/// change. None if the change refers to a non-existent ordinal.
+fn compose_ords(current: &[Option<u32>], change: &[i32]) -> Option<Vec<Option<u32>>> {
+    change
+        .iter()
+        .map(|&ord| {
+            if ord < 0 {
+                Some(None)
+            } else {
+                current.get(ord as usize).copied()
+            }
+        })
+        .collect()
+}
+
+impl NoteType {
+    fn field_ords(&self) -> Vec<Option<u32>> {
+        self.fields.iter().map(|f| f.ord).collect()
+    }
+
+    fn template_ords(&self) -> Vec<Option<u32>> {
+        self.templates.iter().map(|t| t.ord).collect()
+    }
+
+    fn schema_change(&self, version: u32) -> Option<&NoteTypeSchemaChange> {
+        self.config
+            .schema_changes
+            .iter()
+            .find(|change| change.version == version)
+    }
+
+    /// If fields or templates were added, removed or reordered since
+    /// `previous`, log the change so other devices can apply it when syncing.
+    /// Returns true if a change was logged.
+    pub(crate) fn record_schema_change(&mut self, previous: &NoteType) -> bool {
+        let field_ords = self.field_ords();
+        let template_ords = self.template_ords();
+        if !ords_changed(&field_ords, previous.fields.len())
+            && !ords_changed(&template_ords, previous.templates.len())
+        {
+            return false;
+        }
+
+        self.config.schema_version += 1;
+        self.config.schema_changes.push(NoteTypeSchemaChange {
+            version: self.config.schema_version,
+            field_ords: encode_ords(&field_ords),
+            template_ords: encode_ords(&template_ords),
+        });
+        let excess = self
+            .config
+            .schema_changes
+            .len()
+            .saturating_sub(MAX_SCHEMA_CHANGES);
+        self.config.schema_changes.drain(..excess);
+        true
+    }
+
+    /// Point the ordinals of fields and templates to their positions in
+    /// `existing`, by replaying the schema changes `existing` lacks. Returns
+    /// false if the schemas have diverged, or `existing` is older than the
+    /// logged changes.
+    fn set_ords_from_schema_changes(&mut self, existing: &NoteType) -> bool {
+        let from = existing.config.schema_version;
+        let to = self.config.schema_version;
+        if to < from || self.schema_change(from) != existing.schema_change(from) {
+            return false;
+        }
+
+        let mut field_ords: Vec<_> = (0..existing.fields.len() as u32).map(Some).collect();
+        let mut template_ords: Vec<_> = (0..existing.templates.len() as u32).map(Some).collect();
+        for version in from + 1..=to {
+            let change = match self.schema_change(version) {
+                Some(change) => change,
+                None => return false,
+            };
+            field_ords = match compose_ords(&field_ords, &change.field_ords) {
+                Some(ords) => ords,
+                None => return false,
+            };
+            template_ords = match compose_ords(&template_ords, &change.template_ords) {
+                Some(ords) => ords,
+                None => return false,
+            };
+        }
+        if field_ords.len() != self.fields.len() || template_ords.len() != self.templates.len() {
+            return false;
+        }
+
+        for (field, ord) in self.fields.iter_mut().zip(field_ords) {
+            field.ord = ord;
+        }
+        for (template, ord) in self.templates.iter_mut().zip(template_ords) {
+            template.ord = ord;
+        }
+        true
+    }
+}
+
 impl Collection {
     /// Rewrite notes to match the updated field schema.
     /// Caller must create transaction.
@@ -64,7 +190,7 @@ impl Collection {
                 let nids = self.search_notes(&format!("mid:{}", nt.id))?;
                 for nid in nids {
                     let mut note = self.storage.get_note(nid)?.unwrap();
-                    note.prepare_for_update(nt, normalize_text)?;
+                    note.prepare_for_update_unchecked(nt, normalize_text)?;
                     self.storage.update_note(&note)?;
                 }
             } else {
@@ -73,29 +199,33 @@ impl Collection {
             }
         }
 
-        self.storage.set_schema_modified()?;
+        let usn = self.usn()?;
+        self.remap_note_fields(nt, &ords, normalize_text, Some(usn))
+    }
 
+    /// Rewrite the notes of a notetype, and their copies as of the last sync,
+    /// to match its field schema. Notes are only marked as modified if a usn
+    /// is provided.
+    fn remap_note_fields(
+        &mut self,
+        nt: &NoteType,
+        ords: &[Option<u32>],
+        normalize_text: bool,
+        usn: Option<Usn>,
+    ) -> Result<()> {
         let nids = self.search_notes(&format!("mid:{}", nt.id))?;
-        let usn = self.usn()?;
         for nid in nids {
             let mut note = self.storage.get_note(nid)?.unwrap();
-            note.fields = ords
-                .iter()
-                .map(|f| {
-                    if let Some(idx) = f {
-                        note.fields
-                            .get(*idx as usize)
-                            .map(AsRef::as_ref)
-                            .unwrap_or("")
-                    } else {
-                        ""
-                    }
-                })
-                .map(Into::into)
-                .collect();
-            note.prepare_for_update(nt, normalize_text)?;
-            note.set_modified(usn);
+            note.fields = remapped_fields(&note.fields, ords);
+            note.prepare_for_update_unchecked(nt, normalize_text)?;
+            if let Some(usn) = usn {
+                note.set_modified(usn);
+            }
             self.storage.update_note(&note)?;
+            if let Some(base) = self.storage.get_note_sync_base(nid)? {
+                self.storage
+                    .update_note_sync_base_fields(nid, &remapped_fields(&base.fields, ords))?;
+            }
         }
         Ok(())
     }
@@ -108,13 +238,28 @@ impl Collection {
         nt: &NoteType,
         previous_template_count: usize,
     ) -> Result<()> {
-        let ords: Vec<_> = nt.templates.iter().map(|f| f.ord).collect();
-        if !ords_changed(&ords, previous_template_count) {
+        if !self.remap_cards_for_changed_templates(nt, previous_template_count)? {
             // nothing to do
             return Ok(());
         }
 
-        self.storage.set_schema_modified()?;
+        let ctx = CardGenContext::new(nt, self.usn()?);
+        self.generate_cards_for_notetype(&ctx)?;
+
+        Ok(())
+    }
+
+    /// Remove and reposition cards after templates were removed or
+    /// reordered. Returns false if templates are unchanged.
+    fn remap_cards_for_changed_templates(
+        &mut self,
+        nt: &NoteType,
+        previous_template_count: usize,
+    ) -> Result<bool> {
+        let ords = nt.template_ords();
+        if !ords_changed(&ords, previous_template_count) {
+            return Ok(false);
+        }
 
         let changes = TemplateOrdChanges::new(ords, previous_template_count as u32);
         if !changes.removed.is_empty() {
@@ -126,10 +271,38 @@ impl Collection {
                 .move_cards_for_repositioned_templates(nt.id, &changes.moved)?;
         }
 
-        let ctx = CardGenContext::new(nt, self.usn()?);
-        self.generate_cards_for_notetype(&ctx)?;
+        Ok(true)
+    }
 
-        Ok(())
+    /// Apply the schema changes of a notetype received from another device
+    /// to the local notes and cards, before the notetype replaces `existing`.
+    /// They are not marked as modified, as the other device's updated copies
+    /// are synced as well, and cards for added templates are left for the
+    /// client to generate once it has received all notes.
+    /// Returns true if the schema changed.
+    /// Caller must create transaction.
+    pub(crate) fn replay_notetype_schema_changes(
+        &mut self,
+        nt: &mut NoteType,
+        existing: &NoteType,
+    ) -> Result<bool> {
+        if !nt.set_ords_from_schema_changes(existing) {
+            return Err(AnkiError::SyncError {
+                info: "notetype schema changed".into(),
+                kind: SyncErrorKind::ResyncRequired,
+            });
+        }
+
+        let field_ords = nt.field_ords();
+        let fields_changed = ords_changed(&field_ords, existing.fields.len());
+        if fields_changed {
+            let norm = self.normalize_note_text();
+            self.remap_note_fields(nt, &field_ords, norm, None)?;
+        }
+        let templates_changed =
+            self.remap_cards_for_changed_templates(nt, existing.templates.len())?;
+
+        Ok(fields_changed || templates_changed)
     }
 }
 
@@ -244,6 +417,34 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn replaying_schema_changes() -> Result<()> {
+        let mut col = open_test_collection();
+        let ntid = col.get_current_notetype_id().unwrap();
+        let original = col.storage.get_notetype(ntid)?.unwrap();
+
+        let mut nt = original.clone();
+        nt.add_field("three");
+        col.update_notetype(&mut nt, false)?;
+        let mut nt = col.storage.get_notetype(ntid)?.unwrap();
+        nt.fields.remove(0);
+        col.update_notetype(&mut nt, false)?;
+
+        let mut nt = col.storage.get_notetype(ntid)?.unwrap();
+        assert_eq!(nt.config.schema_version, 2);
+        assert!(nt.set_ords_from_schema_changes(&original));
+        assert_eq!(nt.field_ords(), vec![Some(1), None]);
+        assert_eq!(nt.template_ords(), vec![Some(0)]);
+
+        // changes made independently can't be replayed
+        let mut other = original.clone();
+        other.fields.swap(0, 1);
+        other.record_schema_change(&original);
+        assert!(!nt.set_ords_from_schema_changes(&other));
+
+        Ok(())
+    }
+
     #[test]
     fn cards() -> Result<()> {
         let mut col = open_test_collection();
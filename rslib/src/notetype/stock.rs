@@ -26,7 +26,8 @@ impl SqliteStorage {
     }
 }
 
-// if changing this, make sure to update StockNoteType enum
+/// The notetypes added to new collections. If changing this, make sure to
+/// update StockNoteType enum.
 pub fn all_stock_notetypes(i18n: &I18n) -> Vec<NoteType> {
     vec![
         basic(i18n),
@@ -37,6 +38,19 @@ pub fn all_stock_notetypes(i18n: &I18n) -> Vec<NoteType> {
     ]
 }
 
+/// A single stock notetype, including those that are not added to new
+/// collections.
+pub fn get_stock_notetype(kind: StockNoteType, i18n: &I18n) -> NoteType {
+    match kind {
+        StockNoteType::Basic => basic(i18n),
+        StockNoteType::BasicAndReversed => basic_forward_reverse(i18n),
+        StockNoteType::BasicOptionalReversed => basic_optional_reverse(i18n),
+        StockNoteType::BasicTyping => basic_typing(i18n),
+        StockNoteType::Cloze => cloze(i18n),
+        StockNoteType::ImageOcclusion => image_occlusion(i18n),
+    }
+}
+
 /// returns {{name}}
 fn fieldref<S: AsRef<str>>(name: S) -> String {
     format!("{{{{{}}}}}", name.as_ref())
@@ -130,3 +144,67 @@ pub(crate) fn cloze(i18n: &I18n) -> NoteType {
     nt.prepare_for_adding().unwrap();
     nt
 }
+
+pub(crate) fn image_occlusion(i18n: &I18n) -> NoteType {
+    let mut nt = NoteType::default();
+    nt.name = i18n.tr(TR::NotetypesImageOcclusionName).into();
+    let image = i18n.tr(TR::NotetypesImageField);
+    nt.add_field(image.as_ref());
+    let masks = i18n.tr(TR::NotetypesMasksField);
+    nt.add_field(masks.as_ref());
+    let header = i18n.tr(TR::NotetypesHeaderField);
+    nt.add_field(header.as_ref());
+    let back_extra = i18n.tr(TR::NotetypesBackExtraField);
+    nt.add_field(back_extra.as_ref());
+    // the answer repeats the image instead of using FrontSide, so that the
+    // masks are rendered for the answer side
+    let qfmt = format!(
+        "{{{{#{header}}}}}<div>{{{{{header}}}}}</div>{{{{/{header}}}}}\n\
+         <div class=image-occlusion-container>\n{image}\n{{{{image-occlusion:{masks}}}}}\n</div>",
+        header = header,
+        image = fieldref(image),
+        masks = masks,
+    );
+    let afmt = format!(
+        "{}\n{{{{#{}}}}}<div>{}</div>{{{{/{}}}}}",
+        qfmt,
+        back_extra,
+        fieldref(back_extra.as_ref()),
+        back_extra
+    );
+    nt.add_template(nt.name.clone(), qfmt, afmt);
+    nt.config.kind = NoteTypeKind::Cloze as i32;
+    nt.config.css += "
+.image-occlusion-container {
+ position: relative;
+ display: inline-block;
+}
+.image-occlusion-container img {
+ display: block;
+ max-width: 100%;
+}
+.image-occlusion {
+ position: absolute;
+ top: 0;
+ left: 0;
+ width: 100%;
+ height: 100%;
+}
+.image-occlusion .mask {
+ fill: #ffeba2;
+ stroke: #212121;
+ stroke-width: 1px;
+ vector-effect: non-scaling-stroke;
+}
+.image-occlusion .mask.active {
+ fill: #ff8e8e;
+}
+.image-occlusion .mask.revealed {
+ fill: none;
+ stroke: #ff8e8e;
+ stroke-width: 2px;
+}
+";
+    nt.prepare_for_adding().unwrap();
+    nt
+}
@@ -0,0 +1,213 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Printable sheets of cards, such as handouts.
+
+use super::htmlcard::{HtmlMedia, HtmlMediaResolver};
+use crate::{collection::Collection, err::Result, search::SortMode};
+use askama::Template;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    /// The divider most templates place between the question and answer.
+    static ref ANSWER_DIVIDER: Regex =
+        Regex::new(r#"(?i)<hr[^>]*\bid\s*=\s*["']?answer\b[^>]*>"#).unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StudySheetLayout {
+    /// One row per card, with the question and answer side by side.
+    Columns,
+    /// Cards laid out in a grid with the provided number of columns, with
+    /// each card's answer below its question, so they can be cut out and
+    /// folded.
+    Grid { columns: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StudySheetOptions {
+    pub layout: StudySheetLayout,
+    /// Start each note on a new page when printing.
+    pub page_break_per_note: bool,
+    /// Remove sounds and text to speech, instead of including audio players.
+    pub strip_av_tags: bool,
+    pub media: HtmlMedia,
+    pub title: String,
+}
+
+impl Default for StudySheetOptions {
+    fn default() -> Self {
+        StudySheetOptions {
+            layout: StudySheetLayout::Columns,
+            page_break_per_note: false,
+            strip_av_tags: true,
+            media: HtmlMedia::Link {
+                prefix: String::new(),
+            },
+            title: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StudySheet {
+    /// A complete HTML document.
+    pub html: String,
+    pub card_count: usize,
+    /// Media files in the media folder that the sheet refers to.
+    pub media_files: Vec<String>,
+    /// Files the sheet refers to that are not in the media folder.
+    pub missing_media: Vec<String>,
+}
+
+struct SheetCard {
+    class: String,
+    question: String,
+    answer: String,
+}
+
+#[derive(Template)]
+#[template(path = "../src/notetype/study_sheet.html")]
+struct StudySheetTemplate<'a> {
+    title: &'a str,
+    styles: Vec<String>,
+    groups: Vec<Vec<SheetCard>>,
+    layout_class: &'a str,
+    grid_columns: u8,
+    page_breaks: bool,
+}
+
+impl Collection {
+    /// Render the cards matching a search into a single printable document,
+    /// ordered by note.
+    pub fn render_study_sheet(
+        &mut self,
+        search: &str,
+        options: &StudySheetOptions,
+    ) -> Result<StudySheet> {
+        let cids = self.search_cards(search, SortMode::Custom("c.nid, c.ord".into()))?;
+        let mut media = HtmlMediaResolver::new(self.media_folder.clone(), options.media.clone());
+        let mut styles = vec![];
+        let mut groups: Vec<Vec<SheetCard>> = vec![];
+        let mut last_nid = None;
+
+        for &cid in &cids {
+            let body = self.render_card_html_body(cid, false, options.strip_av_tags, &mut media)?;
+            if !styles.contains(&body.css) {
+                styles.push(body.css);
+            }
+            let new_group = options.page_break_per_note && last_nid != Some(body.note_id);
+            if groups.is_empty() || new_group {
+                groups.push(vec![]);
+            }
+            last_nid = Some(body.note_id);
+            groups.last_mut().unwrap().push(SheetCard {
+                class: body.body_class,
+                answer: answer_without_question(&body.answer).to_string(),
+                question: body.question,
+            });
+        }
+
+        let (layout_class, grid_columns) = match options.layout {
+            StudySheetLayout::Columns => ("sheet-columns", 0),
+            StudySheetLayout::Grid { columns } => ("sheet-grid", columns.max(1)),
+        };
+        let html = StudySheetTemplate {
+            title: &options.title,
+            styles,
+            groups,
+            layout_class,
+            grid_columns,
+            page_breaks: options.page_break_per_note,
+        }
+        .render()
+        .unwrap();
+        let (media_files, missing_media) = media.into_files();
+
+        Ok(StudySheet {
+            html,
+            card_count: cids.len(),
+            media_files,
+            missing_media,
+        })
+    }
+}
+
+/// The answer side usually repeats the question, which is redundant when
+/// both are shown.
+fn answer_without_question(answer: &str) -> &str {
+    match ANSWER_DIVIDER.find(answer) {
+        Some(divider) => answer[divider.end()..].trim_start(),
+        None => answer,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID, notes::Note};
+
+    #[test]
+    fn answer_divider() {
+        assert_eq!(answer_without_question("q<hr id=answer>\n\na"), "a");
+        assert_eq!(answer_without_question(r#"q<HR id="answer">a"#), "a");
+        assert_eq!(answer_without_question("q<hr>a"), "q<hr>a");
+    }
+
+    #[test]
+    fn sheets() -> Result<()> {
+        let mut col = open_test_collection();
+        let nt = col
+            .get_notetype_by_name("Basic (and reversed card)")?
+            .unwrap();
+        let add = |col: &mut Collection, front: &str, back: &str| -> Result<Note> {
+            let mut note = nt.new_note();
+            note.fields[0] = front.into();
+            note.fields[1] = back.into();
+            col.add_note(&mut note, DeckID(1))?;
+            Ok(note)
+        };
+        add(&mut col, "one", "uno[sound:uno.mp3]")?;
+        add(&mut col, "two", "dos")?;
+
+        let mut options = StudySheetOptions::default();
+        let sheet = col.render_study_sheet("", &options)?;
+        assert_eq!(sheet.card_count, 4);
+        // css is only included once
+        assert_eq!(sheet.html.matches(nt.config.css.as_str()).count(), 1);
+        assert_eq!(sheet.html.matches(r#"<div class="sheet-card">"#).count(), 4);
+        assert_eq!(sheet.html.matches(r#"class="sheet-group"#).count(), 1);
+        assert!(sheet.html.contains(
+            r#"<div class="card card1 sheet-question">one</div>
+<div class="card card1 sheet-answer">uno</div>"#
+        ));
+        assert!(sheet.html.contains(
+            r#"<div class="card card2 sheet-question">uno</div>
+<div class="card card2 sheet-answer">one</div>"#
+        ));
+        assert!(!sheet.html.contains("sound"));
+        assert!(!sheet.html.contains("page-break\""));
+
+        options.layout = StudySheetLayout::Grid { columns: 3 };
+        options.page_break_per_note = true;
+        options.strip_av_tags = false;
+        let sheet = col.render_study_sheet("dos", &options)?;
+        assert_eq!(sheet.card_count, 2);
+        assert_eq!(
+            sheet
+                .html
+                .matches(r#"<div class="sheet-group sheet-grid page-break" style="grid-template-columns: repeat(3, 1fr)">"#)
+                .count(),
+            1
+        );
+        let sheet = col.render_study_sheet("", &options)?;
+        assert_eq!(sheet.html.matches("page-break\"").count(), 2);
+        assert!(sheet
+            .html
+            .contains(r#"<audio controls src="uno.mp3"></audio>"#));
+        assert_eq!(sheet.missing_media, vec!["uno.mp3"]);
+
+        Ok(())
+    }
+}
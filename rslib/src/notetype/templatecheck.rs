@@ -0,0 +1,222 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::NoteType;
+use crate::{
+    err::TemplateError,
+    i18n::{tr_strs, I18n, TR},
+    template::{
+        field_references, localized_template_error, template_error_offset, FieldReference,
+        ParsedTemplate, SPECIAL_FIELDS,
+    },
+};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemplateSide {
+    Question,
+    Answer,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateWarningKind {
+    /// The template could not be parsed, and would fail to render.
+    InvalidTemplate(TemplateError),
+    /// A replacement or conditional refers to a field the notetype lacks.
+    UnknownField { field: String },
+    /// The answer side does not include the question.
+    MissingFrontSide,
+    /// The question side of a cloze notetype has no cloze filter.
+    MissingClozeFilter,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateWarning {
+    pub template_ord: usize,
+    pub template_name: String,
+    pub side: TemplateSide,
+    /// Byte offset of the problematic tag, if the problem relates to one.
+    pub location: Option<usize>,
+    pub kind: TemplateWarningKind,
+}
+
+impl TemplateWarning {
+    pub fn message(&self, i18n: &I18n) -> String {
+        match &self.kind {
+            TemplateWarningKind::InvalidTemplate(err) => {
+                localized_template_error(i18n, err.clone())
+            }
+            TemplateWarningKind::UnknownField { field } => i18n.trn(
+                TR::CardTemplateRenderingNoSuchField,
+                tr_strs!["found"=>format!("{{{{{}}}}}", field), "field"=>field],
+            ),
+            TemplateWarningKind::MissingFrontSide => {
+                i18n.tr(TR::CardTemplatesMissingFrontSide).into()
+            }
+            TemplateWarningKind::MissingClozeFilter => {
+                i18n.tr(TR::CardTemplatesMissingClozeFilter).into()
+            }
+        }
+    }
+}
+
+/// Check each of the notetype's templates for problems that would prevent
+/// them from rendering, or cause them to render differently than expected.
+pub fn check_notetype_templates(nt: &NoteType) -> Vec<TemplateWarning> {
+    let field_names: HashSet<&str> = nt.fields.iter().map(|f| f.name.as_str()).collect();
+    let mut warnings = vec![];
+    for (ord, template) in nt.templates.iter().enumerate() {
+        let mut add_warning = |side, location, kind| {
+            warnings.push(TemplateWarning {
+                template_ord: ord,
+                template_name: template.name.clone(),
+                side,
+                location,
+                kind,
+            })
+        };
+
+        let question = &template.config.q_format;
+        let answer = &template.config.a_format;
+        let question_refs = field_references(question);
+        let answer_refs = field_references(answer);
+        for &(side, text, refs) in &[
+            (TemplateSide::Question, question, &question_refs),
+            (TemplateSide::Answer, answer, &answer_refs),
+        ] {
+            if let Err(err) = ParsedTemplate::from_text(text) {
+                add_warning(
+                    side,
+                    template_error_offset(text),
+                    TemplateWarningKind::InvalidTemplate(err),
+                );
+            }
+            for reference in refs {
+                if !is_valid_key(reference.key, &field_names) {
+                    add_warning(
+                        side,
+                        Some(reference.offset),
+                        TemplateWarningKind::UnknownField {
+                            field: reference.key.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+
+        if nt.is_cloze() && !question_refs.iter().any(is_cloze_reference) {
+            add_warning(
+                TemplateSide::Question,
+                None,
+                TemplateWarningKind::MissingClozeFilter,
+            );
+        }
+
+        // some notetypes repeat the question's fields instead of using FrontSide
+        let question_keys: HashSet<_> = question_refs.iter().map(|r| r.key).collect();
+        if !answer_refs
+            .iter()
+            .any(|r| r.key == "FrontSide" || question_keys.contains(r.key))
+        {
+            add_warning(
+                TemplateSide::Answer,
+                None,
+                TemplateWarningKind::MissingFrontSide,
+            );
+        }
+    }
+
+    warnings
+}
+
+fn is_valid_key(key: &str, field_names: &HashSet<&str>) -> bool {
+    // an empty key is used by filters that supply their own text
+    key.is_empty()
+        || field_names.contains(key)
+        || key == "FrontSide"
+        || SPECIAL_FIELDS.contains(&key)
+        || is_card_number(key)
+}
+
+/// The c1, c2, etc. fields, which are non-empty on the matching card.
+fn is_card_number(key: &str) -> bool {
+    key.strip_prefix('c')
+        .map(|num| num.parse::<u16>().is_ok())
+        .unwrap_or_default()
+}
+
+fn is_cloze_reference(reference: &FieldReference) -> bool {
+    reference
+        .filters
+        .iter()
+        .any(|&f| f == "cloze" || f == "image-occlusion")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, err::Result};
+
+    #[test]
+    fn checking() -> Result<()> {
+        let mut col = open_test_collection();
+        for nt in col.get_all_notetypes()?.values() {
+            assert_eq!(check_notetype_templates(nt), vec![], "{}", nt.name);
+        }
+
+        let mut nt = col.get_notetype_by_name("Basic")?.unwrap().as_ref().clone();
+        nt.templates[0].config.q_format = "{{Front}}{{#Tags}}{{Backk}}{{/Tags}}".into();
+        nt.templates[0].config.a_format = "{{#Back}}{{Back}}".into();
+        let warnings = check_notetype_templates(&nt);
+        assert_eq!(
+            warnings,
+            vec![
+                TemplateWarning {
+                    template_ord: 0,
+                    template_name: "Card 1".into(),
+                    side: TemplateSide::Question,
+                    location: Some(18),
+                    kind: TemplateWarningKind::UnknownField {
+                        field: "Backk".into()
+                    }
+                },
+                TemplateWarning {
+                    template_ord: 0,
+                    template_name: "Card 1".into(),
+                    side: TemplateSide::Answer,
+                    location: Some(0),
+                    kind: TemplateWarningKind::InvalidTemplate(
+                        TemplateError::ConditionalNotClosed("Back".into())
+                    )
+                },
+                TemplateWarning {
+                    template_ord: 0,
+                    template_name: "Card 1".into(),
+                    side: TemplateSide::Answer,
+                    location: None,
+                    kind: TemplateWarningKind::MissingFrontSide
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[0].message(&col.i18n),
+            "Found '{{Backk}}', but there is no field called 'Backk'"
+        );
+
+        // mismatched closing tags and unclosed handlebars are located
+        nt.templates[0].config.q_format = "{{#Front}}x{{/Back}}{{/Front}}".into();
+        nt.templates[0].config.a_format = "{{FrontSide}}<hr>{{Back".into();
+        let warnings = check_notetype_templates(&nt);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].location, Some(11));
+        assert_eq!(warnings[1].location, Some(17));
+
+        let mut nt = col.get_notetype_by_name("Cloze")?.unwrap().as_ref().clone();
+        nt.templates[0].config.q_format = "{{Text}}{{c1}}".into();
+        let warnings = check_notetype_templates(&nt);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, TemplateWarningKind::MissingClozeFilter);
+
+        Ok(())
+    }
+}
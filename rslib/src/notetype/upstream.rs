@@ -0,0 +1,210 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Keeping notetypes in sync with the notetype they were distributed from.
+//!
+//! A distributed notetype records the ID it had in the author's collection
+//! and a version number. When a newer version arrives, it is merged into the
+//! local copy instead of being added as a separate notetype.
+
+use super::{NoteType, NoteTypeID};
+use crate::{
+    collection::Collection,
+    err::{AnkiError, Result},
+};
+
+impl NoteType {
+    /// The ID of the notetype this one was distributed from, or its own ID
+    /// if it is the original.
+    pub fn origin_id(&self) -> NoteTypeID {
+        if self.config.origin_id == 0 {
+            self.id
+        } else {
+            NoteTypeID(self.config.origin_id)
+        }
+    }
+
+    /// Called by the author before distributing a changed notetype, so
+    /// recipients will merge it into their copies.
+    pub fn bump_origin_version(&mut self) {
+        self.config.origin_id = self.origin_id().0;
+        self.config.origin_version += 1;
+    }
+
+    /// A copy of `upstream`, keeping this notetype's name, deck, fields that
+    /// only exist locally, and card ordinals of templates that still exist.
+    fn merged_with_upstream(&self, upstream: &NoteType) -> NoteType {
+        let mut merged = self.clone();
+        merged.config.kind = upstream.config.kind;
+        merged.config.sort_field_idx = upstream.config.sort_field_idx;
+        merged.config.css = upstream.config.css.clone();
+        merged.config.latex_pre = upstream.config.latex_pre.clone();
+        merged.config.latex_post = upstream.config.latex_post.clone();
+        merged.config.latex_svg = upstream.config.latex_svg;
+        merged.config.origin_id = upstream.origin_id().0;
+        merged.config.origin_version = upstream.config.origin_version;
+
+        // upstream fields come first, so the sort field index remains valid
+        merged.fields = upstream
+            .fields
+            .iter()
+            .map(|field| {
+                let mut field = field.clone();
+                field.ord = self.get_field_ord(&field.name).map(|ord| ord as u32);
+                if let Some(ord) = field.ord {
+                    // a preference of the user rather than part of the design
+                    field.config.sticky = self.fields[ord as usize].config.sticky;
+                }
+                field
+            })
+            .collect();
+        for (ord, field) in self.fields.iter().enumerate() {
+            if upstream.get_field_ord(&field.name).is_none() {
+                let mut field = field.clone();
+                field.ord = Some(ord as u32);
+                merged.fields.push(field);
+            }
+        }
+
+        merged.templates = upstream
+            .templates
+            .iter()
+            .map(|template| {
+                let mut template = template.clone();
+                template.ord = self
+                    .templates
+                    .iter()
+                    .position(|t| t.name == template.name)
+                    .map(|ord| ord as u32);
+                if let Some(ord) = template.ord {
+                    let existing = &self.templates[ord as usize];
+                    template.mtime_secs = existing.mtime_secs;
+                    template.usn = existing.usn;
+                }
+                template
+            })
+            .collect();
+
+        merged
+    }
+}
+
+impl Collection {
+    /// Merge a newer version of a distributed notetype into the local
+    /// notetype with the provided ID. Templates and styling are replaced,
+    /// and fields that were added locally are kept. Returns false if the
+    /// local notetype is already up to date.
+    pub fn update_notetype_from_upstream(
+        &mut self,
+        ntid: NoteTypeID,
+        upstream: &NoteType,
+    ) -> Result<bool> {
+        let local = self.get_notetype(ntid)?.ok_or(AnkiError::NotFound)?;
+        if local.origin_id() != upstream.origin_id() {
+            return Err(AnkiError::invalid_input(
+                "note type was not distributed from the provided one",
+            ));
+        }
+        if local.is_cloze() != upstream.is_cloze() {
+            return Err(AnkiError::invalid_input("note type kind differs"));
+        }
+        if upstream.config.origin_version <= local.config.origin_version {
+            return Ok(false);
+        }
+
+        let mut merged = local.merged_with_upstream(upstream);
+        self.update_notetype(&mut merged, false)?;
+
+        Ok(true)
+    }
+
+    /// The local notetype distributed from the provided origin, if any. The
+    /// original itself is preferred over copies of it.
+    pub fn get_notetype_by_origin(&mut self, origin_id: NoteTypeID) -> Result<Option<NoteTypeID>> {
+        if self.storage.get_notetype(origin_id)?.is_some() {
+            return Ok(Some(origin_id));
+        }
+        let mut copies: Vec<_> = self
+            .get_all_notetypes()?
+            .values()
+            .filter(|nt| nt.origin_id() == origin_id)
+            .map(|nt| nt.id)
+            .collect();
+        copies.sort_unstable();
+        Ok(copies.first().copied())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_test_collection, decks::DeckID};
+
+    #[test]
+    fn updating() -> Result<()> {
+        let mut col = open_test_collection();
+        let mut author = col
+            .get_notetype_by_name("Basic (and reversed card)")?
+            .unwrap()
+            .as_ref()
+            .clone();
+        author.bump_origin_version();
+        assert_eq!(author.config.origin_id, author.id.0);
+        assert_eq!(author.config.origin_version, 1);
+
+        // a recipient's copy, with a field of their own
+        let mut local = author.clone();
+        local.id = NoteTypeID(0);
+        local.name = "Shared".into();
+        local.add_field("Notes");
+        col.add_notetype(&mut local)?;
+        assert_eq!(col.get_notetype_by_origin(author.id)?, Some(author.id));
+        col.remove_notetype(author.id)?;
+        assert_eq!(col.get_notetype_by_origin(author.id)?, Some(local.id));
+        let mut note = local.new_note();
+        note.fields = vec!["front".into(), "back".into(), "mine".into()];
+        col.add_note(&mut note, DeckID(1))?;
+        let card_ids: Vec<_> = col
+            .storage
+            .all_cards_of_note(note.id)?
+            .into_iter()
+            .map(|c| (c.template_idx, c.id))
+            .collect();
+
+        // an outdated version is ignored
+        assert!(!col.update_notetype_from_upstream(local.id, &author)?);
+
+        // the author adds a field, swaps the templates and changes styling
+        author.add_field("Extra");
+        author.templates.swap(0, 1);
+        author.templates[1].config.q_format += "{{Extra}}";
+        author.config.css = "new css".into();
+        author.bump_origin_version();
+        assert!(col.update_notetype_from_upstream(local.id, &author)?);
+
+        let updated = col.get_notetype(local.id)?.unwrap();
+        assert_eq!(updated.name, "Shared");
+        assert_eq!(updated.config.css, "new css");
+        assert_eq!(updated.config.origin_version, 2);
+        let names: Vec<_> = updated.fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["Front", "Back", "Extra", "Notes"]);
+        assert_eq!(updated.templates[0].name, "Card 2");
+
+        let note = col.storage.get_note(note.id)?.unwrap();
+        assert_eq!(
+            note.fields,
+            vec!["front".to_string(), "back".into(), "".into(), "mine".into()]
+        );
+        // cards follow their templates
+        for (old_ord, cid) in card_ids {
+            let card = col.storage.get_card(cid)?.unwrap();
+            assert_eq!(card.template_idx, 1 - old_ord);
+        }
+
+        // notetypes from elsewhere are rejected
+        let other = col.get_notetype_by_name("Basic")?.unwrap();
+        assert!(col.update_notetype_from_upstream(local.id, &other).is_err());
+
+        Ok(())
+    }
+}
@@ -10,7 +10,7 @@ use serde_tuple::Serialize_tuple;
 
 define_newtype!(RevlogID, i64);
 
-#[derive(Serialize_tuple, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize_tuple, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct RevlogEntry {
     pub id: TimestampMillis,
     pub cid: CardID,
@@ -0,0 +1,84 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+use super::SqliteStorage;
+use crate::{
+    err::{AnkiError, Result},
+    sync::{SyncConflict, SyncConflictKind},
+    timestamp::TimestampSecs,
+};
+use rusqlite::{params, Row, NO_PARAMS};
+use std::convert::TryFrom;
+
+fn row_to_sync_conflict(row: &Row) -> Result<SyncConflict> {
+    Ok(SyncConflict {
+        id: row.get(0)?,
+        kind: SyncConflictKind::try_from(row.get::<_, u8>(1)?)
+            .map_err(|_| AnkiError::invalid_input("invalid conflict kind"))?,
+        object_id: row.get(2)?,
+        mtime: TimestampSecs(row.get(3)?),
+        kept_local: row.get(4)?,
+        discarded: row.get(5)?,
+    })
+}
+
+impl SqliteStorage {
+    pub(crate) fn add_sync_conflict(&self, conflict: &SyncConflict) -> Result<()> {
+        self.db
+            .prepare_cached(
+                "insert into sync_conflicts (kind, oid, mod, kept_local, data)
+values (?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                conflict.kind as u8,
+                conflict.object_id,
+                conflict.mtime.0,
+                conflict.kept_local,
+                conflict.discarded
+            ])?;
+        Ok(())
+    }
+
+    pub(crate) fn get_sync_conflict(&self, id: i64) -> Result<Option<SyncConflict>> {
+        self.db
+            .prepare_cached(
+                "select id, kind, oid, mod, kept_local, data from sync_conflicts where id = ?",
+            )?
+            .query_and_then(&[id], row_to_sync_conflict)?
+            .next()
+            .transpose()
+    }
+
+    /// Newest first.
+    pub(crate) fn all_sync_conflicts(&self) -> Result<Vec<SyncConflict>> {
+        self.db
+            .prepare_cached(
+                "select id, kind, oid, mod, kept_local, data from sync_conflicts order by id desc",
+            )?
+            .query_and_then(NO_PARAMS, row_to_sync_conflict)?
+            .collect()
+    }
+
+    pub(crate) fn remove_sync_conflict(&self, id: i64) -> Result<()> {
+        self.db
+            .prepare_cached("delete from sync_conflicts where id = ?")?
+            .execute(&[id])?;
+        Ok(())
+    }
+
+    /// Remove conflicts logged before `cutoff`, and all but the newest `keep`.
+    pub(crate) fn prune_sync_conflicts(&self, cutoff: TimestampSecs, keep: u32) -> Result<()> {
+        self.db
+            .prepare_cached(
+                "delete from sync_conflicts where mod < ? or id not in
+(select id from sync_conflicts order by id desc limit ?)",
+            )?
+            .execute(params![cutoff.0, keep])?;
+        Ok(())
+    }
+
+    pub(crate) fn clear_sync_conflicts(&self) -> Result<()> {
+        self.db.execute("delete from sync_conflicts", NO_PARAMS)?;
+        Ok(())
+    }
+}
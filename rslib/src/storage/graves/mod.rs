@@ -23,10 +23,16 @@ enum GraveKind {
 }
 
 impl SqliteStorage {
+    /// An existing grave for the object is replaced, so applying the same
+    /// graves twice, as a resumed sync may, does not duplicate them.
     fn add_grave(&self, oid: i64, kind: GraveKind, usn: Usn) -> Result<()> {
+        let kind = kind as u8;
+        self.db
+            .prepare_cached("delete from graves where oid = ? and type = ?")?
+            .execute(params![oid, kind])?;
         self.db
             .prepare_cached(include_str!("add.sql"))?
-            .execute(params![usn, oid, kind as u8])?;
+            .execute(params![usn, oid, kind])?;
         Ok(())
     }
 
@@ -47,11 +53,21 @@ impl SqliteStorage {
         self.add_grave(did.0, GraveKind::Deck, usn)
     }
 
-    pub(crate) fn pending_graves(&self, pending_usn: Usn) -> Result<Graves> {
-        let mut stmt = self.db.prepare(&format!(
-            "select oid, type from graves where {}",
-            pending_usn.pending_object_clause()
-        ))?;
+    /// When serving peers, the removals recorded by mark_peer_changes() are
+    /// returned instead of the graves table, which the collection keeps for
+    /// its own server.
+    pub(crate) fn pending_graves(&self, pending_usn: Usn, peers: bool) -> Result<Graves> {
+        let sql = if peers {
+            "select oid, case tbl when 'cards' then 0 when 'notes' then 1 else 2 end
+from peer_usns where removed and usn >= ?"
+                .to_string()
+        } else {
+            format!(
+                "select oid, type from graves where {}",
+                pending_usn.pending_object_clause()
+            )
+        };
+        let mut stmt = self.db.prepare(&sql)?;
         let mut rows = stmt.query(&[pending_usn])?;
         let mut graves = Graves::default();
         while let Some(row) = rows.next()? {
@@ -3,6 +3,7 @@
 
 mod card;
 mod config;
+mod conflicts;
 mod deck;
 mod deckconf;
 mod graves;
@@ -1,7 +1,9 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+use super::ids_to_string;
 use crate::{
+    decks::DeckID,
     err::Result,
     notes::{Note, NoteID},
     notetype::NoteTypeID,
@@ -46,6 +48,9 @@ impl super::SqliteStorage {
     /// Caller must call note.prepare_for_update() prior to calling this.
     pub(crate) fn update_note(&self, note: &Note) -> Result<()> {
         assert!(note.id.0 != 0);
+        if note.usn.0 == -1 {
+            self.save_note_sync_base(note.id)?;
+        }
         let mut stmt = self.db.prepare_cached(include_str!("update.sql"))?;
         stmt.execute(params![
             note.guid,
@@ -110,6 +115,47 @@ impl super::SqliteStorage {
             .map_err(Into::into)
     }
 
+    /// Keep a copy of a note as of the last sync before it is first modified
+    /// locally, so changes made elsewhere can be merged into it.
+    fn save_note_sync_base(&self, nid: NoteID) -> Result<()> {
+        self.db
+            .prepare_cached(
+                "insert or ignore into sync_base_notes
+select id, guid, mid, mod, usn, tags, flds from notes where id = ? and usn != -1",
+            )?
+            .execute(&[nid])?;
+        Ok(())
+    }
+
+    /// The note as of the last sync, if it has been modified since.
+    pub(crate) fn get_note_sync_base(&self, nid: NoteID) -> Result<Option<Note>> {
+        self.db
+            .prepare_cached(
+                "select id, guid, mid, mod, usn, tags, flds from sync_base_notes where id = ?",
+            )?
+            .query_and_then(params![nid], row_to_note)?
+            .next()
+            .transpose()
+    }
+
+    pub(crate) fn update_note_sync_base_fields(
+        &self,
+        nid: NoteID,
+        fields: &[String],
+    ) -> Result<()> {
+        self.db
+            .prepare_cached("update sync_base_notes set flds = ? where id = ?")?
+            .execute(params![join_fields(fields), nid])?;
+        Ok(())
+    }
+
+    /// Called once local changes have been synced.
+    pub(crate) fn clear_note_sync_bases(&self) -> Result<()> {
+        self.db
+            .execute_batch("delete from sync_base_notes")
+            .map_err(Into::into)
+    }
+
     pub(crate) fn clear_pending_note_usns(&self) -> Result<()> {
         self.db
             .prepare("update notes set usn = 0 where usn = -1")?
@@ -149,6 +195,47 @@ impl super::SqliteStorage {
             .collect()
     }
 
+    pub(crate) fn get_note_id_by_guid(&self, guid: &str) -> Result<Option<NoteID>> {
+        self.db
+            .prepare_cached("select id from notes where guid = ?")?
+            .query_and_then(&[guid], |r| r.get(0).map_err(Into::into))?
+            .next()
+            .transpose()
+    }
+
+    /// Returns [(nid, guid)] of notes with at least one card in the provided
+    /// decks. Cards in filtered decks are counted in their original deck.
+    pub(crate) fn note_ids_and_guids_in_decks(
+        &self,
+        dids: &[DeckID],
+    ) -> Result<Vec<(NoteID, String)>> {
+        let mut dids_str = String::new();
+        ids_to_string(&mut dids_str, dids);
+        let sql = format!(
+            "select id, guid from notes where id in (select nid from cards where \
+             (case when odid = 0 then did else odid end) in {})",
+            dids_str
+        );
+        self.db
+            .prepare(&sql)?
+            .query_and_then(NO_PARAMS, |r| Ok((r.get(0)?, r.get(1)?)))?
+            .collect()
+    }
+
+    /// Returns [(nid, field at index)] of all notes of the provided notetype.
+    pub(crate) fn note_ids_and_field(
+        &self,
+        ntid: NoteTypeID,
+        field_ord: usize,
+    ) -> Result<Vec<(NoteID, String)>> {
+        self.db
+            .prepare("select id, field_at_index(flds, ?) from notes where mid=?")?
+            .query_and_then(params![field_ord as u32, ntid], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?
+            .collect()
+    }
+
     /// Return total number of notes. Slow.
     pub(crate) fn total_notes(&self) -> Result<u32> {
         self.db
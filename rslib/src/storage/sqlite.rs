@@ -7,7 +7,7 @@ use crate::err::{AnkiError, DBErrorKind};
 use crate::timestamp::{TimestampMillis, TimestampSecs};
 use crate::{i18n::I18n, sched::cutoff::v1_creation_date, text::without_combining};
 use regex::Regex;
-use rusqlite::{functions::FunctionFlags, params, Connection, NO_PARAMS};
+use rusqlite::{functions::FunctionFlags, params, Connection, OpenFlags, NO_PARAMS};
 use std::cmp::Ordering;
 use std::{borrow::Cow, path::Path, sync::Arc};
 use unicase::UniCase;
@@ -131,6 +131,26 @@ fn schema_version(db: &Connection) -> Result<(bool, u8)> {
     ))
 }
 
+fn check_schema_version(ver: u8) -> Result<()> {
+    let err = match ver {
+        v if v < SCHEMA_MIN_VERSION => Some(DBErrorKind::FileTooOld),
+        v if v > SCHEMA_MAX_VERSION => Some(DBErrorKind::FileTooNew),
+        12 | 13 => {
+            // as schema definition changed, user must perform clean
+            // shutdown to return to schema 11 prior to running this version
+            Some(DBErrorKind::FileTooNew)
+        }
+        _ => None,
+    };
+    if let Some(kind) = err {
+        return Err(AnkiError::DBError {
+            info: "".to_string(),
+            kind,
+        });
+    }
+    Ok(())
+}
+
 fn trace(s: &str) {
     println!("sql: {}", s.trim().replace('\n', " "));
 }
@@ -139,23 +159,7 @@ impl SqliteStorage {
     pub(crate) fn open_or_create(path: &Path, i18n: &I18n, server: bool) -> Result<Self> {
         let db = open_or_create_collection_db(path)?;
         let (create, ver) = schema_version(&db)?;
-
-        let err = match ver {
-            v if v < SCHEMA_MIN_VERSION => Some(DBErrorKind::FileTooOld),
-            v if v > SCHEMA_MAX_VERSION => Some(DBErrorKind::FileTooNew),
-            12 | 13 => {
-                // as schema definition changed, user must perform clean
-                // shutdown to return to schema 11 prior to running this version
-                Some(DBErrorKind::FileTooNew)
-            }
-            _ => None,
-        };
-        if let Some(kind) = err {
-            return Err(AnkiError::DBError {
-                info: "".to_string(),
-                kind,
-            });
-        }
+        check_schema_version(ver)?;
 
         let upgrade = ver != SCHEMA_MAX_VERSION;
         if create || upgrade {
@@ -196,6 +200,22 @@ impl SqliteStorage {
         Ok(storage)
     }
 
+    /// Open an existing collection file as-is, without registering functions
+    /// or upgrading it. Returns the storage and the file's schema version.
+    pub(crate) fn open_existing_without_upgrade(path: &Path) -> Result<(Self, u8)> {
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        let (missing, ver) = schema_version(&db)?;
+        if missing {
+            return Err(AnkiError::DBError {
+                info: "missing col table".to_string(),
+                kind: DBErrorKind::Corrupt,
+            });
+        }
+        check_schema_version(ver)?;
+
+        Ok((SqliteStorage { db }, ver))
+    }
+
     pub(crate) fn close(self, downgrade: bool) -> Result<()> {
         if downgrade {
             self.downgrade_to_schema_11()?;
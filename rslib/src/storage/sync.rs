@@ -7,6 +7,19 @@ use super::*;
 use crate::prelude::*;
 use rusqlite::{params, types::FromSql, Connection, ToSql, NO_PARAMS};
 
+/// The tables whose changes are tracked for peers, with the columns holding
+/// their keys and modification times. Revlog entries and tags are not
+/// modified once added.
+static PEER_TABLES: &[(&str, &str, &str)] = &[
+    ("cards", "id", "t.mod"),
+    ("notes", "id", "t.mod"),
+    ("revlog", "id", "0"),
+    ("decks", "id", "t.mtime_secs"),
+    ("deck_config", "id", "t.mtime_secs"),
+    ("notetypes", "id", "t.mtime_secs"),
+    ("tags", "tag", "0"),
+];
+
 impl SqliteStorage {
     pub(crate) fn usn(&self, server: bool) -> Result<Usn> {
         if server {
@@ -33,13 +46,97 @@ impl SqliteStorage {
         Ok(())
     }
 
-    pub(crate) fn objects_pending_sync<T: FromSql>(&self, table: &str, usn: Usn) -> Result<Vec<T>> {
+    /// Record the objects that changed since the last call as changed at
+    /// `peer_usn`, without touching the usns used when the collection syncs
+    /// as a client. Removed cards, notes and decks are recorded as graves.
+    pub(crate) fn mark_peer_changes(&self, peer_usn: Usn) -> Result<()> {
+        for &(table, key, mtime) in PEER_TABLES {
+            self.db
+                .prepare_cached(&format!(
+                    "insert or replace into peer_usns
+select '{table}', t.{key}, {mtime}, ?, 0 from {table} t
+where not exists (
+    select null from peer_usns
+    where tbl = '{table}' and oid = t.{key} and mtime = {mtime} and not removed
+  )",
+                    table = table,
+                    key = key,
+                    mtime = mtime
+                ))?
+                .execute(&[peer_usn])?;
+            let gone = format!(
+                "where tbl = '{table}' and not removed
+and oid not in (select {key} from {table})",
+                table = table,
+                key = key
+            );
+            if ["cards", "notes", "decks"].contains(&table) {
+                self.db
+                    .prepare_cached(&format!(
+                        "update peer_usns set usn = ?, removed = 1 {}",
+                        gone
+                    ))?
+                    .execute(&[peer_usn])?;
+            } else {
+                self.db
+                    .prepare_cached(&format!("delete from peer_usns {}", gone))?
+                    .execute(NO_PARAMS)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn a copy of a collection served to peers into one last synced
+    /// with them at `usn`, so changes it has pending for its own server are
+    /// not sent back.
+    pub(crate) fn prepare_copy_for_peer(&self, usn: Usn) -> Result<()> {
+        for table in &[
+            "cards",
+            "notes",
+            "revlog",
+            "graves",
+            "decks",
+            "deck_config",
+            "notetypes",
+            "templates",
+            "tags",
+            "config",
+        ] {
+            self.db
+                .prepare_cached(&format!("update {} set usn = 0 where usn < 0", table))?
+                .execute(NO_PARAMS)?;
+        }
+        self.set_usn(usn)
+    }
+
+    /// True if the object was recorded by mark_peer_changes() at `usn` or later.
+    pub(crate) fn changed_for_peers(&self, table: &str, oid: i64, usn: Usn) -> Result<bool> {
+        Ok(self
+            .db
+            .prepare_cached("select null from peer_usns where tbl = ? and oid = ? and usn >= ?")?
+            .query(params![table, oid, usn])?
+            .next()?
+            .is_some())
+    }
+
+    /// Objects of `table` pending sync with a client last synced at `usn`.
+    /// When serving peers, the usns recorded by mark_peer_changes() are used.
+    pub(crate) fn objects_pending_sync<T: FromSql>(
+        &self,
+        table: &str,
+        usn: Usn,
+        peers: bool,
+    ) -> Result<Vec<T>> {
+        let clause = if peers {
+            format!(
+                "id in (select oid from peer_usns where tbl = '{}' and usn >= ?)",
+                table
+            )
+        } else {
+            usn.pending_object_clause().to_string()
+        };
         self.db
-            .prepare_cached(&format!(
-                "select id from {} where {}",
-                table,
-                usn.pending_object_clause()
-            ))?
+            .prepare_cached(&format!("select id from {} where {}", table, clause))?
             .query_and_then(&[usn], |r| r.get(0).map_err(Into::into))?
             .collect()
     }
@@ -27,6 +27,15 @@ impl SqliteStorage {
             .map_err(Into::into)
     }
 
+    /// The IDs of the objects in a table the sanity check counts.
+    pub(crate) fn sanity_check_ids(&self, table: &str) -> Result<Vec<i64>> {
+        let column = if table == "graves" { "oid" } else { "id" };
+        self.db
+            .prepare(&format!("select {} from {}", column, table))?
+            .query_and_then(NO_PARAMS, |row| row.get(0).map_err(Into::into))?
+            .collect()
+    }
+
     pub(crate) fn sanity_check_info(&self) -> Result<SanityCheckCounts> {
         for table in &[
             "cards",
@@ -45,7 +54,12 @@ impl SqliteStorage {
                 });
             }
         }
+        self.sanity_check_counts()
+    }
 
+    /// The counts without the check for pending objects, which a collection
+    /// served to peers may have for its own server.
+    pub(crate) fn sanity_check_counts(&self) -> Result<SanityCheckCounts> {
         Ok(SanityCheckCounts {
             counts: SanityCheckDueCounts::default(),
             cards: self.table_count("cards")?,
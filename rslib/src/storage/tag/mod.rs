@@ -45,12 +45,15 @@ impl SqliteStorage {
 
     // fixme: in the future we could just register tags as part of the sync
     // instead of sending the tag list separately
-    pub(crate) fn tags_pending_sync(&self, usn: Usn) -> Result<Vec<String>> {
+    /// When serving peers, the usns recorded by mark_peer_changes() are used.
+    pub(crate) fn tags_pending_sync(&self, usn: Usn, peers: bool) -> Result<Vec<String>> {
+        let clause = if peers {
+            "tag in (select oid from peer_usns where tbl = 'tags' and usn >= ?)"
+        } else {
+            usn.pending_object_clause()
+        };
         self.db
-            .prepare_cached(&format!(
-                "select tag from tags where {}",
-                usn.pending_object_clause()
-            ))?
+            .prepare_cached(&format!("select tag from tags where {}", clause))?
             .query_and_then(&[usn], |r| r.get(0).map_err(Into::into))?
             .collect()
     }
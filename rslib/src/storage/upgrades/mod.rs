@@ -6,7 +6,7 @@ pub(super) const SCHEMA_MIN_VERSION: u8 = 11;
 /// The version new files are initially created with.
 pub(super) const SCHEMA_STARTING_VERSION: u8 = 11;
 /// The maximum schema version we can open.
-pub(super) const SCHEMA_MAX_VERSION: u8 = 16;
+pub(super) const SCHEMA_MAX_VERSION: u8 = 19;
 
 use super::SqliteStorage;
 use crate::err::Result;
@@ -31,6 +31,18 @@ impl SqliteStorage {
             self.upgrade_deck_conf_to_schema16(server)?;
             self.db.execute_batch("update col set ver = 16")?;
         }
+        if ver < 17 {
+            self.db
+                .execute_batch(include_str!("schema17_upgrade.sql"))?;
+        }
+        if ver < 18 {
+            self.db
+                .execute_batch(include_str!("schema18_upgrade.sql"))?;
+        }
+        if ver < 19 {
+            self.db
+                .execute_batch(include_str!("schema19_upgrade.sql"))?;
+        }
 
         Ok(())
     }
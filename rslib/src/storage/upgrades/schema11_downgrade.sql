@@ -1,3 +1,6 @@
+DROP TABLE peer_usns;
+DROP TABLE sync_conflicts;
+DROP TABLE sync_base_notes;
 DROP TABLE config;
 DROP TABLE deck_config;
 DROP TABLE tags;
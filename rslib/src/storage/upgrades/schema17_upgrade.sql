@@ -0,0 +1,11 @@
+CREATE TABLE sync_base_notes (
+  id integer PRIMARY KEY NOT NULL,
+  guid text NOT NULL,
+  mid integer NOT NULL,
+  mod integer NOT NULL,
+  usn integer NOT NULL,
+  tags text NOT NULL,
+  flds text NOT NULL
+);
+UPDATE col
+SET ver = 17;
@@ -0,0 +1,10 @@
+CREATE TABLE sync_conflicts (
+  id integer PRIMARY KEY NOT NULL,
+  kind integer NOT NULL,
+  oid integer NOT NULL,
+  mod integer NOT NULL,
+  kept_local integer NOT NULL,
+  data text NOT NULL
+);
+UPDATE col
+SET ver = 18;
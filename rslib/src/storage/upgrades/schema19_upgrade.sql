@@ -0,0 +1,10 @@
+CREATE TABLE peer_usns (
+  tbl text NOT NULL,
+  oid NOT NULL,
+  mtime integer NOT NULL,
+  usn integer NOT NULL,
+  removed integer NOT NULL,
+  PRIMARY KEY (tbl, oid)
+) without rowid;
+UPDATE col
+SET ver = 19;
@@ -0,0 +1,207 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A log of the versions of objects that normal syncs discarded, so they can
+//! be reviewed and restored.
+
+use super::CardEntry;
+use crate::{
+    card::Card,
+    deckconf::{DeckConf, DeckConfSchema11},
+    decks::{Deck, DeckSchema11},
+    notes::Note,
+    notetype::{NoteType, NoteTypeSchema11},
+    prelude::*,
+};
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Conflicts older than this are removed at the end of a sync.
+const CONFLICT_RETENTION_SECS: i64 = 30 * 86_400;
+/// The most conflicts that are kept; older ones are removed first.
+const MAX_CONFLICTS: u32 = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum SyncConflictKind {
+    Note,
+    Card,
+    Deck,
+    DeckConfig,
+    NoteType,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncConflict {
+    pub id: i64,
+    pub kind: SyncConflictKind,
+    /// The ID of the note, card, deck, deck config or notetype.
+    pub object_id: i64,
+    /// When the sync took place.
+    pub mtime: TimestampSecs,
+    /// True if the local version was kept, and the remote one discarded.
+    pub kept_local: bool,
+    /// The discarded version, in the JSON format it is synced in. For notes,
+    /// only the discarded fields, see DiscardedFields.
+    pub discarded: String,
+}
+
+/// The fields of a note whose content a sync discarded. Only these are
+/// logged and restored, so later changes to the note's other fields are
+/// kept.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(super) struct DiscardedFields {
+    #[serde(rename = "mid")]
+    pub notetype_id: NoteTypeID,
+    /// The discarded content, by field index.
+    pub fields: BTreeMap<usize, String>,
+}
+
+impl DiscardedFields {
+    /// The fields of `discarded` that differ from `kept`. If the notetypes
+    /// differ, all of them.
+    pub(super) fn new(discarded: &Note, kept: &Note) -> Self {
+        let same_notetype = discarded.notetype_id == kept.notetype_id
+            && discarded.fields.len() == kept.fields.len();
+        DiscardedFields {
+            notetype_id: discarded.notetype_id,
+            fields: discarded
+                .fields
+                .iter()
+                .enumerate()
+                .filter(|(ord, text)| !same_notetype || kept.fields[*ord] != **text)
+                .map(|(ord, text)| (ord, text.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Collection {
+    /// Log a version of an object a sync discarded. Only clients keep a log,
+    /// as the server can't tell the user about it, so callers should check
+    /// logs_sync_conflicts() first.
+    pub(super) fn log_sync_conflict<T: Serialize>(
+        &self,
+        kind: SyncConflictKind,
+        object_id: i64,
+        kept_local: bool,
+        discarded: &T,
+    ) -> Result<()> {
+        self.storage.add_sync_conflict(&SyncConflict {
+            id: 0,
+            kind,
+            object_id,
+            mtime: TimestampSecs::now(),
+            kept_local,
+            discarded: serde_json::to_string(discarded)?,
+        })
+    }
+
+    pub(super) fn logs_sync_conflicts(&self) -> bool {
+        !self.server
+    }
+
+    /// Drop conflicts past their retention period, or over the count limit.
+    pub(super) fn prune_sync_conflicts(&self) -> Result<()> {
+        let cutoff = TimestampSecs(TimestampSecs::now().0 - CONFLICT_RETENTION_SECS);
+        self.storage.prune_sync_conflicts(cutoff, MAX_CONFLICTS)
+    }
+
+    /// The versions previous syncs discarded, newest first.
+    pub fn sync_conflicts(&self) -> Result<Vec<SyncConflict>> {
+        self.storage.all_sync_conflicts()
+    }
+
+    pub fn clear_sync_conflicts(&self) -> Result<()> {
+        self.storage.clear_sync_conflicts()
+    }
+
+    /// Replace an object with the version a sync discarded, and remove the
+    /// conflict from the log. The object is marked as modified, so the
+    /// restored version will be sent in the next sync.
+    pub fn restore_sync_conflict(&mut self, id: i64) -> Result<()> {
+        let conflict = self
+            .storage
+            .get_sync_conflict(id)?
+            .ok_or(AnkiError::NotFound)?;
+        let data = &conflict.discarded;
+        match conflict.kind {
+            SyncConflictKind::Note => {
+                let discarded: DiscardedFields = serde_json::from_str(data)?;
+                let mut note = self
+                    .storage
+                    .get_note(NoteID(conflict.object_id))?
+                    .ok_or(AnkiError::NotFound)?;
+                if note.notetype_id != discarded.notetype_id {
+                    return Err(AnkiError::invalid_input("notetype has changed since"));
+                }
+                for (ord, text) in discarded.fields {
+                    if let Some(field) = note.fields.get_mut(ord) {
+                        *field = text;
+                    }
+                }
+                self.update_note(&mut note)?;
+            }
+            SyncConflictKind::Card => {
+                let mut card: Card = serde_json::from_str::<CardEntry>(data)?.into();
+                self.transact(None, |col| {
+                    let original = col.storage.get_card(card.id)?.ok_or(AnkiError::NotFound)?;
+                    col.update_card(&mut card, &original, col.usn()?)
+                })?;
+            }
+            SyncConflictKind::Deck => {
+                let mut deck: Deck = serde_json::from_str::<DeckSchema11>(data)?.into();
+                if self.storage.get_deck(deck.id)?.is_none() {
+                    return Err(AnkiError::NotFound);
+                }
+                self.add_or_update_deck(&mut deck)?;
+            }
+            SyncConflictKind::DeckConfig => {
+                let mut conf: DeckConf = serde_json::from_str::<DeckConfSchema11>(data)?.into();
+                if self.storage.get_deck_config(conf.id)?.is_none() {
+                    return Err(AnkiError::NotFound);
+                }
+                self.add_or_update_deck_config(&mut conf, false)?;
+            }
+            SyncConflictKind::NoteType => {
+                let mut nt: NoteType = serde_json::from_str::<NoteTypeSchema11>(data)?.into();
+                let existing = self.get_notetype(nt.id)?.ok_or(AnkiError::NotFound)?;
+                if existing.config.schema_version != nt.config.schema_version {
+                    return Err(AnkiError::invalid_input(
+                        "fields or templates have changed since",
+                    ));
+                }
+                // the discarded version is older, but is to replace the current one
+                nt.mtime_secs = existing.mtime_secs;
+                self.update_notetype(&mut nt, false)?;
+            }
+        }
+
+        self.storage.remove_sync_conflict(id)
+    }
+}
+
+/// True if the versions differ in more than their modification time and usn.
+pub(super) fn content_differs<T: Serialize>(local: &T, remote: &T) -> Result<bool> {
+    let content = |obj: &T| -> Result<Value> {
+        let mut val = serde_json::to_value(obj)?;
+        if let Some(map) = val.as_object_mut() {
+            map.remove("mod");
+            map.remove("usn");
+        }
+        Ok(val)
+    };
+    Ok(content(local)? != content(remote)?)
+}
+
+/// True if the versions differ in more than their scheduling state. Reviews
+/// done on either side are kept in the revlog, so a conflict that only
+/// discards scheduling is not worth logging.
+pub(super) fn card_edited(card: &Card, entry: &CardEntry) -> bool {
+    let home_deck = |did: DeckID, odid: DeckID| if odid.0 != 0 { odid } else { did };
+    home_deck(card.deck_id, card.original_deck_id) != home_deck(entry.did, entry.odid)
+        || card.flags != entry.flags
+        || card.data != entry.data
+}
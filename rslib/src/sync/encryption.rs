@@ -0,0 +1,687 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Optional end-to-end encryption of study material, so the server only
+//! stores ciphertext. When enabled, note fields, deck names and media files
+//! are encrypted before they are sent, and decrypted when received, with a
+//! key derived from a passphrase that never leaves the client. Cards, the
+//! review log and other scheduling data are sent as before, so the server
+//! can still check both sides agree. Tags, notetypes and the searches of
+//! filtered decks are also sent as-is.
+//!
+//! The key is derived with scrypt, and text is encrypted with AES-SIV. As
+//! SIV is deterministic, a deck name always gives the same ciphertext, and
+//! the deck tree and name lookups keep working on the server. Each field is
+//! tied to its note and position, so the ciphertext can't be moved
+//! elsewhere undetected.
+//!
+//! Enabling or disabling encryption requires a full sync, and a media sync
+//! that re-sends every file under its new name. The salt the key is derived
+//! with is stored in the collection config, and synced with it. The value
+//! used to check the passphrase is kept on each device, so the server can't
+//! use it to test guesses.
+
+use super::{Chunk, UnchunkedChanges};
+use crate::{
+    collection::open_collection, config::ConfigKey, i18n::I18n, media::MediaManager, prelude::*,
+};
+use aes_siv::{aead::generic_array::GenericArray, siv::Aes256Siv};
+use rusqlite::{Connection, NO_PARAMS};
+use scrypt::{scrypt, ScryptParams};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, convert::TryInto, fmt, fs, iter, path::Path};
+use tempfile::NamedTempFile;
+
+/// Prefix of encrypted text, followed by the hex-encoded ciphertext.
+const TEXT_PREFIX: &str = "e2e1:";
+/// Prefix of the name encrypted media files are stored under, followed by
+/// 16 hex-encoded bytes.
+const MEDIA_PREFIX: &str = "e2e-";
+const MEDIA_HASH_LEN: usize = 16;
+const FORMAT_VERSION: u8 = 1;
+/// scrypt's cost, as a power of 2. This takes 32MiB of memory.
+#[cfg(not(test))]
+const KDF_LOG_N: u8 = 15;
+/// Keys are derived many times in tests, which aren't optimized.
+#[cfg(test)]
+const KDF_LOG_N: u8 = 4;
+const KDF_R: u32 = 8;
+const KDF_P: u32 = 1;
+
+const SIV_CONTEXT: &str = "Anki 2020-11-02 sync encryption";
+const NAMES_CONTEXT: &str = "Anki 2020-11-02 sync media names";
+const CHECK_CONTEXT: &str = "Anki 2020-11-02 sync passphrase check";
+
+/// The passphrase provided for the current session. Kept out of debug
+/// output.
+#[derive(Clone)]
+pub(crate) struct SyncPassphrase(String);
+
+impl fmt::Debug for SyncPassphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SyncPassphrase(..)")
+    }
+}
+
+/// Stored in the config, so other devices can derive the same key. The
+/// scrypt cost is not stored, as the server could then lower it.
+#[derive(Serialize, Deserialize)]
+struct EncryptionConfig {
+    salt: String,
+}
+
+impl EncryptionConfig {
+    fn new() -> EncryptionConfig {
+        let salt: [u8; 16] = rand::random();
+        EncryptionConfig {
+            salt: hex::encode(salt),
+        }
+    }
+
+    fn key(&self, passphrase: &str) -> Result<SyncKey> {
+        let salt = hex::decode(&self.salt).map_err(|_| invalid_config())?;
+        SyncKey::derive(passphrase, &salt)
+    }
+}
+
+/// The keys study material is encrypted with, derived from the sync
+/// passphrase.
+#[derive(Clone)]
+pub struct SyncKey {
+    siv: [u8; 64],
+    names: [u8; 32],
+    check: String,
+}
+
+impl SyncKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<SyncKey> {
+        let params = ScryptParams::new(KDF_LOG_N, KDF_R, KDF_P).map_err(|_| invalid_config())?;
+        let mut master = [0u8; 32];
+        scrypt(passphrase.as_bytes(), salt, &params, &mut master).map_err(|_| invalid_config())?;
+        let mut siv = [0u8; 64];
+        blake3::Hasher::new_derive_key(SIV_CONTEXT)
+            .update(&master)
+            .finalize_xof()
+            .fill(&mut siv);
+        Ok(SyncKey {
+            siv,
+            names: subkey(&master, NAMES_CONTEXT),
+            check: hex::encode(subkey(&master, CHECK_CONTEXT)),
+        })
+    }
+
+    fn cipher(&self) -> Aes256Siv {
+        Aes256Siv::new(GenericArray::clone_from_slice(&self.siv))
+    }
+
+    /// Deterministic authenticated encryption, with `context` as associated
+    /// data.
+    fn seal(&self, plaintext: &[u8], context: &[u8]) -> Vec<u8> {
+        let ciphertext = self
+            .cipher()
+            .encrypt(iter::once(context), plaintext)
+            .expect("SIV only fails with too many headers");
+        let mut out = Vec::with_capacity(1 + ciphertext.len());
+        out.push(FORMAT_VERSION);
+        out.extend(ciphertext);
+        out
+    }
+
+    fn open(&self, data: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        match data.split_first() {
+            Some((&FORMAT_VERSION, ciphertext)) => self
+                .cipher()
+                .decrypt(iter::once(context), ciphertext)
+                .map_err(|_| decryption_failed()),
+            _ => Err(decryption_failed()),
+        }
+    }
+
+    fn encrypt_text(&self, text: &str, context: &str) -> String {
+        format!(
+            "{}{}",
+            TEXT_PREFIX,
+            hex::encode(self.seal(text.as_bytes(), context.as_bytes()))
+        )
+    }
+
+    /// Text without the prefix is rejected, so the server can't substitute
+    /// plaintext of its choosing.
+    fn decrypt_text(&self, text: &str, context: &str) -> Result<String> {
+        if !text.starts_with(TEXT_PREFIX) {
+            return Err(decryption_failed());
+        }
+        let data = hex::decode(&text[TEXT_PREFIX.len()..]).map_err(|_| decryption_failed())?;
+        String::from_utf8(self.open(&data, context.as_bytes())?).map_err(|_| decryption_failed())
+    }
+
+    /// Fields are joined with \x1f, and encrypted separately, so the server
+    /// sees the number of fields its notetype expects.
+    fn encrypt_fields(&self, fields: &str, guid: &str) -> String {
+        fields
+            .split('\x1f')
+            .enumerate()
+            .map(|(ord, field)| self.encrypt_text(field, &field_context(guid, ord)))
+            .collect::<Vec<_>>()
+            .join("\x1f")
+    }
+
+    fn decrypt_fields(&self, fields: &str, guid: &str) -> Result<String> {
+        Ok(fields
+            .split('\x1f')
+            .enumerate()
+            .map(|(ord, field)| self.decrypt_text(field, &field_context(guid, ord)))
+            .collect::<Result<Vec<_>>>()?
+            .join("\x1f"))
+    }
+
+    /// Each component is encrypted separately, so the hierarchy is kept.
+    fn encrypt_deck_name(&self, name: &str, separator: &str) -> String {
+        name.split(separator)
+            .map(|component| self.encrypt_text(component, "deck"))
+            .collect::<Vec<_>>()
+            .join(separator)
+    }
+
+    fn decrypt_deck_name(&self, name: &str, separator: &str) -> Result<String> {
+        Ok(name
+            .split(separator)
+            .map(|component| self.decrypt_text(component, "deck"))
+            .collect::<Result<Vec<_>>>()?
+            .join(separator))
+    }
+
+    /// The name a media file is stored under on the server.
+    pub(crate) fn media_name(&self, fname: &str) -> String {
+        let hash = blake3::keyed_hash(&self.names, fname.as_bytes());
+        format!(
+            "{}{}",
+            MEDIA_PREFIX,
+            hex::encode(&hash.as_bytes()[..MEDIA_HASH_LEN])
+        )
+    }
+
+    /// The real filename is stored with the data, so it can be recovered
+    /// by devices that don't have the file.
+    pub(crate) fn encrypt_media(&self, fname: &str, data: &[u8]) -> Vec<u8> {
+        let mut plaintext = Vec::with_capacity(4 + fname.len() + data.len());
+        plaintext.extend_from_slice(&(fname.len() as u32).to_le_bytes());
+        plaintext.extend_from_slice(fname.as_bytes());
+        plaintext.extend_from_slice(data);
+        self.seal(&plaintext, b"media")
+    }
+
+    /// Returns the real filename and data of a file stored under
+    /// `server_name`.
+    pub(crate) fn decrypt_media(
+        &self,
+        server_name: &str,
+        data: &[u8],
+    ) -> Result<(String, Vec<u8>)> {
+        let mut plaintext = self.open(data, b"media")?;
+        if plaintext.len() < 4 {
+            return Err(decryption_failed());
+        }
+        let fname_len = u32::from_le_bytes(plaintext[..4].try_into().unwrap()) as usize;
+        if plaintext.len() < 4 + fname_len {
+            return Err(decryption_failed());
+        }
+        let data = plaintext.split_off(4 + fname_len);
+        let fname = String::from_utf8(plaintext.split_off(4)).map_err(|_| decryption_failed())?;
+        if self.media_name(&fname) != server_name {
+            return Err(decryption_failed());
+        }
+        Ok((fname, data))
+    }
+}
+
+/// True if `fname` is the name an encrypted media file is stored under.
+pub(crate) fn is_encrypted_media_name(fname: &str) -> bool {
+    fname.starts_with(MEDIA_PREFIX)
+        && fname.len() == MEDIA_PREFIX.len() + MEDIA_HASH_LEN * 2
+        && fname[MEDIA_PREFIX.len()..]
+            .chars()
+            .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+fn subkey(master: &[u8; 32], context: &str) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key(context);
+    hasher.update(master);
+    *hasher.finalize().as_bytes()
+}
+
+fn field_context(guid: &str, ord: usize) -> String {
+    format!("field\0{}\0{}", guid, ord)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn decryption_failed() -> AnkiError {
+    AnkiError::sync_misc("unable to decrypt synced data")
+}
+
+fn passphrase_required() -> AnkiError {
+    AnkiError::sync_misc("sync passphrase required")
+}
+
+fn incorrect_passphrase() -> AnkiError {
+    AnkiError::sync_misc("incorrect sync passphrase")
+}
+
+fn invalid_config() -> AnkiError {
+    AnkiError::sync_misc("invalid sync encryption config")
+}
+
+impl Chunk {
+    pub(super) fn encrypt(&mut self, key: &SyncKey) {
+        for note in &mut self.notes {
+            note.fields = key.encrypt_fields(&note.fields, &note.guid);
+        }
+    }
+
+    pub(super) fn decrypt(&mut self, key: &SyncKey) -> Result<()> {
+        for note in &mut self.notes {
+            note.fields = key.decrypt_fields(&note.fields, &note.guid)?;
+        }
+        Ok(())
+    }
+}
+
+impl UnchunkedChanges {
+    pub(super) fn encrypt(&mut self, key: &SyncKey) {
+        for deck in &mut self.decks_and_config.decks {
+            let common = deck.common_mut();
+            common.name = key.encrypt_deck_name(&common.name, "::");
+        }
+    }
+
+    pub(super) fn decrypt(&mut self, key: &SyncKey) -> Result<()> {
+        for deck in &mut self.decks_and_config.decks {
+            let common = deck.common_mut();
+            common.name = key.decrypt_deck_name(&common.name, "::")?;
+        }
+        Ok(())
+    }
+}
+
+impl Collection {
+    /// Encrypt study material when syncing, with a key derived from
+    /// `passphrase`. This forces a full sync, and other devices will need
+    /// the passphrase to download the collection. Media is sent again under
+    /// encrypted names on the next media sync, and the unencrypted copies are
+    /// removed from the server.
+    ///
+    /// Tags, notetypes (including their templates) and the searches of
+    /// filtered decks are still sent unencrypted; the user should be shown
+    /// `TR::SyncEncryptionLimitations` before enabling this.
+    pub fn enable_sync_encryption(&mut self, passphrase: &str) -> Result<()> {
+        let config = EncryptionConfig::new();
+        let key = config.key(passphrase)?;
+        self.transact(None, |col| {
+            col.set_config(ConfigKey::SyncEncryption, &config)?;
+            col.set_config(ConfigKey::SyncEncryptionCheck, &key.check)?;
+            col.storage.set_schema_modified()
+        })?;
+        self.state.sync_passphrase = Some(SyncPassphrase(passphrase.into()));
+        force_media_resync(&self.media_folder, &self.media_db)
+    }
+
+    /// Send study material unencrypted again. This forces a full sync, and
+    /// a media sync that replaces the encrypted files on the server.
+    pub fn disable_sync_encryption(&mut self) -> Result<()> {
+        self.transact(None, |col| {
+            col.remove_config(ConfigKey::SyncEncryption)?;
+            col.remove_config(ConfigKey::SyncEncryptionCheck)?;
+            col.storage.set_schema_modified()
+        })?;
+        self.state.sync_passphrase = None;
+        force_media_resync(&self.media_folder, &self.media_db)
+    }
+
+    pub fn sync_encryption_enabled(&self) -> bool {
+        self.encryption_config().is_some()
+    }
+
+    /// Must be called after the collection is opened, before syncing an
+    /// encrypted collection, or downloading one for the first time. If
+    /// encryption is enabled, the passphrase is checked against the one it
+    /// was enabled or last downloaded with.
+    pub fn set_sync_passphrase(&mut self, passphrase: &str) -> Result<()> {
+        if let Some(config) = self.encryption_config() {
+            self.check_sync_key(&config.key(passphrase)?)?;
+        }
+        self.state.sync_passphrase = Some(SyncPassphrase(passphrase.into()));
+        Ok(())
+    }
+
+    /// The key study material is encrypted with, if encryption is enabled.
+    pub fn sync_key(&self) -> Result<Option<SyncKey>> {
+        match self.encryption_config() {
+            Some(config) => match &self.state.sync_passphrase {
+                Some(passphrase) => {
+                    let key = config.key(&passphrase.0)?;
+                    self.check_sync_key(&key)?;
+                    Ok(Some(key))
+                }
+                None => Err(passphrase_required()),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// The check value is only stored locally. Without it, a wrong
+    /// passphrase is caught when received text fails to decrypt.
+    fn check_sync_key(&self, key: &SyncKey) -> Result<()> {
+        match self.get_config_optional::<String, _>(ConfigKey::SyncEncryptionCheck) {
+            Some(check) if !constant_time_eq(check.as_bytes(), key.check.as_bytes()) => {
+                Err(incorrect_passphrase())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn encryption_config(&self) -> Option<EncryptionConfig> {
+        self.get_config_optional(ConfigKey::SyncEncryption)
+    }
+
+    /// Encrypt or decrypt the note fields and deck names of the whole
+    /// collection. Modification times and usns are left alone.
+    fn convert_study_material(&mut self, key: &SyncKey, encrypt: bool) -> Result<()> {
+        self.storage.begin_rust_trx()?;
+        for (ntid, nid) in self.storage.all_note_ids_by_notetype()? {
+            let nt = self
+                .get_notetype(ntid)?
+                .ok_or_else(|| AnkiError::invalid_input("note missing notetype"))?;
+            let mut note: Note = match self.storage.get_note(nid)? {
+                Some(note) => note,
+                None => continue,
+            };
+            for (ord, field) in note.fields.iter_mut().enumerate() {
+                let context = field_context(&note.guid, ord);
+                *field = if encrypt {
+                    key.encrypt_text(field, &context)
+                } else {
+                    key.decrypt_text(field, &context)?
+                };
+            }
+            // the sort field and checksum are derived from the new content
+            note.prepare_for_update_unchecked(&nt, false)?;
+            self.storage.update_note(&note)?;
+        }
+        for mut deck in self.storage.get_all_decks()? {
+            deck.name = if encrypt {
+                key.encrypt_deck_name(&deck.name, "\x1f")
+            } else {
+                key.decrypt_deck_name(&deck.name, "\x1f")?
+            };
+            self.storage.update_deck(&deck)?;
+        }
+        self.storage.commit_rust_trx()
+    }
+}
+
+/// Copy a closed collection, and encrypt the copy for a full upload. The copy
+/// is vacuumed, so no plaintext is left in unused pages.
+pub(super) fn encrypted_copy(
+    col_path: &Path,
+    key: &SyncKey,
+    i18n: I18n,
+    log: Logger,
+) -> Result<NamedTempFile> {
+    let file = NamedTempFile::new()?;
+    fs::copy(col_path, file.path())?;
+    let mut col = open_copy(file.path(), i18n, log)?;
+    col.convert_study_material(key, true)?;
+    col.remove_config(ConfigKey::SyncEncryptionCheck)?;
+    col.close(true)?;
+    Connection::open(file.path())?.execute_batch("vacuum")?;
+    Ok(file)
+}
+
+/// Decrypt a downloaded collection in place, if it was encrypted, and
+/// return whether it was. Returns an error, leaving the local collection
+/// alone, if no passphrase or the wrong one was provided.
+pub(super) fn decrypt_download(
+    path: &Path,
+    passphrase: Option<&SyncPassphrase>,
+    i18n: I18n,
+    log: Logger,
+) -> Result<bool> {
+    let config = match encryption_config_in_file(path)? {
+        Some(config) => config,
+        None => return Ok(false),
+    };
+    let passphrase = passphrase.ok_or_else(passphrase_required)?;
+    let key = config.key(&passphrase.0)?;
+    let mut col = open_copy(path, i18n, log)?;
+    col.convert_study_material(&key, false).map_err(|err| {
+        if err == decryption_failed() {
+            incorrect_passphrase()
+        } else {
+            err
+        }
+    })?;
+    // later passphrases are checked against this one
+    col.set_config(ConfigKey::SyncEncryptionCheck, &key.check)?;
+    col.close(true)?;
+    Ok(true)
+}
+
+/// Forget which media files have been synced, so they are all compared with
+/// the server again, and sent under the names the current mode uses.
+pub(super) fn force_media_resync(media_folder: &Path, media_db: &Path) -> Result<()> {
+    MediaManager::new(media_folder, media_db)?.force_resync()
+}
+
+/// Full syncs transfer collections in schema 11, which keeps the config in
+/// the col table.
+fn encryption_config_in_file(path: &Path) -> Result<Option<EncryptionConfig>> {
+    let db = Connection::open(path)?;
+    let conf: String = db.query_row("select conf from col", NO_PARAMS, |row| row.get(0))?;
+    let mut conf: HashMap<String, Value> = serde_json::from_str(&conf)?;
+    let key: &str = ConfigKey::SyncEncryption.into();
+    Ok(conf
+        .remove(key)
+        .and_then(|val| serde_json::from_value(val).ok()))
+}
+
+/// Open a collection file being transferred; its media is not touched.
+fn open_copy(path: &Path, i18n: I18n, log: Logger) -> Result<Collection> {
+    open_collection(path, path, path, false, i18n, log)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        log,
+        media::MediaManager,
+        sync::{LocalPeer, SyncActionRequired},
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn text() {
+        let key = SyncKey::derive("passphrase", b"salt").unwrap();
+        let encrypted = key.encrypt_text("foo", "ctx");
+        assert!(encrypted.starts_with(TEXT_PREFIX));
+        assert_eq!(encrypted, key.encrypt_text("foo", "ctx"));
+        assert_eq!(key.decrypt_text(&encrypted, "ctx").unwrap(), "foo");
+        // text can't be moved to another context
+        assert!(key.decrypt_text(&encrypted, "other").is_err());
+        // or decrypted with another passphrase
+        let other = SyncKey::derive("other", b"salt").unwrap();
+        assert!(other.decrypt_text(&encrypted, "ctx").is_err());
+        // unencrypted text is rejected
+        assert!(key.decrypt_text("bar", "ctx").is_err());
+
+        let name = key.encrypt_deck_name("a::b", "::");
+        assert_eq!(name.split("::").count(), 2);
+        assert!(name.starts_with(&key.encrypt_deck_name("a", "::")));
+        assert_eq!(key.decrypt_deck_name(&name, "::").unwrap(), "a::b");
+
+        let data = key.encrypt_media("a.jpg", b"data");
+        let server_name = key.media_name("a.jpg");
+        assert_eq!(
+            key.decrypt_media(&server_name, &data).unwrap(),
+            ("a.jpg".to_string(), b"data".to_vec())
+        );
+        assert!(key.decrypt_media(&key.media_name("b.jpg"), &data).is_err());
+        assert!(is_encrypted_media_name(&server_name));
+        assert!(!is_encrypted_media_name("a.jpg"));
+    }
+
+    fn open(folder: &Path) -> Result<Collection> {
+        fs::create_dir_all(folder.join("collection.media"))?;
+        let i18n = I18n::new(&[""], "", log::terminal());
+        let mut col = open_collection(
+            folder.join("collection.anki2"),
+            folder.join("collection.media"),
+            folder.join("collection.media.db2"),
+            false,
+            i18n,
+            log::terminal(),
+        )?;
+        col.set_sync_passphrase("passphrase")?;
+        Ok(col)
+    }
+
+    fn add_note(col: &mut Collection, text: &str) -> Result<()> {
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = text.into();
+        col.add_note(&mut note, DeckID(1))
+    }
+
+    fn note_fields(col: &Collection) -> Result<Vec<String>> {
+        col.storage
+            .db
+            .prepare("select flds from notes order by id")?
+            .query_and_then(NO_PARAMS, |row| row.get(0).map_err(Into::into))?
+            .collect()
+    }
+
+    fn media(folder: &Path) -> Result<MediaManager> {
+        MediaManager::new(
+            folder.join("collection.media"),
+            folder.join("collection.media.db2"),
+        )
+    }
+
+    #[test]
+    fn encrypted_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let desktop = dir.path().join("desktop");
+        let phone = dir.path().join("phone");
+        let server = dir.path().join("server");
+        let mut rt = Runtime::new().unwrap();
+
+        // media sent before encryption is enabled
+        let mut col = open(&desktop)?;
+        add_note(&mut col, "desktop")?;
+        fs::create_dir_all(server.join("collection.media"))?;
+        let peer = LocalPeer::open(&server, log::terminal())?;
+        fs::write(desktop.join("collection.media").join("before.txt"), "b")?;
+        rt.block_on(media(&desktop)?.sync_media_with_peer(
+            |_| true,
+            peer.peer(),
+            None,
+            log::terminal(),
+        ))?;
+        let server_media = server.join("collection.media");
+        assert!(server_media.join("before.txt").exists());
+
+        col.enable_sync_encryption("passphrase")?;
+        rt.block_on(col.full_upload_to_peer(peer.peer(), Box::new(|_, _| ())))?;
+
+        // the phone can't download without the passphrase
+        let i18n = I18n::new(&[""], "", log::terminal());
+        fs::create_dir_all(&phone)?;
+        let col = open_collection(
+            phone.join("collection.anki2"),
+            phone.join("collection.media"),
+            phone.join("collection.media.db2"),
+            false,
+            i18n,
+            log::terminal(),
+        )?;
+        assert!(rt
+            .block_on(col.full_download_from_peer(peer.peer(), Box::new(|_, _| ())))
+            .is_err());
+        // or with the wrong one
+        let mut col = open(&phone)?;
+        col.set_sync_passphrase("wrong")?;
+        assert!(rt
+            .block_on(col.full_download_from_peer(peer.peer(), Box::new(|_, _| ())))
+            .is_err());
+        let col = open(&phone)?;
+        rt.block_on(col.full_download_from_peer(peer.peer(), Box::new(|_, _| ())))?;
+        let mut col = open(&phone)?;
+        assert_eq!(note_fields(&col)?, vec!["desktop\x1f"]);
+        // once downloaded, the passphrase is checked locally
+        assert!(col.set_sync_passphrase("wrong").is_err());
+        drop(col);
+
+        // normal syncs and media
+        let mut col = open(&desktop)?;
+        add_note(&mut col, "desktop2")?;
+        let out = rt.block_on(col.normal_sync_with_peer(peer.peer(), |_, _| ()))?;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        fs::write(desktop.join("collection.media").join("d.txt"), "d")?;
+        let key = col.sync_key()?;
+        rt.block_on(media(&desktop)?.sync_media_with_peer(
+            |_| true,
+            peer.peer(),
+            key,
+            log::terminal(),
+        ))?;
+
+        let mut col = open(&phone)?;
+        rt.block_on(col.normal_sync_with_peer(peer.peer(), |_, _| ()))?;
+        assert_eq!(note_fields(&col)?, vec!["desktop\x1f", "desktop2\x1f"]);
+        let deck = col.storage.get_deck(DeckID(1))?.unwrap();
+        assert_eq!(deck.name, "Default");
+        let key = col.sync_key()?;
+        rt.block_on(media(&phone)?.sync_media_with_peer(
+            |_| true,
+            peer.peer(),
+            key,
+            log::terminal(),
+        ))?;
+        for (fname, text) in &[("d.txt", "d"), ("before.txt", "b")] {
+            assert_eq!(
+                fs::read_to_string(phone.join("collection.media").join(fname))?,
+                *text
+            );
+        }
+        drop(peer);
+
+        // the server only has ciphertext
+        let col = open(&server)?;
+        for fields in note_fields(&col)? {
+            assert!(fields
+                .split('\x1f')
+                .all(|field| field.starts_with(TEXT_PREFIX)));
+        }
+        let deck = col.storage.get_deck(DeckID(1))?.unwrap();
+        assert!(deck.name.starts_with(TEXT_PREFIX));
+        // and the file sent before encryption was enabled was replaced
+        let key = col.sync_key()?.unwrap();
+        for (fname, text) in &[("d.txt", "d"), ("before.txt", "b")] {
+            assert!(!server_media.join(fname).exists());
+            assert_ne!(
+                fs::read(server_media.join(key.media_name(fname)))?,
+                text.as_bytes()
+            );
+        }
+        // the passphrase check is not sent
+        assert!(col
+            .get_config_optional::<String, _>(ConfigKey::SyncEncryptionCheck)
+            .is_none());
+
+        Ok(())
+    }
+}
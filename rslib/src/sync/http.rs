@@ -1,12 +1,17 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use std::{fs, path::PathBuf};
+use std::path::PathBuf;
 
-use super::{Chunk, Graves, SanityCheckCounts, UnchunkedChanges};
+use super::{Chunk, Graves, IDDigests, SanityCheckCounts, UnchunkedChanges};
 use crate::backend_proto::sync_server_method_in::Method;
 use crate::prelude::*;
 use serde::{Deserialize, Serialize};
+
+/// Sent with gzipped downloads, which have no content length, so progress can
+/// be reported against the size of the collection.
+pub(crate) const ORIGINAL_SIZE_HEADER: &str = "anki-original-size";
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum SyncRequest {
@@ -16,9 +21,11 @@ pub enum SyncRequest {
     ApplyGraves(ApplyGravesIn),
     ApplyChanges(ApplyChangesIn),
     Chunk,
+    ResumeChunk(ResumeChunkIn),
     ApplyChunk(ApplyChunkIn),
     #[serde(rename = "sanityCheck2")]
     SanityCheck(SanityCheckIn),
+    SanityCheckIds(SanityCheckIdsIn),
     Finish,
     Abort,
     #[serde(rename = "upload")]
@@ -38,13 +45,18 @@ impl SyncRequest {
             SyncRequest::ApplyGraves(v) => ("applyGraves", to_vec(&v)?),
             SyncRequest::ApplyChanges(v) => ("applyChanges", to_vec(&v)?),
             SyncRequest::Chunk => ("chunk", b"{}".to_vec()),
+            SyncRequest::ResumeChunk(v) => ("resumeChunk", to_vec(&v)?),
             SyncRequest::ApplyChunk(v) => ("applyChunk", to_vec(&v)?),
             SyncRequest::SanityCheck(v) => ("sanityCheck2", to_vec(&v)?),
+            SyncRequest::SanityCheckIds(v) => ("sanityCheckIds", to_vec(&v)?),
             SyncRequest::Finish => ("finish", b"{}".to_vec()),
             SyncRequest::Abort => ("abort", b"{}".to_vec()),
-            SyncRequest::FullUpload(v) => {
-                // fixme: stream in the data instead, in a different call
-                ("upload", fs::read(&v)?)
+            SyncRequest::FullUpload(_) => {
+                // the collection is streamed by HTTPSyncClient::full_upload() instead,
+                // so it need not fit in memory
+                return Err(AnkiError::invalid_input(
+                    "uploads are not sent in one request",
+                ));
             }
             SyncRequest::FullDownload => ("download", b"{}".to_vec()),
         })
@@ -59,8 +71,10 @@ impl SyncRequest {
             Method::ApplyGraves => SyncRequest::ApplyGraves(from_slice(&data)?),
             Method::ApplyChanges => SyncRequest::ApplyChanges(from_slice(&data)?),
             Method::Chunk => SyncRequest::Chunk,
+            Method::ResumeChunk => SyncRequest::ResumeChunk(from_slice(&data)?),
             Method::ApplyChunk => SyncRequest::ApplyChunk(from_slice(&data)?),
             Method::SanityCheck => SyncRequest::SanityCheck(from_slice(&data)?),
+            Method::SanityCheckIds => SyncRequest::SanityCheckIds(from_slice(&data)?),
             Method::Finish => SyncRequest::Finish,
             Method::Abort => SyncRequest::Abort,
             Method::FullUpload => {
@@ -110,6 +124,12 @@ pub struct ApplyChangesIn {
     pub changes: UnchunkedChanges,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeChunkIn {
+    /// The number of chunks the client has applied.
+    pub received: usize,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ApplyChunkIn {
     pub chunk: Chunk,
@@ -119,3 +139,8 @@ pub struct ApplyChunkIn {
 pub struct SanityCheckIn {
     pub client: SanityCheckCounts,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SanityCheckIdsIn {
+    pub digests: IDDigests,
+}
@@ -1,27 +1,31 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use super::{server::SyncServer, SYNC_VERSION_MAX};
+use super::{http::ORIGINAL_SIZE_HEADER, server::SyncServer, SYNC_VERSION_MAX};
 use super::{
-    Chunk, FullSyncProgress, Graves, SanityCheckCounts, SanityCheckOut, SyncMeta, UnchunkedChanges,
+    Chunk, DigestIDs, FullSyncProgress, Graves, IDDigests, SanityCheckCounts, SanityCheckOut,
+    SyncMeta, UnchunkedChanges,
 };
 use crate::prelude::*;
 use crate::{err::SyncErrorKind, notes::guid, version::sync_client_version};
+use async_compression::stream::GzipDecoder;
 use async_trait::async_trait;
 use bytes::Bytes;
 use flate2::write::GzEncoder;
 use flate2::Compression;
+use futures::stream::LocalBoxStream;
 use futures::Stream;
 use futures::StreamExt;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
 use reqwest::Body;
-use reqwest::{multipart, Client, Response};
+use reqwest::{multipart, Client, RequestBuilder, Response};
 use serde::de::DeserializeOwned;
 
 use super::http::{
-    ApplyChangesIn, ApplyChunkIn, ApplyGravesIn, HostKeyIn, HostKeyOut, MetaIn, SanityCheckIn,
-    StartIn, SyncRequest,
+    ApplyChangesIn, ApplyChunkIn, ApplyGravesIn, HostKeyIn, HostKeyOut, MetaIn, ResumeChunkIn,
+    SanityCheckIdsIn, SanityCheckIn, StartIn, SyncRequest,
 };
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
 use std::path::Path;
 use std::time::Duration;
 use tempfile::NamedTempFile;
@@ -94,6 +98,11 @@ impl SyncServer for HTTPSyncClient {
         self.json_request(input).await
     }
 
+    async fn resume_chunk(&mut self, received: usize) -> Result<Chunk> {
+        let input = SyncRequest::ResumeChunk(ResumeChunkIn { received });
+        self.json_request(input).await
+    }
+
     async fn apply_chunk(&mut self, chunk: Chunk) -> Result<()> {
         let input = SyncRequest::ApplyChunk(ApplyChunkIn { chunk });
         self.json_request(input).await
@@ -104,6 +113,11 @@ impl SyncServer for HTTPSyncClient {
         self.json_request(input).await
     }
 
+    async fn sanity_check_ids(&mut self, digests: IDDigests) -> Result<DigestIDs> {
+        let input = SyncRequest::SanityCheckIds(SanityCheckIdsIn { digests });
+        self.json_request(input).await
+    }
+
     async fn finish(&mut self) -> Result<TimestampMillis> {
         let input = SyncRequest::Finish;
         self.json_request(input).await
@@ -185,6 +199,12 @@ impl HTTPSyncClient {
         self.full_sync_progress_fn = func;
     }
 
+    /// Send requests to the provided URL instead of AnkiWeb, eg
+    /// `http://localhost:8080/sync/`.
+    pub fn set_endpoint(&mut self, endpoint: String) {
+        self.endpoint = endpoint;
+    }
+
     async fn json_request<T>(&self, req: SyncRequest) -> Result<T>
     where
         T: DeserializeOwned,
@@ -203,10 +223,9 @@ impl HTTPSyncClient {
         req: &[u8],
         timeout_long: bool,
     ) -> Result<Response> {
-        let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
-        gz.write_all(req)?;
-        let part = multipart::Part::bytes(gz.finish()?);
-        let resp = self.request(method, part, timeout_long).await?;
+        let resp = self
+            .request(method, gzipped_part(req)?, timeout_long)
+            .await?;
         resp.error_for_status().map_err(Into::into)
     }
 
@@ -216,14 +235,27 @@ impl HTTPSyncClient {
         data_part: multipart::Part,
         timeout_long: bool,
     ) -> Result<Response> {
+        self.request_builder(method, data_part, timeout_long)
+            .send()
+            .await?
+            .error_for_status()
+            .map_err(Into::into)
+    }
+
+    fn request_builder(
+        &self,
+        method: &str,
+        data_part: multipart::Part,
+        timeout_long: bool,
+    ) -> RequestBuilder {
         let data_part = data_part.file_name("data");
 
-        let mut form = multipart::Form::new()
-            .part("data", data_part)
-            .text("c", "1");
+        let mut form = multipart::Form::new().text("c", "1");
         if let Some(hkey) = &self.hkey {
             form = form.text("k", hkey.clone()).text("s", self.skey.clone());
         }
+        // the key precedes the data, so servers can check it before reading uploads
+        let form = form.part("data", data_part);
 
         let url = format!("{}{}", self.endpoint, method);
         let mut req = self.client.post(&url).multipart(form);
@@ -232,7 +264,7 @@ impl HTTPSyncClient {
             req = req.timeout(Duration::from_secs(60 * 60));
         }
 
-        req.send().await?.error_for_status().map_err(Into::into)
+        req
     }
 
     pub(crate) async fn login<S: Into<String>>(&mut self, username: S, password: S) -> Result<()> {
@@ -250,15 +282,38 @@ impl HTTPSyncClient {
         self.hkey.as_ref().unwrap()
     }
 
-    async fn download_inner(
-        &self,
-    ) -> Result<(
-        usize,
-        impl Stream<Item = std::result::Result<Bytes, reqwest::Error>>,
-    )> {
-        let resp: reqwest::Response = self.request_bytes("download", b"{}", true).await?;
-        let len = resp.content_length().unwrap_or_default();
-        Ok((len as usize, resp.bytes_stream()))
+    /// The size of the collection, and a stream of its content. The server
+    /// may compress the collection as it is sent, in which case it is
+    /// decompressed as it arrives.
+    async fn download_inner(&self) -> Result<(usize, LocalBoxStream<'static, Result<Bytes>>)> {
+        let resp = self
+            .request_builder("download", gzipped_part(b"{}")?, true)
+            .header(ACCEPT_ENCODING, "gzip")
+            .send()
+            .await?
+            .error_for_status()?;
+        let gzipped = resp
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|coding| coding.as_bytes().eq_ignore_ascii_case(b"gzip"))
+            .unwrap_or_default();
+        if !gzipped {
+            let len = resp.content_length().unwrap_or_default();
+            let stream = resp.bytes_stream().map(|res| res.map_err(AnkiError::from));
+            return Ok((len as usize, stream.boxed_local()));
+        }
+
+        let len = resp
+            .headers()
+            .get(ORIGINAL_SIZE_HEADER)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .unwrap_or_default();
+        let stream = resp
+            .bytes_stream()
+            .map(|res| res.map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+        let stream = GzipDecoder::new(stream).map(|res| res.map_err(AnkiError::from));
+        Ok((len, stream.boxed_local()))
     }
 
     async fn upload_inner(&self, body: Body) -> Result<()> {
@@ -319,6 +374,12 @@ where
     }
 }
 
+fn gzipped_part(data: &[u8]) -> Result<multipart::Part> {
+    let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+    gz.write_all(data)?;
+    Ok(multipart::Part::bytes(gz.finish()?))
+}
+
 fn sync_endpoint(host_number: u32) -> String {
     if let Ok(endpoint) = std::env::var("SYNC_ENDPOINT") {
         endpoint
@@ -0,0 +1,1695 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! A standalone HTTP server for the sync protocol, so collections can be
+//! synced with a private server instead of AnkiWeb.
+//!
+//! Each user's collection is kept in a folder of the data directory named
+//! after them. Accounts are listed in a `users` file in the data directory,
+//! one `username:salt:hash` entry per line, with the password hashed by
+//! scrypt, and are added with add_user(). Logging in issues a random host
+//! key, which remains valid until the password is changed. Only its hash is
+//! kept, in the `hkeys` file, so neither file can be used to log in. Clients
+//! are pointed at the server with the SYNC_ENDPOINT and SYNC_ENDPOINT_MEDIA
+//! environment variables, eg `http://localhost:8080/sync/` and
+//! `http://localhost:8080/msync/`.
+//!
+//! The server can also share a single profile with peers on the local
+//! network, with run_peer_server() or PeerServer. Peers log in with the
+//! configured username and password, and sync with the profile's collection
+//! and media directly; see sync/peer.rs for the client side.
+
+use super::{
+    http::{HostKeyIn, HostKeyOut, MetaIn, SyncRequest, ORIGINAL_SIZE_HEADER},
+    LocalServer, SanityCheckStatus, SyncMeta, SyncServer, SYNC_VERSION_MAX, SYNC_VERSION_MIN,
+};
+use crate::{
+    collection::open_collection, err::SyncErrorKind, i18n::I18n, log,
+    media::server::MediaSyncServer, prelude::*,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::executor::block_on;
+use scrypt::{scrypt, ScryptParams};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tempfile::NamedTempFile;
+
+/// The data of authenticated requests held in memory may not be larger than
+/// this, before or after decompression.
+const MAX_REQUEST_BYTES: u64 = 300 * 1024 * 1024;
+/// Uploads are spooled to disk as they arrive, so their size is limited
+/// separately. The client is authenticated before any of it is read.
+const MAX_UPLOAD_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+/// The request line and headers may not be larger than this in total.
+const MAX_HEADER_BYTES: u64 = 8 * 1024;
+/// The parts of a multipart body preceding the data, which are read before
+/// the client is authenticated, may not be larger than this in total...
+const MAX_FIELD_BYTES: u64 = 4 * 1024;
+/// ...or more numerous than this. Clients send at most c, k and s, or c and
+/// sk.
+const MAX_FIELDS: usize = 4;
+/// Further connections are turned away while this many are being handled.
+const MAX_CONNECTIONS: usize = 32;
+/// Connections are closed if the client sends nothing for this long, so idle
+/// clients can't hold on to them.
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Everything up to the data of a request, and all of an unauthenticated
+/// one, must arrive within this time, so a client can't hold a connection
+/// by sending a byte at a time.
+const AUTH_DEADLINE: Duration = Duration::from_secs(30);
+const USERS_FILE: &str = "users";
+/// The hashes of the host keys issued to clients, one `hash:username` entry
+/// per line.
+const HOST_KEYS_FILE: &str = "hkeys";
+/// scrypt's cost for password hashes, as a power of 2. This takes 32MiB of
+/// memory.
+#[cfg(not(test))]
+const PASSWORD_LOG_N: u8 = 15;
+/// Passwords are hashed many times in tests, which aren't optimized.
+#[cfg(test)]
+const PASSWORD_LOG_N: u8 = 4;
+/// The client's media database is left alone when a profile is shared with
+/// peers, as the server's database records changes differently.
+const PEER_MEDIA_DB: &str = "collection.media.peer.db2";
+
+pub struct SyncServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+}
+
+/// Serve sync requests until the process is terminated. Each connection is
+/// handled on its own thread, up to MAX_CONNECTIONS at a time.
+pub fn run_sync_server(config: &SyncServerConfig) -> Result<()> {
+    let log = log::terminal();
+    let listener = TcpListener::bind((config.host.as_str(), config.port))?;
+    debug!(log, "sync server listening"; "host"=>&config.host, "port"=>config.port);
+    let accounts = Accounts::DataDir(config.data_dir.clone());
+    serve(listener, Arc::new(ServerState::new(accounts, log)));
+
+    Ok(())
+}
+
+/// Peers send the password, and then a host key that is valid until the
+/// server stops, over plain HTTP, so the server should only be reachable on
+/// a trusted network.
+pub struct PeerServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// The profile folder, holding collection.anki2 and collection.media.
+    pub folder: PathBuf,
+    pub username: String,
+    pub password: String,
+}
+
+/// Share a profile with peers until the process is terminated. The
+/// collection should not be open elsewhere while it is being served.
+pub fn run_peer_server(config: &PeerServerConfig) -> Result<()> {
+    let log = log::terminal();
+    let listener = TcpListener::bind((config.host.as_str(), config.port))?;
+    debug!(log, "peer server listening"; "host"=>&config.host, "port"=>config.port);
+    serve(
+        listener,
+        Arc::new(ServerState::new(Accounts::peer(config)?, log)),
+    );
+
+    Ok(())
+}
+
+/// A profile shared with peers from a background thread. The server stops,
+/// and the collection is closed, when this is dropped.
+pub struct PeerServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeerServer {
+    /// Start serving. If the port is 0, a free one is chosen.
+    pub fn start(config: &PeerServerConfig, log: Logger) -> Result<PeerServer> {
+        let listener = TcpListener::bind((config.host.as_str(), config.port))?;
+        let addr = listener.local_addr()?;
+        debug!(log, "peer server listening"; "addr"=>addr.to_string());
+        let state = Arc::new(ServerState::new(Accounts::peer(config)?, log));
+        let state2 = state.clone();
+        let thread = thread::spawn(move || serve(listener, state2));
+        Ok(PeerServer {
+            addr,
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    /// The URL peers connect to, eg `http://127.0.0.1:8080/`.
+    pub fn endpoint(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Issue a host key directly, for a client in the same process.
+    pub(super) fn host_key(&self) -> Result<String> {
+        match &self.state.accounts {
+            Accounts::Peer { username, .. } => self.state.accounts.add_host_key(username),
+            Accounts::DataDir(_) => unreachable!(),
+        }
+    }
+}
+
+impl Drop for PeerServer {
+    fn drop(&mut self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+        // wake the listener, so it notices it should stop
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.state.sessions.lock().unwrap().clear();
+    }
+}
+
+fn serve(listener: TcpListener, state: Arc<ServerState>) {
+    for stream in listener.incoming() {
+        if state.stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(mut stream) => {
+                if state.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    state.connections.fetch_sub(1, Ordering::SeqCst);
+                    debug!(state.log, "too many connections");
+                    let _ =
+                        write_response(&mut stream, "503 Service Unavailable", "text/plain", b"");
+                    continue;
+                }
+                let state = state.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle_connection(stream, &state) {
+                        debug!(state.log, "connection failed"; "error"=>format!("{:?}", err));
+                    }
+                    state.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(err) => debug!(state.log, "accept failed"; "error"=>format!("{:?}", err)),
+        }
+    }
+}
+
+/// Add an account, or change the password of an existing one. Clients of the
+/// account will need to log in again.
+pub fn add_user(data_dir: &Path, username: &str, password: &str) -> Result<()> {
+    let invalid_char = |c: char| c == ':' || c == '/' || c == '\\' || c.is_control();
+    if username.is_empty()
+        || username.starts_with('.')
+        || username.trim() != username
+        || username.contains(invalid_char)
+    {
+        return Err(AnkiError::invalid_input("invalid username"));
+    }
+
+    fs::create_dir_all(data_dir)?;
+    let mut users = read_entries(&data_dir.join(USERS_FILE))?;
+    users.retain(|(name, _)| name != username);
+    users.push((
+        username.to_string(),
+        PasswordHash::new(password)?.to_string(),
+    ));
+    write_entries(&data_dir.join(USERS_FILE), &users)?;
+
+    // invalidate the host keys issued with the old password
+    let mut keys = read_entries(&data_dir.join(HOST_KEYS_FILE))?;
+    keys.retain(|(_, name)| name != username);
+    write_entries(&data_dir.join(HOST_KEYS_FILE), &keys)
+}
+
+/// The lines of a file split at their first colon, or nothing if it doesn't
+/// exist.
+fn read_entries(path: &Path) -> Result<Vec<(String, String)>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let first = parts.next()?;
+            let second = parts.next()?;
+            Some((first.to_string(), second.to_string()))
+        })
+        .collect())
+}
+
+fn write_entries(path: &Path, entries: &[(String, String)]) -> Result<()> {
+    let text: String = entries
+        .iter()
+        .map(|(first, second)| format!("{}:{}\n", first, second))
+        .collect();
+    fs::write(path, text).map_err(Into::into)
+}
+
+/// A password hashed with scrypt and a random salt.
+#[derive(Clone)]
+struct PasswordHash {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Result<Self> {
+        let salt = rand::random::<[u8; 16]>().to_vec();
+        let hash = hash_password(password, &salt)?;
+        Ok(PasswordHash { salt, hash })
+    }
+
+    /// Parse the `salt:hash` form written by to_string().
+    fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.splitn(2, ':');
+        let salt = hex::decode(parts.next()?).ok()?;
+        let hash = hex::decode(parts.next()?).ok()?;
+        Some(PasswordHash { salt, hash })
+    }
+
+    fn matches(&self, password: &str) -> Result<bool> {
+        Ok(constant_time_eq(
+            &hash_password(password, &self.salt)?,
+            &self.hash,
+        ))
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", hex::encode(&self.salt), hex::encode(&self.hash))
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Result<Vec<u8>> {
+    let params = ScryptParams::new(PASSWORD_LOG_N, 8, 1)
+        .map_err(|_| AnkiError::invalid_input("invalid scrypt parameters"))?;
+    let mut hash = vec![0; 32];
+    scrypt(password.as_bytes(), salt, &params, &mut hash)
+        .map_err(|_| AnkiError::invalid_input("invalid scrypt parameters"))?;
+    Ok(hash)
+}
+
+/// Compares every byte, so the time taken doesn't reveal where the first
+/// difference is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A random key, which clients of the normal and media sync protocols
+/// authenticate with.
+fn new_key() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Keys are stored and looked up by their hash, so the lookup takes the
+/// same time however much of a guessed key is correct.
+fn key_hash(key: &[u8]) -> String {
+    blake3::hash(key).to_hex().to_string()
+}
+
+fn auth_failed() -> AnkiError {
+    AnkiError::SyncError {
+        info: "invalid username or password".into(),
+        kind: SyncErrorKind::AuthFailed,
+    }
+}
+
+/// Where accounts and their collections are kept.
+enum Accounts {
+    /// A users file, a host keys file, and a folder for each user, in the
+    /// data directory.
+    DataDir(PathBuf),
+    /// A single profile, shared with peers who know its password. Host keys
+    /// are only kept while it is shared.
+    Peer {
+        folder: PathBuf,
+        username: String,
+        password_hash: PasswordHash,
+        host_keys: Mutex<HashMap<String, String>>,
+    },
+}
+
+impl Accounts {
+    fn peer(config: &PeerServerConfig) -> Result<Self> {
+        Ok(Accounts::Peer {
+            folder: config.folder.clone(),
+            username: config.username.clone(),
+            password_hash: PasswordHash::new(&config.password)?,
+            host_keys: Default::default(),
+        })
+    }
+
+    fn password_hash(&self, username: &str) -> Result<Option<PasswordHash>> {
+        match self {
+            Accounts::DataDir(data_dir) => Ok(read_entries(&data_dir.join(USERS_FILE))?
+                .into_iter()
+                .find(|(name, _)| name == username)
+                .and_then(|(_, hash)| PasswordHash::parse(&hash))),
+            Accounts::Peer {
+                username: peer_username,
+                password_hash,
+                ..
+            } => Ok(if username == peer_username {
+                Some(password_hash.clone())
+            } else {
+                None
+            }),
+        }
+    }
+
+    /// Issue a new host key to the user.
+    fn add_host_key(&self, username: &str) -> Result<String> {
+        let key = new_key();
+        match self {
+            Accounts::DataDir(data_dir) => {
+                // appending a single line leaves concurrent logins intact
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(data_dir.join(HOST_KEYS_FILE))?;
+                file.write_all(format!("{}:{}\n", key_hash(key.as_bytes()), username).as_bytes())?;
+            }
+            Accounts::Peer { host_keys, .. } => {
+                host_keys
+                    .lock()
+                    .unwrap()
+                    .insert(key_hash(key.as_bytes()), username.to_string());
+            }
+        }
+        Ok(key)
+    }
+
+    fn user_for_host_key(&self, key: &[u8]) -> Result<Option<String>> {
+        let hash = key_hash(key);
+        Ok(match self {
+            Accounts::DataDir(data_dir) => read_entries(&data_dir.join(HOST_KEYS_FILE))?
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+                .remove(&hash),
+            Accounts::Peer { host_keys, .. } => host_keys.lock().unwrap().get(&hash).cloned(),
+        })
+    }
+
+    fn folder(&self, username: &str) -> PathBuf {
+        match self {
+            Accounts::DataDir(data_dir) => data_dir.join(username),
+            Accounts::Peer { folder, .. } => folder.clone(),
+        }
+    }
+}
+
+struct ServerState {
+    accounts: Accounts,
+    sessions: Mutex<HashMap<String, Arc<Mutex<UserSession>>>>,
+    /// The usernames of media syncs in progress, by the hash of the key they
+    /// were given when they began.
+    media_keys: Mutex<HashMap<String, String>>,
+    /// The connections currently being handled.
+    connections: AtomicUsize,
+    /// Set when a PeerServer is dropped.
+    stopping: AtomicBool,
+    log: Logger,
+}
+
+enum SyncResponse {
+    Json(Vec<u8>),
+    Bytes(Vec<u8>),
+    Text(&'static str),
+    File(NamedTempFile),
+}
+
+impl ServerState {
+    fn new(accounts: Accounts, log: Logger) -> Self {
+        ServerState {
+            accounts,
+            sessions: Default::default(),
+            media_keys: Default::default(),
+            connections: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+            log,
+        }
+    }
+
+    fn session(&self, username: &str) -> Arc<Mutex<UserSession>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(UserSession::new(
+                    self.accounts.folder(username),
+                    matches!(self.accounts, Accounts::Peer { .. }),
+                    self.log.clone(),
+                )))
+            })
+            .clone()
+    }
+
+    /// Handle a request to the provided method, given the fields of the
+    /// multipart body.
+    fn handle_request(&self, method: &str, fields: &RequestFields) -> Result<SyncResponse> {
+        let data = fields.data()?;
+        if method == "hostKey" {
+            let input: HostKeyIn = serde_json::from_reader(data)?;
+            let valid = match self.accounts.password_hash(&input.username)? {
+                Some(hash) => hash.matches(&input.password)?,
+                None => {
+                    // take as long as for an existing user
+                    PasswordHash::new(&input.password)?;
+                    false
+                }
+            };
+            if !valid {
+                return Err(auth_failed());
+            }
+            let out = HostKeyOut {
+                key: self.accounts.add_host_key(&input.username)?,
+            };
+            return Ok(SyncResponse::Json(serde_json::to_vec(&out)?));
+        }
+
+        let username = self.user_for_host_key(fields)?;
+        let req = parse_request(method, data, &self.accounts.folder(&username))?;
+        let session = self.session(&username);
+        let mut session = session.lock().unwrap();
+        session.handle(req, &key_hash(fields.get("k")))
+    }
+
+    /// Handle a media sync request. Begin requests are authenticated with
+    /// the host key and pass it in the query string, and return a key for
+    /// the remaining requests.
+    fn handle_media_request(&self, method: &str, fields: &RequestFields) -> Result<SyncResponse> {
+        let username = if method == "begin" {
+            self.user_for_host_key(fields)?
+        } else {
+            self.user_for_media_key(fields)?
+        };
+        let folder = self.accounts.folder(&username);
+        let peer = matches!(self.accounts, Accounts::Peer { .. });
+        let server_db = if peer {
+            PEER_MEDIA_DB
+        } else {
+            "collection.media.db2"
+        };
+        let server = MediaSyncServer::new(
+            folder.join("collection.media"),
+            &folder.join(server_db),
+            self.log.clone(),
+        )?;
+
+        if method == "begin" {
+            if peer {
+                server.register_folder_changes(&folder.join("collection.media.db2"))?;
+            }
+            let key = new_key();
+            self.media_keys
+                .lock()
+                .unwrap()
+                .insert(key_hash(key.as_bytes()), username);
+            return Ok(SyncResponse::Json(server.begin(key)?));
+        }
+
+        let mut data = vec![];
+        fields.data()?.read_to_end(&mut data)?;
+        let reply = server.handle(method, &data)?;
+        if method == "downloadFiles" {
+            Ok(SyncResponse::Bytes(reply))
+        } else {
+            Ok(SyncResponse::Json(reply))
+        }
+    }
+
+    /// The user whose host key is in the `k` field.
+    fn user_for_host_key(&self, fields: &RequestFields) -> Result<String> {
+        self.accounts
+            .user_for_host_key(fields.get("k"))?
+            .ok_or_else(auth_failed)
+    }
+
+    /// The user whose media sync key is in the `sk` field.
+    fn user_for_media_key(&self, fields: &RequestFields) -> Result<String> {
+        self.media_keys
+            .lock()
+            .unwrap()
+            .get(&key_hash(fields.get("sk")))
+            .cloned()
+            .ok_or_else(auth_failed)
+    }
+}
+
+/// The named parts of a request.
+#[derive(Default)]
+struct RequestFields {
+    fields: HashMap<String, Vec<u8>>,
+    /// The data part of uploads, which is spooled to disk instead of being
+    /// held in memory, as collections can be larger than available memory.
+    file: Option<NamedTempFile>,
+}
+
+impl RequestFields {
+    /// The named field, or an empty slice if missing.
+    fn get(&self, name: &str) -> &[u8] {
+        self.fields.get(name).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// The data field, decompressed as it is read if necessary. Reading
+    /// fails if the decompressed data is larger than the data of a request
+    /// may be.
+    fn data(&self) -> Result<Box<dyn Read + '_>> {
+        let (data, limit): (Box<dyn Read + '_>, _) = match &self.file {
+            Some(file) => (Box::new(BufReader::new(file.reopen()?)), MAX_UPLOAD_BYTES),
+            None => (Box::new(self.get("data")), MAX_REQUEST_BYTES),
+        };
+        Ok(if self.get("c") == b"1" {
+            Box::new(LimitedReader::new(GzDecoder::new(data), limit))
+        } else {
+            data
+        })
+    }
+}
+
+/// Like Read::take(), but fails instead of ending early once the limit is
+/// exceeded, so a truncated upload can't be mistaken for a complete one.
+struct LimitedReader<R: Read> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(reader: R, limit: u64) -> Self {
+        LimitedReader {
+            reader,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        if read as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Uploads are written into `folder`, so they can be moved into place
+/// without crossing filesystems.
+fn parse_request(method: &str, mut data: impl Read, folder: &Path) -> Result<SyncRequest> {
+    use serde_json::from_reader;
+    Ok(match method {
+        "meta" => SyncRequest::Meta(from_reader(data)?),
+        "start" => SyncRequest::Start(from_reader(data)?),
+        "applyGraves" => SyncRequest::ApplyGraves(from_reader(data)?),
+        "applyChanges" => SyncRequest::ApplyChanges(from_reader(data)?),
+        "chunk" => SyncRequest::Chunk,
+        "resumeChunk" => SyncRequest::ResumeChunk(from_reader(data)?),
+        "applyChunk" => SyncRequest::ApplyChunk(from_reader(data)?),
+        "sanityCheck2" => SyncRequest::SanityCheck(from_reader(data)?),
+        "sanityCheckIds" => SyncRequest::SanityCheckIds(from_reader(data)?),
+        "finish" => SyncRequest::Finish,
+        "abort" => SyncRequest::Abort,
+        "upload" => {
+            fs::create_dir_all(folder)?;
+            let mut file = NamedTempFile::new_in(folder)?;
+            io::copy(&mut data, &mut file)?;
+            SyncRequest::FullUpload(file.into_temp_path().keep()?)
+        }
+        "download" => SyncRequest::FullDownload,
+        _ => {
+            return Err(AnkiError::invalid_input(format!(
+                "unknown method: {}",
+                method
+            )))
+        }
+    })
+}
+
+/// A user's collection, and the sync in progress if any. The collection is
+/// opened on first use.
+struct UserSession {
+    folder: PathBuf,
+    /// True if the collection is a profile shared with peers.
+    peer: bool,
+    log: Logger,
+    col: Option<Collection>,
+    server: Option<LocalServer>,
+    /// The hash of the host key the sync in progress was started with. An
+    /// interrupted sync is held open for that device to resume, and the
+    /// user's other devices may not continue it.
+    sync_key: Option<String>,
+}
+
+impl UserSession {
+    fn new(folder: PathBuf, peer: bool, log: Logger) -> Self {
+        UserSession {
+            folder,
+            peer,
+            log,
+            col: None,
+            server: None,
+            sync_key: None,
+        }
+    }
+
+    fn take_col(&mut self) -> Result<Collection> {
+        if let Some(col) = self.col.take() {
+            return Ok(col);
+        }
+        fs::create_dir_all(&self.folder)?;
+        let i18n = I18n::new(&[""], "", self.log.clone());
+        open_collection(
+            self.folder.join("collection.anki2"),
+            self.folder.join("collection.media"),
+            self.folder.join("collection.media.db2"),
+            true,
+            i18n,
+            self.log.clone(),
+        )
+    }
+
+    /// Aborts any sync in progress, as the client has started over.
+    fn take_server(&mut self) -> Result<LocalServer> {
+        self.abort();
+        self.new_server()
+    }
+
+    fn new_server(&mut self) -> Result<LocalServer> {
+        let col = self.take_col()?;
+        Ok(if self.peer {
+            LocalServer::new_for_peers(col)
+        } else {
+            LocalServer::new(col)
+        })
+    }
+
+    fn abort(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            if let Err(err) = block_on(server.abort()) {
+                debug!(self.log, "abort failed"; "error"=>format!("{:?}", err));
+            }
+            self.col = Some(server.into_col());
+        }
+    }
+
+    /// Run func on the sync in progress, aborting it on failure.
+    fn with_server<F, T>(&mut self, func: F) -> Result<T>
+    where
+        F: FnOnce(&mut LocalServer) -> Result<T>,
+    {
+        let server = self.server.as_mut().ok_or_else(|| AnkiError::SyncError {
+            kind: SyncErrorKind::SyncNotStarted,
+            info: String::new(),
+        })?;
+        let out = func(server);
+        if out.is_err() {
+            self.abort();
+        }
+        out
+    }
+
+    fn meta(&mut self, input: MetaIn) -> Result<SyncMeta> {
+        if input.sync_version < SYNC_VERSION_MIN || input.sync_version > SYNC_VERSION_MAX {
+            return Ok(SyncMeta {
+                server_message: "Your Anki version is either too old, or too new.".into(),
+                should_continue: false,
+                ..Default::default()
+            });
+        }
+        self.with_any_server(|server| block_on(server.meta()))
+    }
+
+    /// Run func on the sync in progress, or on the collection if there is
+    /// none.
+    fn with_any_server<F, T>(&mut self, func: F) -> Result<T>
+    where
+        F: FnOnce(&mut LocalServer) -> Result<T>,
+    {
+        if let Some(server) = self.server.as_mut() {
+            return func(server);
+        }
+        let mut server = self.new_server()?;
+        let out = func(&mut server);
+        self.col = Some(server.into_col());
+        out
+    }
+
+    /// `key` is the hash of the host key the request was made with.
+    fn handle(&mut self, req: SyncRequest, key: &str) -> Result<SyncResponse> {
+        use serde_json::to_vec;
+        let continues_sync = !matches!(
+            req,
+            SyncRequest::Meta(_)
+                | SyncRequest::Start(_)
+                | SyncRequest::FullUpload(_)
+                | SyncRequest::FullDownload
+        );
+        if continues_sync && self.server.is_some() && self.sync_key.as_deref() != Some(key) {
+            return Err(AnkiError::SyncError {
+                kind: SyncErrorKind::SyncNotStarted,
+                info: String::new(),
+            });
+        }
+        let json = match req {
+            SyncRequest::HostKey(_) => {
+                return Err(AnkiError::invalid_input("host key requires no session"))
+            }
+            SyncRequest::Meta(input) => to_vec(&self.meta(input)?),
+            SyncRequest::Start(input) => {
+                self.server = Some(self.take_server()?);
+                self.sync_key = Some(key.to_string());
+                let graves = self.with_server(|server| {
+                    block_on(server.start(input.client_usn, input.local_is_newer))
+                })?;
+                to_vec(&graves)
+            }
+            SyncRequest::ApplyGraves(input) => {
+                to_vec(&self.with_server(|server| block_on(server.apply_graves(input.chunk)))?)
+            }
+            SyncRequest::ApplyChanges(input) => {
+                to_vec(&self.with_server(|server| block_on(server.apply_changes(input.changes)))?)
+            }
+            SyncRequest::Chunk => to_vec(&self.with_server(|server| block_on(server.chunk()))?),
+            SyncRequest::ResumeChunk(input) => {
+                to_vec(&self.with_server(|server| block_on(server.resume_chunk(input.received)))?)
+            }
+            SyncRequest::ApplyChunk(input) => {
+                to_vec(&self.with_server(|server| block_on(server.apply_chunk(input.chunk)))?)
+            }
+            SyncRequest::SanityCheck(input) => {
+                let out = self.with_server(|server| block_on(server.sanity_check(input.client)))?;
+                if out.status != SanityCheckStatus::Ok {
+                    // sanity check failures are an implicit abort
+                    self.abort();
+                }
+                to_vec(&out)
+            }
+            SyncRequest::SanityCheckIds(input) => {
+                // sent after a failed sanity check, so may follow an abort
+                to_vec(
+                    &self.with_any_server(|server| {
+                        block_on(server.sanity_check_ids(input.digests))
+                    })?,
+                )
+            }
+            SyncRequest::Finish => {
+                let now = self.with_server(|server| block_on(server.finish()))?;
+                if let Some(server) = self.server.take() {
+                    self.col = Some(server.into_col());
+                }
+                to_vec(&now)
+            }
+            SyncRequest::Abort => {
+                self.abort();
+                to_vec(&())
+            }
+            SyncRequest::FullUpload(path) => {
+                let server = Box::new(self.take_server()?);
+                block_on(server.full_upload(&path, true))?;
+                return Ok(SyncResponse::Text("OK"));
+            }
+            SyncRequest::FullDownload => {
+                let server = Box::new(self.take_server()?);
+                return Ok(SyncResponse::File(block_on(server.full_download())?));
+            }
+        };
+        Ok(SyncResponse::Json(json?))
+    }
+}
+
+// HTTP
+//----------------------------------------------------------------
+
+struct HttpRequest {
+    verb: String,
+    path: String,
+    query: String,
+    /// Names are lowercase.
+    headers: HashMap<String, String>,
+}
+
+impl HttpRequest {
+    /// The value of the named header, or an empty string if missing.
+    fn header(&self, name: &str) -> &str {
+        self.headers
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn accepts_gzip(&self) -> bool {
+        self.header("accept-encoding").split(',').any(|coding| {
+            coding
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("gzip")
+        })
+    }
+
+    /// The body following the headers in `reader`, which is decoded as it is
+    /// read.
+    fn body<'a, R: BufRead>(&self, reader: &'a mut R) -> Result<Box<dyn Read + 'a>> {
+        if self
+            .header("transfer-encoding")
+            .eq_ignore_ascii_case("chunked")
+        {
+            return Ok(Box::new(ChunkedReader::new(reader)));
+        }
+        let len = match self.headers.get("content-length") {
+            Some(len) => len
+                .parse()
+                .map_err(|_| AnkiError::invalid_input("invalid content length"))?,
+            None => 0,
+        };
+        Ok(Box::new(reader.take(len)))
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &ServerState) -> Result<()> {
+    let deadline = Rc::new(Cell::new(Some(Instant::now() + AUTH_DEADLINE)));
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: stream.try_clone()?,
+        deadline: deadline.clone(),
+    });
+    let mut writer = stream;
+    let request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(err) => {
+            write_response(&mut writer, "400 Bad Request", "text/plain", b"")?;
+            return Err(err);
+        }
+    };
+    let (media, method) = if let Some(method) = request.path.strip_prefix("/sync/") {
+        (false, method)
+    } else if let Some(method) = request.path.strip_prefix("/msync/") {
+        (true, method)
+    } else {
+        return write_response(&mut writer, "404 Not Found", "text/plain", b"");
+    };
+
+    // media syncs begin with a GET request, which has no body
+    let fields = if request.verb == "GET" {
+        Ok(query_fields(&request.query))
+    } else {
+        // the data is only read once the client has been authenticated, after
+        // which it may take longer to arrive
+        let authenticate = |fields: &RequestFields| {
+            if !media && method == "hostKey" {
+                return Ok(DataPart::Memory(MAX_FIELD_BYTES));
+            }
+            if media && method != "begin" {
+                state.user_for_media_key(fields)?;
+            } else {
+                state.user_for_host_key(fields)?;
+            }
+            deadline.set(None);
+            Ok(if !media && method == "upload" {
+                DataPart::File
+            } else {
+                DataPart::Memory(MAX_REQUEST_BYTES)
+            })
+        };
+        request
+            .body(&mut reader)
+            .and_then(|body| read_multipart(request.header("content-type"), body, &authenticate))
+    };
+    let response = fields.and_then(|fields| {
+        if media {
+            state.handle_media_request(method, &fields)
+        } else {
+            state.handle_request(method, &fields)
+        }
+    });
+    match response {
+        Ok(SyncResponse::Json(data)) => {
+            write_response(&mut writer, "200 OK", "application/json", &data)
+        }
+        Ok(SyncResponse::Bytes(data)) => {
+            write_response(&mut writer, "200 OK", "application/octet-stream", &data)
+        }
+        Ok(SyncResponse::Text(text)) => {
+            write_response(&mut writer, "200 OK", "text/plain", text.as_bytes())
+        }
+        Ok(SyncResponse::File(file)) => {
+            let len = file.as_file().metadata()?.len();
+            if request.accepts_gzip() {
+                write_gzipped_file(&mut writer, file.reopen()?, len)
+            } else {
+                write_headers(&mut writer, "200 OK", "application/octet-stream", len)?;
+                io::copy(&mut file.reopen()?, &mut writer)?;
+                writer.flush().map_err(Into::into)
+            }
+        }
+        Err(err) => {
+            debug!(state.log, "sync request failed"; "method"=>method, "error"=>format!("{:?}", err));
+            let message = err.to_string();
+            write_response(
+                &mut writer,
+                status_for_error(&err),
+                "text/plain",
+                message.as_bytes(),
+            )
+        }
+    }
+}
+
+/// The client maps these back to the matching sync errors.
+fn status_for_error(err: &AnkiError) -> &'static str {
+    match err {
+        AnkiError::SyncError { kind, .. } => match kind {
+            SyncErrorKind::AuthFailed => "403 Forbidden",
+            SyncErrorKind::Conflict | SyncErrorKind::SyncNotStarted => "409 Conflict",
+            _ => "500 Internal Server Error",
+        },
+        AnkiError::InvalidInput { .. } | AnkiError::JSONError { .. } => "400 Bad Request",
+        _ => "500 Internal Server Error",
+    }
+}
+
+fn write_headers(out: &mut impl Write, status: &str, content_type: &str, len: u64) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, len
+    )
+    .map_err(Into::into)
+}
+
+fn write_response(
+    out: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    write_headers(out, status, content_type, body.len() as u64)?;
+    out.write_all(body)?;
+    out.flush().map_err(Into::into)
+}
+
+/// Compress a file of `len` bytes as it is sent. The compressed size is not
+/// known in advance, so the body is chunked, and the original size is sent
+/// in a separate header for the client's progress.
+fn write_gzipped_file(out: &mut impl Write, mut file: impl Read, len: u64) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Encoding: gzip\r\n\
+        Transfer-Encoding: chunked\r\n{}: {}\r\nConnection: close\r\n\r\n",
+        ORIGINAL_SIZE_HEADER, len
+    )?;
+    let mut gz = GzEncoder::new(ChunkedWriter(&mut *out), Compression::fast());
+    io::copy(&mut file, &mut gz)?;
+    gz.finish()?;
+    // the final, empty chunk
+    out.write_all(b"0\r\n\r\n")?;
+    out.flush().map_err(Into::into)
+}
+
+/// Reads the request line and headers, leaving the body in `reader`.
+fn read_request(reader: &mut impl BufRead) -> Result<HttpRequest> {
+    let mut remaining = MAX_HEADER_BYTES;
+    let request_line = read_line(reader, &mut remaining)?;
+    let mut parts = request_line.split_whitespace();
+    let verb = match parts.next() {
+        Some(verb @ "GET") | Some(verb @ "POST") => verb.to_string(),
+        _ => return Err(AnkiError::invalid_input("unsupported request method")),
+    };
+    let target = parts
+        .next()
+        .ok_or_else(|| AnkiError::invalid_input("missing path"))?;
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or_default().to_string();
+    let query = target.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(reader, &mut remaining)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.insert(
+                line[..idx].trim().to_ascii_lowercase(),
+                line[idx + 1..].trim().to_string(),
+            );
+        }
+    }
+
+    Ok(HttpRequest {
+        verb,
+        path,
+        query,
+        headers,
+    })
+}
+
+/// A line without its line ending. Fails if it would take the bytes read
+/// over the `remaining` limit, which is reduced by its length.
+fn read_line(reader: &mut impl BufRead, remaining: &mut u64) -> Result<String> {
+    let mut line = String::new();
+    let read = reader.take(*remaining).read_line(&mut line)?;
+    *remaining -= read as u64;
+    if !line.ends_with('\n') {
+        return Err(AnkiError::invalid_input(if *remaining == 0 {
+            "request headers too large"
+        } else {
+            "unexpected end of request"
+        }));
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Fails reads once the deadline, if any, has passed. The deadline is shared
+/// with the request handler, which lifts it once the client is
+/// authenticated.
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Rc<Cell<Option<Instant>>>,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline.get() {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                (deadline - now).min(READ_TIMEOUT)
+            }
+            None => READ_TIMEOUT,
+        };
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Decodes a chunked body as it is read. Used by the client when uploading,
+/// as the compressed size is not known in advance.
+struct ChunkedReader<R: BufRead> {
+    reader: R,
+    /// The bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(reader: R) -> Self {
+        ChunkedReader {
+            reader,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Chunk size lines and trailers are short, so longer lines are
+    /// rejected.
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut self.reader)
+            .take(MAX_HEADER_BYTES)
+            .read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid chunked body",
+            ));
+        }
+        Ok(line)
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line()?;
+        let size_text = line.split(';').next().unwrap_or_default().trim();
+        u64::from_str_radix(size_text, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size()?;
+            if self.remaining == 0 {
+                // skip any trailers
+                while !self.read_line()?.trim_end().is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 {
+            // line ending after the chunk data
+            self.read_line()?;
+        }
+        Ok(read)
+    }
+}
+
+/// Writes each buffer it is given as a chunk of a chunked body.
+struct ChunkedWriter<W: Write>(W);
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Values are not percent-decoded, as the only field used is a hex key.
+fn query_fields(query: &str) -> RequestFields {
+    let fields = query
+        .split('&')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            let name = pair.next()?;
+            let value = pair.next().unwrap_or_default();
+            Some((name.to_string(), value.as_bytes().to_vec()))
+        })
+        .collect();
+    RequestFields { fields, file: None }
+}
+
+/// Where the data part of a request is read to.
+enum DataPart {
+    /// Held in memory, up to the provided size.
+    Memory(u64),
+    /// Spooled to a temporary file, as uploads can be larger than available
+    /// memory.
+    File,
+}
+
+/// The named parts of a multipart/form-data body, which is parsed as it is
+/// read. The parts preceding the data are limited to a few small fields, and
+/// passed to `read_data`, which authenticates the client and decides where
+/// the data is read to. Reading stops if it fails.
+fn read_multipart(
+    content_type: &str,
+    body: impl Read,
+    read_data: &dyn Fn(&RequestFields) -> Result<DataPart>,
+) -> Result<RequestFields> {
+    let boundary = content_type
+        .split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .ok_or_else(|| AnkiError::invalid_input("missing multipart boundary"))?;
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // the first delimiter need not be preceded by a line ending
+    let mut reader = MultipartReader {
+        reader: body,
+        buf: b"\r\n".to_vec(),
+    };
+
+    let mut fields = RequestFields::default();
+    let mut data_read = false;
+    // shared by everything but the data
+    let mut remaining = MAX_FIELD_BYTES;
+    // skip the preamble
+    reader.copy_until(&delimiter, &mut io::sink(), &mut remaining)?;
+    // the final delimiter is followed by --
+    while !reader.starts_with(b"--")? {
+        let mut headers = vec![];
+        reader.copy_until(b"\r\n\r\n", &mut headers, &mut remaining)?;
+        match part_name(&String::from_utf8_lossy(&headers)) {
+            Some(name) if name == "data" && !data_read => {
+                data_read = true;
+                match read_data(&fields)? {
+                    DataPart::Memory(mut limit) => {
+                        let mut data = vec![];
+                        reader.copy_until(&delimiter, &mut data, &mut limit)?;
+                        fields.fields.insert(name, data);
+                    }
+                    DataPart::File => {
+                        let mut file = NamedTempFile::new()?;
+                        let mut limit = MAX_UPLOAD_BYTES;
+                        reader.copy_until(&delimiter, &mut file, &mut limit)?;
+                        fields.file = Some(file);
+                    }
+                }
+            }
+            Some(name) if fields.fields.len() < MAX_FIELDS => {
+                let mut data = vec![];
+                reader.copy_until(&delimiter, &mut data, &mut remaining)?;
+                fields.fields.insert(name, data);
+            }
+            _ => return Err(AnkiError::invalid_input("unexpected request field")),
+        }
+    }
+
+    Ok(fields)
+}
+
+struct MultipartReader<R: Read> {
+    reader: R,
+    /// Data that has been read, but not yet consumed.
+    buf: Vec<u8>,
+}
+
+impl<R: Read> MultipartReader<R> {
+    /// Copy the data preceding `delimiter` into `out`, and consume the
+    /// delimiter. Fails if more than `remaining` bytes precede it, which is
+    /// reduced by the bytes copied.
+    fn copy_until(
+        &mut self,
+        delimiter: &[u8],
+        out: &mut impl Write,
+        remaining: &mut u64,
+    ) -> Result<()> {
+        loop {
+            let found = find_bytes(&self.buf, delimiter);
+            // unless found, keep what may be the start of the delimiter
+            let len = found.unwrap_or_else(|| {
+                self.buf
+                    .len()
+                    .saturating_sub(delimiter.len().saturating_sub(1))
+            });
+            if len as u64 > *remaining {
+                return Err(AnkiError::invalid_input("request too large"));
+            }
+            *remaining -= len as u64;
+            out.write_all(&self.buf[..len])?;
+            if found.is_some() {
+                self.buf.drain(..len + delimiter.len());
+                return Ok(());
+            }
+            self.buf.drain(..len);
+            if !self.fill()? {
+                return Err(AnkiError::invalid_input("invalid multipart body"));
+            }
+        }
+    }
+
+    /// True if the unconsumed data starts with `prefix`.
+    fn starts_with(&mut self, prefix: &[u8]) -> Result<bool> {
+        while self.buf.len() < prefix.len() && self.fill()? {}
+        Ok(self.buf.starts_with(prefix))
+    }
+
+    /// Read more of the body, returning false at its end.
+    fn fill(&mut self) -> Result<bool> {
+        let start = self.buf.len();
+        self.buf.resize(start + 64 * 1024, 0);
+        let read = self.reader.read(&mut self.buf[start..])?;
+        self.buf.truncate(start + read);
+        Ok(read > 0)
+    }
+}
+
+fn part_name(headers: &str) -> Option<String> {
+    headers
+        .lines()
+        .filter(|line| {
+            line.to_ascii_lowercase()
+                .starts_with("content-disposition:")
+        })
+        .flat_map(|line| line.split(';'))
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("name="))
+        .map(|name| name.trim_matches('"').to_string())
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::media::{sync::MediaSyncer, MediaManager};
+    use crate::sync::{
+        http::{ApplyChangesIn, ApplyChunkIn, ApplyGravesIn, StartIn},
+        Chunk, FullSyncProgress, Graves, HTTPSyncClient, UnchunkedChanges,
+    };
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn parsing() -> Result<()> {
+        let body = b"preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"c\"\r\n\r\n1\r\n\
+            --xyz\r\nContent-Disposition: form-data; name=\"data\"; filename=\"data\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\nab\r\ncd\r\n--xyz--\r\n";
+        let content_type = "multipart/form-data; boundary=xyz";
+        let in_memory = |_: &RequestFields| Ok(DataPart::Memory(100));
+        let fields = read_multipart(content_type, &body[..], &in_memory)?;
+        assert_eq!(fields.get("c"), b"1");
+        assert_eq!(fields.get("data"), b"ab\r\ncd");
+
+        // spooled data, which spans multiple reads, once the key is checked
+        let data = "ab\r\n".repeat(50_000);
+        let body = format!(
+            "--xyz\r\nContent-Disposition: form-data; name=\"k\"\r\n\r\nkey\r\n\
+            --xyz\r\nContent-Disposition: form-data; name=\"data\"\r\n\r\n{}\r\n--xyz--\r\n",
+            data
+        );
+        let check_key = |fields: &RequestFields| {
+            if fields.get("k") == b"key" {
+                Ok(DataPart::File)
+            } else {
+                Err(auth_failed())
+            }
+        };
+        let fields = read_multipart(content_type, body.as_bytes(), &check_key)?;
+        assert_eq!(fields.get("data"), b"");
+        assert_eq!(fields.get("k"), b"key");
+        assert_eq!(fs::read(fields.file.unwrap().path())?, data.as_bytes());
+
+        // a key sent after the data is too late
+        let body = format!(
+            "--xyz\r\nContent-Disposition: form-data; name=\"data\"\r\n\r\n{}\r\n\
+            --xyz\r\nContent-Disposition: form-data; name=\"k\"\r\n\r\nkey\r\n--xyz--\r\n",
+            data
+        );
+        assert!(matches!(
+            read_multipart(content_type, body.as_bytes(), &check_key),
+            Err(AnkiError::SyncError {
+                kind: SyncErrorKind::AuthFailed,
+                ..
+            })
+        ));
+
+        // the fields preceding the data are limited in size and number
+        let field = |name: &str, value: &str| {
+            format!(
+                "--xyz\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            )
+        };
+        let body = format!("{}--xyz--\r\n", field("k", &"a".repeat(5000)));
+        assert!(read_multipart(content_type, body.as_bytes(), &check_key).is_err());
+        let body: String = (0..5).map(|i| field(&i.to_string(), "")).collect();
+        let body = format!("{}--xyz--\r\n", body);
+        assert!(read_multipart(content_type, body.as_bytes(), &check_key).is_err());
+        // as is data held in memory
+        let body = format!("{}--xyz--\r\n", field("data", &"a".repeat(101)));
+        assert!(read_multipart(content_type, body.as_bytes(), &in_memory).is_err());
+
+        // and decompressed data
+        let mut gz = GzEncoder::new(vec![], Compression::fast());
+        gz.write_all(&[0; 101])?;
+        let data = gz.finish()?;
+        let mut reader = LimitedReader::new(GzDecoder::new(&data[..]), 101);
+        assert_eq!(io::copy(&mut reader, &mut io::sink())?, 101);
+        let mut reader = LimitedReader::new(GzDecoder::new(&data[..]), 100);
+        assert!(io::copy(&mut reader, &mut io::sink()).is_err());
+
+        let request = b"POST /sync/meta HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n";
+        let mut reader = &request[..];
+        let request = read_request(&mut reader)?;
+        assert_eq!(request.path, "/sync/meta");
+        let mut body = vec![];
+        request.body(&mut reader)?.read_to_end(&mut body)?;
+        assert_eq!(body, b"abcde");
+
+        // headers are limited in size
+        let request = format!(
+            "POST /sync/meta HTTP/1.1\r\nA: {}\r\n\r\n",
+            "a".repeat(10_000)
+        );
+        assert!(read_request(&mut request.as_bytes()).is_err());
+        let request = format!(
+            "POST /sync/meta HTTP/1.1\r\n{}\r\n",
+            "A: a\r\n".repeat(2_000)
+        );
+        assert!(read_request(&mut request.as_bytes()).is_err());
+
+        Ok(())
+    }
+
+    fn request<T: serde::Serialize>(
+        state: &ServerState,
+        method: &str,
+        key: &str,
+        input: &T,
+    ) -> Result<SyncResponse> {
+        let mut gz = GzEncoder::new(vec![], Compression::fast());
+        gz.write_all(&serde_json::to_vec(input)?)?;
+        let mut fields = HashMap::new();
+        fields.insert("data".to_string(), gz.finish()?);
+        fields.insert("c".to_string(), b"1".to_vec());
+        fields.insert("k".to_string(), key.as_bytes().to_vec());
+        state.handle_request(method, &RequestFields { fields, file: None })
+    }
+
+    fn json<T: serde::de::DeserializeOwned>(resp: Result<SyncResponse>) -> Result<T> {
+        match resp? {
+            SyncResponse::Json(data) => serde_json::from_slice(&data).map_err(Into::into),
+            _ => panic!("expected json"),
+        }
+    }
+
+    #[test]
+    fn serving() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        add_user(dir.path(), "user", "pass")?;
+        add_user(dir.path(), "other", "pass")?;
+        assert!(add_user(dir.path(), "../user", "pass").is_err());
+        let state = ServerState::new(Accounts::DataDir(dir.path().into()), log::terminal());
+
+        let login = |username: &str, password: &str| {
+            let input = HostKeyIn {
+                username: username.into(),
+                password: password.into(),
+            };
+            json::<HostKeyOut>(request(&state, "hostKey", "", &input))
+        };
+        assert!(matches!(
+            login("user", "wrong"),
+            Err(AnkiError::SyncError {
+                kind: SyncErrorKind::AuthFailed,
+                ..
+            })
+        ));
+        let key = login("user", "pass")?.key;
+        assert_ne!(key, login("other", "pass")?.key);
+        // only hashes are stored
+        let users = fs::read_to_string(dir.path().join(USERS_FILE))?;
+        assert!(!users.contains("pass"));
+        let keys = fs::read_to_string(dir.path().join(HOST_KEYS_FILE))?;
+        assert!(!keys.contains(&key));
+
+        let meta = MetaIn {
+            sync_version: SYNC_VERSION_MAX,
+            client_version: "test".into(),
+        };
+        assert!(request(&state, "meta", "badkey", &meta).is_err());
+        let out: SyncMeta = json(request(&state, "meta", &key, &meta))?;
+        assert!(out.should_continue);
+        assert!(out.empty);
+        assert!(dir.path().join("user").join("collection.anki2").exists());
+
+        // a normal sync
+        assert!(matches!(
+            request(&state, "chunk", &key, &()),
+            Err(AnkiError::SyncError {
+                kind: SyncErrorKind::SyncNotStarted,
+                ..
+            })
+        ));
+        let start = StartIn {
+            client_usn: Usn(0),
+            local_is_newer: true,
+        };
+        let _: Graves = json(request(&state, "start", &key, &start))?;
+        let graves = ApplyGravesIn {
+            chunk: Graves::default(),
+        };
+        let _: () = json(request(&state, "applyGraves", &key, &graves))?;
+        let changes = ApplyChangesIn {
+            changes: UnchunkedChanges::default(),
+        };
+        let _: UnchunkedChanges = json(request(&state, "applyChanges", &key, &changes))?;
+        let chunk: Chunk = json(request(&state, "chunk", &key, &()))?;
+        assert!(chunk.done);
+        let chunk = ApplyChunkIn {
+            chunk: Chunk {
+                done: true,
+                ..Default::default()
+            },
+        };
+        let _: () = json(request(&state, "applyChunk", &key, &chunk))?;
+        let _: TimestampMillis = json(request(&state, "finish", &key, &()))?;
+
+        // full download and upload
+        let file = match request(&state, "download", &key, &())? {
+            SyncResponse::File(file) => file,
+            _ => panic!("expected file"),
+        };
+        let mut fields = RequestFields {
+            file: Some(file),
+            ..Default::default()
+        };
+        fields
+            .fields
+            .insert("k".to_string(), key.as_bytes().to_vec());
+        assert!(matches!(
+            state.handle_request("upload", &fields)?,
+            SyncResponse::Text("OK")
+        ));
+        // the replaced collection was backed up
+        let backups = dir.path().join("user").join("backups");
+        assert_eq!(fs::read_dir(backups)?.count(), 1);
+        let out: SyncMeta = json(request(&state, "meta", &key, &meta))?;
+        assert!(out.should_continue);
+
+        // changing the password invalidates the user's keys
+        let other_key = login("other", "pass")?.key;
+        add_user(dir.path(), "user", "new")?;
+        assert!(request(&state, "meta", &key, &meta).is_err());
+        assert!(login("user", "pass").is_err());
+        login("user", "new")?;
+        json::<SyncMeta>(request(&state, "meta", &other_key, &meta))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn media_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        add_user(dir.path(), "user", "pass")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}/msync/", listener.local_addr()?);
+        let state = Arc::new(ServerState::new(
+            Accounts::DataDir(dir.path().into()),
+            log::terminal(),
+        ));
+        let hkey = state.accounts.add_host_key("user")?;
+        thread::spawn(move || serve(listener, state));
+
+        // two clients of the same account
+        let media_folder = |client: &str| dir.path().join(client).join("media");
+        let mut clients = vec![];
+        for client in &["a", "b"] {
+            fs::create_dir_all(media_folder(client))?;
+            let db = dir.path().join(client).join("media.db");
+            clients.push(MediaManager::new(media_folder(client), db)?);
+        }
+        fs::write(media_folder("a").join("a.txt"), "a")?;
+        fs::write(media_folder("b").join("b.txt"), "b")?;
+
+        let mut rt = Runtime::new().unwrap();
+        let mut sync = |mgr: &MediaManager| {
+            let mut syncer = MediaSyncer::new(mgr, |_| true, 0, log::terminal());
+            syncer.set_endpoint(endpoint.clone());
+            rt.block_on(syncer.sync(&hkey))
+        };
+        sync(&clients[0])?;
+        sync(&clients[1])?;
+        sync(&clients[0])?;
+        for client in &["a", "b"] {
+            assert_eq!(fs::read_to_string(media_folder(client).join("a.txt"))?, "a");
+            assert_eq!(fs::read_to_string(media_folder(client).join("b.txt"))?, "b");
+        }
+
+        // deletions are synced too
+        clients[0].remove_files(&mut clients[0].dbctx(), &["a.txt"])?;
+        sync(&clients[0])?;
+        sync(&clients[1])?;
+        assert!(!media_folder("b").join("a.txt").exists());
+        assert!(media_folder("b").join("b.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        add_user(dir.path(), "user", "pass")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}/sync/", listener.local_addr()?);
+        let state = Arc::new(ServerState::new(
+            Accounts::DataDir(dir.path().into()),
+            log::terminal(),
+        ));
+        let hkey = state.accounts.add_host_key("user")?;
+        let client = || {
+            let mut client = HTTPSyncClient::new(Some(hkey.clone()), 0);
+            client.set_endpoint(endpoint.clone());
+            client
+        };
+        thread::spawn(move || serve(listener, state));
+
+        let open = |name: &str| {
+            let i18n = I18n::new(&[""], "", log::terminal());
+            let path = dir.path().join(name);
+            open_collection(path, "".into(), "".into(), false, i18n, log::terminal())
+        };
+        let mut col = open("client1.anki2")?;
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = "front".into();
+        col.add_note(&mut note, DeckID(1))?;
+
+        // the upload is streamed, and the download compressed as it is sent
+        let mut rt = Runtime::new().unwrap();
+        let mut server = client();
+        server.set_full_sync_progress_fn(Some(Box::new(|_, _| ())));
+        rt.block_on(col.full_upload_inner(Box::new(server)))?;
+        let progress = Arc::new(Mutex::new(FullSyncProgress::default()));
+        let progress2 = progress.clone();
+        let mut server = client();
+        server.set_full_sync_progress_fn(Some(Box::new(move |prog, _| {
+            *progress2.lock().unwrap() = prog
+        })));
+        let col = open("client2.anki2")?;
+        rt.block_on(col.full_download_inner(Box::new(server)))?;
+
+        let progress = *progress.lock().unwrap();
+        assert!(progress.total_bytes > 0);
+        assert_eq!(progress.transferred_bytes, progress.total_bytes);
+        assert_eq!(open("client2.anki2")?.storage.total_notes()?, 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Three-way merging of notes that were modified both locally and remotely
+//! since the last sync.
+
+use crate::prelude::*;
+use unicase::UniCase;
+
+/// A field that was changed on both sides, where one of the changes had to
+/// be discarded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteConflict {
+    pub note_id: NoteID,
+    pub field_ord: usize,
+    /// True if the local change was kept, as its note was modified more
+    /// recently.
+    pub kept_local: bool,
+    /// The content of the discarded change.
+    pub discarded: String,
+}
+
+/// Merge the local and remote versions of a note, given the version they
+/// had in common at the last sync. Fields and tags are merged independently,
+/// so only fields changed on both sides conflict; in that case the more
+/// recently modified note's content is kept, and the conflict is added to
+/// `conflicts`.
+///
+/// Returns None if the versions can't be merged field by field, as their
+/// notetypes or field counts differ.
+pub(super) fn merge_notes(
+    base: &Note,
+    local: &Note,
+    remote: &Note,
+    conflicts: &mut Vec<NoteConflict>,
+) -> Option<Note> {
+    if local.notetype_id != base.notetype_id
+        || remote.notetype_id != base.notetype_id
+        || local.fields.len() != base.fields.len()
+        || remote.fields.len() != base.fields.len()
+    {
+        return None;
+    }
+    // on ties, the local note was kept prior to merging
+    let local_is_newer = local.mtime >= remote.mtime;
+
+    let mut merged = local.clone();
+    for (ord, ((base_text, local_text), remote_text)) in base
+        .fields
+        .iter()
+        .zip(&local.fields)
+        .zip(&remote.fields)
+        .enumerate()
+    {
+        if remote_text == base_text || remote_text == local_text {
+            continue;
+        }
+        if local_text == base_text {
+            merged.fields[ord] = remote_text.clone();
+            continue;
+        }
+        let discarded = if local_is_newer {
+            remote_text.clone()
+        } else {
+            merged.fields[ord] = remote_text.clone();
+            local_text.clone()
+        };
+        conflicts.push(NoteConflict {
+            note_id: local.id,
+            field_ord: ord,
+            kept_local: local_is_newer,
+            discarded,
+        });
+    }
+    merged.tags = merge_tags(&base.tags, &local.tags, &remote.tags);
+
+    Some(merged)
+}
+
+/// Tags added on either side are kept, and tags removed on either side are
+/// removed.
+fn merge_tags(base: &[String], local: &[String], remote: &[String]) -> Vec<String> {
+    let contains = |tags: &[String], tag: &str| {
+        tags.iter()
+            .any(|other| UniCase::new(other.as_str()) == UniCase::new(tag))
+    };
+
+    let mut merged: Vec<String> = local
+        .iter()
+        .filter(|tag| !contains(base, tag) || contains(remote, tag))
+        .cloned()
+        .collect();
+    for tag in remote {
+        if !contains(base, tag) && !contains(&merged, tag) {
+            merged.push(tag.clone());
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn note(mtime: i64, fields: &[&str], tags: &[&str]) -> Note {
+        Note {
+            id: NoteID(1),
+            guid: "guid".into(),
+            notetype_id: NoteTypeID(1),
+            mtime: TimestampSecs(mtime),
+            usn: Usn(-1),
+            fields: fields.iter().map(ToString::to_string).collect(),
+            tags: tags.iter().map(ToString::to_string).collect(),
+            sort_field: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn merging() {
+        let base = note(1, &["a", "b", "c"], &["one", "two"]);
+        let local = note(3, &["a2", "b", "c3"], &["One", "three"]);
+        let remote = note(2, &["a", "b2", "c4"], &["one", "two", "four"]);
+
+        let mut conflicts = vec![];
+        let merged = merge_notes(&base, &local, &remote, &mut conflicts).unwrap();
+        assert_eq!(merged.fields, vec!["a2", "b2", "c3"]);
+        assert_eq!(merged.tags, vec!["One", "three", "four"]);
+        assert_eq!(
+            conflicts,
+            vec![NoteConflict {
+                note_id: NoteID(1),
+                field_ord: 2,
+                kept_local: true,
+                discarded: "c4".into()
+            }]
+        );
+
+        // the newer remote change wins the conflict
+        let remote = note(4, &["a", "b2", "c4"], &["one", "two"]);
+        conflicts.clear();
+        let merged = merge_notes(&base, &local, &remote, &mut conflicts).unwrap();
+        assert_eq!(merged.fields, vec!["a2", "b2", "c4"]);
+        assert!(!conflicts[0].kept_local);
+        assert_eq!(conflicts[0].discarded, "c3");
+
+        // field counts must match
+        let remote = note(4, &["a", "b"], &[]);
+        assert!(merge_notes(&base, &local, &remote, &mut conflicts).is_none());
+    }
+}
@@ -1,28 +1,48 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
+mod conflicts;
+mod encryption;
 pub mod http;
 mod http_client;
+pub mod http_server;
+mod merge;
+mod peer;
+mod resume;
+mod sanity;
 mod server;
 
 use crate::{
     backend_proto::{sync_status_out, SyncStatusOut},
     card::{Card, CardQueue, CardType},
+    config::{ConfigKey, LOCAL_ONLY_CONFIG},
     deckconf::DeckConfSchema11,
     decks::DeckSchema11,
     err::SyncErrorKind,
     notes::Note,
-    notetype::{NoteType, NoteTypeSchema11},
+    notetype::{CardGenContext, NoteType, NoteTypeSchema11},
     prelude::*,
     revlog::RevlogEntry,
     serde::{default_on_invalid, deserialize_int_from_number},
     storage::open_and_check_sqlite_file,
     tags::{join_tags, split_tags},
 };
+use conflicts::DiscardedFields;
+pub use conflicts::{SyncConflict, SyncConflictKind};
+pub use encryption::SyncKey;
+pub(crate) use encryption::{is_encrypted_media_name, SyncPassphrase};
 pub use http_client::FullSyncProgressFn;
 use http_client::HTTPSyncClient;
 pub use http_client::Timeouts;
 use itertools::Itertools;
+pub use merge::NoteConflict;
+pub use peer::{LocalPeer, SyncPeer};
+use resume::SyncCheckpoint;
+use rusqlite::{Connection, NO_PARAMS};
+pub use sanity::{
+    BucketIDs, DeckCountsDiff, DeckDueCounts, DigestIDs, IDDigests, MissingIDs, SanityCheckDetails,
+    SanityCheckReport, TableCountDiff,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_tuple::Serialize_tuple;
@@ -71,9 +91,25 @@ pub struct SyncMeta {
     pub host_number: u32,
     #[serde(default)]
     pub empty: bool,
+    /// True if the server can replay notetype schema changes in a normal
+    /// sync. Other servers need a full sync after such changes.
+    #[serde(rename = "schemaReplay", default)]
+    pub schema_replay: bool,
+    /// True if the server lists the IDs that may differ when the sanity
+    /// check fails, given digests of the client's.
+    #[serde(rename = "sanityDigests", default)]
+    pub sanity_digests: bool,
+    /// True if the server holds a sync open when a connection drops, so it
+    /// can be resumed, and supports resume_chunk().
+    #[serde(default)]
+    pub resume: bool,
+    /// Identifies the server; empty for AnkiWeb. For the local collection,
+    /// the server it last synced with.
+    #[serde(rename = "serverId", default)]
+    pub server_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Graves {
     pub(crate) cards: Vec<CardID>,
     pub(crate) decks: Vec<DeckID>,
@@ -101,7 +137,7 @@ pub struct UnchunkedChanges {
     creation_stamp: Option<TimestampSecs>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Chunk {
     done: bool,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
@@ -118,7 +154,7 @@ struct ChunkableIDs {
     notes: Vec<NoteID>,
 }
 
-#[derive(Serialize_tuple, Deserialize, Debug)]
+#[derive(Serialize_tuple, Deserialize, Debug, Clone)]
 pub struct NoteEntry {
     pub id: NoteID,
     pub guid: String,
@@ -135,7 +171,7 @@ pub struct NoteEntry {
     pub data: String,
 }
 
-#[derive(Serialize_tuple, Deserialize, Debug)]
+#[derive(Serialize_tuple, Deserialize, Debug, Clone)]
 pub struct CardEntry {
     pub id: CardID,
     pub nid: NoteID,
@@ -166,6 +202,14 @@ pub struct SanityCheckOut {
     pub client: Option<SanityCheckCounts>,
     #[serde(rename = "s", default, deserialize_with = "default_on_invalid")]
     pub server: Option<SanityCheckCounts>,
+    /// Only sent by our own server, when the check fails.
+    #[serde(
+        rename = "d",
+        default,
+        deserialize_with = "default_on_invalid",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub details: Option<SanityCheckDetails>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -175,7 +219,7 @@ pub enum SanityCheckStatus {
     Bad,
 }
 
-#[derive(Serialize_tuple, Deserialize, Debug, PartialEq)]
+#[derive(Serialize_tuple, Deserialize, Debug, Clone, PartialEq)]
 pub struct SanityCheckCounts {
     pub counts: SanityCheckDueCounts,
     pub cards: u32,
@@ -188,7 +232,7 @@ pub struct SanityCheckCounts {
     pub deck_config: u32,
 }
 
-#[derive(Serialize_tuple, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize_tuple, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
 pub struct SanityCheckDueCounts {
     pub new: u32,
     pub learn: u32,
@@ -220,6 +264,9 @@ struct SyncState {
     new_usn: Option<Usn>,
     server_message: String,
     host_number: u32,
+    server_id: String,
+    sanity_digests: bool,
+    resume: bool,
 }
 
 #[derive(Debug)]
@@ -227,6 +274,8 @@ pub struct SyncOutput {
     pub required: SyncActionRequired,
     pub server_message: String,
     pub host_number: u32,
+    /// Fields changed both locally and remotely.
+    pub conflicts: Vec<NoteConflict>,
 }
 
 #[derive(Clone)]
@@ -240,6 +289,12 @@ struct NormalSyncer<'a, F> {
     remote: Box<dyn SyncServer>,
     progress: NormalSyncProgress,
     progress_fn: F,
+    conflicts: Vec<NoteConflict>,
+    changed_notetypes: Vec<NoteTypeID>,
+    checkpoint: SyncCheckpoint,
+    /// True if the server can resume a sync; see resume.rs.
+    resume: bool,
+    key: Option<SyncKey>,
 }
 
 impl Usn {
@@ -262,11 +317,16 @@ impl Usn {
 }
 
 impl SyncMeta {
-    fn compared_to_remote(&self, remote: SyncMeta) -> SyncState {
+    /// `pending_replay` is true if the local collection has notetype schema
+    /// changes that have not been synced yet.
+    fn compared_to_remote(&self, remote: SyncMeta, pending_replay: bool) -> SyncState {
         let local = self;
         let required = if remote.modified == local.modified {
             SyncActionRequired::NoChanges
-        } else if remote.schema != local.schema {
+        } else if remote.schema != local.schema
+            || remote.server_id != local.server_id
+            || (pending_replay && !remote.schema_replay)
+        {
             let upload_ok = !local.empty || remote.empty;
             let download_ok = !remote.empty || local.empty;
             SyncActionRequired::FullSyncRequired {
@@ -286,6 +346,9 @@ impl SyncMeta {
             new_usn: Some(remote.usn),
             server_message: remote.server_message,
             host_number: remote.host_number,
+            server_id: remote.server_id,
+            sanity_digests: remote.sanity_digests,
+            resume: remote.resume,
         }
     }
 }
@@ -307,6 +370,11 @@ where
             remote: server,
             progress: NormalSyncProgress::default(),
             progress_fn,
+            conflicts: vec![],
+            changed_notetypes: vec![],
+            checkpoint: SyncCheckpoint::default(),
+            resume: false,
+            key: None,
         }
     }
 
@@ -316,40 +384,56 @@ where
 
     pub async fn sync(&mut self) -> Result<SyncOutput> {
         debug!(self.col.log, "fetching meta...");
+        self.key = self.col.sync_key()?;
         self.fire_progress_cb(false);
-        let state: SyncState = self.get_sync_state().await?;
-        debug!(self.col.log, "fetched"; "state"=>?&state);
-        match state.required {
-            SyncActionRequired::NoChanges => Ok(state.into()),
-            SyncActionRequired::FullSyncRequired { .. } => Ok(state.into()),
-            SyncActionRequired::NormalSyncRequired => {
-                self.col.storage.begin_trx()?;
-                self.col
-                    .unbury_if_day_rolled_over(self.col.timing_today()?)?;
-                match self.normal_sync_inner(state).await {
-                    Ok(success) => {
-                        self.col.storage.commit_trx()?;
-                        Ok(success)
-                    }
-                    Err(e) => {
-                        self.col.storage.rollback_trx()?;
-                        let _ = self.remote.abort().await;
-
-                        if let AnkiError::SyncError {
-                            kind: SyncErrorKind::DatabaseCheckRequired,
-                            info,
-                        } = &e
-                        {
-                            debug!(self.col.log, "sanity check failed:\n{}", info);
+        loop {
+            let state: SyncState = self.get_sync_state().await?;
+            debug!(self.col.log, "fetched"; "state"=>?&state);
+            match state.required {
+                SyncActionRequired::NoChanges => return Ok(state.into()),
+                SyncActionRequired::FullSyncRequired { .. } => return Ok(state.into()),
+                SyncActionRequired::NormalSyncRequired => {
+                    let resuming = self.load_checkpoint(&state);
+                    match self.normal_sync(state).await {
+                        // remote servers report this as a conflict
+                        Err(AnkiError::SyncError {
+                            kind: SyncErrorKind::SyncNotStarted,
+                            ..
+                        })
+                        | Err(AnkiError::SyncError {
+                            kind: SyncErrorKind::Conflict,
+                            ..
+                        }) if resuming => {
+                            // the checkpoint has been discarded
+                            debug!(self.col.log, "sync no longer open on server; starting over");
                         }
-
-                        Err(e)
+                        out => return out,
                     }
                 }
             }
         }
     }
 
+    async fn normal_sync(&mut self, state: SyncState) -> Result<SyncOutput> {
+        self.col.storage.begin_trx()?;
+        match self.normal_sync_and_commit(state).await {
+            Ok(success) => Ok(success),
+            Err(e) => {
+                self.roll_back(&e).await?;
+
+                if let AnkiError::SyncError {
+                    kind: SyncErrorKind::DatabaseCheckRequired,
+                    info,
+                } = &e
+                {
+                    debug!(self.col.log, "sanity check failed:\n{}", info);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
     async fn get_sync_state(&self) -> Result<SyncState> {
         let remote: SyncMeta = self.remote.meta().await?;
         debug!(self.col.log, "remote {:?}", &remote);
@@ -373,7 +457,18 @@ where
             });
         }
 
-        Ok(local.compared_to_remote(remote))
+        let pending_replay = self.col.get_config_default(ConfigKey::PendingSchemaReplay);
+        Ok(local.compared_to_remote(remote, pending_replay))
+    }
+
+    /// Caller must have created a transaction, and should call roll_back()
+    /// on failure.
+    async fn normal_sync_and_commit(&mut self, state: SyncState) -> Result<SyncOutput> {
+        self.col
+            .unbury_if_day_rolled_over(self.col.timing_today()?)?;
+        let output = self.normal_sync_inner(state).await?;
+        self.col.storage.commit_trx()?;
+        Ok(output)
     }
 
     /// Sync. Caller must have created a transaction, and should call
@@ -388,6 +483,7 @@ where
         self.process_unchunked_changes(&state).await?;
         debug!(self.col.log, "begin stream from server");
         self.process_chunks_from_server(&state).await?;
+        self.generate_cards_for_changed_notetypes()?;
         debug!(self.col.log, "begin stream to server");
         self.send_chunks_to_server(&state).await?;
 
@@ -395,27 +491,36 @@ where
         self.fire_progress_cb(false);
 
         debug!(self.col.log, "sanity check");
-        self.sanity_check().await?;
+        self.sanity_check(state.sanity_digests).await?;
         debug!(self.col.log, "finalize");
         self.finalize(&state).await?;
         state.required = SyncActionRequired::NoChanges;
-        Ok(state.into())
+        let mut output: SyncOutput = state.into();
+        output.conflicts = std::mem::take(&mut self.conflicts);
+        Ok(output)
     }
 
     // The following operations assume a transaction has been set up.
 
     async fn start_and_process_deletions(&mut self, state: &SyncState) -> Result<()> {
-        let remote: Graves = self
-            .remote
-            .start(state.usn_at_last_sync, state.local_is_newer)
-            .await?;
+        let remote: Graves = match self.checkpoint.graves.clone() {
+            Some(graves) => graves,
+            None => {
+                let graves = self
+                    .remote
+                    .start(state.usn_at_last_sync, state.local_is_newer)
+                    .await?;
+                self.checkpoint.graves = Some(graves.clone());
+                graves
+            }
+        };
 
         debug!(self.col.log, "removed on remote";
             "cards"=>remote.cards.len(),
             "notes"=>remote.notes.len(),
             "decks"=>remote.decks.len());
 
-        let mut local = self.col.storage.pending_graves(state.pending_usn)?;
+        let mut local = self.col.storage.pending_graves(state.pending_usn, false)?;
         if let Some(new_usn) = state.new_usn {
             self.col.storage.update_pending_grave_usns(new_usn)?;
         }
@@ -428,7 +533,7 @@ where
         while let Some(chunk) = local.take_chunk() {
             debug!(self.col.log, "sending graves chunk");
             self.progress.local_remove += chunk.cards.len() + chunk.notes.len() + chunk.decks.len();
-            self.remote.apply_graves(chunk).await?;
+            self.send_graves(chunk).await?;
             self.fire_progress_cb(true);
         }
 
@@ -446,11 +551,17 @@ where
     // usefulness.
     async fn process_unchunked_changes(&mut self, state: &SyncState) -> Result<()> {
         debug!(self.col.log, "gathering local changes");
-        let local = self.col.local_unchunked_changes(
-            state.pending_usn,
-            state.new_usn,
-            state.local_is_newer,
-        )?;
+        // the local objects remain pending until the remote changes have
+        // been applied, so changes made on both sides can be detected
+        let mut local =
+            self.col
+                .local_unchunked_changes(state.pending_usn, None, state.local_is_newer)?;
+        if let Some(new_usn) = state.new_usn {
+            local.set_usns(new_usn);
+        }
+        if let Some(key) = &self.key {
+            local.encrypt(key);
+        }
 
         debug!(self.col.log, "sending";
             "notetypes"=>local.notetypes.len(),
@@ -463,7 +574,10 @@ where
             + local.decks_and_config.decks.len()
             + local.decks_and_config.config.len()
             + local.tags.len();
-        let remote = self.remote.apply_changes(local).await?;
+        let mut remote = self.remote.apply_changes(local).await?;
+        if let Some(key) = &self.key {
+            remote.decrypt(key)?;
+        }
         self.fire_progress_cb(true);
 
         debug!(self.col.log, "received";
@@ -478,41 +592,76 @@ where
             + remote.decks_and_config.config.len()
             + remote.tags.len();
 
-        self.col.apply_changes(remote, state.latest_usn)?;
+        self.changed_notetypes =
+            self.col
+                .apply_changes(remote, state.latest_usn, state.pending_usn)?;
+        // the local objects that are still pending were sent, and kept
+        self.col
+            .local_unchunked_changes(state.pending_usn, state.new_usn, false)?;
         self.fire_progress_cb(true);
         Ok(())
     }
 
     async fn process_chunks_from_server(&mut self, state: &SyncState) -> Result<()> {
+        // chunks received before the sync was interrupted are applied again
+        for chunk in std::mem::take(&mut self.checkpoint.chunks) {
+            if self.apply_server_chunk(chunk, state)? {
+                return Ok(());
+            }
+        }
         loop {
-            let chunk: Chunk = self.remote.chunk().await?;
+            let chunk: Chunk = self.receive_chunk().await?;
+            if self.apply_server_chunk(chunk, state)? {
+                return Ok(());
+            }
+        }
+    }
 
-            debug!(self.col.log, "received";
-                "done"=>chunk.done,
-                "cards"=>chunk.cards.len(),
-                "notes"=>chunk.notes.len(),
-                "revlog"=>chunk.revlog.len(),
-            );
+    /// Returns true if it was the last chunk.
+    fn apply_server_chunk(&mut self, mut chunk: Chunk, state: &SyncState) -> Result<bool> {
+        self.checkpoint.chunks.push(chunk.clone());
+        if let Some(key) = &self.key {
+            chunk.decrypt(key)?;
+        }
 
-            self.progress.remote_update +=
-                chunk.cards.len() + chunk.notes.len() + chunk.revlog.len();
+        debug!(self.col.log, "received";
+            "done"=>chunk.done,
+            "cards"=>chunk.cards.len(),
+            "notes"=>chunk.notes.len(),
+            "revlog"=>chunk.revlog.len(),
+        );
 
-            let done = chunk.done;
-            self.col.apply_chunk(chunk, state.pending_usn)?;
+        self.progress.remote_update += chunk.cards.len() + chunk.notes.len() + chunk.revlog.len();
 
-            self.fire_progress_cb(true);
+        let done = chunk.done;
+        let conflicts = self.col.apply_chunk(chunk, state.pending_usn)?;
+        self.conflicts.extend(conflicts);
 
-            if done {
-                return Ok(());
+        self.fire_progress_cb(true);
+
+        Ok(done)
+    }
+
+    /// Cards for templates added on another device are generated once its
+    /// notes have been received, so only cards of notes it lacked are added.
+    fn generate_cards_for_changed_notetypes(&mut self) -> Result<()> {
+        for ntid in std::mem::take(&mut self.changed_notetypes) {
+            if let Some(nt) = self.col.get_notetype(ntid)? {
+                let ctx = CardGenContext::new(&nt, self.col.usn()?);
+                self.col.generate_cards_for_notetype(&ctx)?;
             }
         }
+        Ok(())
     }
 
     async fn send_chunks_to_server(&mut self, state: &SyncState) -> Result<()> {
         let mut ids = self.col.get_chunkable_ids(state.pending_usn)?;
 
         loop {
-            let chunk: Chunk = self.col.get_chunk(&mut ids, state.new_usn)?;
+            let mut chunk: Chunk = self.col.get_chunk(&mut ids, state.new_usn)?;
+            if let Some(key) = &self.key {
+                chunk.encrypt(key);
+            }
             let done = chunk.done;
 
             debug!(self.col.log, "sending";
@@ -525,7 +674,7 @@ where
             self.progress.local_update +=
                 chunk.cards.len() + chunk.notes.len() + chunk.revlog.len();
 
-            self.remote.apply_chunk(chunk).await?;
+            self.send_chunk(chunk).await?;
 
             self.fire_progress_cb(true);
 
@@ -536,7 +685,9 @@ where
     }
 
     /// Caller should force full sync after rolling back.
-    async fn sanity_check(&mut self) -> Result<()> {
+    /// If `compare_ids` is true and the counts differ, the server is asked
+    /// for the IDs that may differ.
+    async fn sanity_check(&mut self, compare_ids: bool) -> Result<()> {
         let mut local_counts = self.col.storage.sanity_check_info()?;
         self.col.add_due_counts(&mut local_counts.counts)?;
 
@@ -544,14 +695,29 @@ where
             self.col.log,
             "gathered local counts; waiting for server reply"
         );
-        let out: SanityCheckOut = self.remote.sanity_check(local_counts).await?;
+        let out: SanityCheckOut = self.remote.sanity_check(local_counts.clone()).await?;
         debug!(self.col.log, "got server reply");
         if out.status != SanityCheckStatus::Ok {
+            let mut report =
+                self.col
+                    .sanity_check_report(&local_counts, out.server.as_ref(), out.details)?;
+            if compare_ids && !report.counts.is_empty() {
+                let digests = self.col.sanity_check_digests(&report.counts)?;
+                let remote_ids = self.remote.sanity_check_ids(digests.clone()).await?;
+                self.col
+                    .add_missing_ids(&mut report, &digests, remote_ids)?;
+            }
+            let info = format!(
+                "local {:?}\nremote {:?}\n{}",
+                out.client, out.server, report
+            );
+            self.col.state.last_sanity_check = Some(report);
             Err(AnkiError::SyncError {
-                info: format!("local {:?}\nremote {:?}", out.client, out.server),
+                info,
                 kind: SyncErrorKind::DatabaseCheckRequired,
             })
         } else {
+            self.col.state.last_sanity_check = None;
             Ok(())
         }
     }
@@ -624,7 +790,12 @@ impl Collection {
     }
 
     pub fn get_sync_status(&self, remote: SyncMeta) -> Result<sync_status_out::Required> {
-        Ok(self.sync_meta()?.compared_to_remote(remote).required.into())
+        let pending_replay = self.get_config_default(ConfigKey::PendingSchemaReplay);
+        Ok(self
+            .sync_meta()?
+            .compared_to_remote(remote, pending_replay)
+            .required
+            .into())
     }
 
     /// Create a new syncing instance. If host_number is unavailable, use 0.
@@ -650,10 +821,22 @@ impl Collection {
     }
 
     pub(crate) async fn full_upload_inner(mut self, server: Box<dyn SyncServer>) -> Result<()> {
+        let key = self.sync_key()?;
+        let server_id = server.meta().await?.server_id;
         self.before_upload()?;
+        self.transact(None, |col| {
+            col.set_config(ConfigKey::LastSyncServer, &server_id)
+        })?;
         let col_path = self.col_path.clone();
+        let (i18n, log) = (self.i18n.clone(), self.log.clone());
         self.close(true)?;
-        server.full_upload(&col_path, false).await
+        match key {
+            Some(key) => {
+                let encrypted = encryption::encrypted_copy(&col_path, &key, i18n, log)?;
+                server.full_upload(encrypted.path(), false).await
+            }
+            None => server.full_upload(&col_path, false).await,
+        }
     }
 
     /// Download collection from AnkiWeb. Caller must re-open afterwards.
@@ -669,18 +852,40 @@ impl Collection {
 
     pub(crate) async fn full_download_inner(self, server: Box<dyn SyncServer>) -> Result<()> {
         let col_path = self.col_path.clone();
+        let passphrase = self.state.sync_passphrase.clone();
+        let was_encrypted = self.sync_encryption_enabled();
+        let (media_folder, media_db) = (self.media_folder.clone(), self.media_db.clone());
+        let (i18n, log) = (self.i18n.clone(), self.log.clone());
         self.close(false)?;
+        let server_id = server.meta().await?.server_id;
         let out_file = server.full_download().await?;
         // check file ok
         let db = open_and_check_sqlite_file(out_file.path())?;
         db.execute_batch("update col set ls=mod")?;
+        // the server's own ID is replaced by the one it is known by, along
+        // with the usn it serves its peers at
+        set_config_in_file(
+            &db,
+            &[
+                (ConfigKey::LastSyncServer, Some(server_id.as_str())),
+                (ConfigKey::ServerID, None),
+                (ConfigKey::PeerUsn, None),
+                (ConfigKey::SyncCheckpoint, None),
+            ],
+        )?;
         drop(db);
+        let encrypted =
+            encryption::decrypt_download(out_file.path(), passphrase.as_ref(), i18n, log)?;
         // overwrite existing collection atomically
         out_file
             .persist(&col_path)
             .map_err(|e| AnkiError::IOError {
                 info: format!("download save failed: {}", e),
             })?;
+        if encrypted != was_encrypted {
+            // the server's media is stored under different names
+            encryption::force_media_resync(&media_folder, &media_db)?;
+        }
         Ok(())
     }
 
@@ -694,6 +899,10 @@ impl Collection {
             should_continue: true,
             host_number: 0,
             empty: !self.storage.have_at_least_one_card()?,
+            schema_replay: true,
+            sanity_digests: true,
+            resume: true,
+            server_id: self.get_config_default(ConfigKey::LastSyncServer),
         })
     }
 
@@ -744,9 +953,11 @@ impl Collection {
         pending_usn: Usn,
         new_usn: Option<Usn>,
     ) -> Result<Vec<NoteTypeSchema11>> {
-        let ids = self
-            .storage
-            .objects_pending_sync("notetypes", pending_usn)?;
+        let ids = self.storage.objects_pending_sync(
+            "notetypes",
+            pending_usn,
+            self.state.serving_peers,
+        )?;
         self.storage
             .maybe_update_object_usns("notetypes", &ids, new_usn)?;
         self.state.notetype_cache.clear();
@@ -766,7 +977,9 @@ impl Collection {
         pending_usn: Usn,
         new_usn: Option<Usn>,
     ) -> Result<Vec<DeckSchema11>> {
-        let ids = self.storage.objects_pending_sync("decks", pending_usn)?;
+        let ids =
+            self.storage
+                .objects_pending_sync("decks", pending_usn, self.state.serving_peers)?;
         self.storage
             .maybe_update_object_usns("decks", &ids, new_usn)?;
         self.state.deck_cache.clear();
@@ -786,9 +999,11 @@ impl Collection {
         pending_usn: Usn,
         new_usn: Option<Usn>,
     ) -> Result<Vec<DeckConfSchema11>> {
-        let ids = self
-            .storage
-            .objects_pending_sync("deck_config", pending_usn)?;
+        let ids = self.storage.objects_pending_sync(
+            "deck_config",
+            pending_usn,
+            self.state.serving_peers,
+        )?;
         self.storage
             .maybe_update_object_usns("deck_config", &ids, new_usn)?;
         ids.into_iter()
@@ -803,17 +1018,20 @@ impl Collection {
     }
 
     fn changed_tags(&self, pending_usn: Usn, new_usn: Option<Usn>) -> Result<Vec<String>> {
-        let changed = self.storage.tags_pending_sync(pending_usn)?;
+        let changed = self
+            .storage
+            .tags_pending_sync(pending_usn, self.state.serving_peers)?;
         if let Some(usn) = new_usn {
             self.storage.update_tag_usns(&changed, usn)?;
         }
         Ok(changed)
     }
 
-    /// Currently this is all config, as legacy clients overwrite the local items
-    /// with the provided value.
+    /// Currently this is all config apart from the local-only keys, as legacy
+    /// clients overwrite the local items with the provided value.
     fn changed_config(&self) -> Result<HashMap<String, Value>> {
-        let conf = self.storage.get_all_config()?;
+        let mut conf = self.storage.get_all_config()?;
+        conf.retain(|key, _| !LOCAL_ONLY_CONFIG.contains(&key.as_str()));
         self.storage.clear_config_usns()?;
         Ok(conf)
     }
@@ -821,39 +1039,82 @@ impl Collection {
     // Remote->local unchunked changes
     //----------------------------------------------------------------
 
-    fn apply_changes(&mut self, remote: UnchunkedChanges, latest_usn: Usn) -> Result<()> {
-        self.merge_notetypes(remote.notetypes)?;
-        self.merge_decks(remote.decks_and_config.decks)?;
-        self.merge_deck_config(remote.decks_and_config.config)?;
+    /// Returns the IDs of notetypes whose fields or templates changed.
+    /// pending_usn is used to decide whether the local objects were modified
+    /// too, as in apply_chunk().
+    fn apply_changes(
+        &mut self,
+        remote: UnchunkedChanges,
+        latest_usn: Usn,
+        pending_usn: Usn,
+    ) -> Result<Vec<NoteTypeID>> {
+        let changed_schemas = self.merge_notetypes(remote.notetypes, pending_usn)?;
+        self.merge_decks(remote.decks_and_config.decks, pending_usn)?;
+        self.merge_deck_config(remote.decks_and_config.config, pending_usn)?;
         self.merge_tags(remote.tags, latest_usn)?;
         if let Some(crt) = remote.creation_stamp {
             self.storage.set_creation_stamp(crt)?;
         }
-        if let Some(config) = remote.config {
+        if let Some(mut config) = remote.config {
+            for &key in LOCAL_ONLY_CONFIG {
+                config.remove(key);
+                if let Some(val) = self.storage.get_config_value::<Value>(key)? {
+                    config.insert(key.into(), val);
+                }
+            }
             self.storage
                 .set_all_config(config, latest_usn, TimestampSecs::now())?;
         }
 
-        Ok(())
+        Ok(changed_schemas)
     }
 
-    fn merge_notetypes(&mut self, notetypes: Vec<NoteTypeSchema11>) -> Result<()> {
+    /// Returns the IDs of notetypes whose fields or templates changed.
+    fn merge_notetypes(
+        &mut self,
+        notetypes: Vec<NoteTypeSchema11>,
+        pending_usn: Usn,
+    ) -> Result<Vec<NoteTypeID>> {
+        let mut changed_schemas = vec![];
         for nt in notetypes {
-            let nt: NoteType = nt.into();
+            let remote = nt.clone();
+            let mut nt: NoteType = nt.into();
             let proceed = if let Some(existing_nt) = self.storage.get_notetype(nt.id)? {
-                if existing_nt.mtime_secs <= nt.mtime_secs {
-                    if (existing_nt.fields.len() != nt.fields.len())
-                        || (existing_nt.templates.len() != nt.templates.len())
-                    {
-                        return Err(AnkiError::SyncError {
-                            info: "notetype schema changed".into(),
-                            kind: SyncErrorKind::ResyncRequired,
-                        });
+                let proceed = existing_nt.mtime_secs <= nt.mtime_secs;
+                if !proceed && nt.config.schema_version > existing_nt.config.schema_version {
+                    // the local notetype would lack the remote schema changes
+                    return Err(AnkiError::SyncError {
+                        info: "notetype schema changed".into(),
+                        kind: SyncErrorKind::ResyncRequired,
+                    });
+                }
+                if self.logs_sync_conflicts()
+                    && self.changed_since_sync(
+                        "notetypes",
+                        nt.id.0,
+                        existing_nt.usn,
+                        pending_usn,
+                    )?
+                {
+                    let local = NoteTypeSchema11::from(existing_nt.clone());
+                    if conflicts::content_differs(&local, &remote)? {
+                        let (kept_local, discarded) = if proceed {
+                            (false, local)
+                        } else {
+                            (true, remote)
+                        };
+                        self.log_sync_conflict(
+                            SyncConflictKind::NoteType,
+                            nt.id.0,
+                            kept_local,
+                            &discarded,
+                        )?;
                     }
-                    true
-                } else {
-                    false
                 }
+                if proceed && self.replay_notetype_schema_changes(&mut nt, &existing_nt)? {
+                    changed_schemas.push(nt.id);
+                }
+                proceed
             } else {
                 true
             };
@@ -862,13 +1123,37 @@ impl Collection {
                 self.state.notetype_cache.remove(&nt.id);
             }
         }
-        Ok(())
+        Ok(changed_schemas)
     }
 
-    fn merge_decks(&mut self, decks: Vec<DeckSchema11>) -> Result<()> {
+    fn merge_decks(&mut self, decks: Vec<DeckSchema11>, pending_usn: Usn) -> Result<()> {
         for deck in decks {
             let proceed = if let Some(existing_deck) = self.storage.get_deck(deck.id())? {
-                existing_deck.mtime_secs <= deck.common().mtime
+                let proceed = existing_deck.mtime_secs <= deck.common().mtime;
+                if self.logs_sync_conflicts()
+                    && self.changed_since_sync(
+                        "decks",
+                        deck.id().0,
+                        existing_deck.usn,
+                        pending_usn,
+                    )?
+                {
+                    let local = DeckSchema11::from(existing_deck);
+                    if conflicts::content_differs(&local, &deck)? {
+                        let (kept_local, discarded) = if proceed {
+                            (false, &local)
+                        } else {
+                            (true, &deck)
+                        };
+                        self.log_sync_conflict(
+                            SyncConflictKind::Deck,
+                            deck.id().0,
+                            kept_local,
+                            discarded,
+                        )?;
+                    }
+                }
+                proceed
             } else {
                 true
             };
@@ -881,10 +1166,34 @@ impl Collection {
         Ok(())
     }
 
-    fn merge_deck_config(&self, dconf: Vec<DeckConfSchema11>) -> Result<()> {
+    fn merge_deck_config(&self, dconf: Vec<DeckConfSchema11>, pending_usn: Usn) -> Result<()> {
         for conf in dconf {
             let proceed = if let Some(existing_conf) = self.storage.get_deck_config(conf.id)? {
-                existing_conf.mtime_secs <= conf.mtime
+                let proceed = existing_conf.mtime_secs <= conf.mtime;
+                if self.logs_sync_conflicts()
+                    && self.changed_since_sync(
+                        "deck_config",
+                        conf.id.0,
+                        existing_conf.usn,
+                        pending_usn,
+                    )?
+                {
+                    let local = DeckConfSchema11::from(existing_conf);
+                    if conflicts::content_differs(&local, &conf)? {
+                        let (kept_local, discarded) = if proceed {
+                            (false, &local)
+                        } else {
+                            (true, &conf)
+                        };
+                        self.log_sync_conflict(
+                            SyncConflictKind::DeckConfig,
+                            conf.id.0,
+                            kept_local,
+                            discarded,
+                        )?;
+                    }
+                }
+                proceed
             } else {
                 true
             };
@@ -909,7 +1218,8 @@ impl Collection {
     /// pending_usn is used to decide whether the local objects are newer.
     /// If the provided objects are not modified locally, the USN inside
     /// the individual objects is used.
-    fn apply_chunk(&mut self, chunk: Chunk, pending_usn: Usn) -> Result<()> {
+    /// Returns the fields that were changed on both sides.
+    fn apply_chunk(&mut self, chunk: Chunk, pending_usn: Usn) -> Result<Vec<NoteConflict>> {
         self.merge_revlog(chunk.revlog)?;
         self.merge_cards(chunk.cards, pending_usn)?;
         self.merge_notes(chunk.notes, pending_usn)
@@ -922,6 +1232,17 @@ impl Collection {
         Ok(())
     }
 
+    /// True if the local object was modified since the other side last
+    /// synced. When serving peers, the usns of the object are those of the
+    /// collection's own server, so the ones recorded for peers are used.
+    fn changed_since_sync(&self, table: &str, id: i64, usn: Usn, pending_usn: Usn) -> Result<bool> {
+        if self.state.serving_peers {
+            self.storage.changed_for_peers(table, id, pending_usn)
+        } else {
+            Ok(usn.is_pending_sync(pending_usn))
+        }
+    }
+
     fn merge_cards(&self, entries: Vec<CardEntry>, pending_usn: Usn) -> Result<()> {
         for entry in entries {
             self.add_or_update_card_if_newer(entry, pending_usn)?;
@@ -930,39 +1251,119 @@ impl Collection {
     }
 
     fn add_or_update_card_if_newer(&self, entry: CardEntry, pending_usn: Usn) -> Result<()> {
-        let proceed = if let Some(existing_card) = self.storage.get_card(entry.id)? {
-            !existing_card.usn.is_pending_sync(pending_usn) || existing_card.mtime < entry.mtime
-        } else {
-            true
-        };
-        if proceed {
-            let card = entry.into();
-            self.storage.add_or_update_card(&card)?;
+        if let Some(existing_card) = self.storage.get_card(entry.id)? {
+            if self.changed_since_sync("cards", entry.id.0, existing_card.usn, pending_usn)? {
+                let edited =
+                    self.logs_sync_conflicts() && conflicts::card_edited(&existing_card, &entry);
+                if existing_card.mtime < entry.mtime {
+                    if edited {
+                        self.log_sync_conflict(
+                            SyncConflictKind::Card,
+                            entry.id.0,
+                            false,
+                            &CardEntry::from(existing_card),
+                        )?;
+                    }
+                } else {
+                    if edited {
+                        self.log_sync_conflict(SyncConflictKind::Card, entry.id.0, true, &entry)?;
+                    }
+                    return Ok(());
+                }
+            }
         }
+        let card = entry.into();
+        self.storage.add_or_update_card(&card)?;
         Ok(())
     }
 
-    fn merge_notes(&mut self, entries: Vec<NoteEntry>, pending_usn: Usn) -> Result<()> {
+    fn merge_notes(
+        &mut self,
+        entries: Vec<NoteEntry>,
+        pending_usn: Usn,
+    ) -> Result<Vec<NoteConflict>> {
+        let mut conflicts = vec![];
         for entry in entries {
-            self.add_or_update_note_if_newer(entry, pending_usn)?;
+            self.add_or_update_note_if_newer(entry, pending_usn, &mut conflicts)?;
         }
-        Ok(())
+        Ok(conflicts)
     }
 
-    fn add_or_update_note_if_newer(&mut self, entry: NoteEntry, pending_usn: Usn) -> Result<()> {
-        let proceed = if let Some(existing_note) = self.storage.get_note(entry.id)? {
-            !existing_note.usn.is_pending_sync(pending_usn) || existing_note.mtime < entry.mtime
-        } else {
-            true
-        };
-        if proceed {
-            let mut note: Note = entry.into();
-            let nt = self
-                .get_notetype(note.notetype_id)?
-                .ok_or_else(|| AnkiError::invalid_input("note missing notetype"))?;
-            note.prepare_for_update(&nt, false)?;
-            self.storage.add_or_update_note(&note)?;
+    /// If the note was modified locally as well, the changes are merged when
+    /// the version at the last sync is known, and the more recently modified
+    /// note is kept otherwise.
+    fn add_or_update_note_if_newer(
+        &mut self,
+        entry: NoteEntry,
+        pending_usn: Usn,
+        conflicts: &mut Vec<NoteConflict>,
+    ) -> Result<()> {
+        let mut note: Note = entry.into();
+        if let Some(existing_note) = self.storage.get_note(note.id)? {
+            if self.changed_since_sync("notes", note.id.0, existing_note.usn, pending_usn)? {
+                let prior_conflicts = conflicts.len();
+                // merging is left to clients, as the server's base may be
+                // from a different server
+                let base = if self.server {
+                    None
+                } else {
+                    self.storage.get_note_sync_base(note.id)?
+                };
+                let merged = match base {
+                    Some(base) => merge::merge_notes(&base, &existing_note, &note, conflicts),
+                    None => None,
+                };
+                if let Some(mut merged) = merged {
+                    // conflicts in the note's fields have the same outcome
+                    if let Some(conflict) = conflicts.get(prior_conflicts) {
+                        let kept_local = conflict.kept_local;
+                        let discarded = DiscardedFields {
+                            notetype_id: note.notetype_id,
+                            fields: conflicts[prior_conflicts..]
+                                .iter()
+                                .map(|c| (c.field_ord, c.discarded.clone()))
+                                .collect(),
+                        };
+                        self.log_sync_conflict(
+                            SyncConflictKind::Note,
+                            note.id.0,
+                            kept_local,
+                            &discarded,
+                        )?;
+                    }
+                    // remains pending, and must be newer than the remote note for
+                    // the server to accept it
+                    merged.mtime = TimestampSecs(TimestampSecs::now().0.max(note.mtime.0 + 1));
+                    note = merged;
+                } else {
+                    let kept_local = existing_note.mtime >= note.mtime;
+                    if self.logs_sync_conflicts() {
+                        let discarded = if kept_local {
+                            DiscardedFields::new(&note, &existing_note)
+                        } else {
+                            DiscardedFields::new(&existing_note, &note)
+                        };
+                        if !discarded.fields.is_empty() {
+                            self.log_sync_conflict(
+                                SyncConflictKind::Note,
+                                note.id.0,
+                                kept_local,
+                                &discarded,
+                            )?;
+                        }
+                    }
+                    if kept_local {
+                        return Ok(());
+                    }
+                }
+            }
         }
+
+        let nt = self
+            .get_notetype(note.notetype_id)?
+            .ok_or_else(|| AnkiError::invalid_input("note missing notetype"))?;
+        note.prepare_for_update_unchecked(&nt, false)?;
+        self.storage.add_or_update_note(&note)?;
         Ok(())
     }
 
@@ -971,9 +1372,21 @@ impl Collection {
 
     fn get_chunkable_ids(&self, pending_usn: Usn) -> Result<ChunkableIDs> {
         Ok(ChunkableIDs {
-            revlog: self.storage.objects_pending_sync("revlog", pending_usn)?,
-            cards: self.storage.objects_pending_sync("cards", pending_usn)?,
-            notes: self.storage.objects_pending_sync("notes", pending_usn)?,
+            revlog: self.storage.objects_pending_sync(
+                "revlog",
+                pending_usn,
+                self.state.serving_peers,
+            )?,
+            cards: self.storage.objects_pending_sync(
+                "cards",
+                pending_usn,
+                self.state.serving_peers,
+            )?,
+            notes: self.storage.objects_pending_sync(
+                "notes",
+                pending_usn,
+                self.state.serving_peers,
+            )?,
         })
     }
 
@@ -1066,6 +1479,11 @@ impl Collection {
     }
 
     fn finalize_sync(&self, state: &SyncState, new_server_mtime: TimestampMillis) -> Result<()> {
+        self.storage.clear_note_sync_bases()?;
+        self.remove_config(ConfigKey::PendingSchemaReplay)?;
+        self.remove_config(ConfigKey::SyncCheckpoint)?;
+        self.prune_sync_conflicts()?;
+        self.set_config(ConfigKey::LastSyncServer, &state.server_id)?;
         self.storage.set_last_sync(new_server_mtime)?;
         let mut usn = state.latest_usn;
         usn.0 += 1;
@@ -1074,6 +1492,25 @@ impl Collection {
     }
 }
 
+/// Full syncs transfer collections in schema 11, which keeps the config in
+/// the col table. Keys with no value are removed.
+pub(crate) fn set_config_in_file(
+    db: &Connection,
+    items: &[(ConfigKey, Option<&str>)],
+) -> Result<()> {
+    let conf: String = db.query_row("select conf from col", NO_PARAMS, |row| row.get(0))?;
+    let mut conf: HashMap<String, Value> = serde_json::from_str(&conf)?;
+    for &(key, val) in items {
+        let key: &str = key.into();
+        match val {
+            Some(val) => conf.insert(key.into(), val.into()),
+            None => conf.remove(key),
+        };
+    }
+    db.execute("update col set conf = ?", &[serde_json::to_string(&conf)?])?;
+    Ok(())
+}
+
 impl From<CardEntry> for Card {
     fn from(e: CardEntry) -> Self {
         Card {
@@ -1164,6 +1601,7 @@ impl From<SyncState> for SyncOutput {
             required: s.required,
             server_message: s.server_message,
             host_number: s.host_number,
+            conflicts: vec![],
         }
     }
 }
@@ -1195,10 +1633,10 @@ mod test {
     use super::*;
     use crate::log;
     use crate::{
-        collection::open_collection, deckconf::DeckConf, decks::DeckKind, i18n::I18n,
-        notetype::all_stock_notetypes, search::SortMode,
+        collection::open_collection, deckconf::DeckConf, decks::DeckKind, err::NetworkErrorKind,
+        i18n::I18n, notetype::all_stock_notetypes, search::SortMode,
     };
-    use tempfile::{tempdir, TempDir};
+    use tempfile::{tempdir, NamedTempFile, TempDir};
     use tokio::runtime::Runtime;
 
     fn norm_progress(_: NormalSyncProgress, _: bool) {}
@@ -1504,6 +1942,110 @@ mod test {
         // should still match
         compare_sides(&mut col1, &mut col2)?;
 
+        // edits to different fields of the same note are merged
+        let mut note = col1.storage.get_note(noteid)?.unwrap();
+        note.fields[0] = "first".into();
+        note.tags.push("tag3".into());
+        col1.update_note(&mut note)?;
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+
+        let mut note = col2.storage.get_note(noteid)?.unwrap();
+        note.fields[1] = "second".into();
+        note.tags.retain(|tag| tag != "tag2");
+        col2.update_note(&mut note)?;
+        let out = ctx.normal_sync(&mut col2).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        assert!(out.conflicts.is_empty());
+
+        let note = col2.storage.get_note(noteid)?.unwrap();
+        assert_eq!(&note.fields[..2], &["first", "second"]);
+        assert_eq!(note.tags, vec!["tag", "tag3"]);
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        compare_sides(&mut col1, &mut col2)?;
+
+        // edits to the same field conflict, and the newer one is kept
+        let mut note = col1.storage.get_note(noteid)?.unwrap();
+        note.fields[0] = "a".into();
+        col1.update_note(&mut note)?;
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+
+        let mut note = col2.storage.get_note(noteid)?.unwrap();
+        note.fields[0] = "b".into();
+        col2.update_note(&mut note)?;
+        let out = ctx.normal_sync(&mut col2).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        assert_eq!(
+            out.conflicts,
+            vec![NoteConflict {
+                note_id: noteid,
+                field_ord: 0,
+                kept_local: true,
+                discarded: "a".into()
+            }]
+        );
+
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        compare_sides(&mut col1, &mut col2)?;
+        assert_eq!(col1.storage.get_note(noteid)?.unwrap().fields[0], "b");
+
+        // the discarded version was logged, and can be restored
+        let logged = col2.sync_conflicts()?;
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].kind, SyncConflictKind::Note);
+        assert_eq!(logged[0].object_id, noteid.0);
+        assert!(logged[0].kept_local);
+        assert_eq!(
+            logged[0].discarded,
+            format!(r#"{{"mid":{},"fields":{{"0":"a"}}}}"#, note.notetype_id.0)
+        );
+        // only the conflicting field is restored
+        let mut note = col2.storage.get_note(noteid)?.unwrap();
+        note.fields[1] = "edited".into();
+        col2.update_note(&mut note)?;
+        col2.restore_sync_conflict(logged[0].id)?;
+        assert!(col2.sync_conflicts()?.is_empty());
+        let note = col2.storage.get_note(noteid)?.unwrap();
+        assert_eq!(&note.fields[..2], &["a", "edited"]);
+
+        let out = ctx.normal_sync(&mut col2).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        compare_sides(&mut col1, &mut col2)?;
+
+        // decks changed on both sides only conflict if their content differs
+        for (col, name) in &mut [(&mut col1, "same"), (&mut col2, "same")] {
+            let mut deck = col.storage.get_deck(deck.id)?.unwrap();
+            deck.name = (*name).into();
+            col.add_or_update_deck(&mut deck)?;
+            let out = ctx.normal_sync(col).await;
+            assert_eq!(out.required, SyncActionRequired::NoChanges);
+        }
+        assert!(col2.sync_conflicts()?.is_empty());
+        let mut deck1 = col1.storage.get_deck(deck.id)?.unwrap();
+        deck1.name = "one".into();
+        col1.add_or_update_deck(&mut deck1)?;
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        let mut deck2 = col2.storage.get_deck(deck.id)?.unwrap();
+        deck2.name = "two".into();
+        col2.add_or_update_deck(&mut deck2)?;
+        deck2.mtime_secs = TimestampSecs(deck1.mtime_secs.0 + 1);
+        col2.storage.update_deck(&deck2)?;
+        let out = ctx.normal_sync(&mut col2).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        let logged = col2.sync_conflicts()?;
+        assert_eq!(logged.len(), 1);
+        assert_eq!(logged[0].kind, SyncConflictKind::Deck);
+        assert!(logged[0].kept_local);
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        compare_sides(&mut col1, &mut col2)?;
+
         // deletions should sync too
         for table in &["cards", "notes", "decks"] {
             assert_eq!(
@@ -1532,10 +2074,339 @@ mod test {
             );
         }
 
+        // fields and templates can be changed without a full sync
+        let basic_id = col1.get_notetype_by_name("Basic")?.unwrap().id;
+        let mut nt = col1.storage.get_notetype(basic_id)?.unwrap();
+        nt.fields.swap(0, 1);
+        nt.add_field("Extra");
+        nt.add_template("Reverse", "{{Back}}", "{{Front}}");
+        col1.update_notetype(&mut nt, false)?;
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+
+        // a note added before the change was received is updated to match
+        let mut note = col2.get_notetype(basic_id)?.unwrap().new_note();
+        note.fields = vec!["front".into(), "back".into()];
+        col2.add_note(&mut note, DeckID(1))?;
+        let out = ctx.normal_sync(&mut col2).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        let out = ctx.normal_sync(&mut col1).await;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+
+        assert_eq!(
+            col1.get_notetype(basic_id)?.unwrap(),
+            col2.get_notetype(basic_id)?.unwrap()
+        );
+        for col in &[&col1, &col2] {
+            let note = col.storage.get_note(note.id)?.unwrap();
+            assert_eq!(note.fields, vec!["back", "front", ""]);
+            assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 2);
+        }
+
         // removing things like a notetype forces a full sync
         col2.remove_notetype(ntid)?;
         let out = ctx.normal_sync(&mut col2).await;
-        assert!(matches!(out.required, SyncActionRequired::FullSyncRequired { .. }));
+        assert!(matches!(
+            out.required,
+            SyncActionRequired::FullSyncRequired { .. }
+        ));
+        Ok(())
+    }
+
+    // Interrupted syncs
+    /////////////////////
+
+    /// Drops the connection once for each listed request or reply.
+    struct FlakyServer {
+        inner: LocalServer,
+        lost_requests: Vec<&'static str>,
+        lost_replies: Vec<&'static str>,
+    }
+
+    fn lose(lost: &mut Vec<&'static str>, method: &str) -> Result<()> {
+        if let Some(idx) = lost.iter().position(|lost| *lost == method) {
+            lost.remove(idx);
+            Err(AnkiError::NetworkError {
+                info: "connection reset".into(),
+                kind: NetworkErrorKind::Other,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl SyncServer for FlakyServer {
+        async fn meta(&self) -> Result<SyncMeta> {
+            self.inner.meta().await
+        }
+
+        async fn start(&mut self, client_usn: Usn, local_is_newer: bool) -> Result<Graves> {
+            self.inner.start(client_usn, local_is_newer).await
+        }
+
+        async fn apply_graves(&mut self, chunk: Graves) -> Result<()> {
+            lose(&mut self.lost_requests, "applyGraves")?;
+            self.inner.apply_graves(chunk).await?;
+            lose(&mut self.lost_replies, "applyGraves")
+        }
+
+        async fn apply_changes(&mut self, changes: UnchunkedChanges) -> Result<UnchunkedChanges> {
+            self.inner.apply_changes(changes).await
+        }
+
+        async fn chunk(&mut self) -> Result<Chunk> {
+            lose(&mut self.lost_requests, "chunk")?;
+            let chunk = self.inner.chunk().await?;
+            lose(&mut self.lost_replies, "chunk")?;
+            Ok(chunk)
+        }
+
+        async fn resume_chunk(&mut self, received: usize) -> Result<Chunk> {
+            lose(&mut self.lost_requests, "chunk")?;
+            let chunk = self.inner.resume_chunk(received).await?;
+            lose(&mut self.lost_replies, "chunk")?;
+            Ok(chunk)
+        }
+
+        async fn apply_chunk(&mut self, chunk: Chunk) -> Result<()> {
+            lose(&mut self.lost_requests, "applyChunk")?;
+            self.inner.apply_chunk(chunk).await?;
+            lose(&mut self.lost_replies, "applyChunk")
+        }
+
+        async fn sanity_check(&mut self, client: SanityCheckCounts) -> Result<SanityCheckOut> {
+            self.inner.sanity_check(client).await
+        }
+
+        async fn sanity_check_ids(&mut self, digests: IDDigests) -> Result<DigestIDs> {
+            self.inner.sanity_check_ids(digests).await
+        }
+
+        async fn finish(&mut self) -> Result<TimestampMillis> {
+            self.inner.finish().await
+        }
+
+        async fn abort(&mut self) -> Result<()> {
+            self.inner.abort().await
+        }
+
+        async fn full_upload(self: Box<Self>, col_path: &Path, can_consume: bool) -> Result<()> {
+            Box::new(self.inner)
+                .full_upload(col_path, can_consume)
+                .await
+        }
+
+        async fn full_download(self: Box<Self>) -> Result<NamedTempFile> {
+            Box::new(self.inner).full_download().await
+        }
+    }
+
+    #[test]
+    fn interrupted_sync() -> Result<()> {
+        let dir = tempdir()?;
+        let server = |lost_requests: &[&'static str], lost_replies: &[&'static str]| {
+            let col = open_col(dir.path(), true, "server.anki2").unwrap();
+            Box::new(FlakyServer {
+                inner: LocalServer::new(col),
+                lost_requests: lost_requests.to_vec(),
+                lost_replies: lost_replies.to_vec(),
+            })
+        };
+        let add_note = |col: &mut Collection, text: &str| {
+            let nt = col.get_notetype_by_name("Basic").unwrap().unwrap();
+            let mut note = nt.new_note();
+            note.fields[0] = text.into();
+            col.add_note(&mut note, DeckID(1)).unwrap();
+            note.id
+        };
+        let mut rt = Runtime::new().unwrap();
+
+        let mut col1 = open_col(dir.path(), false, "col1.anki2")?;
+        let removed = add_note(&mut col1, "removed");
+        rt.block_on(col1.full_upload_inner(server(&[], &[])))?;
+        let col2 = open_col(dir.path(), false, "col2.anki2")?;
+        rt.block_on(col2.full_download_inner(server(&[], &[])))?;
+        let mut col1 = open_col(dir.path(), false, "col1.anki2")?;
+        let mut col2 = open_col(dir.path(), false, "col2.anki2")?;
+
+        // the sync resumes from where the connection dropped
+        let nid1 = add_note(&mut col1, "1");
+        col1.remove_notes(&[removed])?;
+        let server1 = server(&["applyGraves"], &["applyGraves", "chunk", "applyChunk"]);
+        let out = rt.block_on(NormalSyncer::new(&mut col1, server1, norm_progress).sync())?;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+
+        let nid2 = add_note(&mut col2, "2");
+        let server2 = server(&["chunk"], &[]);
+        rt.block_on(NormalSyncer::new(&mut col2, server2, norm_progress).sync())?;
+        rt.block_on(NormalSyncer::new(&mut col1, server(&[], &[]), norm_progress).sync())?;
+        for col in &[&col1, &col2] {
+            assert!(col.storage.get_note(nid1)?.is_some());
+            assert!(col.storage.get_note(nid2)?.is_some());
+            assert!(col.storage.get_note(removed)?.is_none());
+        }
+
+        // if the connection stays down, the local changes are rolled back,
+        // and the next sync resumes from what was received
+        let nid3 = add_note(&mut col1, "3");
+        let lost = ["applyChunk"; 4];
+        let mut syncer = NormalSyncer::new(&mut col1, server(&[], &lost), norm_progress);
+        let out = rt.block_on(syncer.sync());
+        assert!(matches!(out, Err(AnkiError::NetworkError { .. })));
+        assert!(syncer.col.storage.db.is_autocommit());
+        assert_eq!(syncer.col.storage.get_note(nid3)?.unwrap().usn, Usn(-1));
+        let checkpoint: Option<SyncCheckpoint> =
+            syncer.col.get_config_optional(ConfigKey::SyncCheckpoint);
+        assert_eq!(checkpoint.unwrap().chunks.len(), 1);
+        let out = rt.block_on(syncer.sync())?;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        drop(syncer);
+        let checkpoint: Option<SyncCheckpoint> =
+            col1.get_config_optional(ConfigKey::SyncCheckpoint);
+        assert!(checkpoint.is_none());
+        rt.block_on(NormalSyncer::new(&mut col2, server(&[], &[]), norm_progress).sync())?;
+        assert!(col2.storage.get_note(nid3)?.is_some());
+
+        // if the server no longer has the sync open, both sides are rolled
+        // back, and the next sync starts over
+        let nid4 = add_note(&mut col1, "4");
+        let out =
+            rt.block_on(NormalSyncer::new(&mut col1, server(&[], &lost), norm_progress).sync());
+        assert!(matches!(out, Err(AnkiError::NetworkError { .. })));
+        let server_col = open_col(dir.path(), true, "server.anki2")?;
+        assert!(server_col.storage.get_note(nid4)?.is_none());
+        drop(server_col);
+        rt.block_on(NormalSyncer::new(&mut col1, server(&[], &[]), norm_progress).sync())?;
+        rt.block_on(NormalSyncer::new(&mut col2, server(&[], &[]), norm_progress).sync())?;
+        assert!(col2.storage.get_note(nid4)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_conflict_retention() -> Result<()> {
+        let dir = tempdir()?;
+        let col = open_col(dir.path(), false, "col.anki2")?;
+        let log = |mtime| {
+            col.storage.add_sync_conflict(&SyncConflict {
+                id: 0,
+                kind: SyncConflictKind::Card,
+                object_id: 1,
+                mtime,
+                kept_local: true,
+                discarded: "[]".into(),
+            })
+        };
+        log(TimestampSecs(0))?;
+        for _ in 0..1_010 {
+            log(TimestampSecs::now())?;
+        }
+        col.prune_sync_conflicts()?;
+        let kept = col.sync_conflicts()?;
+        assert_eq!(kept.len(), 1_000);
+        assert!(kept.iter().all(|c| c.mtime.0 > 0));
+
+        // scheduling-only differences are not worth logging
+        let card = Card::new(NoteID(1), 0, DeckID(1), 0);
+        let mut entry = CardEntry::from(card.clone());
+        entry.due = 10;
+        entry.ivl = 5;
+        assert!(!conflicts::card_edited(&card, &entry));
+        entry.flags = 1;
+        assert!(conflicts::card_edited(&card, &entry));
+
+        Ok(())
+    }
+
+    #[test]
+    fn schema_replay_requires_server_support() {
+        let local = SyncMeta {
+            modified: TimestampMillis(2),
+            schema: TimestampMillis(1),
+            ..Default::default()
+        };
+        let remote = |schema_replay| SyncMeta {
+            modified: TimestampMillis(1),
+            schema: TimestampMillis(1),
+            schema_replay,
+            ..Default::default()
+        };
+        let required = |remote, pending| local.compared_to_remote(remote, pending).required;
+
+        assert_eq!(
+            required(remote(false), false),
+            SyncActionRequired::NormalSyncRequired
+        );
+        assert_eq!(
+            required(remote(true), true),
+            SyncActionRequired::NormalSyncRequired
+        );
+        assert!(matches!(
+            required(remote(false), true),
+            SyncActionRequired::FullSyncRequired { .. }
+        ));
+    }
+
+    #[test]
+    fn failed_sanity_check() -> Result<()> {
+        let dir = tempdir()?;
+        let server = || {
+            let col = open_col(dir.path(), true, "server.anki2").unwrap();
+            Box::new(LocalServer::new(col))
+        };
+        let add_note = |col: &mut Collection| {
+            let nt = col.get_notetype_by_name("Basic").unwrap().unwrap();
+            let mut note = nt.new_note();
+            note.fields[0] = "front".into();
+            col.add_note(&mut note, DeckID(1)).unwrap();
+            note.id
+        };
+        let mut rt = Runtime::new().unwrap();
+
+        let mut col = open_col(dir.path(), false, "col.anki2")?;
+        let nid = add_note(&mut col);
+        rt.block_on(col.full_upload_inner(server()))?;
+        let mut col = open_col(dir.path(), false, "col.anki2")?;
+        let cid = col.storage.all_card_ids_of_note(nid)?[0];
+
+        // a card goes missing on the server
+        let server_col = open_col(dir.path(), true, "server.anki2")?;
+        server_col.storage.remove_card(cid)?;
+        drop(server_col);
+
+        add_note(&mut col);
+        let out = rt.block_on(NormalSyncer::new(&mut col, server(), norm_progress).sync());
+        assert!(matches!(
+            out,
+            Err(AnkiError::SyncError {
+                kind: SyncErrorKind::DatabaseCheckRequired,
+                ..
+            })
+        ));
+        let report = col.last_sanity_check().unwrap();
+        assert!(!report.is_scheduler_divergence());
+        assert_eq!(
+            report.counts,
+            vec![TableCountDiff {
+                table: "cards",
+                local: 2,
+                remote: 1
+            }]
+        );
+        assert_eq!(
+            report.missing,
+            vec![MissingIDs {
+                table: "cards",
+                local_only: vec![cid.0],
+                remote_only: vec![]
+            }]
+        );
+        assert_eq!(report.decks.len(), 1);
+        assert_eq!(report.decks[0].name, "Default");
+        assert_eq!(report.decks[0].local.new, 2);
+        assert_eq!(report.decks[0].remote.new, 1);
+
         Ok(())
     }
 }
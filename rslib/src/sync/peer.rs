@@ -0,0 +1,303 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Syncing with a profile shared by a peer, instead of AnkiWeb. The peer runs
+//! a PeerServer (see http_server.rs), and the normal and media sync protocols
+//! are used as they are with AnkiWeb, so the first sync with a new peer is a
+//! full one.
+//!
+//! Another profile on the same device is synced with by sharing it on the
+//! loopback interface with LocalPeer.
+//!
+//! Clients only remember the usn of the last server they synced with, so
+//! each server identifies itself in its meta reply, and switching between
+//! AnkiWeb and peers, or from one peer to another, requires a full sync.
+//! A profile that is served keeps its usns for the server it syncs with
+//! itself, and tracks the changes its peers need with separate usns, so
+//! serving it does not affect its own syncs.
+//!
+//! The password and host key are sent over plain HTTP, so peers are only
+//! logged in to on the local network. Host keys are valid until the peer
+//! stops sharing its profile.
+
+use super::{
+    http_server::{PeerServer, PeerServerConfig},
+    FullSyncProgressFn, HTTPSyncClient, NormalSyncProgress, NormalSyncer, SyncKey, SyncOutput,
+};
+use crate::{
+    media::{
+        sync::{MediaSyncProgress, MediaSyncer},
+        MediaManager,
+    },
+    notes::guid,
+    prelude::*,
+};
+use reqwest::Url;
+use std::{net::IpAddr, path::Path};
+
+/// A peer that has been logged in to.
+#[derive(Clone)]
+pub struct SyncPeer {
+    /// The URL the peer is served on, eg `http://192.168.1.5:8080/`.
+    pub endpoint: String,
+    pub hkey: String,
+}
+
+impl SyncPeer {
+    /// `endpoint` must use https, or be on the local network.
+    pub async fn login(endpoint: &str, username: &str, password: &str) -> Result<SyncPeer> {
+        let endpoint = if endpoint.ends_with('/') {
+            endpoint.to_string()
+        } else {
+            format!("{}/", endpoint)
+        };
+        if !is_private_endpoint(&endpoint) {
+            return Err(AnkiError::invalid_input(
+                "peers outside the local network require https",
+            ));
+        }
+        let mut remote = HTTPSyncClient::new(None, 0);
+        remote.set_endpoint(format!("{}sync/", endpoint));
+        remote.login(username, password).await?;
+        Ok(SyncPeer {
+            endpoint,
+            hkey: remote.hkey().to_string(),
+        })
+    }
+
+    fn client(&self) -> HTTPSyncClient {
+        let mut client = HTTPSyncClient::new(Some(self.hkey.clone()), 0);
+        client.set_endpoint(format!("{}sync/", self.endpoint));
+        client
+    }
+}
+
+/// True if credentials can be sent to the endpoint: it uses https, or
+/// plain http to a loopback, private or link-local address.
+fn is_private_endpoint(endpoint: &str) -> bool {
+    let url = match Url::parse(endpoint) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    if url.scheme() == "https" {
+        return true;
+    }
+    let host = url.host_str().unwrap_or_default();
+    match host.trim_matches(|c| c == '[' || c == ']').parse() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => ip.is_loopback(),
+        Err(_) => host == "localhost" || host.ends_with(".local"),
+    }
+}
+
+/// Another profile on this device, shared on the loopback interface while
+/// this is kept, so it can be synced with like a peer on the network.
+pub struct LocalPeer {
+    peer: SyncPeer,
+    _server: PeerServer,
+}
+
+impl LocalPeer {
+    /// The profile's collection should not be open elsewhere.
+    pub fn open(folder: &Path, log: Logger) -> Result<LocalPeer> {
+        let config = PeerServerConfig {
+            host: "127.0.0.1".into(),
+            port: 0,
+            folder: folder.into(),
+            username: "local".into(),
+            password: guid(),
+        };
+        let server = PeerServer::start(&config, log)?;
+        let peer = SyncPeer {
+            endpoint: server.endpoint(),
+            hkey: server.host_key()?,
+        };
+        Ok(LocalPeer {
+            peer,
+            _server: server,
+        })
+    }
+
+    pub fn peer(&self) -> &SyncPeer {
+        &self.peer
+    }
+}
+
+impl Collection {
+    pub async fn normal_sync_with_peer<F>(
+        &mut self,
+        peer: &SyncPeer,
+        progress_fn: F,
+    ) -> Result<SyncOutput>
+    where
+        F: FnMut(NormalSyncProgress, bool),
+    {
+        NormalSyncer::new(self, Box::new(peer.client()), progress_fn)
+            .sync()
+            .await
+    }
+
+    /// Replace the peer's collection with this one. Caller must re-open
+    /// afterwards.
+    pub async fn full_upload_to_peer(
+        self,
+        peer: &SyncPeer,
+        progress_fn: FullSyncProgressFn,
+    ) -> Result<()> {
+        let mut server = peer.client();
+        server.set_full_sync_progress_fn(Some(progress_fn));
+        self.full_upload_inner(Box::new(server)).await
+    }
+
+    /// Replace this collection with the peer's. Caller must re-open
+    /// afterwards.
+    pub async fn full_download_from_peer(
+        self,
+        peer: &SyncPeer,
+        progress_fn: FullSyncProgressFn,
+    ) -> Result<()> {
+        let mut server = peer.client();
+        server.set_full_sync_progress_fn(Some(progress_fn));
+        self.full_download_inner(Box::new(server)).await
+    }
+}
+
+impl MediaManager {
+    pub async fn sync_media_with_peer<'a, F>(
+        &'a self,
+        progress: F,
+        peer: &'a SyncPeer,
+        key: Option<SyncKey>,
+        log: Logger,
+    ) -> Result<()>
+    where
+        F: FnMut(MediaSyncProgress) -> bool,
+    {
+        let mut syncer = MediaSyncer::new(self, progress, 0, log);
+        syncer.set_endpoint(format!("{}msync/", peer.endpoint));
+        syncer.set_encryption_key(key);
+        syncer.sync(&peer.hkey).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collection::open_collection, i18n::I18n, log, sync::SyncActionRequired};
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn private_endpoints() {
+        assert!(is_private_endpoint("http://192.168.1.5:8080/"));
+        assert!(is_private_endpoint("http://127.0.0.1:8080/"));
+        assert!(is_private_endpoint("http://desktop.local/"));
+        assert!(is_private_endpoint("https://example.com/"));
+        assert!(!is_private_endpoint("http://example.com/"));
+        assert!(!is_private_endpoint("http://8.8.8.8/"));
+    }
+
+    fn open(folder: &Path) -> Result<Collection> {
+        fs::create_dir_all(folder.join("collection.media"))?;
+        let i18n = I18n::new(&[""], "", log::terminal());
+        open_collection(
+            folder.join("collection.anki2"),
+            folder.join("collection.media"),
+            folder.join("collection.media.db2"),
+            false,
+            i18n,
+            log::terminal(),
+        )
+    }
+
+    fn add_note(folder: &Path, text: &str) -> Result<()> {
+        let mut col = open(folder)?;
+        let nt = col.get_notetype_by_name("Basic")?.unwrap();
+        let mut note = nt.new_note();
+        note.fields[0] = text.into();
+        col.add_note(&mut note, DeckID(1))?;
+        col.close(false)
+    }
+
+    fn media(folder: &Path) -> Result<MediaManager> {
+        MediaManager::new(
+            folder.join("collection.media"),
+            folder.join("collection.media.db2"),
+        )
+    }
+
+    #[test]
+    fn local_peer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let desktop = dir.path().join("desktop");
+        let laptop = dir.path().join("laptop");
+        add_note(&desktop, "desktop")?;
+        let mut rt = Runtime::new().unwrap();
+
+        // the first sync with a new peer is a full one
+        let peer = LocalPeer::open(&laptop, log::terminal())?;
+        let mut col = open(&desktop)?;
+        let out = rt.block_on(col.normal_sync_with_peer(peer.peer(), |_, _| ()))?;
+        assert_eq!(
+            out.required,
+            SyncActionRequired::FullSyncRequired {
+                upload_ok: true,
+                download_ok: false
+            }
+        );
+        rt.block_on(col.full_upload_to_peer(peer.peer(), Box::new(|_, _| ())))?;
+        drop(peer);
+
+        // both sides are then changed, the laptop while it is not shared
+        add_note(&desktop, "desktop2")?;
+        add_note(&laptop, "laptop")?;
+        fs::write(desktop.join("collection.media").join("d.txt"), "d")?;
+        fs::write(laptop.join("collection.media").join("l.txt"), "l")?;
+
+        let peer = LocalPeer::open(&laptop, log::terminal())?;
+        let mut col = open(&desktop)?;
+        let out = rt.block_on(col.normal_sync_with_peer(peer.peer(), |_, _| ()))?;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        assert_eq!(col.storage.total_notes()?, 3);
+        rt.block_on(media(&desktop)?.sync_media_with_peer(
+            |_| true,
+            peer.peer(),
+            None,
+            log::terminal(),
+        ))?;
+        drop(peer);
+
+        assert_eq!(open(&laptop)?.storage.total_notes()?, 3);
+        for folder in &[&desktop, &laptop] {
+            let media_folder = folder.join("collection.media");
+            assert_eq!(fs::read_to_string(media_folder.join("d.txt"))?, "d");
+            assert_eq!(fs::read_to_string(media_folder.join("l.txt"))?, "l");
+        }
+
+        // the laptop's changes, and those it received, remain pending for its
+        // own server
+        let pending: Vec<NoteID> =
+            open(&laptop)?
+                .storage
+                .objects_pending_sync("notes", Usn(-1), false)?;
+        assert_eq!(pending.len(), 2);
+
+        // later changes are sent without another full sync
+        add_note(&laptop, "laptop2")?;
+        let peer = LocalPeer::open(&laptop, log::terminal())?;
+        let out = rt.block_on(col.normal_sync_with_peer(peer.peer(), |_, _| ()))?;
+        assert_eq!(out.required, SyncActionRequired::NoChanges);
+        assert_eq!(col.storage.total_notes()?, 4);
+        drop(peer);
+        col.close(false)?;
+        assert_eq!(
+            open(&laptop)?
+                .storage
+                .objects_pending_sync::<NoteID>("notes", Usn(-1), false)?
+                .len(),
+            3
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Resuming a normal sync when the connection to the server drops part way
+//! through, and rolling it back when it can't be resumed.
+//!
+//! Servers that advertise `resume` in their meta reply hold a sync open until
+//! it is finished or aborted, so a request whose connection dropped can be
+//! made again. Graves and chunks are applied on both sides with add-if-newer
+//! semantics, so sending one the server already received does no harm, and
+//! chunks from the server are fetched with resume_chunk(), which sends the
+//! last one again if its reply was lost.
+//!
+//! If the connection stays down, the local transaction is rolled back, so the
+//! collection is never left partly merged, but the server's graves and the
+//! chunks received so far are saved as a checkpoint, and the server's sync is
+//! left open. The next sync with the same server and usns resumes it: the
+//! saved graves and chunks are applied again, local changes are sent again,
+//! and the server continues with the chunk after the last one received. If
+//! the server no longer has the sync open, the sync starts over.
+//!
+//! Other servers, like AnkiWeb, can't resume a sync, so it is rolled back on
+//! both sides as soon as a request fails.
+
+use super::{Chunk, Graves, NormalSyncProgress, NormalSyncer, SyncState};
+use crate::{config::ConfigKey, err::NetworkErrorKind, prelude::*};
+use serde::{Deserialize, Serialize};
+
+/// The number of times a request is repeated after its connection drops.
+const MAX_RETRIES: usize = 3;
+
+/// How far a normal sync has got. Requests the client sends are simply
+/// repeated, so only what was received from the server is kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub(super) struct SyncCheckpoint {
+    /// The sync this is for; a later one only resumes it if they match.
+    server_id: String,
+    usn_at_last_sync: Usn,
+    latest_usn: Usn,
+    /// The server's graves, once start() has returned them. They are applied
+    /// again from here, as start() would begin a new sync.
+    pub graves: Option<Graves>,
+    /// The chunks received from the server, as they were sent.
+    pub chunks: Vec<Chunk>,
+}
+
+impl SyncCheckpoint {
+    fn new(state: &SyncState) -> Self {
+        SyncCheckpoint {
+            server_id: state.server_id.clone(),
+            usn_at_last_sync: state.usn_at_last_sync,
+            latest_usn: state.latest_usn,
+            ..Default::default()
+        }
+    }
+
+    fn matches(&self, state: &SyncState) -> bool {
+        self.server_id == state.server_id
+            && self.usn_at_last_sync == state.usn_at_last_sync
+            && self.latest_usn == state.latest_usn
+    }
+}
+
+impl<F> NormalSyncer<'_, F>
+where
+    F: FnMut(NormalSyncProgress, bool),
+{
+    /// Continue from the saved checkpoint if it is for this sync, and the
+    /// server can resume it. Returns true if resuming.
+    pub(super) fn load_checkpoint(&mut self, state: &SyncState) -> bool {
+        self.resume = state.resume;
+        let saved: Option<SyncCheckpoint> = self.col.get_config_optional(ConfigKey::SyncCheckpoint);
+        self.checkpoint = match saved {
+            Some(saved) if state.resume && saved.matches(state) => saved,
+            _ => SyncCheckpoint::new(state),
+        };
+        let resuming = self.checkpoint.graves.is_some();
+        if resuming {
+            debug!(self.col.log, "resuming sync";
+                "chunks received"=>self.checkpoint.chunks.len());
+        }
+        resuming
+    }
+
+    pub(super) async fn send_graves(&mut self, chunk: Graves) -> Result<()> {
+        let mut attempts = 0;
+        loop {
+            match self.remote.apply_graves(chunk.clone()).await {
+                Ok(()) => break,
+                Err(err) => self.check_retry(err, &mut attempts)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) async fn send_chunk(&mut self, chunk: Chunk) -> Result<()> {
+        let mut attempts = 0;
+        loop {
+            match self.remote.apply_chunk(chunk.clone()).await {
+                Ok(()) => break,
+                Err(err) => self.check_retry(err, &mut attempts)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch the server's next chunk. The caller should add it to the
+    /// checkpoint before applying it.
+    pub(super) async fn receive_chunk(&mut self) -> Result<Chunk> {
+        if !self.resume {
+            return self.remote.chunk().await;
+        }
+        let mut attempts = 0;
+        loop {
+            let received = self.checkpoint.chunks.len();
+            match self.remote.resume_chunk(received).await {
+                Ok(chunk) => return Ok(chunk),
+                Err(err) => self.check_retry(err, &mut attempts)?,
+            }
+        }
+    }
+
+    /// Returns the error unless it was caused by a dropped connection, and
+    /// the request may be repeated again.
+    fn check_retry(&self, err: AnkiError, attempts: &mut usize) -> Result<()> {
+        *attempts += 1;
+        if !self.resume || !is_dropped_connection(&err) || *attempts > MAX_RETRIES {
+            return Err(err);
+        }
+        debug!(self.col.log, "connection dropped; retrying";
+            "chunks received"=>self.checkpoint.chunks.len(), "error"=>?err);
+        Ok(())
+    }
+
+    /// Undo the local changes of a failed sync. The local transaction is
+    /// always rolled back, so the collection is never left partly merged. If
+    /// the connection dropped and the server can resume, the checkpoint is
+    /// saved for the next sync; otherwise the server is asked to undo its
+    /// own changes.
+    pub(super) async fn roll_back(&mut self, err: &AnkiError) -> Result<()> {
+        let resumable =
+            self.resume && is_dropped_connection(err) && self.checkpoint.graves.is_some();
+        if !resumable {
+            debug!(self.col.log, "rolling back");
+            if let Err(err) = self.remote.abort().await {
+                debug!(self.col.log, "abort failed"; "error"=>?err);
+            }
+        }
+        self.col.storage.rollback_trx()?;
+        if resumable {
+            debug!(self.col.log, "saving checkpoint";
+                "chunks received"=>self.checkpoint.chunks.len());
+            self.col
+                .set_config(ConfigKey::SyncCheckpoint, &self.checkpoint)?;
+        } else {
+            self.col.remove_config(ConfigKey::SyncCheckpoint)?;
+        }
+        self.checkpoint = SyncCheckpoint::default();
+        self.conflicts.clear();
+        self.changed_notetypes.clear();
+        Ok(())
+    }
+}
+
+fn is_dropped_connection(err: &AnkiError) -> bool {
+    match err {
+        AnkiError::NetworkError { kind, .. } => *kind != NetworkErrorKind::ProxyAuth,
+        _ => false,
+    }
+}
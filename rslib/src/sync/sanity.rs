@@ -0,0 +1,393 @@
+// Copyright: Ankitects Pty Ltd and contributors
+// License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
+
+//! Details of a failed sanity check, so it can be told whether the two sides
+//! schedule cards differently, or whether objects are missing on one side,
+//! before a full sync is forced.
+//!
+//! When the check fails, our own server includes its due counts for each
+//! deck in its reply. The client then sends it a digest of its IDs in each
+//! table whose count differs: the IDs are hashed into buckets, about
+//! IDS_PER_BUCKET to a bucket, and each bucket summed. The server replies
+//! with its IDs in the buckets whose sums differ, up to MAX_REPLY_IDS.
+//! AnkiWeb does not support this, in which case only the counts are
+//! compared.
+
+use super::{SanityCheckCounts, SanityCheckDueCounts};
+use crate::{backend_proto::DeckTreeNode, prelude::*};
+use serde::{Deserialize, Serialize};
+use serde_tuple::Serialize_tuple;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+/// The most IDs listed for each table and side.
+const MAX_SAMPLE_IDS: usize = 10;
+/// The number of IDs hashed into each bucket of a digest, on average.
+const IDS_PER_BUCKET: usize = 8;
+/// The most buckets a digest may have.
+const MAX_DIGEST_BUCKETS: usize = 1 << 16;
+/// The tables the sanity check counts.
+const TABLES: [&str; 7] = [
+    "cards",
+    "notes",
+    "revlog",
+    "graves",
+    "notetypes",
+    "decks",
+    "deck_config",
+];
+/// The most IDs the server includes in its reply, across all tables.
+const MAX_REPLY_IDS: usize = 5_000;
+
+/// The bucket sums of each table's IDs, keyed by table name. The number of
+/// buckets is chosen by the client.
+pub type IDDigests = HashMap<String, Vec<u64>>;
+
+/// The server's IDs in the buckets that differ, keyed by table name.
+pub type DigestIDs = HashMap<String, BucketIDs>;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct SanityCheckDetails {
+    pub decks: Vec<DeckDueCounts>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct BucketIDs {
+    /// The buckets whose IDs are listed in full. Once the reply reaches
+    /// MAX_REPLY_IDS, the remaining buckets are left out.
+    pub buckets: Vec<usize>,
+    pub ids: Vec<i64>,
+}
+
+#[derive(Serialize_tuple, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeckDueCounts {
+    pub deck_id: DeckID,
+    /// The full name, including parents.
+    pub name: String,
+    pub counts: SanityCheckDueCounts,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SanityCheckReport {
+    /// Decks on both sides whose due counts differ.
+    pub decks: Vec<DeckCountsDiff>,
+    pub counts: Vec<TableCountDiff>,
+    /// A sample of the IDs found on only one side, for each table whose
+    /// count differs.
+    pub missing: Vec<MissingIDs>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DeckCountsDiff {
+    pub deck_id: DeckID,
+    pub name: String,
+    pub local: SanityCheckDueCounts,
+    pub remote: SanityCheckDueCounts,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TableCountDiff {
+    pub table: &'static str,
+    pub local: u32,
+    pub remote: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MissingIDs {
+    pub table: &'static str,
+    pub local_only: Vec<i64>,
+    pub remote_only: Vec<i64>,
+}
+
+impl SanityCheckReport {
+    /// True if the same objects exist on both sides, but cards are due
+    /// differently.
+    pub fn is_scheduler_divergence(&self) -> bool {
+        self.counts.is_empty() && !self.decks.is_empty()
+    }
+}
+
+impl fmt::Display for SanityCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for deck in &self.decks {
+            writeln!(
+                f,
+                "deck {} ({}): local {:?}, remote {:?}",
+                deck.name, deck.deck_id, deck.local, deck.remote
+            )?;
+        }
+        for diff in &self.counts {
+            writeln!(
+                f,
+                "{}: local {}, remote {}",
+                diff.table, diff.local, diff.remote
+            )?;
+        }
+        for missing in &self.missing {
+            writeln!(
+                f,
+                "{} only local: {:?}, only remote: {:?}",
+                missing.table, missing.local_only, missing.remote_only
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SanityCheckCounts {
+    fn table_counts(&self) -> [(&'static str, u32); 7] {
+        [
+            ("cards", self.cards),
+            ("notes", self.notes),
+            ("revlog", self.revlog),
+            ("graves", self.graves),
+            ("notetypes", self.notetypes),
+            ("decks", self.decks),
+            ("deck_config", self.deck_config),
+        ]
+    }
+
+    fn differing_tables(&self, other: &SanityCheckCounts) -> Vec<TableCountDiff> {
+        self.table_counts()
+            .iter()
+            .zip(other.table_counts().iter())
+            .filter(|(local, remote)| local.1 != remote.1)
+            .map(|(local, remote)| TableCountDiff {
+                table: local.0,
+                local: local.1,
+                remote: remote.1,
+            })
+            .collect()
+    }
+}
+
+impl Collection {
+    /// Details of the last sanity check that failed during a normal sync.
+    pub fn last_sanity_check(&self) -> Option<&SanityCheckReport> {
+        self.state.last_sanity_check.as_ref()
+    }
+
+    /// Sent to servers that support it after a failed sanity check, for the
+    /// tables whose counts differ.
+    pub(super) fn sanity_check_digests(&self, diffs: &[TableCountDiff]) -> Result<IDDigests> {
+        diffs
+            .iter()
+            .map(|diff| {
+                let ids = self.storage.sanity_check_ids(diff.table)?;
+                let buckets = digest_buckets(diff.local.max(diff.remote));
+                Ok((diff.table.to_string(), digest(&ids, buckets)))
+            })
+            .collect()
+    }
+
+    /// Gathered by the server when the client's counts differ from its own.
+    pub(crate) fn sanity_check_details(&mut self) -> Result<SanityCheckDetails> {
+        Ok(SanityCheckDetails {
+            decks: self.deck_due_counts()?,
+        })
+    }
+
+    /// The server's reply to the client's digests.
+    pub(crate) fn sanity_check_digest_ids(&self, digests: &IDDigests) -> Result<DigestIDs> {
+        let mut out = HashMap::new();
+        let mut remaining = MAX_REPLY_IDS;
+        // the client's table names are not trusted
+        for table in &TABLES {
+            if let Some(client_digest) = digests.get(*table) {
+                if client_digest.is_empty() || client_digest.len() > MAX_DIGEST_BUCKETS {
+                    return Err(AnkiError::invalid_input("invalid digest"));
+                }
+                let server_ids = self.storage.sanity_check_ids(table)?;
+                let listed = ids_in_differing_buckets(&server_ids, client_digest, remaining);
+                remaining -= listed.ids.len();
+                out.insert(table.to_string(), listed);
+            }
+        }
+        Ok(out)
+    }
+
+    fn deck_due_counts(&mut self) -> Result<Vec<DeckDueCounts>> {
+        let tree = self.deck_tree(Some(TimestampSecs::now()), None)?;
+        let mut out = vec![];
+        for child in &tree.children {
+            add_deck_counts(child, "", &mut out);
+        }
+        Ok(out)
+    }
+
+    /// Compare the local collection with the server's reply to a failed
+    /// sanity check.
+    pub(super) fn sanity_check_report(
+        &mut self,
+        local: &SanityCheckCounts,
+        remote: Option<&SanityCheckCounts>,
+        details: Option<SanityCheckDetails>,
+    ) -> Result<SanityCheckReport> {
+        let mut report = SanityCheckReport::default();
+        if let Some(remote) = remote {
+            report.counts = local.differing_tables(remote);
+        }
+        if let Some(details) = details {
+            let remote_decks: HashMap<_, _> = details
+                .decks
+                .into_iter()
+                .map(|deck| (deck.deck_id, deck.counts))
+                .collect();
+            for deck in self.deck_due_counts()? {
+                if let Some(remote) = remote_decks.get(&deck.deck_id) {
+                    if *remote != deck.counts {
+                        report.decks.push(DeckCountsDiff {
+                            deck_id: deck.deck_id,
+                            name: deck.name,
+                            local: deck.counts,
+                            remote: *remote,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Compare the local IDs with those the server listed for `digests`.
+    pub(super) fn add_missing_ids(
+        &self,
+        report: &mut SanityCheckReport,
+        digests: &IDDigests,
+        mut remote_ids: DigestIDs,
+    ) -> Result<()> {
+        for diff in &report.counts {
+            if let (Some(digest), Some(remote)) =
+                (digests.get(diff.table), remote_ids.remove(diff.table))
+            {
+                // only the listed buckets can be compared
+                let buckets: HashSet<_> = remote.buckets.iter().collect();
+                let local_ids: Vec<_> = self
+                    .storage
+                    .sanity_check_ids(diff.table)?
+                    .into_iter()
+                    .filter(|id| buckets.contains(&bucket(*id, digest.len()).0))
+                    .collect();
+                report.missing.push(MissingIDs {
+                    table: diff.table,
+                    local_only: sample_missing(&local_ids, &remote.ids),
+                    remote_only: sample_missing(&remote.ids, &local_ids),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_deck_counts(node: &DeckTreeNode, parent_name: &str, out: &mut Vec<DeckDueCounts>) {
+    let name = if parent_name.is_empty() {
+        node.name.clone()
+    } else {
+        format!("{}::{}", parent_name, node.name)
+    };
+    for child in &node.children {
+        add_deck_counts(child, &name, out);
+    }
+    out.push(DeckDueCounts {
+        deck_id: DeckID(node.deck_id),
+        name,
+        counts: SanityCheckDueCounts {
+            new: node.new_count,
+            learn: node.learn_count,
+            review: node.review_count,
+        },
+    });
+}
+
+/// Up to MAX_SAMPLE_IDS of the IDs in `ids` that are not in `other`.
+fn sample_missing(ids: &[i64], other: &[i64]) -> Vec<i64> {
+    let other: HashSet<_> = other.iter().collect();
+    ids.iter()
+        .filter(|id| !other.contains(id))
+        .take(MAX_SAMPLE_IDS)
+        .cloned()
+        .collect()
+}
+
+/// The number of buckets a digest of a table with `count` IDs has.
+fn digest_buckets(count: u32) -> usize {
+    (count as usize / IDS_PER_BUCKET)
+        .max(1)
+        .min(MAX_DIGEST_BUCKETS)
+}
+
+/// The bucket an ID is hashed into, and the value it adds to the bucket's
+/// sum.
+fn bucket(id: i64, buckets: usize) -> (usize, u64) {
+    // splitmix64's finalizer, so sequential IDs are spread evenly
+    let mut hash = id as u64;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    ((hash % buckets as u64) as usize, hash)
+}
+
+fn digest(ids: &[i64], buckets: usize) -> Vec<u64> {
+    let mut sums = vec![0u64; buckets];
+    for &id in ids {
+        let (idx, hash) = bucket(id, buckets);
+        sums[idx] = sums[idx].wrapping_add(hash);
+    }
+    sums
+}
+
+/// The IDs in the buckets whose sums differ from `other`, listing whole
+/// buckets until there would be more than `limit` IDs.
+fn ids_in_differing_buckets(ids: &[i64], other: &[u64], limit: usize) -> BucketIDs {
+    let sums = digest(ids, other.len());
+    let mut by_bucket: HashMap<usize, Vec<i64>> = HashMap::new();
+    for &id in ids {
+        by_bucket
+            .entry(bucket(id, other.len()).0)
+            .or_default()
+            .push(id);
+    }
+    let mut out = BucketIDs::default();
+    for (idx, sum) in sums.iter().enumerate() {
+        if other.get(idx) == Some(sum) {
+            continue;
+        }
+        let bucket_ids = by_bucket.remove(&idx).unwrap_or_default();
+        if out.ids.len() + bucket_ids.len() > limit {
+            break;
+        }
+        out.buckets.push(idx);
+        out.ids.extend(bucket_ids);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn differing_buckets() {
+        let server: Vec<i64> = (1..=1000).collect();
+        let mut client = server.clone();
+        client.retain(|&id| id != 10 && id != 20);
+        client.push(5000);
+        let client_digest = digest(&client, digest_buckets(server.len() as u32));
+
+        let listed = ids_in_differing_buckets(&server, &client_digest, MAX_REPLY_IDS);
+        assert!(listed.buckets.len() <= 3);
+        assert!(listed.ids.contains(&10) && listed.ids.contains(&20));
+        assert!(listed.ids.len() < server.len() / 10);
+
+        // buckets stay small as tables grow
+        assert_eq!(digest_buckets(1_000_000), 125_000.min(MAX_DIGEST_BUCKETS));
+        assert_eq!(digest_buckets(3), 1);
+
+        // the reply is capped
+        let listed = ids_in_differing_buckets(&server, &client_digest, 0);
+        assert!(listed.buckets.is_empty());
+        assert!(listed.ids.is_empty());
+    }
+}
@@ -1,11 +1,19 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::{
+    backup::BackupLimits,
+    collection::open_collection,
+    config::ConfigKey,
+    err::SyncErrorKind,
+    notes::guid,
     prelude::*,
     storage::open_and_check_sqlite_file,
     sync::{
-        Chunk, Graves, SanityCheckCounts, SanityCheckOut, SanityCheckStatus, SyncMeta,
-        UnchunkedChanges, Usn,
+        set_config_in_file, Chunk, DigestIDs, Graves, IDDigests, SanityCheckCounts, SanityCheckOut,
+        SanityCheckStatus, SyncMeta, UnchunkedChanges, Usn,
     },
 };
 use async_trait::async_trait;
@@ -20,8 +28,15 @@ pub trait SyncServer {
     async fn apply_changes(&mut self, client_changes: UnchunkedChanges)
         -> Result<UnchunkedChanges>;
     async fn chunk(&mut self) -> Result<Chunk>;
+    /// Called instead of chunk() when the reply to it was lost. `received` is
+    /// the number of chunks the client has applied, so the server can tell
+    /// whether to send its last chunk again, or the next one.
+    async fn resume_chunk(&mut self, received: usize) -> Result<Chunk>;
     async fn apply_chunk(&mut self, client_chunk: Chunk) -> Result<()>;
     async fn sanity_check(&mut self, client: SanityCheckCounts) -> Result<SanityCheckOut>;
+    /// Called after a failed sanity check, on servers that advertise support
+    /// for it, with digests of the tables whose counts differ.
+    async fn sanity_check_ids(&mut self, digests: IDDigests) -> Result<DigestIDs>;
     async fn finish(&mut self) -> Result<TimestampMillis>;
     async fn abort(&mut self) -> Result<()>;
 
@@ -33,6 +48,10 @@ pub trait SyncServer {
 
 pub struct LocalServer {
     col: Collection,
+    /// True if the collection is a profile shared with peers, which keeps
+    /// its usns for the server it syncs with itself. Its changes are
+    /// recorded with separate usns instead, see mark_peer_changes().
+    peers: bool,
 
     // The current sync protocol is stateful, so unfortunately we need to
     // retain a bunch of information across requests. These are set either
@@ -44,6 +63,10 @@ pub struct LocalServer {
     client_is_newer: bool,
     /// Set on the first call to chunk()
     server_chunk_ids: Option<ChunkableIDs>,
+    /// The number of chunks sent, and the last one, in case its reply is
+    /// lost.
+    sent_chunks: usize,
+    last_chunk: Option<Chunk>,
 }
 
 impl LocalServer {
@@ -52,18 +75,101 @@ impl LocalServer {
         assert!(col.server);
         LocalServer {
             col,
+            peers: false,
             server_usn: Usn(0),
             client_usn: Usn(0),
             client_is_newer: false,
             server_chunk_ids: None,
+            sent_chunks: 0,
+            last_chunk: None,
+        }
+    }
+
+    /// Serve a profile to peers. The collection should have been opened as
+    /// a server.
+    pub fn new_for_peers(mut col: Collection) -> LocalServer {
+        col.state.serving_peers = true;
+        LocalServer {
+            peers: true,
+            ..LocalServer::new(col)
         }
     }
 
     /// Consumes self and returns the stored collection. If a sync has begun, caller must ensure they
     /// call .finish() or .abort() before calling this.
-    pub fn into_col(self) -> Collection {
+    pub fn into_col(mut self) -> Collection {
+        self.col.state.serving_peers = false;
         self.col
     }
+
+    /// The usn objects received from the client are stored with. When
+    /// serving peers, they remain pending for the collection's own server.
+    fn received_usn(&self) -> Usn {
+        if self.peers {
+            Usn(-1)
+        } else {
+            self.server_usn
+        }
+    }
+
+    /// Fail like remote servers do when a sync is continued that was never
+    /// started, or was since finished or aborted.
+    fn check_sync_open(&self) -> Result<()> {
+        if self.col.storage.db.is_autocommit() {
+            Err(AnkiError::SyncError {
+                info: String::new(),
+                kind: SyncErrorKind::SyncNotStarted,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Collection {
+    /// Identifies the collection to the clients it is served to, so they can
+    /// tell it apart from AnkiWeb and other servers. Created on first use.
+    fn server_id(&self) -> Result<String> {
+        if let Some(id) = self.get_config_optional(ConfigKey::ServerID) {
+            return Ok(id);
+        }
+        let id = guid();
+        self.set_config(ConfigKey::ServerID, &id)?;
+        Ok(id)
+    }
+
+    /// The usn the collection is served to peers at.
+    fn peer_usn(&self) -> Usn {
+        self.get_config_default(ConfigKey::PeerUsn)
+    }
+}
+
+impl UnchunkedChanges {
+    pub(super) fn set_usns(&mut self, usn: Usn) {
+        for nt in &mut self.notetypes {
+            nt.usn = usn;
+        }
+        for deck in &mut self.decks_and_config.decks {
+            deck.common_mut().usn = usn;
+        }
+        for conf in &mut self.decks_and_config.config {
+            conf.usn = usn;
+        }
+    }
+}
+
+impl Chunk {
+    fn set_usns(&mut self, usn: Usn) {
+        for entry in &mut self.revlog {
+            entry.usn = usn;
+        }
+        for entry in &mut self.cards {
+            entry.usn = usn;
+        }
+        for entry in &mut self.notes {
+            entry.usn = usn;
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -72,36 +178,59 @@ impl SyncServer for LocalServer {
         Ok(SyncMeta {
             modified: self.col.storage.get_modified_time()?,
             schema: self.col.storage.get_schema_mtime()?,
-            usn: self.col.storage.usn(true)?,
+            usn: if self.peers {
+                self.col.peer_usn()
+            } else {
+                self.col.storage.usn(true)?
+            },
             current_time: TimestampSecs::now(),
             server_message: String::new(),
             should_continue: true,
             host_number: 0,
             empty: !self.col.storage.have_at_least_one_card()?,
+            schema_replay: true,
+            sanity_digests: true,
+            resume: true,
+            server_id: self.col.server_id()?,
         })
     }
 
     async fn start(&mut self, client_usn: Usn, client_is_newer: bool) -> Result<Graves> {
-        self.server_usn = self.col.usn()?;
         self.client_usn = client_usn;
         self.client_is_newer = client_is_newer;
 
         self.col.storage.begin_rust_trx()?;
-        self.col.storage.pending_graves(client_usn)
+        if self.peers {
+            // the profile may have been edited, or synced with its own
+            // server, since it was last served
+            self.server_usn = self.col.peer_usn();
+            self.col.storage.mark_peer_changes(self.server_usn)?;
+        } else {
+            self.server_usn = self.col.usn()?;
+        }
+        self.col.storage.pending_graves(client_usn, self.peers)
     }
 
     async fn apply_graves(&mut self, client_chunk: Graves) -> Result<()> {
-        self.col.apply_graves(client_chunk, self.server_usn)
+        self.check_sync_open()?;
+        self.col.apply_graves(client_chunk, self.received_usn())
     }
 
     async fn apply_changes(
         &mut self,
-        client_changes: UnchunkedChanges,
+        mut client_changes: UnchunkedChanges,
     ) -> Result<UnchunkedChanges> {
-        let server_changes =
+        self.check_sync_open()?;
+        let mut server_changes =
             self.col
                 .local_unchunked_changes(self.client_usn, None, !self.client_is_newer)?;
-        self.col.apply_changes(client_changes, self.server_usn)?;
+        if self.peers {
+            server_changes.set_usns(self.server_usn);
+            client_changes.set_usns(self.received_usn());
+        }
+        // clients generate any cards the schema changes require
+        self.col
+            .apply_changes(client_changes, self.received_usn(), self.client_usn)?;
         Ok(server_changes)
     }
 
@@ -110,33 +239,87 @@ impl SyncServer for LocalServer {
             self.server_chunk_ids = Some(self.col.get_chunkable_ids(self.client_usn)?);
         }
 
-        self.col
-            .get_chunk(self.server_chunk_ids.as_mut().unwrap(), None)
+        let mut chunk = self
+            .col
+            .get_chunk(self.server_chunk_ids.as_mut().unwrap(), None)?;
+        if self.peers {
+            chunk.set_usns(self.server_usn);
+        }
+        self.sent_chunks += 1;
+        self.last_chunk = Some(chunk.clone());
+
+        Ok(chunk)
     }
 
-    async fn apply_chunk(&mut self, client_chunk: Chunk) -> Result<()> {
-        self.col.apply_chunk(client_chunk, self.client_usn)
+    async fn resume_chunk(&mut self, received: usize) -> Result<Chunk> {
+        self.check_sync_open()?;
+        if received == self.sent_chunks {
+            // the request never arrived
+            self.chunk().await
+        } else if received + 1 == self.sent_chunks && self.last_chunk.is_some() {
+            Ok(self.last_chunk.clone().unwrap())
+        } else {
+            Err(AnkiError::SyncError {
+                info: format!(
+                    "client received {} chunks, but {} were sent",
+                    received, self.sent_chunks
+                ),
+                kind: SyncErrorKind::Other,
+            })
+        }
+    }
+
+    async fn apply_chunk(&mut self, mut client_chunk: Chunk) -> Result<()> {
+        if self.peers {
+            client_chunk.set_usns(self.received_usn());
+        }
+        // the server never modifies notes itself, so has no conflicts to report
+        self.col
+            .apply_chunk(client_chunk, self.client_usn)
+            .map(|_conflicts| ())
     }
 
     async fn sanity_check(&mut self, mut client: SanityCheckCounts) -> Result<SanityCheckOut> {
         client.counts = Default::default();
-        let server = self.col.storage.sanity_check_info()?;
+        let server = if self.peers {
+            // the graves are those of the collection's own server
+            SanityCheckCounts {
+                graves: client.graves,
+                ..self.col.storage.sanity_check_counts()?
+            }
+        } else {
+            self.col.storage.sanity_check_info()?
+        };
+        let (status, details) = if client == server {
+            (SanityCheckStatus::Ok, None)
+        } else {
+            let details = self.col.sanity_check_details()?;
+            (SanityCheckStatus::Bad, Some(details))
+        };
         Ok(SanityCheckOut {
-            status: if client == server {
-                SanityCheckStatus::Ok
-            } else {
-                SanityCheckStatus::Bad
-            },
+            status,
             client: Some(client),
             server: Some(server),
+            details,
         })
     }
 
+    async fn sanity_check_ids(&mut self, digests: IDDigests) -> Result<DigestIDs> {
+        self.col.sanity_check_digest_ids(&digests)
+    }
+
     async fn finish(&mut self) -> Result<TimestampMillis> {
         let now = TimestampMillis::now();
         self.col.storage.set_modified_time(now)?;
-        self.col.storage.set_last_sync(now)?;
-        self.col.storage.increment_usn()?;
+        if self.peers {
+            // the client has the changes it sent
+            self.col.storage.mark_peer_changes(self.server_usn)?;
+            self.col
+                .set_config(ConfigKey::PeerUsn, &Usn(self.server_usn.0 + 1))?;
+        } else {
+            self.col.storage.set_last_sync(now)?;
+            self.col.storage.increment_usn()?;
+        }
         self.col.storage.commit_rust_trx()?;
         Ok(now)
     }
@@ -148,33 +331,67 @@ impl SyncServer for LocalServer {
     /// `col_path` should point to the uploaded file, and the caller is
     /// responsible for imposing limits on its size if it wishes.
     /// If `can_consume` is true, the provided file will be moved into place,
-    /// or removed on failure. If false, the original will be left alone.
+    /// or removed on failure, so it should be on the same filesystem as the
+    /// collection. If false, the original will be left alone.
+    ///
+    /// The existing collection is backed up into the backups folder next to
+    /// it before it is replaced.
     async fn full_upload(
         mut self: Box<Self>,
         mut col_path: &Path,
         can_consume: bool,
     ) -> Result<()> {
+        let target_col_path = self.col.col_path.clone();
+        let col_folder = target_col_path.parent().unwrap_or_else(|| Path::new(""));
+
         // create a copy if necessary
         let new_file: NamedTempFile;
         if !can_consume {
-            new_file = NamedTempFile::new()?;
+            new_file = NamedTempFile::new_in(col_folder)?;
             fs::copy(col_path, &new_file.path())?;
             col_path = new_file.path();
         }
 
-        open_and_check_sqlite_file(col_path).map_err(|check_err| {
-            match fs::remove_file(col_path) {
-                Ok(_) => check_err,
-                Err(remove_err) => remove_err.into(),
-            }
-        })?;
+        let db =
+            open_and_check_sqlite_file(col_path).map_err(|check_err| {
+                match fs::remove_file(col_path) {
+                    Ok(_) => check_err,
+                    Err(remove_err) => remove_err.into(),
+                }
+            })?;
+        // the replacement is served under the same ID
+        let server_id = self.col.server_id()?;
+        set_config_in_file(&db, &[(ConfigKey::ServerID, Some(server_id.as_str()))])?;
+        drop(db);
 
-        let target_col_path = self.col.col_path.clone();
+        self.col
+            .create_backup(col_folder.join("backups"), BackupLimits::default())?
+            .join()
+            .map_err(|_| AnkiError::sync_misc("backup failed"))??;
+
+        let (media_folder, media_db) = (self.col.media_folder.clone(), self.col.media_db.clone());
+        let (i18n, log) = (self.col.i18n.clone(), self.col.log.clone());
         self.col.close(false)?;
-        fs::rename(col_path, &target_col_path).map_err(Into::into)
+        fs::rename(col_path, &target_col_path)?;
+
+        if self.peers {
+            // the client has all the uploaded objects
+            let mut col =
+                open_collection(target_col_path, media_folder, media_db, true, i18n, log)?;
+            col.transact(None, |col| {
+                let usn = col.storage.usn(true)?;
+                col.storage.mark_peer_changes(Usn(usn.0 - 1))?;
+                col.set_config(ConfigKey::PeerUsn, &usn)
+            })?;
+            col.close(false)?;
+        }
+        Ok(())
     }
 
     async fn full_download(mut self: Box<Self>) -> Result<NamedTempFile> {
+        if self.peers {
+            return self.full_download_for_peer();
+        }
         // bump usn/mod & close
         self.col.transact(None, |col| col.storage.increment_usn())?;
         let col_path = self.col.col_path.clone();
@@ -187,3 +404,38 @@ impl SyncServer for LocalServer {
         Ok(temp_file)
     }
 }
+
+impl LocalServer {
+    /// The profile is copied as it is, and the copy is given the state of
+    /// one that has synced with it.
+    fn full_download_for_peer(mut self) -> Result<NamedTempFile> {
+        let usn = self.col.transact(None, |col| {
+            let usn = col.peer_usn();
+            col.storage.mark_peer_changes(usn)?;
+            let usn = Usn(usn.0 + 1);
+            col.set_config(ConfigKey::PeerUsn, &usn)?;
+            Ok(usn)
+        })?;
+        let col_path = self.col.col_path.clone();
+        let (i18n, log) = (self.col.i18n.clone(), self.col.log.clone());
+        self.col.close(false)?;
+
+        let temp_file = NamedTempFile::new()?;
+        fs::copy(&col_path, temp_file.path())?;
+        let mut copy = open_collection(
+            temp_file.path().into(),
+            PathBuf::new(),
+            PathBuf::new(),
+            true,
+            i18n,
+            log,
+        )?;
+        copy.transact(None, |col| {
+            col.storage.prepare_copy_for_peer(usn)?;
+            col.remove_config(ConfigKey::PeerUsn)
+        })?;
+        copy.close(true)?;
+
+        Ok(temp_file)
+    }
+}
@@ -5,13 +5,25 @@ use crate::{
     collection::Collection,
     err::{AnkiError, Result},
     notes::{NoteID, TransformNoteOutput},
+    notetype::{NoteType, NoteTypeID},
     text::to_re,
     {text::normalize_to_nfc, types::Usn},
 };
 use regex::{NoExpand, Regex, Replacer};
-use std::{borrow::Cow, collections::HashSet};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 use unicase::UniCase;
 
+/// Tag changes only require cards to be generated if the notetype's templates
+/// compare tags. The result is cached for each notetype.
+fn tags_affect_cards(nt: &NoteType, cache: &mut HashMap<NoteTypeID, bool>) -> bool {
+    *cache
+        .entry(nt.id)
+        .or_insert_with(|| nt.compared_keys().contains("Tags"))
+}
+
 pub(crate) fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
     tags.split(is_tag_separator).filter(|tag| !tag.is_empty())
 }
@@ -104,8 +116,9 @@ impl Collection {
         tags: &[Regex],
         mut repl: R,
     ) -> Result<usize> {
+        let mut affects_cards = HashMap::new();
         self.transact(None, |col| {
-            col.transform_notes(nids, |note, _nt| {
+            col.transform_notes(nids, |note, nt| {
                 let mut changed = false;
                 for re in tags {
                     if note.replace_tags(re, repl.by_ref()) {
@@ -115,7 +128,7 @@ impl Collection {
 
                 Ok(TransformNoteOutput {
                     changed,
-                    generate_cards: false,
+                    generate_cards: changed && tags_affect_cards(nt, &mut affects_cards),
                     mark_modified: true,
                 })
             })
@@ -156,8 +169,9 @@ impl Collection {
         )
         .map_err(|_| AnkiError::invalid_input("invalid regex"))?;
 
+        let mut affects_cards = HashMap::new();
         self.transact(None, |col| {
-            col.transform_notes(nids, |note, _nt| {
+            col.transform_notes(nids, |note, nt| {
                 let mut need_to_add = true;
                 let mut match_count = 0;
                 for tag in &note.tags {
@@ -176,7 +190,7 @@ impl Collection {
 
                 Ok(TransformNoteOutput {
                     changed: need_to_add,
-                    generate_cards: false,
+                    generate_cards: need_to_add && tags_affect_cards(nt, &mut affects_cards),
                     mark_modified: true,
                 })
             })
@@ -263,6 +277,21 @@ mod test {
         let note = col.storage.get_note(note.id)?.unwrap();
         assert_eq!(&note.tags, &["cee"]);
 
+        // cards are generated when templates compare tags
+        let mut nt = col
+            .get_notetype_by_name("Basic (and reversed card)")?
+            .unwrap()
+            .as_ref()
+            .clone();
+        nt.templates[1].config.q_format = "{{#Tags:has=both}}{{Back}}{{/Tags}}".into();
+        col.update_notetype(&mut nt, false)?;
+        let mut note = nt.new_note();
+        note.fields = vec!["1".into(), "2".into()];
+        col.add_note(&mut note, DeckID(1))?;
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 1);
+        col.add_tags_for_notes(&[note.id], "both")?;
+        assert_eq!(col.storage.all_cards_of_note(note.id)?.len(), 2);
+
         Ok(())
     }
 }
@@ -3,7 +3,12 @@
 
 use crate::err::{AnkiError, Result, TemplateError};
 use crate::i18n::{tr_args, tr_strs, I18n, TR};
-use crate::{cloze::add_cloze_numbers_in_string, template_filters::apply_filters};
+use crate::{
+    cloze::add_cloze_numbers_in_string,
+    image_occlusion::{add_mask_groups_in_string, uses_image_occlusion},
+    template_filters::{apply_filters, FilterRegistry},
+    text::strip_html,
+};
 use lazy_static::lazy_static;
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_until};
@@ -13,8 +18,9 @@ use nom::{
 };
 use regex::Regex;
 use std::collections::{HashMap, HashSet};
-use std::fmt::Write;
+use std::fmt::{self, Write};
 use std::{borrow::Cow, iter};
+use unicase::eq as uni_eq;
 
 pub type FieldMap<'a> = HashMap<&'a str, u16>;
 type TemplateResult<T> = std::result::Result<T, TemplateError>;
@@ -182,6 +188,121 @@ enum ParsedNode {
         key: String,
         children: Vec<ParsedNode>,
     },
+    /// A conditional that compares the key's content, such as
+    /// `{{#Tags:has=verb}}` or `{{^Card:ord=2}}`.
+    ComparisonConditional {
+        key: String,
+        comparison: Comparison,
+        negated: bool,
+        children: Vec<ParsedNode>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Comparison {
+    /// Content without HTML equals the value, ignoring case.
+    Is(String),
+    /// One of the space-separated words in the content equals the value,
+    /// ignoring case. Mainly intended for tags.
+    Has(String),
+    /// The card's ordinal, starting at 1, equals the value. The key is not
+    /// used, and is conventionally `Card`.
+    Ord(u16),
+}
+
+impl Comparison {
+    /// Returns None if the result depends on content that is not known.
+    fn evaluate(&self, content: Option<&str>, card_ord: u16) -> Option<bool> {
+        match self {
+            Comparison::Is(value) => {
+                content.map(|text| uni_eq(strip_html(text).trim(), value.as_str()))
+            }
+            Comparison::Has(value) => content.map(|text| {
+                strip_html(text)
+                    .split_whitespace()
+                    .any(|word| uni_eq(word, value.as_str()))
+            }),
+            Comparison::Ord(ord) => Some(card_ord + 1 == *ord),
+        }
+    }
+
+    /// True if the comparison can only match when the key is non-empty.
+    fn requires_nonempty(&self) -> bool {
+        match self {
+            Comparison::Is(value) | Comparison::Has(value) => !value.is_empty(),
+            Comparison::Ord(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Comparison::Is(value) => write!(f, "is={}", value),
+            Comparison::Has(value) => write!(f, "has={}", value),
+            Comparison::Ord(ord) => write!(f, "ord={}", ord),
+        }
+    }
+}
+
+/// Split a conditional like `Tags:has=verb` into its key and comparison.
+/// Returns None if the conditional is a plain field reference.
+fn parse_comparison(text: &str) -> Option<(&str, Comparison)> {
+    let colon = text.find(':')?;
+    let (key, condition) = (text[..colon].trim(), &text[colon + 1..]);
+    let equals = condition.find('=')?;
+    let (op, value) = (condition[..equals].trim(), &condition[equals + 1..]);
+    let comparison = match op {
+        "is" => Comparison::Is(value.trim().into()),
+        "has" => Comparison::Has(value.trim().into()),
+        "ord" => Comparison::Ord(value.trim().parse().ok()?),
+        _ => return None,
+    };
+    Some((key, comparison))
+}
+
+/// Special fields other than Tags depend on the card, so their content is
+/// not known when deciding which cards to generate, and can't be compared.
+fn is_unsupported_comparison(text: &str) -> bool {
+    match parse_comparison(text) {
+        Some((key, comparison)) => {
+            key != "Tags" && SPECIAL_FIELDS.contains(&key) && comparison.requires_nonempty()
+        }
+        None => false,
+    }
+}
+
+fn conditional_node(
+    text: &str,
+    negated: bool,
+    children: Vec<ParsedNode>,
+) -> TemplateResult<ParsedNode> {
+    if is_unsupported_comparison(text) {
+        return Err(TemplateError::UnsupportedComparison(text.to_string()));
+    }
+    Ok(if let Some((key, comparison)) = parse_comparison(text) {
+        ParsedNode::ComparisonConditional {
+            key: key.into(),
+            comparison,
+            negated,
+            children,
+        }
+    } else if negated {
+        ParsedNode::NegatedConditional {
+            key: text.into(),
+            children,
+        }
+    } else {
+        ParsedNode::Conditional {
+            key: text.into(),
+            children,
+        }
+    })
+}
+
+/// A comparison may be closed with either its full text, or just its key.
+fn closing_tag_matches(open: &str, close: &str) -> bool {
+    open == close || matches!(parse_comparison(open), Some((key, _)) if key == close)
 }
 
 #[derive(Debug)]
@@ -212,17 +333,11 @@ fn parse_inner<'a, I: Iterator<Item = TemplateResult<Token<'a>>>>(
                     filters: it.map(Into::into).collect(),
                 }
             }
-            OpenConditional(t) => ParsedNode::Conditional {
-                key: t.into(),
-                children: parse_inner(iter, Some(t))?,
-            },
-            OpenNegated(t) => ParsedNode::NegatedConditional {
-                key: t.into(),
-                children: parse_inner(iter, Some(t))?,
-            },
+            OpenConditional(t) => conditional_node(t, false, parse_inner(iter, Some(t))?)?,
+            OpenNegated(t) => conditional_node(t, true, parse_inner(iter, Some(t))?)?,
             CloseConditional(t) => {
                 let currently_open = if let Some(open) = open_tag {
-                    if open == t {
+                    if closing_tag_matches(open, t) {
                         // matching closing tag, move back to parent
                         return Ok(nodes);
                     } else {
@@ -262,7 +377,7 @@ fn template_error_to_anki_error(err: TemplateError, q_side: bool, i18n: &I18n) -
     AnkiError::TemplateError { info }
 }
 
-fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
+pub(crate) fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
     match err {
         TemplateError::NoClosingBrackets(tag) => i18n.trn(
             TR::CardTemplateRenderingNoClosingBrackets,
@@ -300,20 +415,46 @@ fn localized_template_error(i18n: &I18n, err: TemplateError) -> String {
             "found"=>format!("{{{{{}{}}}}}", filters, field),
             "field"=>field),
         ),
+        TemplateError::UnsupportedComparison(tag) => i18n.trn(
+            TR::CardTemplateRenderingUnsupportedComparison,
+            tr_strs!("found"=>format!("{{{{#{}}}}}", tag)),
+        ),
     }
 }
 
 // Checking if template is empty
 //----------------------------------------
 
+/// Content used to evaluate comparisons when checking if a template is empty.
+pub(crate) struct ComparisonValues<'a> {
+    /// Field content, and any special fields like Tags that are available.
+    pub fields: &'a HashMap<&'a str, Cow<'a, str>>,
+    pub card_ord: u16,
+}
+
+/// Special fields that are not in nonempty_fields, but may have content.
+pub(crate) const SPECIAL_FIELDS: &[&str] = &["Tags", "Type", "Deck", "Subdeck", "CardFlag", "Card"];
+
 impl ParsedTemplate {
-    /// true if provided fields are sufficient to render the template
+    /// true if provided fields are sufficient to render the template.
+    /// As field content is not known, comparisons are assumed to match
+    /// where possible.
     pub fn renders_with_fields(&self, nonempty_fields: &HashSet<&str>) -> bool {
-        !template_is_empty(nonempty_fields, &self.0, true)
+        !template_is_empty(nonempty_fields, &self.0, true, None)
     }
 
     pub fn renders_with_fields_for_reqs(&self, nonempty_fields: &HashSet<&str>) -> bool {
-        !template_is_empty(nonempty_fields, &self.0, false)
+        !template_is_empty(nonempty_fields, &self.0, false, None)
+    }
+
+    /// Like renders_with_fields(), but evaluates comparisons with the
+    /// provided content.
+    pub(crate) fn renders_with_values(
+        &self,
+        nonempty_fields: &HashSet<&str>,
+        values: &ComparisonValues,
+    ) -> bool {
+        !template_is_empty(nonempty_fields, &self.0, true, Some(values))
     }
 }
 
@@ -324,6 +465,7 @@ fn template_is_empty(
     nonempty_fields: &HashSet<&str>,
     nodes: &[ParsedNode],
     check_negated: bool,
+    values: Option<&ComparisonValues>,
 ) -> bool {
     use ParsedNode::*;
     for node in nodes {
@@ -340,7 +482,7 @@ fn template_is_empty(
                 if !nonempty_fields.contains(key.as_str()) {
                     continue;
                 }
-                if !template_is_empty(nonempty_fields, children, check_negated) {
+                if !template_is_empty(nonempty_fields, children, check_negated, values) {
                     return false;
                 }
             }
@@ -349,7 +491,34 @@ fn template_is_empty(
                     continue;
                 }
 
-                if !template_is_empty(nonempty_fields, children, check_negated) {
+                if !template_is_empty(nonempty_fields, children, check_negated, values) {
+                    return false;
+                }
+            }
+            ComparisonConditional {
+                key,
+                comparison,
+                negated,
+                children,
+            } => {
+                let evaluated = values.and_then(|values| {
+                    comparison.evaluate(
+                        values.fields.get(key.as_str()).map(AsRef::as_ref),
+                        values.card_ord,
+                    )
+                });
+                let may_match = match evaluated {
+                    Some(matched) => matched != *negated,
+                    // without content, only an empty field rules out a match
+                    None => {
+                        *negated
+                            || !comparison.requires_nonempty()
+                            || nonempty_fields.contains(key.as_str())
+                            || SPECIAL_FIELDS.contains(&key.as_str())
+                    }
+                };
+                if may_match && !template_is_empty(nonempty_fields, children, check_negated, values)
+                {
                     return false;
                 }
             }
@@ -380,6 +549,8 @@ pub(crate) struct RenderContext<'a> {
     pub nonempty_fields: &'a HashSet<&'a str>,
     pub question_side: bool,
     pub card_ord: u16,
+    /// Filters that can be used in addition to the built-in ones.
+    pub filters: &'a FilterRegistry,
 }
 
 impl ParsedTemplate {
@@ -477,6 +648,22 @@ fn render_into(
                     render_into(rendered_nodes, children.as_ref(), context)?;
                 }
             }
+            ComparisonConditional {
+                key,
+                comparison,
+                negated,
+                children,
+            } => {
+                let matched = comparison
+                    .evaluate(
+                        context.fields.get(key.as_str()).map(AsRef::as_ref),
+                        context.card_ord,
+                    )
+                    .unwrap_or(false);
+                if matched != *negated {
+                    render_into(rendered_nodes, children.as_ref(), context)?;
+                }
+            }
         };
     }
 
@@ -542,6 +729,7 @@ pub fn render_card(
     field_map: &HashMap<&str, Cow<str>>,
     card_ord: u16,
     is_cloze: bool,
+    filters: &FilterRegistry,
     i18n: &I18n,
 ) -> Result<(Vec<RenderedNode>, Vec<RenderedNode>)> {
     // prepare context
@@ -550,6 +738,7 @@ pub fn render_card(
         nonempty_fields: &nonempty_fields(field_map),
         question_side: true,
         card_ord,
+        filters,
     };
 
     // question side
@@ -559,7 +748,7 @@ pub fn render_card(
 
     // check if the front side was empty
     if is_cloze {
-        if cloze_is_empty(field_map, card_ord) {
+        if cloze_is_empty(field_map, card_ord, uses_image_occlusion(qfmt)) {
             let info = format!(
                 "<div>{}<br><a href='{}'>{}</a></div>",
                 i18n.trn(
@@ -571,7 +760,13 @@ pub fn render_card(
             );
             qnodes.push(RenderedNode::Text { text: info });
         }
-    } else if !qtmpl.renders_with_fields(context.nonempty_fields) {
+    } else if !qtmpl.renders_with_values(
+        context.nonempty_fields,
+        &ComparisonValues {
+            fields: field_map,
+            card_ord,
+        },
+    ) {
         let info = format!(
             "<div>{}<br><a href='{}'>{}</a></div>",
             i18n.tr(TR::CardTemplateRenderingEmptyFront),
@@ -590,10 +785,13 @@ pub fn render_card(
     Ok((qnodes, anodes))
 }
 
-fn cloze_is_empty(field_map: &HashMap<&str, Cow<str>>, card_ord: u16) -> bool {
+fn cloze_is_empty(field_map: &HashMap<&str, Cow<str>>, card_ord: u16, masks: bool) -> bool {
     let mut set = HashSet::with_capacity(4);
     for field in field_map.values() {
         add_cloze_numbers_in_string(field.as_ref(), &mut set);
+        if masks {
+            add_mask_groups_in_string(field.as_ref(), &mut set);
+        }
     }
     !set.contains(&(card_ord + 1))
 }
@@ -651,6 +849,90 @@ impl ParsedTemplate {
     }
 }
 
+// Locating tags
+//----------------------------------------
+
+/// A field referenced by a replacement or conditional.
+#[derive(Debug, PartialEq)]
+pub(crate) struct FieldReference<'a> {
+    /// Byte offset of the start of the tag in the template.
+    pub offset: usize,
+    pub key: &'a str,
+    /// Filters in the order they're applied. Always empty for conditionals.
+    pub filters: Vec<&'a str>,
+}
+
+/// Byte offset of the handlebars surrounding `inner`, which must be a slice
+/// of `template`.
+fn tag_offset(template: &str, inner: &str) -> usize {
+    let inner_offset = inner.as_ptr() as usize - template.as_ptr() as usize;
+    let before = &template[..inner_offset];
+    before
+        .rfind("{{")
+        .into_iter()
+        .chain(before.rfind("<%"))
+        .max()
+        .unwrap_or(inner_offset)
+}
+
+/// Iterate over the template's tokens, stopping at the first error.
+fn valid_tokens<'a>(template: &'a str) -> impl Iterator<Item = Token<'a>> + 'a {
+    tokens(template)
+        .take_while(|token| token.is_ok())
+        .filter_map(|token| token.ok())
+}
+
+/// Fields referenced in a template, up to the first unclosed tag.
+pub(crate) fn field_references(template: &str) -> Vec<FieldReference> {
+    valid_tokens(template)
+        .filter_map(|token| match token {
+            Token::Replacement(t) => {
+                let mut it = t.rsplit(':');
+                Some(FieldReference {
+                    offset: tag_offset(template, t),
+                    key: it.next().unwrap(),
+                    filters: it.collect(),
+                })
+            }
+            Token::OpenConditional(t) | Token::OpenNegated(t) => Some(FieldReference {
+                offset: tag_offset(template, t),
+                key: parse_comparison(t).map(|(key, _)| key).unwrap_or(t),
+                filters: vec![],
+            }),
+            Token::Text(_) | Token::CloseConditional(_) => None,
+        })
+        .collect()
+}
+
+/// Byte offset of the tag that caused parsing to fail, if the template
+/// is invalid.
+pub(crate) fn template_error_offset(template: &str) -> Option<usize> {
+    let mut open_tags: Vec<&str> = vec![];
+    for token in tokens(template) {
+        match token {
+            Err(TemplateError::NoClosingBrackets(remaining)) => {
+                return Some(template.len() - remaining.len());
+            }
+            Err(_) => return None,
+            Ok(Token::OpenConditional(t)) | Ok(Token::OpenNegated(t)) => {
+                if is_unsupported_comparison(t) {
+                    return Some(tag_offset(template, t));
+                }
+                open_tags.push(t)
+            }
+            Ok(Token::CloseConditional(t)) => match open_tags.last() {
+                Some(open) if closing_tag_matches(open, t) => {
+                    open_tags.pop();
+                }
+                _ => return Some(tag_offset(template, t)),
+            },
+            Ok(_) => (),
+        }
+    }
+    // the innermost unclosed conditional
+    open_tags.last().map(|t| tag_offset(template, t))
+}
+
 // Renaming & deleting fields
 //----------------------------------------
 
@@ -715,6 +997,25 @@ fn rename_and_remove_fields(
                     None => out.push(ParsedNode::NegatedConditional { key, children }),
                 }
             }
+            ParsedNode::ComparisonConditional {
+                key,
+                comparison,
+                negated,
+                children,
+            } => {
+                let children = rename_and_remove_fields(children, fields);
+                match fields.get(&key) {
+                    // remove the field, preserving children
+                    Some(None) => out.extend(children),
+                    // rename it, or leave it alone
+                    other => out.push(ParsedNode::ComparisonConditional {
+                        key: other.cloned().flatten().unwrap_or(key),
+                        comparison,
+                        negated,
+                        children,
+                    }),
+                }
+            }
         }
     }
     out
@@ -759,6 +1060,17 @@ fn nodes_to_string(buf: &mut String, nodes: &[ParsedNode]) {
                 nodes_to_string(buf, &children);
                 write!(buf, "{{{{/{}}}}}", key).unwrap();
             }
+            ParsedNode::ComparisonConditional {
+                key,
+                comparison,
+                negated,
+                children,
+            } => {
+                let kind = if *negated { '^' } else { '#' };
+                write!(buf, "{{{{{}{}:{}}}}}", kind, key, comparison).unwrap();
+                nodes_to_string(buf, &children);
+                write!(buf, "{{{{/{}:{}}}}}", key, comparison).unwrap();
+            }
         }
     }
 }
@@ -776,6 +1088,43 @@ impl ParsedTemplate {
     }
 }
 
+// Detecting compared keys
+//----------------------------------------
+
+impl ParsedTemplate {
+    /// Keys whose content is compared in conditionals, so that changing
+    /// their content may change whether the template renders, even when
+    /// their emptiness stays the same.
+    pub(crate) fn compared_keys(&self) -> HashSet<&str> {
+        let mut set = HashSet::new();
+        find_compared_keys(&self.0, &mut set);
+        set
+    }
+}
+
+fn find_compared_keys<'a>(nodes: &'a [ParsedNode], keys: &mut HashSet<&'a str>) {
+    for node in nodes {
+        match node {
+            ParsedNode::Text(_) | ParsedNode::Replacement { .. } => {}
+            ParsedNode::Conditional { children, .. }
+            | ParsedNode::NegatedConditional { children, .. } => {
+                find_compared_keys(&children, keys);
+            }
+            ParsedNode::ComparisonConditional {
+                key,
+                comparison,
+                children,
+                ..
+            } => {
+                if !matches!(comparison, Comparison::Ord(_)) {
+                    keys.insert(key);
+                }
+                find_compared_keys(&children, keys);
+            }
+        }
+    }
+}
+
 fn find_fields_with_filter<'a>(
     nodes: &'a [ParsedNode],
     fields: &mut HashSet<&'a str>,
@@ -795,6 +1144,9 @@ fn find_fields_with_filter<'a>(
             ParsedNode::NegatedConditional { children, .. } => {
                 find_fields_with_filter(&children, fields, filter);
             }
+            ParsedNode::ComparisonConditional { children, .. } => {
+                find_fields_with_filter(&children, fields, filter);
+            }
         }
     }
 }
@@ -962,6 +1314,126 @@ mod test {
         );
     }
 
+    #[test]
+    fn comparisons() {
+        use crate::template::{
+            template_error_offset, Comparison, ComparisonValues, RenderedNode as FN,
+        };
+        use std::borrow::Cow;
+
+        let tmpl = PT::from_text("{{#Tags:has=verb}}{{F}}{{/Tags}}{{^Card:ord=2}}x{{/Card:ord=2}}")
+            .unwrap();
+        assert_eq!(
+            tmpl.0,
+            vec![
+                ComparisonConditional {
+                    key: "Tags".into(),
+                    comparison: Comparison::Has("verb".into()),
+                    negated: false,
+                    children: vec![Replacement {
+                        key: "F".into(),
+                        filters: vec![]
+                    }]
+                },
+                ComparisonConditional {
+                    key: "Card".into(),
+                    comparison: Comparison::Ord(2),
+                    negated: true,
+                    children: vec![Text("x".into())]
+                }
+            ]
+        );
+        assert_eq!(
+            tmpl.template_to_string(),
+            "{{#Tags:has=verb}}{{F}}{{/Tags:has=verb}}{{^Card:ord=2}}x{{/Card:ord=2}}"
+        );
+        // ordinal comparisons don't depend on content
+        assert_eq!(tmpl.compared_keys(), vec!["Tags"].into_iter().collect());
+        // unknown operators are treated as field names
+        assert_eq!(
+            PT::from_text("{{#F:foo=bar}}{{/F:foo=bar}}").unwrap().0,
+            vec![Conditional {
+                key: "F:foo=bar".into(),
+                children: vec![]
+            }]
+        );
+        PT::from_text("{{#Tags:has=verb}}{{/F}}").unwrap_err();
+
+        // rendering
+        let map: HashMap<_, Cow<str>> = vec![
+            ("F", "<b>Yes</b>"),
+            ("B", ""),
+            ("Tags", "noun Verb"),
+            ("Deck", "Spanish::Verbs"),
+        ]
+        .into_iter()
+        .map(|r| (r.0, r.1.into()))
+        .collect();
+        let ctx = RenderContext {
+            fields: &map,
+            nonempty_fields: &nonempty_fields(&map),
+            question_side: true,
+            card_ord: 1,
+            filters: &Default::default(),
+        };
+        let render = |text: &str| match PT::from_text(text).unwrap().render(&ctx).unwrap().pop() {
+            Some(FN::Text { text }) => text,
+            None => "".into(),
+            _ => unreachable!(),
+        };
+        assert_eq!(render("{{#Tags:has=verb}}1{{/Tags}}"), "1");
+        assert_eq!(render("{{#Tags:has=adj}}1{{/Tags}}"), "");
+        assert_eq!(render("{{^Tags:has=adj}}1{{/Tags}}"), "1");
+        assert_eq!(render("{{#F:is=yes}}1{{/F}}"), "1");
+        assert_eq!(render("{{#B:is=yes}}1{{/B}}"), "");
+        // other special fields depend on the card, so can't be compared
+        assert_eq!(
+            PT::from_text("{{#Deck:is=spanish::verbs}}1{{/Deck}}").unwrap_err(),
+            TemplateError::UnsupportedComparison("Deck:is=spanish::verbs".into())
+        );
+        assert_eq!(template_error_offset("x{{^CardFlag:is=flag1}}"), Some(1));
+        assert_eq!(
+            render("{{#Card:ord=2}}1{{/Card}}{{#Card:ord=1}}2{{/Card}}"),
+            "1"
+        );
+        assert_eq!(render("{{#Missing:is=x}}1{{/Missing}}"), "");
+
+        // without content, comparisons may match if their field is nonempty
+        let field_map: FieldMap = vec!["a", "b"]
+            .iter()
+            .enumerate()
+            .map(|(a, b)| (*b, a as u16))
+            .collect();
+        let tmpl = PT::from_text("{{#a:is=x}}{{b}}{{/a:is=x}}").unwrap();
+        assert_eq!(
+            tmpl.requirements(&field_map),
+            FieldRequirements::All(HashSet::from_iter(vec![0, 1].into_iter()))
+        );
+        let tmpl = PT::from_text("{{#Tags:has=verb}}{{a}}{{/Tags}}").unwrap();
+        assert_eq!(
+            tmpl.requirements(&field_map),
+            FieldRequirements::Any(HashSet::from_iter(vec![0].into_iter()))
+        );
+
+        // but are evaluated when it is available
+        let nonempty = HashSet::from_iter(vec!["a"].into_iter());
+        assert!(tmpl.renders_with_fields(&nonempty));
+        let mut fields: HashMap<_, Cow<str>> = HashMap::new();
+        fields.insert("a", "a".into());
+        fields.insert("Tags", "noun".into());
+        let values = ComparisonValues {
+            fields: &fields,
+            card_ord: 0,
+        };
+        assert!(!tmpl.renders_with_values(&nonempty, &values));
+        fields.insert("Tags", "noun verb".into());
+        let values = ComparisonValues {
+            fields: &fields,
+            card_ord: 0,
+        };
+        assert!(tmpl.renders_with_values(&nonempty, &values));
+    }
+
     #[test]
     fn alt_syntax() {
         let input = "
@@ -1022,6 +1494,7 @@ mod test {
             nonempty_fields: &nonempty_fields(&map),
             question_side: true,
             card_ord: 1,
+            filters: &Default::default(),
         };
 
         use crate::template::RenderedNode as FN;
@@ -1129,9 +1602,10 @@ mod test {
         let i18n = I18n::new(&[""], "", log::terminal());
         use crate::template::RenderedNode as FN;
 
-        let qnodes = super::render_card("test{{E}}", "", &map, 1, false, &i18n)
-            .unwrap()
-            .0;
+        let qnodes =
+            super::render_card("test{{E}}", "", &map, 1, false, &Default::default(), &i18n)
+                .unwrap()
+                .0;
         assert_eq!(
             qnodes[0],
             FN::Text {
@@ -1,21 +1,173 @@
 // Copyright: Ankitects Pty Ltd and contributors
 // License: GNU AGPL, version 3 or later; http://www.gnu.org/licenses/agpl.html
 
-use crate::cloze::{cloze_filter, cloze_only_filter};
+use crate::cloze::{cloze_filter, cloze_only_filter, strip_clozes};
+use crate::err::{AnkiError, Result};
+use crate::image_occlusion::image_occlusion_filter;
 use crate::template::RenderContext;
-use crate::text::strip_html;
+use crate::text::{map_text_outside_tags, strip_html};
 use blake3::Hasher;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap, fmt, sync::Arc};
+
+// Registry
+//----------------------------------------
+
+/// Filters handled by apply_filter(), which registered filters can not replace.
+const CORE_FILTERS: &[&str] = &[
+    "text",
+    "furigana",
+    "kanji",
+    "kana",
+    "type",
+    "type-cloze",
+    "hint",
+    "cloze",
+    "cloze-only",
+    "image-occlusion",
+    "tts",
+];
+
+/// Information about the field being filtered.
+pub struct FilterContext<'a> {
+    /// Text between the filter name and the field, eg '20' in
+    /// 'truncate:20:Field'. Empty if no argument was provided, or the filter
+    /// does not take one.
+    pub arg: &'a str,
+    pub field_name: &'a str,
+    pub question_side: bool,
+    pub card_ord: u16,
+}
+
+/// A filter that can be referred to by name in card templates.
+///
+/// Filters that are neither built in nor registered are returned to the
+/// caller unapplied, so they can be handled elsewhere.
+pub trait TemplateFilter: Send + Sync {
+    fn apply(&self, text: &str, context: &FilterContext) -> String;
+
+    /// If true, an unknown name between the filter and the field is passed
+    /// to the filter as its argument, instead of being treated as another
+    /// filter.
+    fn takes_arg(&self) -> bool {
+        false
+    }
+}
+
+impl<F> TemplateFilter for F
+where
+    F: Fn(&str, &FilterContext) -> String + Send + Sync,
+{
+    fn apply(&self, text: &str, context: &FilterContext) -> String {
+        self(text, context)
+    }
+}
+
+/// Wraps a filter that takes an argument.
+pub struct WithArg<F>(pub F);
+
+impl<F> TemplateFilter for WithArg<F>
+where
+    F: Fn(&str, &FilterContext) -> String + Send + Sync,
+{
+    fn apply(&self, text: &str, context: &FilterContext) -> String {
+        (self.0)(text, context)
+    }
+
+    fn takes_arg(&self) -> bool {
+        true
+    }
+}
+
+/// Named filters available when rendering. The default registry contains
+/// the standard extra filters; more can be added with register().
+#[derive(Clone)]
+pub struct FilterRegistry {
+    filters: HashMap<String, Arc<dyn TemplateFilter>>,
+}
+
+impl FilterRegistry {
+    /// A registry with no filters.
+    pub fn empty() -> Self {
+        FilterRegistry {
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Add a filter, replacing any existing filter with the same name.
+    /// Names may not be empty or contain spaces or colons, and the core
+    /// filters like 'cloze' can not be overridden.
+    pub fn register<F>(&mut self, name: impl Into<String>, filter: F) -> Result<()>
+    where
+        F: TemplateFilter + 'static,
+    {
+        let name = name.into();
+        if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+            return Err(AnkiError::invalid_input(format!(
+                "invalid filter name: {:?}",
+                name
+            )));
+        }
+        if CORE_FILTERS.contains(&name.as_str()) {
+            return Err(AnkiError::invalid_input(format!(
+                "can not replace core filter: {}",
+                name
+            )));
+        }
+        self.insert(&name, filter);
+        Ok(())
+    }
+
+    fn insert<F>(&mut self, name: &str, filter: F)
+    where
+        F: TemplateFilter + 'static,
+    {
+        self.filters.insert(name.into(), Arc::new(filter));
+    }
+
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.filters.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn TemplateFilter> {
+        self.filters.get(name).map(AsRef::as_ref)
+    }
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        let mut registry = FilterRegistry::empty();
+        registry.insert("lower", lower_filter);
+        registry.insert("upper", upper_filter);
+        registry.insert("nohtml", nohtml_filter);
+        registry.insert("truncate", WithArg(truncate_filter));
+        registry.insert("strip-cloze", strip_cloze_filter);
+        registry.insert("join-tags", WithArg(join_tags_filter));
+        registry
+    }
+}
+
+impl fmt::Debug for FilterRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.filters.keys().collect();
+        names.sort_unstable();
+        f.debug_struct("FilterRegistry")
+            .field("filters", &names)
+            .finish()
+    }
+}
 
 // Filtering
 //----------------------------------------
 
-/// Applies built in filters, returning the resulting text and remaining filters.
+/// Applies built in and registered filters, returning the resulting text and
+/// remaining filters.
 ///
-/// The first non-standard filter that is encountered will terminate processing,
-/// so non-standard filters must come at the end.
+/// The first unknown filter that is encountered will terminate processing,
+/// so unknown filters must come at the end. An unknown name that comes just
+/// before a filter that takes an argument is used as that argument, as in
+/// `{{truncate:20:Field}}`.
 pub(crate) fn apply_filters<'a>(
     text: &'a str,
     filters: &[&str],
@@ -31,8 +183,23 @@ pub(crate) fn apply_filters<'a>(
         filters
     };
 
+    let mut is_arg = false;
     for (idx, &filter_name) in filters.iter().enumerate() {
-        match apply_filter(filter_name, text.as_ref(), field_name, context) {
+        if std::mem::replace(&mut is_arg, false) {
+            // already applied with the preceding argument
+            continue;
+        }
+        let mut result = apply_filter(filter_name, text.as_ref(), field_name, context);
+        if !result.0 {
+            let next = filters.get(idx + 1).copied().unwrap_or_default();
+            if context.filters.get(next).map_or(false, |f| f.takes_arg()) {
+                let output =
+                    apply_registered_filter(next, filter_name, text.as_ref(), field_name, context);
+                is_arg = true;
+                result = (true, output);
+            }
+        }
+        match result {
             (true, None) => {
                 // filter did not change text
             }
@@ -74,11 +241,16 @@ fn apply_filter<'a>(
         "hint" => hint_filter(text, field_name),
         "cloze" => cloze_filter(text, context),
         "cloze-only" => cloze_only_filter(text, context),
+        "image-occlusion" => image_occlusion_filter(text, context),
         // an empty filter name (caused by using two colons) is ignored
         "" => text.into(),
         _ => {
             if filter_name.starts_with("tts ") {
                 tts_filter(filter_name, text)
+            } else if let Some(output) =
+                apply_registered_filter(filter_name, "", text, field_name, context)
+            {
+                output.into()
             } else {
                 // unrecognized filter
                 return (false, None);
@@ -95,6 +267,26 @@ fn apply_filter<'a>(
     )
 }
 
+fn apply_registered_filter(
+    filter_name: &str,
+    arg: &str,
+    text: &str,
+    field_name: &str,
+    context: &RenderContext,
+) -> Option<String> {
+    let filter = context.filters.get(filter_name)?;
+    let output = filter.apply(
+        text,
+        &FilterContext {
+            arg,
+            field_name,
+            question_side: context.question_side,
+            card_ord: context.card_ord,
+        },
+    );
+    Some(output)
+}
+
 // Ruby filters
 //----------------------------------------
 
@@ -187,6 +379,43 @@ return false;">
     .into()
 }
 
+/// HTML and sound tags are left alone, as changing their case
+/// could break references to media files.
+fn lower_filter(text: &str, _context: &FilterContext) -> String {
+    map_text_outside_tags(text, str::to_lowercase)
+}
+
+fn upper_filter(text: &str, _context: &FilterContext) -> String {
+    map_text_outside_tags(text, str::to_uppercase)
+}
+
+fn nohtml_filter(text: &str, _context: &FilterContext) -> String {
+    strip_html(text).into_owned()
+}
+
+fn strip_cloze_filter(text: &str, _context: &FilterContext) -> String {
+    strip_clozes(text).into_owned()
+}
+
+/// Keep the first N characters of the text, after removing HTML. The text is
+/// returned unchanged if no valid length was provided.
+fn truncate_filter(text: &str, context: &FilterContext) -> String {
+    match context.arg.trim().parse::<usize>() {
+        Ok(len) => strip_html(text).chars().take(len).collect(),
+        Err(_) => text.to_string(),
+    }
+}
+
+/// Join space-separated tags with the provided separator, or ', ' by default.
+fn join_tags_filter(text: &str, context: &FilterContext) -> String {
+    let separator = if context.arg.is_empty() {
+        ", "
+    } else {
+        context.arg
+    };
+    text.split_whitespace().collect::<Vec<_>>().join(separator)
+}
+
 fn tts_filter(filter_name: &str, text: &str) -> Cow<'static, str> {
     let args = filter_name.splitn(2, ' ').nth(1).unwrap_or("");
 
@@ -200,7 +429,7 @@ mod test {
     use crate::template::RenderContext;
     use crate::template_filters::{
         apply_filters, cloze_filter, furigana_filter, hint_filter, kana_filter, kanji_filter,
-        tts_filter, type_cloze_filter, type_filter,
+        tts_filter, type_cloze_filter, type_filter, FilterContext, FilterRegistry, WithArg,
     };
     use crate::text::strip_html;
 
@@ -239,6 +468,7 @@ field</a>
             nonempty_fields: &Default::default(),
             question_side: false,
             card_ord: 0,
+            filters: &Default::default(),
         };
         assert_eq!(
             apply_filters("ignored", &["cloze", "type"], "Text", &ctx),
@@ -254,6 +484,7 @@ field</a>
             nonempty_fields: &Default::default(),
             question_side: true,
             card_ord: 0,
+            filters: &Default::default(),
         };
         assert_eq!(strip_html(&cloze_filter(text, &ctx)).as_ref(), "[...] two");
         assert_eq!(
@@ -281,4 +512,81 @@ field</a>
             "[anki:tts][en_US voices=Bob,Jane]foo[/anki:tts]"
         );
     }
+
+    #[test]
+    fn registry() {
+        fn reverse(text: &str, _context: &FilterContext) -> String {
+            text.chars().rev().collect()
+        }
+        let mut filters = FilterRegistry::default();
+        filters.register("reverse", reverse).unwrap();
+        filters
+            .register(
+                "suffix",
+                WithArg(|text: &str, ctx: &FilterContext| format!("{}{}", text, ctx.arg)),
+            )
+            .unwrap();
+        // names that could not be referred to, and core filters, are rejected
+        assert!(filters.register("re verse", reverse).is_err());
+        assert!(filters.register("re:verse", reverse).is_err());
+        assert!(filters.register("", reverse).is_err());
+        assert!(filters.register("cloze", reverse).is_err());
+        let ctx = RenderContext {
+            fields: &Default::default(),
+            nonempty_fields: &Default::default(),
+            question_side: true,
+            card_ord: 0,
+            filters: &filters,
+        };
+        let apply =
+            |text: &'static str, filters: &[&str]| apply_filters(text, filters, "Front", &ctx);
+
+        assert_eq!(
+            apply("<b>Ab</b>&nbsp;[sound:Ab.mp3]", &["lower"]),
+            ("<b>ab</b>&nbsp;[sound:Ab.mp3]".into(), vec![])
+        );
+        assert_eq!(
+            apply("<img src=a.jpg>ab", &["upper"]),
+            ("<img src=a.jpg>AB".into(), vec![])
+        );
+        assert_eq!(apply("<b>ab</b>", &["nohtml"]), ("ab".into(), vec![]));
+        // arguments come between the filter and the field
+        assert_eq!(
+            apply("<b>abc</b>d", &["2", "truncate"]),
+            ("ab".into(), vec![])
+        );
+        assert_eq!(apply("abc", &["truncate"]), ("abc".into(), vec![]));
+        assert_eq!(
+            apply("{{c1::a::hint}} {{c2::b {{c3::c}}}}", &["strip-cloze"]),
+            ("a b c".into(), vec![])
+        );
+        assert_eq!(apply("a  b c", &["join-tags"]), ("a, b, c".into(), vec![]));
+        assert_eq!(apply("a b", &["/", "join-tags"]), ("a/b".into(), vec![]));
+        assert_eq!(
+            apply("a b", &["/", "join-tags", "upper"]),
+            ("A/B".into(), vec![])
+        );
+        assert_eq!(apply("a", &["!", "suffix"]), ("a!".into(), vec![]));
+        // but only for filters that take one
+        assert_eq!(
+            apply("ab", &["2", "upper"]),
+            ("ab".into(), vec!["2".to_string(), "upper".to_string()])
+        );
+
+        // registered filters can be combined with built in ones
+        assert_eq!(
+            apply("<b>ab</b>", &["text", "reverse", "upper"]),
+            ("BA".into(), vec![])
+        );
+
+        // unknown filters are returned
+        assert_eq!(
+            apply("ab", &["reverse", "foo", "upper"]),
+            ("ba".into(), vec!["foo".to_string(), "upper".to_string()])
+        );
+        let mut filters = FilterRegistry::default();
+        assert!(filters.unregister("lower"));
+        assert!(filters.get("lower").is_none());
+        assert!(filters.get("upper").is_some());
+    }
 }
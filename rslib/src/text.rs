@@ -62,6 +62,8 @@ lazy_static! {
             "#
     ).unwrap();
 
+    static ref HTML_ENTITY: Regex = Regex::new(r"&#?[[:alnum:]]+;").unwrap();
+
     // videos are also in sound tags
     static ref AV_TAGS: Regex = Regex::new(
         r#"(?xs)
@@ -112,6 +114,30 @@ pub fn strip_html_for_tts(html: &str) -> Cow<str> {
     }
 }
 
+/// Apply func to the text between any HTML tags, entities and sound tags,
+/// leaving them untouched.
+pub(crate) fn map_text_outside_tags(text: &str, func: impl Fn(&str) -> String) -> String {
+    let mut buf = String::with_capacity(text.len());
+    let mut last_end = 0;
+    let mut tags: Vec<_> = HTML
+        .find_iter(text)
+        .chain(HTML_ENTITY.find_iter(text))
+        .chain(AV_TAGS.find_iter(text))
+        .collect();
+    tags.sort_unstable_by_key(|m| m.start());
+    for tag in tags {
+        if tag.start() < last_end {
+            // overlaps a previous tag
+            continue;
+        }
+        buf.push_str(&func(&text[last_end..tag.start()]));
+        buf.push_str(tag.as_str());
+        last_end = tag.end();
+    }
+    buf.push_str(&func(&text[last_end..]));
+    buf
+}
+
 pub fn strip_av_tags(text: &str) -> Cow<str> {
     AV_TAGS.replace_all(text, "")
 }
@@ -179,6 +205,45 @@ pub(crate) fn extract_media_refs(text: &str) -> Vec<MediaRef> {
     out
 }
 
+/// Replace the filename in each image, audio or object tag with the output of
+/// func. The filename is passed as it appears in the HTML.
+pub(crate) fn replace_html_media_filenames(
+    html: &str,
+    mut func: impl FnMut(&str) -> String,
+) -> Cow<str> {
+    HTML_MEDIA_TAGS.replace_all(html, |caps: &Captures| {
+        let tag = caps.get(0).unwrap();
+        let fname = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .or_else(|| caps.get(3))
+            .unwrap();
+        let (start, end) = (fname.start() - tag.start(), fname.end() - tag.start());
+        format!(
+            "{}{}{}",
+            &tag.as_str()[..start],
+            func(fname.as_str()),
+            &tag.as_str()[end..]
+        )
+    })
+}
+
+/// Replace sound tags with audio elements, using func to convert the decoded
+/// filename to a URL. Text to speech tags are removed.
+pub(crate) fn av_tags_to_html(text: &str, mut func: impl FnMut(&str) -> String) -> Cow<str> {
+    AV_TAGS.replace_all(text, |caps: &Captures| {
+        if let Some(fname) = caps.get(1) {
+            let src = func(&decode_entities(fname.as_str()));
+            format!(
+                r#"<audio controls src="{}"></audio>"#,
+                src.replace('&', "&amp;").replace('"', "&quot;")
+            )
+        } else {
+            String::new()
+        }
+    })
+}
+
 fn tts_tag_from_string<'a>(field_text: &'a str, args: &'a str) -> AVTag {
     let mut other_args = vec![];
     let mut split_args = args.split_ascii_whitespace();
@@ -377,6 +442,11 @@ mod test {
             "abc[sound:fo&amp;o.mp3]def[anki:tts][en_US voices=Bob,Jane speed=1.2]foo<br>1&gt;2[/anki:tts]gh";
         assert_eq!(strip_av_tags(s), "abcdefgh");
 
+        assert_eq!(
+            av_tags_to_html(s, |fname| format!("media/{}", fname)),
+            r#"abc<audio controls src="media/fo&amp;o.mp3"></audio>defgh"#
+        );
+
         let (text, tags) = extract_av_tags(s, true);
         assert_eq!(text, "abc[anki:play:q:0]def[anki:play:q:1]gh");
 